@@ -13,5 +13,21 @@ fn main() {
         println!("cargo:rustc-env=BUILD_VERSION={}", clean_version);
     }
 
+    #[cfg(feature = "grpc")]
+    {
+        // Use the vendored protoc so builds don't depend on a system install.
+        // SAFETY: build scripts are single-threaded
+        unsafe {
+            env::set_var(
+                "PROTOC",
+                protoc_bin_vendored::protoc_bin_path().expect("Failed to locate vendored protoc"),
+            );
+        }
+        tonic_prost_build::configure()
+            .build_client(false)
+            .compile_protos(&["proto/wineventlog.proto"], &["proto"])
+            .expect("Failed to compile protobuf definitions");
+    }
+
     built::write_built_file().expect("Failed to acquire build-time information")
 }
@@ -0,0 +1,189 @@
+use windows::Win32::Foundation::{E_ACCESSDENIED, HLOCAL, LocalFree};
+use windows::Win32::Security::Authorization::{
+    ConvertSidToStringSidW, ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1,
+};
+use windows::Win32::Security::*;
+use windows::Win32::System::EventLog::*;
+use windows::core::{HSTRING, PCWSTR, PWSTR, w};
+
+// ACE types (winnt.h)
+const ACCESS_ALLOWED_ACE_TYPE: u8 = 0;
+const ACCESS_DENIED_ACE_TYPE: u8 = 1;
+
+// Channel access rights (winevt.h), followed by the standard rights
+const RIGHTS: [(u32, &str); 8] = [
+    (0x1, "read"),
+    (0x2, "write"),
+    (0x4, "clear"),
+    (0x1_0000, "delete"),
+    (0x2_0000, "read-control"),
+    (0x4_0000, "write-dac"),
+    (0x8_0000, "write-owner"),
+    (0x1000_0000, "generic-all"),
+];
+
+/// Prints a channel's access control list with account names and channel
+/// rights, and whether the current process can read it.
+pub fn show(channel: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let sddl = channel_sddl(channel)?;
+    println!("Channel: {}", channel);
+    println!("SDDL:    {}", sddl);
+    println!();
+
+    for ace in unsafe { aces(&sddl)? } {
+        println!("{:<6} {:<45} {}", ace.kind, ace.account, rights(ace.mask));
+    }
+    println!();
+
+    // Actually opening the channel also accounts for privileges such as
+    // SeSecurityPrivilege, which the Security channel relies on
+    match can_read(channel) {
+        Ok(()) => println!("Current process can read this channel: yes"),
+        Err(e) if e.code() == E_ACCESSDENIED => {
+            println!("Current process can read this channel: no (access denied)")
+        }
+        Err(e) => println!("Current process can read this channel: unknown ({})", e),
+    }
+    Ok(())
+}
+
+fn channel_sddl(channel: &str) -> Result<String, Box<dyn std::error::Error>> {
+    unsafe {
+        let config = EvtOpenChannelConfig(None, &HSTRING::from(channel), 0)
+            .map_err(|e| format!("cannot open channel '{}': {}", channel, e))?;
+
+        let mut used = 0u32;
+        let _ = EvtGetChannelConfigProperty(config, EvtChannelConfigAccess, 0, 0, None, &mut used);
+        // Returned as u64 words so the buffer is aligned for EVT_VARIANT
+        let mut buffer = vec![0u64; (used as usize).div_ceil(8).max(1)];
+        let result = EvtGetChannelConfigProperty(
+            config,
+            EvtChannelConfigAccess,
+            0,
+            used,
+            Some(buffer.as_mut_ptr() as *mut EVT_VARIANT),
+            &mut used,
+        );
+        let _ = EvtClose(config);
+        result?;
+
+        let variant = &*(buffer.as_ptr() as *const EVT_VARIANT);
+        Ok(variant.Anonymous.StringVal.to_string()?)
+    }
+}
+
+struct Ace {
+    // Allow, Deny, or the raw type of anything else
+    kind: String,
+    account: String,
+    mask: u32,
+}
+
+unsafe fn aces(sddl: &str) -> Result<Vec<Ace>, Box<dyn std::error::Error>> {
+    unsafe {
+        let mut descriptor = PSECURITY_DESCRIPTOR::default();
+        ConvertStringSecurityDescriptorToSecurityDescriptorW(
+            &HSTRING::from(sddl),
+            SDDL_REVISION_1,
+            &mut descriptor,
+            None,
+        )?;
+
+        let mut present = Default::default();
+        let mut defaulted = Default::default();
+        let mut dacl: *mut ACL = std::ptr::null_mut();
+        let result = GetSecurityDescriptorDacl(descriptor, &mut present, &mut dacl, &mut defaulted);
+
+        let mut entries = Vec::new();
+        if result.is_ok() && present.as_bool() && !dacl.is_null() {
+            for i in 0..(*dacl).AceCount as u32 {
+                let mut ace = std::ptr::null_mut();
+                if GetAce(dacl, i, &mut ace).is_err() {
+                    continue;
+                }
+                // Allow and deny entries share the ACCESS_ALLOWED_ACE layout
+                let ace = &*(ace as *const ACCESS_ALLOWED_ACE);
+                let kind = match ace.Header.AceType {
+                    ACCESS_ALLOWED_ACE_TYPE => "Allow".to_string(),
+                    ACCESS_DENIED_ACE_TYPE => "Deny".to_string(),
+                    other => format!("Type {}", other),
+                };
+                let sid = PSID(&ace.SidStart as *const u32 as *mut _);
+                entries.push(Ace {
+                    kind,
+                    account: account_name(sid),
+                    mask: ace.Mask,
+                });
+            }
+        }
+
+        let _ = LocalFree(Some(HLOCAL(descriptor.0)));
+        result?;
+        Ok(entries)
+    }
+}
+
+// DOMAIN\name when the SID resolves, otherwise its S-1-... form
+unsafe fn account_name(sid: PSID) -> String {
+    unsafe {
+        let mut name = [0u16; 256];
+        let mut domain = [0u16; 256];
+        let mut name_len = name.len() as u32;
+        let mut domain_len = domain.len() as u32;
+        let mut usage = SID_NAME_USE::default();
+        if LookupAccountSidW(
+            PCWSTR::null(),
+            sid,
+            Some(PWSTR(name.as_mut_ptr())),
+            &mut name_len,
+            Some(PWSTR(domain.as_mut_ptr())),
+            &mut domain_len,
+            &mut usage,
+        )
+        .is_ok()
+        {
+            let name = String::from_utf16_lossy(&name[..name_len as usize]);
+            let domain = String::from_utf16_lossy(&domain[..domain_len as usize]);
+            let account = if domain.is_empty() {
+                name
+            } else {
+                format!("{}\\{}", domain, name)
+            };
+            return account;
+        }
+
+        let mut text = PWSTR::null();
+        if ConvertSidToStringSidW(sid, &mut text).is_ok() {
+            let sid = text.to_string().unwrap_or_default();
+            let _ = LocalFree(Some(HLOCAL(text.0 as _)));
+            return sid;
+        }
+        "(unknown)".to_string()
+    }
+}
+
+fn rights(mask: u32) -> String {
+    let mut names: Vec<String> = RIGHTS
+        .iter()
+        .filter(|(bit, _)| mask & bit != 0)
+        .map(|(_, name)| name.to_string())
+        .collect();
+    let known = RIGHTS.iter().fold(0, |all, (bit, _)| all | bit);
+    if mask & !known != 0 {
+        names.push(format!("0x{:x}", mask & !known));
+    }
+    format!("{} (0x{:x})", names.join(", "), mask)
+}
+
+pub fn can_read(channel: &str) -> windows::core::Result<()> {
+    unsafe {
+        let query = EvtQuery(
+            None,
+            &HSTRING::from(channel),
+            w!("*"),
+            EvtQueryChannelPath.0 | EvtQueryReverseDirection.0,
+        )?;
+        let _ = EvtClose(query);
+        Ok(())
+    }
+}
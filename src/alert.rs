@@ -0,0 +1,619 @@
+use crate::config::{AlertRule, Config, RateLimit, Threshold};
+#[cfg(feature = "email")]
+use crate::email::Email;
+#[cfg(feature = "webhook")]
+use crate::webhook::{Kind, Webhook};
+use crate::{filter, hub};
+use chrono::{DateTime, TimeDelta, Utc};
+use log::warn;
+use serde_json::Value as JsonValue;
+use std::collections::{HashMap, VecDeque};
+#[cfg(feature = "email")]
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::mpsc::Sender;
+#[cfg(any(feature = "email", feature = "webhook"))]
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+#[cfg(any(feature = "email", feature = "webhook"))]
+use std::thread;
+#[cfg(feature = "email")]
+use std::time::Duration;
+use std::time::Instant;
+
+// Alerts held for a digest or a retry; beyond it they are only counted
+#[cfg(feature = "email")]
+const MAX_HELD: usize = 1000;
+// How long alert emails that failed to send wait to be sent again
+#[cfg(feature = "email")]
+const EMAIL_RETRY: Duration = Duration::from_secs(60);
+// Threshold windows kept before idle ones are cleared out
+const MAX_GROUPS: usize = 10_000;
+
+// When the matching events of a threshold group were created, oldest first
+type Matched = VecDeque<DateTime<Utc>>;
+
+/// An event that matched an alert rule. For a threshold rule, the event
+/// that reached the threshold.
+pub struct Alert {
+    pub rule: String,
+    pub event: JsonValue,
+    // Matching events counted by a threshold, 1 otherwise
+    pub matches: usize,
+    // The threshold's group_by value
+    pub group: Option<String>,
+}
+
+/// Matches written events against the `alerts` rules and hands matches to
+/// every configured notifier (email, Slack, Teams). Each notifier sends from
+/// its own thread, so a slow server never holds up collection or the others.
+pub struct Alerts {
+    rules: Vec<AlertRule>,
+    notifiers: Vec<Sender<Arc<Alert>>>,
+    // Per rule with a rate_limit
+    windows: Mutex<HashMap<String, Window>>,
+    // Per threshold rule (by index) and group
+    thresholds: Mutex<HashMap<(usize, String), Matched>>,
+}
+
+#[derive(Default)]
+struct Window {
+    // When the alerts of the current window were raised
+    raised: VecDeque<Instant>,
+    suppressed: u64,
+}
+
+impl Alerts {
+    /// None when no rules are configured.
+    pub fn start(config: &Config) -> Result<Option<Alerts>, String> {
+        if config.alerts.is_empty() {
+            return Ok(None);
+        }
+        #[cfg_attr(not(any(feature = "email", feature = "webhook")), allow(unused_mut))]
+        let mut notifiers = Vec::new();
+        if let Some(email) = &config.email {
+            #[cfg(feature = "email")]
+            {
+                let email = Email::new(email)?;
+                notifiers.push(notifier(move |alerts| send_email(&email, alerts)));
+            }
+            #[cfg(not(feature = "email"))]
+            {
+                let _ = email;
+                return Err("email: alerts require a build with the 'email' feature".to_string());
+            }
+        }
+        if config.slack.is_some() || config.teams.is_some() {
+            #[cfg(feature = "webhook")]
+            for (kind, webhook) in [(Kind::Slack, &config.slack), (Kind::Teams, &config.teams)] {
+                if let Some(webhook) = webhook {
+                    let webhook = Webhook::new(kind, webhook);
+                    notifiers.push(notifier(move |alerts| post(&webhook, alerts)));
+                }
+            }
+            #[cfg(not(feature = "webhook"))]
+            return Err(
+                "slack: and teams: alerts require a build with the 'webhook' feature".to_string(),
+            );
+        }
+        if notifiers.is_empty() {
+            return Err("alerts need an email:, slack: or teams: section to send them".to_string());
+        }
+        Ok(Some(Alerts {
+            rules: config.alerts.clone(),
+            notifiers,
+            windows: Mutex::new(HashMap::new()),
+            thresholds: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    pub fn check(&self, event: &JsonValue) {
+        for (index, rule) in self.rules.iter().enumerate() {
+            if !filter::event_matches(event, &rule.channels, &rule.providers, &rule.event_ids) {
+                continue;
+            }
+            let (matches, group) = match &rule.threshold {
+                Some(threshold) => match self.count(index, threshold, event) {
+                    Some(reached) => reached,
+                    None => continue,
+                },
+                None => (1, None),
+            };
+            if !self.admit(rule) {
+                continue;
+            }
+            let alert = Arc::new(Alert {
+                rule: rule.name.clone(),
+                event: event.clone(),
+                matches,
+                group,
+            });
+            for notifier in &self.notifiers {
+                let _ = notifier.send(Arc::clone(&alert));
+            }
+        }
+    }
+
+    // Adds the event to its group's sliding window. Once it holds the
+    // threshold's count, the window starts over and the count is returned.
+    // Windows run on the events' TimeCreated, so a backlog read in one go
+    // isn't taken for a burst; arrival time stands in where it's missing.
+    fn count(
+        &self,
+        rule: usize,
+        threshold: &Threshold,
+        event: &JsonValue,
+    ) -> Option<(usize, Option<String>)> {
+        let group = threshold.group_by.as_deref().map(|path| value(path, event));
+        let created = hub::time_created(event)
+            .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+            .map_or_else(Utc::now, |t| t.to_utc());
+        let within = |threshold: &Threshold| {
+            TimeDelta::from_std(threshold.within()).unwrap_or(TimeDelta::MAX)
+        };
+        let mut windows = self.thresholds.lock().unwrap();
+        if windows.len() >= MAX_GROUPS {
+            windows.retain(|(rule, _), created_at| {
+                let within = self.rules[*rule]
+                    .threshold
+                    .as_ref()
+                    .map_or(TimeDelta::zero(), within);
+                created_at.back().is_some_and(|t| created - *t < within)
+            });
+        }
+        let created_at = windows
+            .entry((rule, group.clone().unwrap_or_default()))
+            .or_default();
+        // Events of several channels don't come in time order
+        let at = created_at.partition_point(|t| *t <= created);
+        created_at.insert(at, created);
+        let newest = *created_at.back()?;
+        while created_at
+            .front()
+            .is_some_and(|t| newest - *t >= within(threshold))
+        {
+            created_at.pop_front();
+        }
+        if created_at.len() < threshold.count {
+            return None;
+        }
+        let matches = created_at.len();
+        created_at.clear();
+        Some((matches, group))
+    }
+
+    // Applies the rule's rate_limit over a sliding window
+    fn admit(&self, rule: &AlertRule) -> bool {
+        let Some(limit) = &rule.rate_limit else {
+            return true;
+        };
+        let mut windows = self.windows.lock().unwrap();
+        let window = windows.entry(rule.name.clone()).or_default();
+        let now = Instant::now();
+        window.expire(limit, now);
+        if window.raised.len() >= limit.max {
+            window.suppressed += 1;
+            return false;
+        }
+        window.report(&rule.name);
+        window.raised.push_back(now);
+        true
+    }
+
+    /// Logs how many alerts each rule's rate_limit dropped, once the limit
+    /// lets alerts through again (or, with `now`, right away), whether or
+    /// not another one comes.
+    pub fn report_suppressed(&self, now: bool) {
+        let mut windows = self.windows.lock().unwrap();
+        for rule in &self.rules {
+            let (Some(limit), Some(window)) = (&rule.rate_limit, windows.get_mut(&rule.name))
+            else {
+                continue;
+            };
+            window.expire(limit, Instant::now());
+            if now || window.raised.len() < limit.max {
+                window.report(&rule.name);
+            }
+        }
+    }
+}
+
+impl Window {
+    // Forgets alerts raised before the limit's window
+    fn expire(&mut self, limit: &RateLimit, now: Instant) {
+        while self
+            .raised
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= limit.per())
+        {
+            self.raised.pop_front();
+        }
+    }
+
+    fn report(&mut self, rule: &str) {
+        if self.suppressed > 0 {
+            warn!(
+                "Alert rule '{}' hit its rate_limit, {} alerts were dropped",
+                rule, self.suppressed
+            );
+            self.suppressed = 0;
+        }
+    }
+}
+
+#[cfg(any(feature = "email", feature = "webhook"))]
+fn notifier<F>(run: F) -> Sender<Arc<Alert>>
+where
+    F: FnOnce(Receiver<Arc<Alert>>) + Send + 'static,
+{
+    let (queue, received) = mpsc::channel();
+    thread::spawn(move || run(received));
+    queue
+}
+
+#[cfg(feature = "webhook")]
+fn post(webhook: &Webhook, alerts: Receiver<Arc<Alert>>) {
+    for alert in alerts {
+        if let Err(e) = webhook.send(&alert) {
+            warn!(
+                "Failed to post alert '{}' to {}: {}",
+                alert.rule,
+                webhook.kind(),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(feature = "email")]
+fn send_email(email: &Email, alerts: Receiver<Arc<Alert>>) {
+    deliver(alerts, email.digest(), EMAIL_RETRY, |held, dropped| {
+        email.send(held, dropped)
+    });
+}
+
+// Sends each alert as it comes or, with a digest interval, the alerts
+// collected over it in one go. Alerts that fail to send are held and sent
+// again after `retry`, and once more when the queue closes.
+#[cfg(feature = "email")]
+fn deliver<F, E>(
+    alerts: Receiver<Arc<Alert>>,
+    digest: Option<Duration>,
+    retry: Duration,
+    mut send: F,
+) where
+    F: FnMut(&[Arc<Alert>], usize) -> Result<(), E>,
+    E: std::fmt::Display,
+{
+    let mut held = Vec::new();
+    let mut dropped = 0;
+    let mut due = digest.map(|interval| Instant::now() + interval);
+    loop {
+        let received = match due {
+            Some(due) => alerts.recv_timeout(due.saturating_duration_since(Instant::now())),
+            None => alerts.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let closed = match received {
+            Ok(alert) => {
+                if held.len() < MAX_HELD {
+                    held.push(alert);
+                } else {
+                    dropped += 1;
+                }
+                // Sent right away unless a digest or a retry is pending
+                if due.is_some() {
+                    continue;
+                }
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            // Reload or shutdown: send what was collected
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+        if digest.is_some() {
+            if !held.is_empty() {
+                match send(&held, dropped) {
+                    Ok(()) => {
+                        held.clear();
+                        dropped = 0;
+                    }
+                    Err(e) => warn!("Failed to email alert digest: {}", e),
+                }
+            }
+        } else {
+            while let Some(alert) = held.first() {
+                if let Err(e) = send(std::slice::from_ref(alert), 0) {
+                    warn!("Failed to email alert '{}': {}", alert.rule, e);
+                    break;
+                }
+                held.remove(0);
+            }
+            if held.is_empty() && dropped > 0 {
+                warn!("{} alerts were not emailed while sending failed", dropped);
+                dropped = 0;
+            }
+        }
+        if closed {
+            if !held.is_empty() {
+                warn!("{} alerts were not emailed", held.len() + dropped);
+            }
+            return;
+        }
+        due = match digest {
+            Some(interval) if held.is_empty() => Some(Instant::now() + interval),
+            Some(interval) => Some(Instant::now() + interval.min(retry)),
+            None => (!held.is_empty()).then(|| Instant::now() + retry),
+        };
+    }
+}
+
+/// Fills `{...}` placeholders in an alert template: `{rule}`, `{hostname}`,
+/// `{matches}` and `{group}` for threshold rules, and event fields by their
+/// dotted path, e.g. `{EventData.TargetUserName}`.
+/// `{Provider}` and `{TimeCreated}` stand for the provider name and time.
+/// Fields the event doesn't have are left empty.
+#[cfg_attr(not(any(feature = "email", feature = "webhook")), allow(dead_code))]
+pub fn expand(template: &str, alert: &Alert) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        out.push_str(&rest[..start]);
+        out.push_str(&field(&rest[start + 1..start + end], alert));
+        rest = &rest[start + end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// The text of one template placeholder, see `expand`.
+#[cfg_attr(not(any(feature = "email", feature = "webhook")), allow(dead_code))]
+pub fn field(name: &str, alert: &Alert) -> String {
+    let event = &alert.event;
+    match name {
+        "rule" => alert.rule.clone(),
+        "hostname" => std::env::var("COMPUTERNAME").unwrap_or_else(|_| "localhost".to_string()),
+        "matches" => alert.matches.to_string(),
+        "group" => alert.group.clone().unwrap_or_default(),
+        "Provider" => hub::provider(event).unwrap_or_default().to_string(),
+        "TimeCreated" => hub::time_created(event).unwrap_or_default().to_string(),
+        path => value(path, event),
+    }
+}
+
+// An event field by its dotted path, as text
+fn value(path: &str, event: &JsonValue) -> String {
+    match path.split('.').try_fold(event, |v, key| v.get(key)) {
+        Some(JsonValue::String(s)) => s.clone(),
+        Some(JsonValue::Null) | None => String::new(),
+        Some(other) => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::mpsc::{self, Receiver};
+
+    // Alerts for one rule, raised into the returned queue
+    fn alerts(rule: JsonValue) -> (Alerts, Receiver<Arc<Alert>>) {
+        let (queue, raised) = mpsc::channel();
+        let alerts = Alerts {
+            rules: vec![serde_json::from_value(rule).unwrap()],
+            notifiers: vec![queue],
+            windows: Mutex::new(HashMap::new()),
+            thresholds: Mutex::new(HashMap::new()),
+        };
+        (alerts, raised)
+    }
+
+    fn logon_failure(time: &str, ip: &str) -> JsonValue {
+        json!({
+            "Channel": "Security",
+            "EventID": 4625,
+            "TimeCreated": { "@SystemTime": time },
+            "EventData": { "IpAddress": ip },
+        })
+    }
+
+    fn threshold() -> JsonValue {
+        json!({
+            "name": "brute force",
+            "event_ids": [4625],
+            "threshold": { "count": 3, "within": 60, "group_by": "EventData.IpAddress" },
+        })
+    }
+
+    #[test]
+    fn threshold_counts_events_created_within_the_window() {
+        let (alerts, raised) = alerts(threshold());
+        for time in [
+            "2024-05-01T12:00:00.0000000Z",
+            "2024-05-01T12:00:20.0000000Z",
+            "2024-05-01T12:00:40.0000000Z",
+        ] {
+            alerts.check(&logon_failure(time, "10.0.0.1"));
+        }
+        let alert = raised.try_recv().unwrap();
+        assert_eq!(alert.matches, 3);
+        assert_eq!(alert.group.as_deref(), Some("10.0.0.1"));
+        assert!(raised.try_recv().is_err());
+    }
+
+    #[test]
+    fn threshold_ignores_backlog_spread_over_hours() {
+        let (alerts, raised) = alerts(threshold());
+        // Read back to back, as after downtime, but created an hour apart
+        for hour in 10..16 {
+            let time = format!("2024-05-01T{}:00:00.0000000Z", hour);
+            alerts.check(&logon_failure(&time, "10.0.0.1"));
+        }
+        assert!(raised.try_recv().is_err());
+    }
+
+    #[test]
+    fn threshold_counts_events_out_of_order() {
+        let (alerts, raised) = alerts(threshold());
+        for time in [
+            "2024-05-01T12:00:40.0000000Z",
+            "2024-05-01T11:00:00.0000000Z",
+            "2024-05-01T12:00:00.0000000Z",
+            "2024-05-01T12:00:20.0000000Z",
+        ] {
+            alerts.check(&logon_failure(time, "10.0.0.1"));
+        }
+        assert_eq!(raised.try_recv().unwrap().matches, 3);
+    }
+
+    #[test]
+    fn threshold_groups_count_separately() {
+        let (alerts, raised) = alerts(threshold());
+        for (time, ip) in [
+            ("2024-05-01T12:00:00.0000000Z", "10.0.0.1"),
+            ("2024-05-01T12:00:10.0000000Z", "10.0.0.2"),
+            ("2024-05-01T12:00:20.0000000Z", "10.0.0.1"),
+            ("2024-05-01T12:00:30.0000000Z", "10.0.0.2"),
+        ] {
+            alerts.check(&logon_failure(time, ip));
+        }
+        assert!(raised.try_recv().is_err());
+    }
+
+    #[test]
+    fn threshold_falls_back_to_arrival_time() {
+        let (alerts, raised) = alerts(threshold());
+        for _ in 0..3 {
+            alerts.check(&json!({ "EventID": 4625, "EventData": { "IpAddress": "10.0.0.1" } }));
+        }
+        assert_eq!(raised.try_recv().unwrap().matches, 3);
+    }
+
+    #[test]
+    fn rate_limit_reports_dropped_alerts_once_it_lets_alerts_through() {
+        let (alerts, raised) = alerts(json!({
+            "name": "logon failures",
+            "event_ids": [4625],
+            "rate_limit": { "max": 1, "per": 1 },
+        }));
+        for _ in 0..3 {
+            alerts.check(&logon_failure("2024-05-01T12:00:00.0000000Z", "10.0.0.1"));
+        }
+        assert!(raised.try_recv().is_ok());
+        assert!(raised.try_recv().is_err());
+        let suppressed = || alerts.windows.lock().unwrap()["logon failures"].suppressed;
+        alerts.report_suppressed(false);
+        assert_eq!(suppressed(), 2);
+        // Reported without another alert coming
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        alerts.report_suppressed(false);
+        assert_eq!(suppressed(), 0);
+    }
+
+    fn alert(rule: &str) -> Alert {
+        Alert {
+            rule: rule.to_string(),
+            event: json!({
+                "Provider": { "@Name": "Microsoft-Windows-Security-Auditing" },
+                "EventID": 4625,
+                "TimeCreated": { "@SystemTime": "2024-05-01T12:00:00.0000000Z" },
+                "EventData": { "TargetUserName": "alice", "LogonType": 3 },
+            }),
+            matches: 5,
+            group: Some("10.0.0.1".to_string()),
+        }
+    }
+
+    #[test]
+    fn expands_template_fields() {
+        let alert = alert("brute force");
+        assert_eq!(
+            expand("{rule}: {matches} events for {group}", &alert),
+            "brute force: 5 events for 10.0.0.1"
+        );
+        assert_eq!(
+            expand("{Provider} {EventID} at {TimeCreated}", &alert),
+            "Microsoft-Windows-Security-Auditing 4625 at 2024-05-01T12:00:00.0000000Z"
+        );
+        assert_eq!(
+            expand(
+                "{EventData.TargetUserName} (type {EventData.LogonType})",
+                &alert
+            ),
+            "alice (type 3)"
+        );
+    }
+
+    #[test]
+    fn expands_missing_fields_to_nothing() {
+        let mut alert = alert("logon");
+        alert.group = None;
+        assert_eq!(
+            expand("[{group}] [{EventData.Missing}] [{Nope.x}]", &alert),
+            "[] [] []"
+        );
+        assert_eq!(
+            field("EventData", &alert),
+            r#"{"TargetUserName":"alice","LogonType":3}"#
+        );
+        assert_eq!(expand("{rule} {unclosed", &alert), "logon {unclosed");
+        assert_eq!(expand("no fields", &alert), "no fields");
+    }
+
+    #[cfg(feature = "email")]
+    #[test]
+    fn failed_sends_are_retried() {
+        let (queue, received) = mpsc::channel();
+        let (sent, delivered) = mpsc::channel();
+        let mut failures = 1;
+        let delivery = std::thread::spawn(move || {
+            deliver(
+                received,
+                None,
+                Duration::from_millis(10),
+                |held, dropped| {
+                    if failures > 0 {
+                        failures -= 1;
+                        return Err("connection refused");
+                    }
+                    let rules: Vec<String> = held.iter().map(|a| a.rule.clone()).collect();
+                    sent.send((rules, dropped)).unwrap();
+                    Ok(())
+                },
+            )
+        });
+        let wait = Duration::from_secs(5);
+        queue.send(Arc::new(alert("first"))).unwrap();
+        // Sent again without another alert coming
+        assert_eq!(
+            delivered.recv_timeout(wait).unwrap(),
+            (vec!["first".to_string()], 0)
+        );
+        queue.send(Arc::new(alert("second"))).unwrap();
+        assert_eq!(
+            delivered.recv_timeout(wait).unwrap(),
+            (vec!["second".to_string()], 0)
+        );
+        drop(queue);
+        delivery.join().unwrap();
+    }
+
+    #[cfg(feature = "email")]
+    #[test]
+    fn failed_sends_get_a_last_try_on_shutdown() {
+        let (queue, received) = mpsc::channel();
+        let mut attempts = 0;
+        queue.send(Arc::new(alert("first"))).unwrap();
+        drop(queue);
+        deliver(received, None, Duration::from_secs(60), |_, _| {
+            attempts += 1;
+            if attempts == 1 {
+                Err("connection refused")
+            } else {
+                Ok(())
+            }
+        });
+        assert_eq!(attempts, 2);
+    }
+}
@@ -0,0 +1,423 @@
+use crate::config::{Config, Framing, RetentionConfig, SinkConfig};
+use crate::hub;
+use crate::output::{self, Record, Sink};
+use crate::sftp::Uploader;
+use arrow_array::builder::{
+    ListBuilder, MapBuilder, StringBuilder, TimestampNanosecondBuilder, UInt32Builder,
+    UInt64Builder,
+};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_ipc::writer::StreamWriter;
+use arrow_schema::{DataType, Field, Fields, Schema, SchemaRef, TimeUnit};
+use log::warn;
+use serde_json::Value as JsonValue;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Used when the sink sets no batch limits
+const DEFAULT_BATCH_EVENTS: usize = 1000;
+const DEFAULT_BATCH_INTERVAL: Duration = Duration::from_millis(1000);
+
+/// An Arrow IPC stream file (`.arrows`) of record batches with one row per
+/// event, for pyarrow/pandas and DuckDB to read without parsing JSON. A batch
+/// is written once it holds `max_events`, once its oldest event is
+/// `max_interval` old (see `write_if_due`), or on flush.
+///
+/// The columns are fixed: the System fields most queries use, the message,
+/// and EventData and Labels as string-to-string maps. Records that aren't
+/// events (load shedding summaries) are skipped.
+pub struct ArrowFile {
+    path: PathBuf,
+    // Started with the first batch
+    writer: Option<StreamWriter<BufWriter<File>>>,
+    file: Option<File>,
+    rows: Rows,
+    oldest: Instant,
+    max_events: usize,
+    max_interval: Duration,
+}
+
+impl ArrowFile {
+    /// Starts a new stream at `path`, replacing the file if there is one.
+    pub fn create(path: &Path, sink: &SinkConfig) -> io::Result<ArrowFile> {
+        Ok(ArrowFile {
+            path: path.to_path_buf(),
+            writer: None,
+            file: Some(File::create(path)?),
+            rows: Rows::default(),
+            oldest: Instant::now(),
+            max_events: sink.batch_max_events.unwrap_or(DEFAULT_BATCH_EVENTS).max(1),
+            max_interval: sink
+                .batch_max_interval_ms
+                .map_or(DEFAULT_BATCH_INTERVAL, Duration::from_millis),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn write_event(&mut self, event: &JsonValue) -> io::Result<()> {
+        if hub::record_id(event).is_none() {
+            return Ok(());
+        }
+        if self.rows.len == 0 {
+            self.oldest = Instant::now();
+        }
+        self.rows.push(event);
+        if self.rows.len >= self.max_events {
+            self.write_batch()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Writes a partial batch whose oldest event has waited long enough.
+    pub fn write_if_due(&mut self) -> io::Result<()> {
+        if self.rows.len > 0 && self.oldest.elapsed() >= self.max_interval {
+            self.flush()
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.write_batch()?;
+        match &mut self.writer {
+            Some(writer) => writer.flush().map_err(io::Error::other),
+            None => Ok(()),
+        }
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.flush()?;
+        match &self.writer {
+            Some(writer) => writer.get_ref().get_ref().sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes what is left and the end-of-stream marker.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.write_batch()?;
+        if let Some(writer) = &mut self.writer {
+            writer.finish().map_err(io::Error::other)?;
+            writer.flush().map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+
+    fn write_batch(&mut self) -> io::Result<()> {
+        if self.rows.len == 0 {
+            return Ok(());
+        }
+        let batch = self.rows.finish().map_err(io::Error::other)?;
+        if self.writer.is_none()
+            && let Some(file) = self.file.take()
+        {
+            let writer =
+                StreamWriter::try_new(BufWriter::new(file), &schema()).map_err(io::Error::other)?;
+            self.writer = Some(writer);
+        }
+        match &mut self.writer {
+            Some(writer) => writer.write(&batch).map_err(io::Error::other),
+            None => Err(io::Error::other("arrow output is closed")),
+        }
+    }
+}
+
+impl Drop for ArrowFile {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+/// The `arrow://<path>` output: an `ArrowFile` that rotates like a file output.
+pub struct ArrowOutput {
+    file: ArrowFile,
+    retention: RetentionConfig,
+    sink: SinkConfig,
+    upload: Option<Uploader>,
+}
+
+impl Sink for ArrowOutput {
+    fn send_batch(&mut self, batch: &[Record]) -> io::Result<()> {
+        batch
+            .iter()
+            .try_for_each(|r| self.file.write_event(r.event))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync()
+    }
+
+    fn send_if_due(&mut self) -> Option<io::Result<()>> {
+        Some(self.file.write_if_due())
+    }
+
+    // The stream is closed off so the rotated file is complete
+    fn rotate(&mut self) -> io::Result<PathBuf> {
+        self.file.finish()?;
+        let path = self.file.path().to_path_buf();
+        let rotated = output::rotated_path(&path);
+        std::fs::rename(&path, &rotated)?;
+        self.file = ArrowFile::create(&path, &self.sink)?;
+        if let Some(upload) = &self.upload {
+            upload.upload(rotated.clone());
+        }
+        output::prune(&path, &self.retention);
+        Ok(rotated)
+    }
+}
+
+/// Opens an `arrow://<path>` output. A stream can't be appended to, so a
+/// file left by an earlier run is moved aside as if rotated.
+pub fn create(
+    target: &str,
+    _framing: Framing,
+    config: &Config,
+) -> Result<Box<dyn Sink>, Box<dyn std::error::Error>> {
+    let path = Path::new(target.strip_prefix("arrow://").unwrap_or(target));
+    let upload = output::uploader(path, config)?;
+    if std::fs::metadata(path).is_ok_and(|m| m.len() > 0) {
+        let rotated = output::rotated_path(path);
+        std::fs::rename(path, &rotated)?;
+        if let Some(upload) = &upload {
+            upload.upload(rotated);
+        }
+    }
+    output::prune(path, &config.retention);
+    if config.journal {
+        warn!("journal is not supported for arrow output, ignoring");
+    }
+    Ok(Box::new(ArrowOutput {
+        file: ArrowFile::create(path, &config.sink)
+            .map_err(|e| format!("cannot create output {}: {}", path.display(), e))?,
+        retention: config.retention.clone(),
+        sink: config.sink.clone(),
+        upload,
+    }))
+}
+
+/// Checks that an `arrow://<path>` output could be written, leaving any file
+/// there as it is.
+pub fn probe(target: &str, _config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let path = target.strip_prefix("arrow://").unwrap_or(target);
+    output::probe_file(path).map_err(|e| format!("cannot write {}: {}", target, e))?;
+    Ok(())
+}
+
+// Column builders for the batch being filled
+struct Rows {
+    len: usize,
+    time_created: TimestampNanosecondBuilder,
+    channel: StringBuilder,
+    provider: StringBuilder,
+    event_id: UInt32Builder,
+    record_id: UInt64Builder,
+    level: StringBuilder,
+    task: StringBuilder,
+    opcode: StringBuilder,
+    keywords: ListBuilder<StringBuilder>,
+    computer: StringBuilder,
+    user_id: StringBuilder,
+    process_id: UInt32Builder,
+    thread_id: UInt32Builder,
+    activity_id: StringBuilder,
+    message: StringBuilder,
+    event_data: MapBuilder<StringBuilder, StringBuilder>,
+    labels: MapBuilder<StringBuilder, StringBuilder>,
+}
+
+impl Default for Rows {
+    fn default() -> Rows {
+        Rows {
+            len: 0,
+            time_created: TimestampNanosecondBuilder::new().with_timezone("UTC"),
+            channel: StringBuilder::new(),
+            provider: StringBuilder::new(),
+            event_id: UInt32Builder::new(),
+            record_id: UInt64Builder::new(),
+            level: StringBuilder::new(),
+            task: StringBuilder::new(),
+            opcode: StringBuilder::new(),
+            keywords: ListBuilder::new(StringBuilder::new()),
+            computer: StringBuilder::new(),
+            user_id: StringBuilder::new(),
+            process_id: UInt32Builder::new(),
+            thread_id: UInt32Builder::new(),
+            activity_id: StringBuilder::new(),
+            message: StringBuilder::new(),
+            event_data: MapBuilder::new(None, StringBuilder::new(), StringBuilder::new()),
+            labels: MapBuilder::new(None, StringBuilder::new(), StringBuilder::new()),
+        }
+    }
+}
+
+impl Rows {
+    fn push(&mut self, event: &JsonValue) {
+        let field = |pointer: &str| event.pointer(pointer).and_then(text);
+        let number = |pointer: &str| {
+            event
+                .pointer(pointer)
+                .and_then(|v| v.as_u64().or_else(|| v.as_str()?.parse().ok()))
+        };
+        self.len += 1;
+        self.time_created.append_option(
+            hub::time_created(event)
+                .and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok())
+                .and_then(|t| t.timestamp_nanos_opt()),
+        );
+        self.channel.append_option(hub::channel(event));
+        self.provider.append_option(hub::provider(event));
+        self.event_id.append_option(hub::event_id(event));
+        self.record_id.append_option(hub::record_id(event));
+        self.level.append_option(field("/Level"));
+        self.task.append_option(field("/Task"));
+        self.opcode.append_option(field("/Opcode"));
+        match event.get("Keywords") {
+            Some(JsonValue::Array(names)) => {
+                for name in names.iter().filter_map(text) {
+                    self.keywords.values().append_value(name);
+                }
+                self.keywords.append(true);
+            }
+            _ => self.keywords.append(false),
+        }
+        self.computer.append_option(field("/Computer"));
+        self.user_id.append_option(field("/Security/@UserID"));
+        self.process_id
+            .append_option(number("/Execution/@ProcessID").map(|n| n as u32));
+        self.thread_id
+            .append_option(number("/Execution/@ThreadID").map(|n| n as u32));
+        self.activity_id.append_option(field("/ActivityID"));
+        self.message.append_option(field("/Message"));
+        append_map(&mut self.event_data, event.get("EventData"));
+        append_map(&mut self.labels, event.get("Labels"));
+    }
+
+    // Empties the builders into a batch, in the order of schema()
+    fn finish(&mut self) -> Result<RecordBatch, arrow_schema::ArrowError> {
+        self.len = 0;
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(self.time_created.finish()),
+            Arc::new(self.channel.finish()),
+            Arc::new(self.provider.finish()),
+            Arc::new(self.event_id.finish()),
+            Arc::new(self.record_id.finish()),
+            Arc::new(self.level.finish()),
+            Arc::new(self.task.finish()),
+            Arc::new(self.opcode.finish()),
+            Arc::new(self.keywords.finish()),
+            Arc::new(self.computer.finish()),
+            Arc::new(self.user_id.finish()),
+            Arc::new(self.process_id.finish()),
+            Arc::new(self.thread_id.finish()),
+            Arc::new(self.activity_id.finish()),
+            Arc::new(self.message.finish()),
+            Arc::new(self.event_data.finish()),
+            Arc::new(self.labels.finish()),
+        ];
+        RecordBatch::try_new(schema(), columns)
+    }
+}
+
+// The stream's one schema. Every column is nullable, so batches whose
+// events all happen to have a field are written in the same schema as
+// those where some lack it.
+fn schema() -> SchemaRef {
+    let map = || {
+        let entries = Fields::from(vec![
+            Field::new("key", DataType::Utf8, false),
+            Field::new("value", DataType::Utf8, true),
+        ]);
+        DataType::Map(
+            Arc::new(Field::new("entries", DataType::Struct(entries), false)),
+            false,
+        )
+    };
+    let columns = [
+        (
+            "time_created",
+            DataType::Timestamp(TimeUnit::Nanosecond, Some("UTC".into())),
+        ),
+        ("channel", DataType::Utf8),
+        ("provider", DataType::Utf8),
+        ("event_id", DataType::UInt32),
+        ("record_id", DataType::UInt64),
+        ("level", DataType::Utf8),
+        ("task", DataType::Utf8),
+        ("opcode", DataType::Utf8),
+        (
+            "keywords",
+            DataType::List(Arc::new(Field::new_list_field(DataType::Utf8, true))),
+        ),
+        ("computer", DataType::Utf8),
+        ("user_id", DataType::Utf8),
+        ("process_id", DataType::UInt32),
+        ("thread_id", DataType::UInt32),
+        ("activity_id", DataType::Utf8),
+        ("message", DataType::Utf8),
+        ("event_data", map()),
+        ("labels", map()),
+    ];
+    Arc::new(Schema::new(
+        columns
+            .into_iter()
+            .map(|(name, kind)| Field::new(name, kind, true))
+            .collect::<Vec<_>>(),
+    ))
+}
+
+// Nested values (e.g. Sysmon's parsed Hashes) are kept as JSON text
+fn append_map(map: &mut MapBuilder<StringBuilder, StringBuilder>, value: Option<&JsonValue>) {
+    let Some(JsonValue::Object(obj)) = value else {
+        let _ = map.append(false);
+        return;
+    };
+    for (key, value) in obj {
+        map.keys().append_value(key);
+        map.values().append_option(text(value));
+    }
+    let _ = map.append(true);
+}
+
+fn text(value: &JsonValue) -> Option<String> {
+    match value {
+        JsonValue::Null => None,
+        JsonValue::String(s) => Some(s.clone()),
+        other => Some(other.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn batches_share_one_schema() {
+        let mut rows = Rows::default();
+        rows.push(&json!({
+            "TimeCreated": "2024-01-01T00:00:00.000Z",
+            "Channel": "Security",
+            "EventID": 4624,
+            "Keywords": ["Audit Success"],
+            "EventData": {"TargetUserName": "alice"},
+        }));
+        rows.push(&json!({}));
+        let full = rows.finish().unwrap();
+        assert_eq!(full.num_rows(), 2);
+        rows.push(&json!({"Channel": "System"}));
+        let sparse = rows.finish().unwrap();
+        assert_eq!(full.schema(), sparse.schema());
+        assert_eq!(full.schema(), schema());
+    }
+}
@@ -0,0 +1,346 @@
+use crate::config::Config;
+use glob_match::glob_match;
+use serde_json::json;
+use std::collections::BTreeSet;
+use windows::Win32::Security::Authentication::Identity::{
+    AUDIT_POLICY_INFORMATION, AuditFree, AuditQuerySystemPolicy, POLICY_AUDIT_EVENT_FAILURE,
+    POLICY_AUDIT_EVENT_SUCCESS,
+};
+use windows::core::GUID;
+
+// Audit subcategories, by the first field of their GUID (the rest is the
+// same for all: -69AE-11D9-BED3-505054503030), and the Security events
+// they log. An event some subcategories share can occur when any of them is on.
+const SUBCATEGORIES: [(u32, &str, &[u32]); 44] = [
+    (0x0CCE9210, "Security State Change", &[4608, 4616, 4621]),
+    (
+        0x0CCE9211,
+        "Security System Extension",
+        &[4610, 4611, 4614, 4622, 4697],
+    ),
+    (
+        0x0CCE9212,
+        "System Integrity",
+        &[
+            4612, 4615, 4618, 4816, 5038, 5056, 5057, 5060, 5061, 5062, 6281,
+        ],
+    ),
+    (
+        0x0CCE9214,
+        "Other System Events",
+        &[
+            5024, 5025, 5027, 5028, 5029, 5030, 5032, 5033, 5034, 5035, 5037, 5058, 5059,
+        ],
+    ),
+    (0x0CCE9215, "Logon", &[4624, 4625, 4648, 4675]),
+    (0x0CCE9216, "Logoff", &[4634, 4647]),
+    (0x0CCE9217, "Account Lockout", &[4625]),
+    (0x0CCE921B, "Special Logon", &[4672, 4964]),
+    (
+        0x0CCE921C,
+        "Other Logon/Logoff Events",
+        &[4649, 4778, 4779, 4800, 4801, 4802, 4803, 5378, 5632, 5633],
+    ),
+    (
+        0x0CCE9243,
+        "Network Policy Server",
+        &[6272, 6273, 6274, 6275, 6276, 6277, 6278, 6279, 6280],
+    ),
+    (0x0CCE9249, "Group Membership", &[4627]),
+    (
+        0x0CCE921D,
+        "File System",
+        &[4656, 4658, 4660, 4663, 4664, 4670, 4985, 5051],
+    ),
+    (
+        0x0CCE921E,
+        "Registry",
+        &[4656, 4657, 4658, 4660, 4663, 4670, 5039],
+    ),
+    (0x0CCE921F, "Kernel Object", &[4656, 4658, 4660, 4663]),
+    (0x0CCE9220, "SAM", &[4661]),
+    (
+        0x0CCE9221,
+        "Certification Services",
+        &[
+            4868, 4869, 4870, 4871, 4872, 4873, 4874, 4875, 4876, 4877, 4878, 4879, 4880, 4881,
+            4882, 4883, 4884, 4885, 4886, 4887, 4888, 4889, 4890, 4891, 4892, 4893, 4894, 4895,
+            4896, 4897, 4898,
+        ],
+    ),
+    (
+        0x0CCE9222,
+        "Application Generated",
+        &[4665, 4666, 4667, 4668],
+    ),
+    (0x0CCE9223, "Handle Manipulation", &[4658, 4690]),
+    (0x0CCE9224, "File Share", &[5140, 5142, 5143, 5144, 5168]),
+    (0x0CCE9225, "Filtering Platform Packet Drop", &[5152, 5153]),
+    (
+        0x0CCE9226,
+        "Filtering Platform Connection",
+        &[5031, 5150, 5151, 5154, 5155, 5156, 5157, 5158, 5159],
+    ),
+    (
+        0x0CCE9227,
+        "Other Object Access Events",
+        &[
+            4671, 4691, 4698, 4699, 4700, 4701, 4702, 5148, 5149, 5888, 5889, 5890,
+        ],
+    ),
+    (0x0CCE9244, "Detailed File Share", &[5145]),
+    (0x0CCE9245, "Removable Storage", &[4656, 4658, 4663]),
+    (0x0CCE9228, "Sensitive Privilege Use", &[4673, 4674, 4985]),
+    (
+        0x0CCE9229,
+        "Non Sensitive Privilege Use",
+        &[4673, 4674, 4985],
+    ),
+    (0x0CCE922B, "Process Creation", &[4688, 4696]),
+    (0x0CCE922C, "Process Termination", &[4689]),
+    (0x0CCE922D, "DPAPI Activity", &[4692, 4693, 4694, 4695]),
+    (0x0CCE922E, "RPC Events", &[5712]),
+    (
+        0x0CCE9248,
+        "Plug and Play Events",
+        &[6416, 6419, 6420, 6421, 6422, 6423],
+    ),
+    (0x0CCE924A, "Token Right Adjusted Events", &[4703]),
+    (
+        0x0CCE922F,
+        "Audit Policy Change",
+        &[4715, 4719, 4817, 4902, 4904, 4905, 4906, 4907, 4908, 4912],
+    ),
+    (
+        0x0CCE9230,
+        "Authentication Policy Change",
+        &[
+            4706, 4707, 4713, 4716, 4717, 4718, 4739, 4864, 4865, 4866, 4867,
+        ],
+    ),
+    (
+        0x0CCE9231,
+        "Authorization Policy Change",
+        &[4670, 4704, 4705, 4911, 4913],
+    ),
+    (
+        0x0CCE9234,
+        "Other Policy Change Events",
+        &[
+            4714, 4819, 4826, 4909, 4910, 5063, 5064, 5065, 5066, 5067, 5068, 5069, 5070, 5447,
+            6144, 6145,
+        ],
+    ),
+    (
+        0x0CCE9235,
+        "User Account Management",
+        &[
+            4720, 4722, 4723, 4724, 4725, 4726, 4738, 4740, 4765, 4766, 4767, 4780, 4781, 4794,
+            4798, 5376, 5377,
+        ],
+    ),
+    (
+        0x0CCE9236,
+        "Computer Account Management",
+        &[4741, 4742, 4743],
+    ),
+    (
+        0x0CCE9237,
+        "Security Group Management",
+        &[
+            4727, 4728, 4729, 4730, 4731, 4732, 4733, 4734, 4735, 4737, 4754, 4755, 4756, 4757,
+            4758, 4764, 4799,
+        ],
+    ),
+    (0x0CCE923B, "Directory Service Access", &[4661, 4662]),
+    (
+        0x0CCE923C,
+        "Directory Service Changes",
+        &[5136, 5137, 5138, 5139, 5141],
+    ),
+    (
+        0x0CCE923F,
+        "Credential Validation",
+        &[4774, 4775, 4776, 4777],
+    ),
+    (
+        0x0CCE9240,
+        "Kerberos Service Ticket Operations",
+        &[4769, 4770, 4773],
+    ),
+    (
+        0x0CCE9242,
+        "Kerberos Authentication Service",
+        &[4768, 4771, 4772],
+    ),
+];
+
+// Events only logged as audit failures, which need failure auditing on
+const FAILURE_EVENTS: [u32; 9] = [4625, 4771, 4772, 4773, 4777, 5031, 5152, 5157, 5159];
+
+/// Reads the system audit policy, as `auditpol /get /category:*` shows it,
+/// and reports which wanted Security EventIDs can't occur because every
+/// subcategory logging them is off (or doesn't audit failures, for failure
+/// events). Wanted are `event_ids`, those of alert rules on Security and
+/// the Security channel's include_event_ids; with none of them, every
+/// EventID the report knows about. Excluded EventIDs aren't wanted.
+pub fn coverage(
+    config: &Config,
+    event_ids: &[u32],
+    as_json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut wanted: BTreeSet<u32> = event_ids.iter().copied().collect();
+    for rule in &config.alerts {
+        let on_security = rule.channels.is_empty()
+            || rule
+                .channels
+                .iter()
+                .any(|c| glob_match(&c.to_lowercase(), "security"));
+        if on_security {
+            wanted.extend(&rule.event_ids);
+        }
+    }
+    let security: Vec<_> = config
+        .channels
+        .iter()
+        .filter(|c| glob_match(&c.name.to_lowercase(), "security"))
+        .collect();
+    for channel in &security {
+        wanted.extend(&channel.include_event_ids);
+    }
+    if wanted.is_empty() {
+        wanted = SUBCATEGORIES
+            .iter()
+            .flat_map(|(_, _, ids)| ids.iter().copied())
+            .collect();
+    }
+    // Not read anyway, so whether they are logged doesn't matter
+    for channel in &security {
+        for id in &channel.exclude_event_ids {
+            wanted.remove(id);
+        }
+    }
+    if security.is_empty() {
+        eprintln!("Note: the Security channel isn't among the configured channels");
+    }
+
+    let settings = policy()?;
+    let setting = |data1: u32| {
+        settings
+            .iter()
+            .find(|(guid, _)| guid.data1 == data1)
+            .map_or(0, |(_, flags)| *flags)
+    };
+
+    let mut missing = Vec::new();
+    let mut unknown = Vec::new();
+    for &id in &wanted {
+        let needed = if FAILURE_EVENTS.contains(&id) {
+            POLICY_AUDIT_EVENT_FAILURE as u32
+        } else {
+            (POLICY_AUDIT_EVENT_SUCCESS | POLICY_AUDIT_EVENT_FAILURE) as u32
+        };
+        let logged_by: Vec<_> = SUBCATEGORIES
+            .iter()
+            .filter(|(_, _, ids)| ids.contains(&id))
+            .collect();
+        if logged_by.is_empty() {
+            unknown.push(id);
+        } else if !logged_by
+            .iter()
+            .any(|(data1, ..)| setting(*data1) & needed != 0)
+        {
+            let names: Vec<&str> = logged_by.iter().map(|(_, name, _)| *name).collect();
+            missing.push((id, names, FAILURE_EVENTS.contains(&id)));
+        }
+    }
+
+    if as_json {
+        let subcategories: Vec<_> = SUBCATEGORIES
+            .iter()
+            .map(|(data1, name, _)| json!({ "subcategory": name, "setting": describe(setting(*data1)) }))
+            .collect();
+        let missing: Vec<_> = missing
+            .iter()
+            .map(|(id, names, failure)| {
+                json!({ "event_id": id, "subcategories": names, "needs_failure_auditing": failure })
+            })
+            .collect();
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&json!({
+                "policy": subcategories,
+                "cannot_occur": missing,
+                "unknown_event_ids": unknown,
+            }))?
+        );
+        return Ok(());
+    }
+
+    println!("{:<36}  SETTING", "SUBCATEGORY");
+    for (data1, name, _) in &SUBCATEGORIES {
+        println!("{:<36}  {}", name, describe(setting(*data1)));
+    }
+    println!();
+    if missing.is_empty() {
+        println!(
+            "All {} wanted EventIDs can occur",
+            wanted.len() - unknown.len()
+        );
+    } else {
+        println!("{} wanted EventIDs cannot occur:", missing.len());
+        for (id, names, failure) in &missing {
+            let needs = if *failure {
+                "failure auditing"
+            } else {
+                "auditing"
+            };
+            println!("  {:>5}  needs {} on: {}", id, needs, names.join(" or "));
+        }
+    }
+    if !unknown.is_empty() {
+        let ids: Vec<String> = unknown.iter().map(u32::to_string).collect();
+        println!("Not known to this report: {}", ids.join(", "));
+    }
+    Ok(())
+}
+
+fn describe(flags: u32) -> &'static str {
+    let success = flags & POLICY_AUDIT_EVENT_SUCCESS as u32 != 0;
+    let failure = flags & POLICY_AUDIT_EVENT_FAILURE as u32 != 0;
+    match (success, failure) {
+        (true, true) => "Success and Failure",
+        (true, false) => "Success",
+        (false, true) => "Failure",
+        (false, false) => "No Auditing",
+    }
+}
+
+// Each subcategory's GUID and auditing flags
+fn policy() -> Result<Vec<(GUID, u32)>, Box<dyn std::error::Error>> {
+    let guids: Vec<GUID> = SUBCATEGORIES
+        .iter()
+        .map(|(data1, ..)| GUID {
+            data1: *data1,
+            data2: 0x69AE,
+            data3: 0x11D9,
+            data4: [0xBE, 0xD3, 0x50, 0x50, 0x54, 0x50, 0x30, 0x30],
+        })
+        .collect();
+    unsafe {
+        let mut info: *mut AUDIT_POLICY_INFORMATION = std::ptr::null_mut();
+        if !AuditQuerySystemPolicy(&guids, &mut info) {
+            return Err(format!(
+                "cannot read the audit policy (run as administrator): {}",
+                windows::core::Error::from_thread()
+            )
+            .into());
+        }
+        let settings = std::slice::from_raw_parts(info, guids.len())
+            .iter()
+            .map(|i| (i.AuditSubCategoryGuid, i.AuditingInformation))
+            .collect();
+        AuditFree(info as *const _);
+        Ok(settings)
+    }
+}
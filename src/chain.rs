@@ -0,0 +1,282 @@
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+// Field added to the end of every chained line
+const FIELD: &str = "ChainHash";
+// The previous hash of a chain's first line
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Hash chain over a file output. Each line gets a ChainHash field holding
+/// the SHA-256 (hex) of the previous line's hash followed by the line as it
+/// was before the field was added, so altering, removing or reordering any
+/// line breaks every hash after it. `verify` checks a file's chain.
+pub struct Chain {
+    prev: String,
+}
+
+impl Chain {
+    /// Continues the chain from the last line of `path`. A missing file, or
+    /// one whose last line isn't chained, starts a new chain.
+    pub fn resume(path: &Path) -> io::Result<Chain> {
+        let last = match File::open(path) {
+            Ok(mut file) => last_line(&mut file)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let prev = last
+            .as_deref()
+            .and_then(split)
+            .map_or(GENESIS.to_string(), |(_, hash)| hash.to_string());
+        Ok(Chain { prev })
+    }
+
+    /// Returns `line` with its ChainHash added.
+    pub fn link(&mut self, line: &str) -> String {
+        let record = fold(line);
+        let hash = digest(&self.prev, &record);
+        let body = record.strip_suffix('}').unwrap_or(&record);
+        let separator = if body.ends_with('{') { "" } else { "," };
+        let linked = format!("{}{}\"{}\":\"{}\"}}", body, separator, FIELD, hash);
+        self.prev = hash;
+        linked
+    }
+}
+
+/// Checks the chain through `paths`, read as one file in the order given.
+/// The chain starts from `prev`, e.g. the last hash of the file rotated out
+/// before the first one, or from scratch. Unchained lines ahead of the
+/// chain's first line (written before hash_chain was enabled) are skipped.
+pub fn verify(paths: &[PathBuf], prev: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut prev = prev.map(str::to_ascii_lowercase);
+    let (mut chained, mut skipped) = (0u64, 0u64);
+    for path in paths {
+        let file =
+            File::open(path).map_err(|e| format!("cannot open {}: {}", path.display(), e))?;
+        for (n, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                continue;
+            }
+            let Some((record, hash)) = split(line) else {
+                if prev.is_none() {
+                    skipped += 1;
+                    continue;
+                }
+                return Err(format!("{}:{}: line has no {}", path.display(), n + 1, FIELD).into());
+            };
+            if hash != digest(prev.as_deref().unwrap_or(GENESIS), &record) {
+                return Err(format!(
+                    "{}:{}: chain broken, this line or one before it was altered, removed or reordered",
+                    path.display(),
+                    n + 1
+                )
+                .into());
+            }
+            prev = Some(hash.to_string());
+            chained += 1;
+        }
+    }
+    let Some(last) = prev.filter(|_| chained > 0) else {
+        return Err("no chained lines found".into());
+    };
+    println!("OK: {} chained lines verified", chained);
+    if skipped > 0 {
+        println!(
+            "{} lines before the chain started were not checked",
+            skipped
+        );
+    }
+    println!("Last hash: {}", last);
+    Ok(())
+}
+
+fn digest(prev: &str, record: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(prev.as_bytes());
+    hasher.update(record.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+// Pretty JSON onto one line. JSON strings can't hold a raw line break, so
+// every one of them is whitespace between tokens.
+fn fold(line: &str) -> String {
+    line.split('\n').map(str::trim).collect()
+}
+
+// A chained line's original record and its hash
+fn split(line: &str) -> Option<(String, &str)> {
+    let marker = format!("\"{}\":\"", FIELD);
+    let start = line.rfind(&marker)?;
+    let hash = line[start + marker.len()..].strip_suffix("\"}")?;
+    if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let body = &line[..start];
+    Some((
+        format!("{}}}", body.strip_suffix(',').unwrap_or(body)),
+        hash,
+    ))
+}
+
+// The last non-empty line, read backwards from the end of the file
+fn last_line(file: &mut File) -> io::Result<Option<String>> {
+    let mut pos = file.metadata()?.len();
+    let mut tail = Vec::new();
+    while pos > 0 {
+        let step = pos.min(64 * 1024);
+        pos -= step;
+        let mut chunk = vec![0; step as usize];
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut chunk)?;
+        chunk.extend_from_slice(&tail);
+        tail = chunk;
+
+        let end = tail
+            .iter()
+            .rposition(|b| !b.is_ascii_whitespace())
+            .map_or(0, |i| i + 1);
+        if let Some(start) = tail[..end].iter().rposition(|&b| b == b'\n') {
+            return Ok(Some(
+                String::from_utf8_lossy(&tail[start + 1..end]).into_owned(),
+            ));
+        }
+        if pos == 0 && end > 0 {
+            return Ok(Some(String::from_utf8_lossy(&tail[..end]).into_owned()));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "rs-wineventlog-chain-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn write(path: &Path, lines: &[String]) {
+        let text: String = lines.iter().map(|l| format!("{}\n", l)).collect();
+        std::fs::write(path, text).unwrap();
+    }
+
+    fn linked(chain: &mut Chain, records: &[&str]) -> Vec<String> {
+        records.iter().map(|r| chain.link(r)).collect()
+    }
+
+    #[test]
+    fn verifies_a_good_chain() {
+        let path = temp("good");
+        let mut chain = Chain::resume(&path).unwrap();
+        let lines = linked(
+            &mut chain,
+            &[r#"{"EventRecordID":1}"#, "{}", "{\n  \"a\": 1\n}"],
+        );
+        assert!(lines[1].starts_with(r#"{"ChainHash":""#));
+        assert!(lines[2].starts_with(r#"{"a": 1,"ChainHash":""#));
+        write(&path, &lines);
+        verify(std::slice::from_ref(&path), None).unwrap();
+
+        // Lines from before the chain was enabled are skipped
+        let mut with_unchained = vec![r#"{"EventRecordID":0}"#.to_string()];
+        with_unchained.extend(lines);
+        write(&path, &with_unchained);
+        verify(std::slice::from_ref(&path), None).unwrap();
+        assert!(verify(&[temp("missing")], None).is_err());
+    }
+
+    #[test]
+    fn resumes_from_an_existing_file() {
+        let path = temp("resume");
+        let mut chain = Chain::resume(&path).unwrap();
+        let mut lines = linked(&mut chain, &[r#"{"n":1}"#, r#"{"n":2}"#]);
+        // Trailing blank lines don't hide the last one
+        write(&path, &[lines.clone(), vec![String::new()]].concat());
+
+        let mut resumed = Chain::resume(&path).unwrap();
+        assert_eq!(resumed.prev, chain.prev);
+        lines.extend(linked(&mut resumed, &[r#"{"n":3}"#]));
+        write(&path, &lines);
+        verify(std::slice::from_ref(&path), None).unwrap();
+
+        // A file not ending in a chained line starts a new chain
+        write(&path, &[r#"{"n":1}"#.to_string()]);
+        assert_eq!(Chain::resume(&path).unwrap().prev, GENESIS);
+    }
+
+    #[test]
+    fn finds_the_last_line_across_reads() {
+        let path = temp("long");
+        let long = format!(r#"{{"x":"{}"}}"#, "a".repeat(100_000));
+        write(&path, &["{}".to_string(), long.clone()]);
+        let mut file = File::open(&path).unwrap();
+        assert_eq!(last_line(&mut file).unwrap(), Some(long.clone()));
+
+        std::fs::write(&path, &long).unwrap();
+        let mut file = File::open(&path).unwrap();
+        assert_eq!(last_line(&mut file).unwrap(), Some(long));
+
+        std::fs::write(&path, "\n\n").unwrap();
+        let mut file = File::open(&path).unwrap();
+        assert_eq!(last_line(&mut file).unwrap(), None);
+    }
+
+    #[test]
+    fn continues_across_rotated_files() {
+        let (first, second) = (temp("rotated-1"), temp("rotated-2"));
+        let mut chain = Chain::resume(&first).unwrap();
+        write(&first, &linked(&mut chain, &[r#"{"n":1}"#]));
+        let last = chain.prev.clone();
+        write(&second, &linked(&mut chain, &[r#"{"n":2}"#]));
+
+        verify(&[first.clone(), second.clone()], None).unwrap();
+        verify(std::slice::from_ref(&second), Some(&last.to_uppercase())).unwrap();
+        assert!(verify(std::slice::from_ref(&second), None).is_err());
+    }
+
+    #[test]
+    fn detects_edited_deleted_and_reordered_lines() {
+        let path = temp("broken");
+        let mut chain = Chain::resume(&path).unwrap();
+        let lines = linked(
+            &mut chain,
+            &[r#"{"n":1}"#, r#"{"n":2}"#, r#"{"n":3}"#, r#"{"n":4}"#],
+        );
+
+        let edited = {
+            let mut lines = lines.clone();
+            lines[1] = lines[1].replace(r#""n":2"#, r#""n":5"#);
+            lines
+        };
+        let deleted = [&lines[..1], &lines[2..]].concat();
+        let reordered = {
+            let mut lines = lines.clone();
+            lines.swap(1, 2);
+            lines
+        };
+        let unlinked = {
+            let mut lines = lines.clone();
+            lines[3] = r#"{"n":4}"#.to_string();
+            lines
+        };
+        for (name, lines, at) in [
+            ("edited", edited, ":2:"),
+            ("deleted", deleted, ":2:"),
+            ("reordered", reordered, ":2:"),
+            ("unlinked", unlinked, ":4:"),
+        ] {
+            write(&path, &lines);
+            let e = verify(std::slice::from_ref(&path), None).unwrap_err();
+            assert!(e.to_string().contains(at), "{}: {}", name, e);
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use crate::crash;
+use crate::state::{self, State};
+use std::io;
+use std::sync::Arc;
+use windows::Win32::System::EventLog::*;
+use windows::core::{HSTRING, PCWSTR};
+
+/// Per-channel Event Log bookmarks, kept in the state database so reading
+/// resumes where it stopped across runs.
+pub struct Checkpoints {
+    state: Arc<State>,
+}
+
+impl Checkpoints {
+    pub fn new(state: Arc<State>) -> Checkpoints {
+        Checkpoints { state }
+    }
+
+    pub fn get(&self, channel: &str) -> Option<String> {
+        self.state.bookmark(channel)
+    }
+
+    /// The EventRecordID a channel's saved bookmark points at.
+    pub fn record_id(&self, channel: &str) -> Option<u64> {
+        state::record_id(&self.get(channel)?)
+    }
+
+    /// Records a channel's bookmark XML and the EventRecordID of the newest
+    /// event written.
+    pub fn set(&self, channel: &str, bookmark: String, high_water: Option<u64>) -> io::Result<()> {
+        self.state
+            .set_bookmark(channel, &bookmark, high_water)
+            .map_err(io::Error::other)?;
+        crash::settled(channel);
+        Ok(())
+    }
+
+    /// Notes where a read got to with every event before it written, which
+    /// a panic saves if the read never gets to `set`.
+    pub fn reached(&self, channel: &str, bookmark: String, high_water: Option<u64>) {
+        crash::reached(channel, &self.state, bookmark, high_water);
+    }
+
+    /// The EventRecordID of the newest event written from a channel, kept
+    /// apart from its bookmark.
+    pub fn high_water(&self, channel: &str) -> Option<u64> {
+        self.state.high_water(channel)
+    }
+}
+
+/// An Event Log bookmark handle.
+pub struct Bookmark(EVT_HANDLE);
+
+impl Bookmark {
+    /// A bookmark restored from its XML, or a fresh one.
+    pub fn new(xml: Option<&str>) -> windows::core::Result<Bookmark> {
+        let xml = xml.map(HSTRING::from);
+        let handle = unsafe {
+            EvtCreateBookmark(xml.as_ref().map_or(PCWSTR::null(), |x| PCWSTR(x.as_ptr())))?
+        };
+        Ok(Bookmark(handle))
+    }
+
+    pub fn handle(&self) -> EVT_HANDLE {
+        self.0
+    }
+
+    pub fn update(&self, event: EVT_HANDLE) -> windows::core::Result<()> {
+        unsafe { EvtUpdateBookmark(self.0, event) }
+    }
+
+    pub fn to_xml(&self) -> windows::core::Result<String> {
+        unsafe {
+            let mut used = 0u32;
+            let _ = EvtRender(
+                None,
+                self.0,
+                EvtRenderBookmark.0,
+                0,
+                None,
+                &mut used,
+                &mut 0,
+            );
+            let mut buffer = vec![0u16; (used / 2) as usize + 1];
+            EvtRender(
+                None,
+                self.0,
+                EvtRenderBookmark.0,
+                used,
+                Some(buffer.as_mut_ptr() as *mut _),
+                &mut used,
+                &mut 0,
+            )?;
+            let len = buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len());
+            Ok(String::from_utf16_lossy(&buffer[..len]))
+        }
+    }
+}
+
+impl Drop for Bookmark {
+    fn drop(&mut self) {
+        let _ = unsafe { EvtClose(self.0) };
+    }
+}
@@ -0,0 +1,204 @@
+use crate::{hub, sysmon};
+use serde_json::{Value as JsonValue, json};
+
+/// Adds `ParsedCommandLine` to process creation events (Security 4688 and
+/// Sysmon 1): `Argv` split the way Windows programs split their command
+/// line, plus the `Executable` (the event's image path, else argv[0]) and
+/// its lowercase `Extension`.
+pub fn parse(event: &mut JsonValue) {
+    let image_field = match (hub::channel(event), hub::event_id(event)) {
+        (Some(c), Some(4688)) if c.eq_ignore_ascii_case("Security") => "NewProcessName",
+        (Some(c), Some(1)) if c.eq_ignore_ascii_case(sysmon::CHANNEL) => "Image",
+        _ => return,
+    };
+    let Some(data) = event.get_mut("EventData").and_then(|d| d.as_object_mut()) else {
+        return;
+    };
+
+    let argv = data
+        .get("CommandLine")
+        .and_then(|c| c.as_str())
+        .map(split)
+        .unwrap_or_default();
+    let Some(executable) = data
+        .get(image_field)
+        .and_then(|i| i.as_str())
+        .filter(|i| !i.is_empty())
+        .or(argv.first().map(String::as_str))
+        .map(str::to_string)
+    else {
+        return;
+    };
+    let extension = std::path::Path::new(&executable)
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase());
+
+    data.insert(
+        "ParsedCommandLine".to_string(),
+        json!({
+            "Argv": argv,
+            "Executable": executable,
+            "Extension": extension,
+        }),
+    );
+}
+
+// CommandLineToArgvW rules: the program name ends at the first whitespace, or
+// at the closing quote when it starts with one, without escapes. In the
+// arguments 2n backslashes before a quote become n and the quote toggles
+// quoting, 2n+1 become n and a literal quote, other backslashes are literal,
+// and "" inside quotes is a literal quote.
+fn split(command_line: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut chars = command_line.trim_start().chars().peekable();
+
+    let mut program = String::new();
+    if chars.peek() == Some(&'"') {
+        chars.next();
+        program.extend(chars.by_ref().take_while(|&c| c != '"'));
+    } else {
+        while let Some(&c) = chars.peek() {
+            if c == ' ' || c == '\t' {
+                break;
+            }
+            program.push(c);
+            chars.next();
+        }
+    }
+    if program.is_empty() && chars.peek().is_none() {
+        return args;
+    }
+    args.push(program);
+
+    loop {
+        while chars.peek().is_some_and(|&c| c == ' ' || c == '\t') {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            break;
+        }
+
+        let mut arg = String::new();
+        let mut quoted = false;
+        while let Some(&c) = chars.peek() {
+            match c {
+                ' ' | '\t' if !quoted => break,
+                '\\' => {
+                    let mut backslashes = 0;
+                    while chars.peek() == Some(&'\\') {
+                        backslashes += 1;
+                        chars.next();
+                    }
+                    if chars.peek() == Some(&'"') {
+                        arg.extend(std::iter::repeat_n('\\', backslashes / 2));
+                        if backslashes % 2 == 1 {
+                            arg.push('"');
+                            chars.next();
+                        }
+                    } else {
+                        arg.extend(std::iter::repeat_n('\\', backslashes));
+                    }
+                    continue;
+                }
+                '"' => {
+                    chars.next();
+                    if quoted && chars.peek() == Some(&'"') {
+                        arg.push('"');
+                        chars.next();
+                    } else {
+                        quoted = !quoted;
+                    }
+                    continue;
+                }
+                c => arg.push(c),
+            }
+            chars.next();
+        }
+        args.push(arg);
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_like_windows() {
+        assert_eq!(
+            split(r#""C:\Program Files\app.exe" -a "b c"  d"#),
+            [r"C:\Program Files\app.exe", "-a", "b c", "d"]
+        );
+        assert_eq!(split("cmd.exe\t/c\tdir"), ["cmd.exe", "/c", "dir"]);
+    }
+
+    #[test]
+    fn program_name_has_no_escapes() {
+        assert_eq!(split(r#"C:\dir\"x y"#), [r#"C:\dir\"x"#, "y"]);
+        assert_eq!(split(r#""C:\dir\\"x"#), [r"C:\dir\\", "x"]);
+    }
+
+    #[test]
+    fn backslashes_escape_quotes_only() {
+        // 2n backslashes before a quote: n of them, and the quote toggles
+        assert_eq!(split(r#"p a\\"b c" d"#), ["p", r"a\b c", "d"]);
+        // 2n+1: n of them and a literal quote
+        assert_eq!(split(r#"p a\"b a\\\"b"#), ["p", r#"a"b"#, r#"a\"b"#]);
+        // Elsewhere they are literal
+        assert_eq!(split(r"p C:\dir\\file a\\"), ["p", r"C:\dir\\file", r"a\\"]);
+    }
+
+    #[test]
+    fn doubled_quotes_inside_quotes_are_literal() {
+        assert_eq!(split(r#"p "a""b" "c"""#), ["p", r#"a"b"#, r#"c""#]);
+        assert_eq!(split(r#"p """#), ["p", ""]);
+    }
+
+    #[test]
+    fn empty_command_lines_have_no_arguments() {
+        assert!(split("").is_empty());
+        assert!(split("  \t").is_empty());
+        assert_eq!(split(r#""" x"#), ["", "x"]);
+    }
+
+    #[test]
+    fn parses_process_creation_events() {
+        let mut event = json!({
+            "Channel": "Security",
+            "EventID": 4688,
+            "EventData": {
+                "NewProcessName": r"C:\Windows\System32\WindowsPowerShell\v1.0\PowerShell.EXE",
+                "CommandLine": r#"powershell.exe -NoProfile -Command "Get-Date""#,
+            },
+        });
+        parse(&mut event);
+        assert_eq!(
+            event["EventData"]["ParsedCommandLine"],
+            json!({
+                "Argv": ["powershell.exe", "-NoProfile", "-Command", "Get-Date"],
+                "Executable": r"C:\Windows\System32\WindowsPowerShell\v1.0\PowerShell.EXE",
+                "Extension": "exe",
+            })
+        );
+
+        // Without an image path, argv[0] is the executable
+        let mut event = json!({
+            "Channel": sysmon::CHANNEL,
+            "EventID": 1,
+            "EventData": { "CommandLine": "notepad.exe a.txt" },
+        });
+        parse(&mut event);
+        assert_eq!(
+            event["EventData"]["ParsedCommandLine"]["Executable"],
+            "notepad.exe"
+        );
+
+        let mut other = json!({
+            "Channel": "Security",
+            "EventID": 4624,
+            "EventData": { "CommandLine": "x" },
+        });
+        parse(&mut other);
+        assert!(other["EventData"].get("ParsedCommandLine").is_none());
+    }
+}
@@ -1,6 +1,25 @@
 // Import the config crate's Config type and rename it to avoid confusion with our struct
-use config::{Config as ConfigBuilder, Environment, File};
-use serde::Deserialize;
+use crate::fatal::{Fatal, Kind};
+use crate::registry::RegistrySource;
+use crate::timestamp::Timezone;
+use config::{
+    Config as ConfigBuilder, Environment, File, FileFormat, Source as _, Value, ValueKind,
+};
+use glob_match::glob_match;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+// Registry key holding machine-wide settings, e.g. deployed by management tooling
+const REGISTRY_KEY: &str = r"SOFTWARE\rs-wineventlog";
+// Settings deployed by Group Policy; these win over every other source
+const POLICY_KEY: &str = r"SOFTWARE\Policies\rs-wineventlog";
+
+// Stdin can only be read once; reloads reuse what was read at startup
+static STDIN_CONFIG: OnceLock<String> = OnceLock::new();
 
 // Our application's configuration structure
 // The #[derive(Deserialize)] macro automatically generates code to convert
@@ -8,16 +27,1101 @@ use serde::Deserialize;
 #[derive(Deserialize)]
 pub struct Config {
     // Required field - must be present in config or will error
-    pub channels: Vec<String>,
+    // Each entry is a channel name/glob pattern, a map with per-channel
+    // settings, or a channel name mapped to its query
+    #[serde(deserialize_with = "channel_list")]
+    pub channels: Vec<ChannelConfig>,
+
+    // Events dropped as soon as they are read, before they are rendered
+    #[serde(default)]
+    pub filter: FilterConfig,
+
+    // XPath query selecting the events read from every channel without a
+    // query of its own, e.g. *[System[(Level=1 or Level=2)]] (default: all
+    // of them)
+    #[serde(default)]
+    pub query: Option<String>,
+
+    // Optional per-provider message locales, overriding the channel's
+    // e.g. Microsoft-Windows-Security-Auditing: [de-DE, en-US]
+    #[serde(default)]
+    pub provider_locales: BTreeMap<String, Vec<String>>,
+
+    // Optional directory of cached provider metadata, used to render events
+    // of providers that aren't installed here (see export-metadata)
+    #[serde(default)]
+    pub metadata_cache: Option<String>,
+
+    // Static labels added to every event as "Labels", e.g.
+    //   labels: { datacenter: eu-west, role: dc }
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
 
     // Optional field - if not present in config, defaults to None
+    // A file path, file://<path>, tcp://<host>:<port> or "-" for stdout
     #[serde(default)]
     pub output_file: Option<String>,
 
+    // Send events matching a route to its outputs instead; output_file gets
+    // the events no route takes
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+
+    // Journal events before appending them to a file output, so a crash can't
+    // leave a torn line behind (default: false)
+    #[serde(default)]
+    pub journal: bool,
+
+    // Add a ChainHash to every line of a file output: the SHA-256 of the
+    // previous line's hash plus this line, checked by the "verify" command
+    // (default: false)
+    #[serde(default)]
+    pub hash_chain: bool,
+
+    // When written events are flushed to the output (default: after every event)
+    #[serde(default)]
+    pub flush: FlushConfig,
+
+    // How a file output on a network share (\\server\share\...) rides out
+    // disconnects
+    #[serde(default)]
+    pub share: ShareConfig,
+
+    // How events are sent to a network output (tcp://)
+    #[serde(default)]
+    pub sink: SinkConfig,
+
+    // Limits for files moved aside by the "rotate" control command; the
+    // oldest ones are deleted once any limit is exceeded
+    #[serde(default)]
+    pub retention: RetentionConfig,
+
+    // Upload files moved aside by "rotate" to an SFTP server
+    #[serde(default)]
+    pub sftp: Option<SftpConfig>,
+
+    // Where a sentinel:// output sends events: a data collection rule of
+    // the Azure Monitor Logs Ingestion API
+    #[serde(default)]
+    #[cfg_attr(not(feature = "sentinel"), allow(dead_code))]
+    pub sentinel: Option<SentinelConfig>,
+
+    // Poll channels on an interval instead of subscribing to them live
+    #[serde(default)]
+    pub schedule: Option<ScheduleConfig>,
+
+    // Database of per-channel read positions and counters kept between
+    // runs (default: state.redb next to the executable)
+    #[serde(default)]
+    pub state_file: Option<String>,
+
+    // JSON read positions of older versions, imported into state_file once
+    // (default: checkpoints.json next to the executable)
+    #[serde(default)]
+    pub checkpoint_file: Option<String>,
+
     // Optional field with custom default function
     // If not present, calls default_batch_size() to get value
     #[serde(default = "default_batch_size")]
     pub batch_size: usize,
+
+    // How events are read: xml (default) renders each one as XML and parses it,
+    // values reads System and EventData values directly (XML only as a fallback)
+    #[serde(default)]
+    pub render: RenderMode,
+
+    // Emit numeric fields (EventID, Level, ProcessID, ports...) as JSON numbers
+    // and "true"/"false" as booleans instead of strings (default: false);
+    // EventData values as their event template types them, where known
+    #[serde(default)]
+    pub typed_json: bool,
+
+    // Optional channel-specific parsing of events
+    #[serde(default)]
+    pub parsers: ParsersConfig,
+
+    // Warn when a channel's newest written event is older than this, i.e. the
+    // collector is falling behind; seconds or a number with an s/m/h/d suffix
+    #[serde(default, deserialize_with = "duration")]
+    pub lag_alert: Option<Duration>,
+
+    // Rules picking out events to be told about, e.g. by email
+    #[serde(default)]
+    pub alerts: Vec<AlertRule>,
+
+    // SMTP server and recipients for alert emails
+    #[serde(default)]
+    pub email: Option<EmailConfig>,
+
+    // Incoming webhooks alerts are posted to as cards
+    #[serde(default)]
+    pub slack: Option<WebhookConfig>,
+    #[serde(default)]
+    pub teams: Option<WebhookConfig>,
+
+    // Most events written per second across all channels; beyond it the
+    // lowest priority channels (then those listed last) are dropped first
+    #[serde(default)]
+    pub max_events_per_sec: Option<u64>,
+
+    // Caps on the process as a whole, so collection can't starve the host
+    #[serde(default)]
+    pub limits: LimitsConfig,
+
+    // Hold events this long to write all channels in TimeCreated order;
+    // seconds or a number with an s/m/h/d suffix
+    #[serde(default, deserialize_with = "duration")]
+    pub merge_window: Option<Duration>,
+
+    // What to do when a channel can't be read for lack of rights
+    #[serde(default)]
+    pub on_access_denied: AccessDenied,
+
+    // Time zone of TimeCreated: utc (default), local, or a name like Europe/Berlin
+    #[serde(default)]
+    pub timezone: Timezone,
+
+    // Also write the startup identification record to the Application event log
+    #[serde(default)]
+    pub startup_event: bool,
+
+    // Optional named pipe for the runtime control API (status, reload, pause...)
+    // e.g. \\.\pipe\rs-wineventlog - disabled when not set
+    #[serde(default)]
+    pub control_pipe: Option<String>,
+
+    // Optional gRPC streaming API (requires a build with the "grpc" feature)
+    #[serde(default)]
+    pub grpc: Option<GrpcConfig>,
+
+    // Optional WebSocket live stream, e.g. "127.0.0.1:8765"
+    #[serde(default)]
+    pub websocket_listen: Option<String>,
+
+    // Optional REST API for querying recent events, e.g. "127.0.0.1:8080"
+    #[serde(default)]
+    pub http_listen: Option<String>,
+
+    // Lookback buffer: recent events kept per channel for REST queries and for
+    // stream consumers requesting history on connect (default: 1000)
+    #[serde(default = "default_lookback_events")]
+    pub lookback_events: usize,
+
+    // SHA-256 of the settings as written, set by load
+    #[serde(skip)]
+    pub fingerprint: String,
+}
+
+// Settings for one entry of the "channels:" list
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct ChannelConfig {
+    // Channel name or glob pattern, e.g. "Microsoft-Windows-*/Operational"
+    pub name: String,
+
+    // Preferred message locales in fallback order, e.g. [de-DE, en-US]
+    // The system default is used when none of them can format the message
+    #[serde(default)]
+    pub locales: Vec<String>,
+
+    // Read this channel as another account instead of the process's own,
+    // e.g. an Event Log Readers member for Security without running elevated
+    #[serde(default)]
+    pub run_as: Option<RunAsConfig>,
+
+    // high, normal (default) or low: how the channel's thread is scheduled
+    // and which channels max_events_per_sec drops last
+    #[serde(default)]
+    pub priority: Priority,
+
+    // Turn the channel on at startup when it is disabled, e.g.
+    // Microsoft-Windows-DNS-Client/Operational (default: false). The channel
+    // is skipped when that fails. An enabled analytic channel logs, but
+    // can't be read until it is disabled again
+    #[serde(default)]
+    pub enable: bool,
+
+    // XPath query selecting the events read from this channel, e.g.
+    // *[System[(EventID=4624 or EventID=4625)]]; replaces the global query.
+    // A structured <QueryList> query may read several channels, name then
+    // only labelling the subscription
+    #[serde(default)]
+    pub query: Option<String>,
+
+    // Only read these EventIDs, or all but those excluded; handed to the
+    // Event Log with the query, so other events are never delivered
+    #[serde(default)]
+    pub include_event_ids: Vec<u32>,
+    #[serde(default)]
+    pub exclude_event_ids: Vec<u32>,
+
+    // critical, error, warning, information or verbose: less severe events
+    // are left out by the Event Log, before anything is rendered
+    #[serde(default)]
+    pub min_level: Option<Level>,
+
+    // Regexes on the rendered Message: only events matching one of
+    // include_messages are written, and none matching exclude_messages
+    #[serde(default)]
+    pub include_messages: Vec<String>,
+    #[serde(default)]
+    pub exclude_messages: Vec<String>,
+
+    // Users and computers whose events are kept or dropped, as the filter
+    // section takes them; replaces that section for this channel
+    #[serde(default)]
+    pub filter: Option<FilterConfig>,
+}
+
+// Policy for channels the account may not read
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessDenied {
+    // Relaunch elevated (UAC prompt), then stop (default)
+    #[default]
+    Elevate,
+    // Stop every channel and exit with the access denied exit code
+    Shutdown,
+    // Keep running without the channel
+    Skip,
+    // Retry it with the usual restart backoff, e.g. until a group
+    // membership takes effect
+    Retry,
+}
+
+// Declared from most to least important, so sorting puts High first
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+//   run_as:
+//     user: CORP\svc-eventlog     (or svc-eventlog@corp.example.com, or a local user)
+//     password: dpapi:AQAAANCMnd8B...
+//     logon_type: network         (network, batch, service or interactive)
+#[derive(Deserialize, Serialize, Clone)]
+pub struct RunAsConfig {
+    pub user: String,
+
+    // Best kept out of plain text: use a dpapi: or ${env:...} value
+    #[serde(skip_serializing)]
+    pub password: String,
+
+    #[serde(default)]
+    pub logon_type: LogonType,
+}
+
+// Which LogonUser logon type to use; the account needs the matching right
+#[derive(Deserialize, Serialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogonType {
+    #[default]
+    Network,
+    Batch,
+    Service,
+    Interactive,
+}
+
+// A "channels:" entry can be written as just the name, or as the name
+// mapped to its query:
+//   channels:
+//     - Application
+//     - Security: "*[System[(EventID=4624 or EventID=4625)]]"
+//     - name: System
+//       locales: [de-DE, en-US]
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ChannelEntry {
+    Name(String),
+    Full(Box<ChannelConfig>),
+    Query(BTreeMap<String, String>),
+}
+
+fn channel_list<'de, D>(deserializer: D) -> Result<Vec<ChannelConfig>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let entries = Vec::<ChannelEntry>::deserialize(deserializer)?;
+    Ok(entries
+        .into_iter()
+        .flat_map(|entry| match entry {
+            ChannelEntry::Name(name) => vec![ChannelConfig {
+                name,
+                ..Default::default()
+            }],
+            ChannelEntry::Full(channel) => vec![*channel],
+            ChannelEntry::Query(queries) => queries
+                .into_iter()
+                .map(|(name, query)| ChannelConfig {
+                    name,
+                    query: Some(query),
+                    ..Default::default()
+                })
+                .collect(),
+        })
+        .collect())
+}
+
+// Nested struct - maps to the "grpc:" section of the YAML file
+#[cfg_attr(not(feature = "grpc"), allow(dead_code))]
+#[derive(Deserialize, Serialize)]
+pub struct GrpcConfig {
+    // Address to listen on, e.g. "127.0.0.1:50051"
+    pub listen: String,
+
+    // PEM certificate and key - both must be set to enable TLS
+    // (requires the "grpc-tls" feature)
+    #[serde(default)]
+    pub tls_cert: Option<String>,
+    #[serde(default)]
+    pub tls_key: Option<String>,
+}
+
+// Maps to the "flush:" section. With neither limit set every event is
+// flushed; otherwise whichever is reached first triggers a flush
+//   flush:
+//     every_n_events: 500
+//     interval_ms: 1000
+//     fsync: true
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct FlushConfig {
+    #[serde(default)]
+    pub every_n_events: Option<usize>,
+
+    #[serde(default)]
+    pub interval_ms: Option<u64>,
+
+    // Also force file outputs to disk on every flush, so a power failure
+    // can't lose events that were already flushed
+    #[serde(default)]
+    pub fsync: bool,
+}
+
+// Maps to the "sink:" section. Network outputs send events in batches
+// (Lumberjack: windows) instead of following the flush policy; by default
+// every event on its own
+//   sink:
+//     batch_max_events: 500
+//     batch_max_interval_ms: 1000
+//     framing: length_prefixed
+//     gzip_min_bytes: 4096
+//     exclude_fields: [EventData.ScriptBlockText, "EventData.*Hash*"]
+//     flatten: true
+//     key_case: snake_case
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct SinkConfig {
+    #[serde(default)]
+    pub batch_max_events: Option<usize>,
+
+    // A partial batch is sent once its oldest event is this old
+    // (default: 1000 when batch_max_events is set)
+    #[serde(default)]
+    pub batch_max_interval_ms: Option<u64>,
+
+    #[serde(default)]
+    pub framing: Framing,
+
+    // PEM file with an extra root CA trusted for lumberjack+tls:// receivers
+    #[serde(default)]
+    pub tls_ca: Option<String>,
+
+    // Request bodies of HTTP outputs this size or larger are sent gzipped
+    #[serde(default)]
+    pub gzip_min_bytes: Option<usize>,
+
+    // Dotted paths of the fields to write (all when empty) and of fields to
+    // drop; each part may use * and ? wildcards
+    #[serde(default)]
+    pub include_fields: Vec<String>,
+
+    #[serde(default)]
+    pub exclude_fields: Vec<String>,
+
+    // Write nested objects as dotted keys ("EventData.TargetUserName")
+    #[serde(default)]
+    pub flatten: bool,
+
+    #[serde(default)]
+    pub key_case: KeyCase,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RenderMode {
+    #[default]
+    Xml,
+    // Falls back to XML for UserData events and ones without a template
+    Values,
+}
+
+// Case of the keys the output writes
+#[derive(Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyCase {
+    // As in the event: PascalCase System fields, @-prefixed attributes
+    #[default]
+    Original,
+    // event_record_id, system_time
+    SnakeCase,
+    // eventRecordId, systemTime
+    CamelCase,
+}
+
+// How events are delimited on the wire or in a file
+#[derive(Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Framing {
+    // One JSON object per line
+    #[default]
+    Ndjson,
+    // Each batch as one JSON array, followed by a newline
+    JsonArray,
+    // Each event preceded by its length as a 4-byte big-endian integer
+    LengthPrefixed,
+}
+
+// Maps to the "parsers:" section; every parser is off by default
+//   parsers:
+//     sysmon: true
+//     command_line: true
+//     script_blocks: true
+//     security_alerts: true
+//     dns: true
+//     knowledge_base: true
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct ParsersConfig {
+    // Normalize Microsoft-Windows-Sysmon/Operational EventData
+    #[serde(default)]
+    pub sysmon: bool,
+
+    // Split the command line of process creation events (Security 4688,
+    // Sysmon 1) into arguments
+    #[serde(default)]
+    pub command_line: bool,
+
+    // Join PowerShell 4104 script blocks logged in several parts into one event
+    #[serde(default)]
+    pub script_blocks: bool,
+
+    // How long parts wait for the rest of their block before they are
+    // written as they are (default: 30s)
+    #[serde(default, deserialize_with = "duration")]
+    pub script_block_timeout: Option<Duration>,
+
+    // Add a SecurityAlert object of the same shape to Defender detections
+    // and AppLocker decisions; events also keep their UserData
+    #[serde(default)]
+    pub security_alerts: bool,
+
+    // Add a Dns object with the query, response and answers to DNS Server
+    // (Audit, Analytical) and DNS Client events
+    #[serde(default)]
+    pub dns: bool,
+
+    // Add readable names next to the coded fields of well-known Security
+    // events, e.g. LogonTypeName, StatusText and FailureReasonText for 4625
+    #[serde(default)]
+    pub knowledge_base: bool,
+
+    // More coded fields to decode; these apply without knowledge_base too,
+    // and their values win over the built-in ones
+    #[serde(default)]
+    pub lookups: Vec<LookupConfig>,
+}
+
+// A "lookups:" entry: adds `name` next to `field` of the matching events
+//   lookups:
+//     - event_ids: [4625]
+//       field: SubStatus
+//       name: SubStatusText
+//       values:
+//         "0xC0000064": user name does not exist
+//         "0xC000006A": wrong password
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct LookupConfig {
+    // Events of any ID (or provider) when empty
+    #[serde(default)]
+    pub event_ids: Vec<u32>,
+    #[serde(default)]
+    pub providers: Vec<String>,
+
+    // EventData field holding the code
+    pub field: String,
+
+    // Field added with the decoded value (default: the field name + "Text")
+    #[serde(default)]
+    pub name: Option<String>,
+
+    // Codes and what they mean; numbers match whether written in hex or
+    // decimal, other codes ignoring case
+    pub values: BTreeMap<String, String>,
+}
+
+// Maps to the "schedule:" section
+//   schedule:
+//     interval: 15m
+//     max_events: 10000
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ScheduleConfig {
+    // Time between polls; same units as retention.max_age (default: 5m)
+    #[serde(default, deserialize_with = "duration")]
+    pub interval: Option<Duration>,
+
+    // Most events read from one channel per poll, so a large backlog is
+    // caught up over several polls (default: 10000)
+    #[serde(default = "default_schedule_max_events")]
+    pub max_events: usize,
+}
+
+impl FlushConfig {
+    /// Whether `pending` events written since the last flush, `since` ago,
+    /// are due to be flushed.
+    pub fn due(&self, pending: usize, since: Duration) -> bool {
+        // Buffered events are flushed at least every second when only
+        // every_n_events is set
+        let interval = match (self.every_n_events, self.interval_ms) {
+            (_, Some(ms)) => Some(Duration::from_millis(ms)),
+            (Some(_), None) => Some(Duration::from_secs(1)),
+            (None, None) => None,
+        };
+        interval.is_none()
+            || self.every_n_events.is_some_and(|n| pending >= n)
+            || interval.is_some_and(|i| since >= i)
+    }
+}
+
+impl ScheduleConfig {
+    pub fn interval(&self) -> Duration {
+        self.interval.unwrap_or(Duration::from_secs(300))
+    }
+}
+
+// Maps to the "retention:" section; every limit is optional
+//   retention:
+//     max_files: 10
+//     max_total_size: 5GB
+//     max_age: 30d
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct RetentionConfig {
+    #[serde(default)]
+    pub max_files: Option<usize>,
+
+    // Bytes, or a number with a KB/MB/GB/TB suffix
+    #[serde(default, deserialize_with = "size")]
+    pub max_total_size: Option<u64>,
+
+    // Seconds, or a number with an s/m/h/d suffix
+    #[serde(default, deserialize_with = "duration")]
+    pub max_age: Option<Duration>,
+}
+
+// Maps to the "filter:" section
+//   filter:
+//     exclude_users: ['CORP\svc-backup', 'NT AUTHORITY\SYSTEM', S-1-5-19]
+//     include_computers: ['dc*.corp.example.com', 'file:C:\lists\servers.txt']
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct FilterConfig {
+    // Accounts whose events are the only ones kept (all when empty), and
+    // accounts whose events are dropped; DOMAIN\name, a local name or a SID
+    #[serde(default)]
+    pub include_users: Vec<String>,
+
+    #[serde(default)]
+    pub exclude_users: Vec<String>,
+
+    // The same for the Computer events come from (e.g. the forwarding
+    // sources of ForwardedEvents): names with * and ? wildcards, or
+    // file:<path> for a list of them, one per line
+    #[serde(default)]
+    pub include_computers: Vec<String>,
+
+    #[serde(default)]
+    pub exclude_computers: Vec<String>,
+}
+
+// Maps to the "limits:" section; memory and cpu_percent are enforced by a
+// Job Object the process puts itself in
+//   limits:
+//     memory: 512MB
+//     cpu_percent: 10
+//     cpu_priority: low
+//     background: true
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct LimitsConfig {
+    // Most memory the process may commit; bytes or KB/MB/GB/TB
+    #[serde(default, deserialize_with = "size")]
+    pub memory: Option<u64>,
+
+    // Share of the machine's total CPU time, 1-100, as a hard cap
+    #[serde(default)]
+    pub cpu_percent: Option<u32>,
+
+    #[serde(default)]
+    pub cpu_priority: CpuPriority,
+
+    // Windows background mode: very low I/O and low memory priority
+    #[serde(default)]
+    pub background: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CpuPriority {
+    #[default]
+    Normal,
+    // Below normal
+    Low,
+    // Only runs when nothing else wants the CPU
+    Idle,
+}
+
+// Maps to the "share:" section
+//   share:
+//     fallback_file: C:\ProgramData\rs-wineventlog\fallback.ndjson
+//     fallback_after: 5m
+//     buffer_max: 64MB
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct ShareConfig {
+    // Where events go once the share has been unreachable for fallback_after
+    // (default: the output's file name, next to the executable)
+    #[serde(default)]
+    pub fallback_file: Option<String>,
+
+    // Seconds, or a number with an s/m/h/d suffix (default: 5m)
+    #[serde(default, deserialize_with = "duration")]
+    pub fallback_after: Option<Duration>,
+
+    // Events held in memory while the share is down; once full they go to
+    // the fallback file early. Bytes or KB/MB/GB/TB (default: 64MB)
+    #[serde(default, deserialize_with = "size")]
+    pub buffer_max: Option<u64>,
+}
+
+// One entry of the "alerts:" list. Every condition given must match; an
+// empty list matches anything
+//   alerts:
+//     - name: Account locked out
+//       channels: [Security]
+//       event_ids: [4740]
+#[derive(Deserialize, Serialize, Clone)]
+pub struct AlertRule {
+    pub name: String,
+
+    // Channel names or globs
+    #[serde(default)]
+    pub channels: Vec<String>,
+
+    #[serde(default)]
+    pub providers: Vec<String>,
+
+    #[serde(default)]
+    pub event_ids: Vec<u32>,
+
+    // Only alert once enough matching events were created close together
+    #[serde(default)]
+    pub threshold: Option<Threshold>,
+
+    // Most alerts this rule raises per window; more are dropped
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+}
+
+// At least `count` matching events within `within`, counted separately for
+// each value of the group_by field, e.g. failed logons per source address
+//   threshold: { count: 5, within: 2m, group_by: EventData.IpAddress }
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Threshold {
+    pub count: usize,
+
+    // Seconds, or a number with an s/m/h/d suffix (default: 1m)
+    #[serde(default, deserialize_with = "duration")]
+    pub within: Option<Duration>,
+
+    // Dotted path of an event field (default: all matching events count together)
+    #[serde(default)]
+    pub group_by: Option<String>,
+}
+
+impl Threshold {
+    pub fn within(&self) -> Duration {
+        self.within.unwrap_or(Duration::from_secs(60))
+    }
+}
+
+//   rate_limit: { max: 5, per: 10m }
+#[derive(Deserialize, Serialize, Clone)]
+pub struct RateLimit {
+    pub max: usize,
+
+    // Seconds, or a number with an s/m/h/d suffix (default: 1m)
+    #[serde(default, deserialize_with = "duration")]
+    pub per: Option<Duration>,
+}
+
+impl RateLimit {
+    pub fn per(&self) -> Duration {
+        self.per.unwrap_or(Duration::from_secs(60))
+    }
+}
+
+// One entry of the "routes:" list. Every condition given must match; an
+// empty list matches anything. Routes are tried in order once an event is
+// complete (parsed, labelled), and the event goes to the outputs of every
+// route that matches up to the first with stop: true
+//   routes:
+//     - name: errors
+//       min_level: error
+//       outputs: [tcp://pager-relay:5140, D:\logs\errors.ndjson]
+//     - name: branch-offices
+//       channels: [ForwardedEvents]
+//       computers: ["*.branch.corp.example.com", file:C:\ProgramData\rs-wineventlog\branch.txt]
+//       outputs: [tcp://branch-siem:5140]
+//     - name: archive
+//       outputs: [\\archive\logs\{hostname}\{date}.ndjson]
+//       include_fields: [TimeCreated, EventID, Computer, EventData]
+//       key_case: snake_case
+//       framing: length_prefixed
+#[derive(Deserialize, Serialize, Clone)]
+pub struct RouteConfig {
+    pub name: String,
+
+    // Channel names or globs
+    #[serde(default)]
+    pub channels: Vec<String>,
+
+    #[serde(default)]
+    pub providers: Vec<String>,
+
+    #[serde(default)]
+    pub event_ids: Vec<u32>,
+
+    // The Computer events come from (e.g. a forwarding source of
+    // ForwardedEvents): names with * and ? wildcards, or file:<path> for a
+    // list of them, one per line
+    #[serde(default)]
+    pub computers: Vec<String>,
+
+    // Only events at least this severe
+    #[serde(default)]
+    pub min_level: Option<Level>,
+
+    // Targets as output_file takes them; a target named by several routes
+    // is opened once and gets each event once
+    pub outputs: Vec<String>,
+
+    // Don't try later routes for events this one takes (default: false)
+    #[serde(default)]
+    pub stop: bool,
+
+    // What the outputs write of each event, as the sink section's settings
+    // of the same names, which apply where these are left out
+    #[serde(default)]
+    pub include_fields: Option<Vec<String>>,
+    #[serde(default)]
+    pub exclude_fields: Option<Vec<String>>,
+    #[serde(default)]
+    pub flatten: Option<bool>,
+    #[serde(default)]
+    pub key_case: Option<KeyCase>,
+    #[serde(default)]
+    pub framing: Option<Framing>,
+}
+
+// Event levels, most severe first
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Critical,
+    Error,
+    Warning,
+    Information,
+    Verbose,
+}
+
+// Maps to the "slack:" and "teams:" sections
+//   slack:
+//     webhook_url: https://hooks.slack.com/services/...
+//     fields: [Computer, EventData.TargetUserName]
+#[derive(Deserialize, Serialize, Clone)]
+pub struct WebhookConfig {
+    pub webhook_url: String,
+
+    // Event fields shown on the card, by their dotted path
+    // (default: Computer, Channel, Provider, EventID, TimeCreated)
+    #[serde(default = "default_webhook_fields")]
+    pub fields: Vec<String>,
+}
+
+// Maps to the "email:" section
+//   email:
+//     server: smtp.example.com
+//     username: alerts@example.com
+//     password: ${env:SMTP_PASSWORD}
+//     from: alerts@example.com
+//     to: [ops@example.com]
+//     digest: 15m
+#[derive(Deserialize, Serialize, Clone)]
+pub struct EmailConfig {
+    pub server: String,
+
+    // Default: 465 for tls, 587 for starttls, 25 for none
+    #[serde(default)]
+    pub port: Option<u16>,
+
+    #[serde(default)]
+    pub tls: SmtpTls,
+
+    // Both must be set to log in
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+
+    pub from: String,
+    pub to: Vec<String>,
+
+    // Collect alerts and send them as one email this often instead of one
+    // email per event; seconds or a number with an s/m/h/d suffix
+    #[serde(default, deserialize_with = "duration")]
+    pub digest: Option<Duration>,
+
+    // Templates; {rule}, {hostname}, {count} and event fields such as
+    // {EventID}, {Provider}, {TimeCreated} or {EventData.TargetUserName}
+    #[serde(default = "default_email_subject")]
+    pub subject: String,
+    #[serde(default = "default_email_body")]
+    pub body: String,
+    // Subject of a digest; the body lists each event
+    #[serde(default = "default_email_digest_subject")]
+    pub digest_subject: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    // Plain connection upgraded with STARTTLS (default)
+    #[default]
+    Starttls,
+    // TLS from the start (SMTPS)
+    Tls,
+    // Unencrypted, e.g. for a local relay
+    None,
+}
+
+// Maps to the "sftp:" section; uploads run the Windows OpenSSH client
+// (sftp.exe) with key authentication
+//   sftp:
+//     host: sftp.example.com
+//     user: collector
+//     key_file: C:\ProgramData\rs-wineventlog\id_ed25519
+//     remote_path: /incoming/{hostname}/{file}
+//     after_upload: archive
+//     archive_dir: D:\uploaded
+#[derive(Deserialize, Serialize, Clone)]
+pub struct SftpConfig {
+    pub host: String,
+
+    #[serde(default = "default_sftp_port")]
+    pub port: u16,
+
+    pub user: String,
+
+    // Private key without a passphrase (default: the account's ~/.ssh keys)
+    #[serde(default)]
+    pub key_file: Option<String>,
+
+    // File holding the server's host key (default: ~/.ssh/known_hosts);
+    // unknown servers are refused
+    #[serde(default)]
+    pub known_hosts: Option<String>,
+
+    // Where a file goes; {file} is its name, {hostname} and {date} as in
+    // output paths (default: {file}, i.e. the login directory)
+    #[serde(default = "default_remote_path")]
+    pub remote_path: String,
+
+    // What happens to the local copy once it's uploaded
+    #[serde(default)]
+    pub after_upload: AfterUpload,
+
+    // Where "archive" moves uploaded files
+    #[serde(default)]
+    pub archive_dir: Option<String>,
+
+    // Time between attempts while an upload fails; seconds or a number with
+    // an s/m/h/d suffix (default: 1m)
+    #[serde(default, deserialize_with = "duration")]
+    pub retry_interval: Option<Duration>,
+}
+
+impl SftpConfig {
+    pub fn retry_interval(&self) -> Duration {
+        self.retry_interval.unwrap_or(Duration::from_secs(60))
+    }
+}
+
+// Maps to the "sentinel:" section. Events are posted to a data collection
+// rule (DCR) with an Entra ID app's client credentials; the app needs the
+// Monitoring Metrics Publisher role on the DCR
+//   sentinel:
+//     endpoint: https://my-dce-a1b2.eastus-1.ingest.monitor.azure.com
+//     dcr_id: dcr-00000000000000000000000000000000
+//     stream: Custom-WindowsEvents_CL
+//     tenant_id: 00000000-0000-0000-0000-000000000000
+//     client_id: 00000000-0000-0000-0000-000000000000
+//     client_secret: ${env:SENTINEL_CLIENT_SECRET}
+#[derive(Deserialize, Serialize, Clone)]
+pub struct SentinelConfig {
+    // The DCR's logs ingestion endpoint (or its data collection endpoint's)
+    pub endpoint: String,
+
+    // The DCR's immutable ID, dcr-...
+    pub dcr_id: String,
+
+    // Stream declared in the DCR that events go to when no entry of
+    // streams matches their channel
+    pub stream: String,
+
+    // Streams for particular channels, first match wins
+    #[serde(default)]
+    pub streams: Vec<SentinelStream>,
+
+    // Columns of the stream and the dotted event path each is filled from,
+    // e.g. TimeGenerated: TimeCreated; by default the whole event is sent
+    // with TimeGenerated added
+    #[serde(default)]
+    pub columns: BTreeMap<String, String>,
+
+    pub tenant_id: String,
+
+    pub client_id: String,
+
+    pub client_secret: String,
+
+    // Entra ID host tokens are requested from (default:
+    // login.microsoftonline.com; login.microsoftonline.us for US Government)
+    #[serde(default = "default_sentinel_authority")]
+    pub authority: String,
+
+    // Events held in memory while the service is throttling or unreachable;
+    // beyond that they are spooled to spool_file until they can be sent.
+    // Bytes or KB/MB/GB/TB (default: 64MB)
+    #[serde(default, deserialize_with = "size")]
+    pub buffer_max: Option<u64>,
+
+    // Default: sentinel.spool next to the executable
+    #[serde(default)]
+    pub spool_file: Option<String>,
+}
+
+//     streams:
+//       - channels: [Microsoft-Windows-Sysmon/Operational]
+//         stream: Custom-Sysmon_CL
+#[derive(Deserialize, Serialize, Clone)]
+pub struct SentinelStream {
+    // Channel names or globs
+    pub channels: Vec<String>,
+
+    pub stream: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AfterUpload {
+    // Leave it where it is (default)
+    #[default]
+    Keep,
+    Delete,
+    // Move it to archive_dir
+    Archive,
+}
+
+// Accepts 1048576, "1048576" or "1MB"
+fn size<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let Some(value) = Option::<NumberOrString>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    let text = value.0.trim().to_uppercase();
+    let (number, unit) = split_unit(&text);
+    let multiplier: u64 = match unit.trim_end_matches("IB").trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => {
+            return Err(serde::de::Error::custom(format!(
+                "invalid size '{}'",
+                value.0
+            )));
+        }
+    };
+    number
+        .parse::<u64>()
+        .map(|n| Some(n * multiplier))
+        .map_err(|_| serde::de::Error::custom(format!("invalid size '{}'", value.0)))
+}
+
+// Accepts 3600, "3600", "60m" or "1h"
+fn duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match Option::<NumberOrString>::deserialize(deserializer)? {
+        Some(value) => parse_duration(&value.0)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+/// Parses seconds, optionally with an s/m/h/d suffix, e.g. "90" or "15m".
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let text = value.trim().to_lowercase();
+    let (number, unit) = split_unit(&text);
+    let seconds: u64 = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86_400,
+        _ => return Err(format!("invalid duration '{}'", value)),
+    };
+    number
+        .parse::<u64>()
+        .map(|n| Duration::from_secs(n * seconds))
+        .map_err(|_| format!("invalid duration '{}'", value))
+}
+
+// YAML numbers and environment/registry strings both end up here as text
+struct NumberOrString(String);
+
+impl<'de> Deserialize<'de> for NumberOrString {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Number(u64),
+            Text(String),
+        }
+        Ok(NumberOrString(match Raw::deserialize(deserializer)? {
+            Raw::Number(n) => n.to_string(),
+            Raw::Text(s) => s,
+        }))
+    }
+}
+
+// "500MB" -> ("500", "MB")
+fn split_unit(text: &str) -> (&str, &str) {
+    let digits = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    (&text[..digits], text[digits..].trim())
 }
 
 // Default value function for batch_size
@@ -26,30 +1130,201 @@ fn default_batch_size() -> usize {
     10
 }
 
-pub fn load(path: Option<String>) -> Result<Config, Box<dyn std::error::Error>> {
+fn default_email_subject() -> String {
+    "[{hostname}] {rule}: {Provider} {EventID}".to_string()
+}
+
+fn default_email_body() -> String {
+    "{TimeCreated} {Computer} {Channel} {Provider} {EventID}\n{Message}".to_string()
+}
+
+fn default_email_digest_subject() -> String {
+    "[{hostname}] {count} alerts".to_string()
+}
+
+fn default_webhook_fields() -> Vec<String> {
+    ["Computer", "Channel", "Provider", "EventID", "TimeCreated"]
+        .map(String::from)
+        .to_vec()
+}
+
+fn default_sftp_port() -> u16 {
+    22
+}
+
+fn default_sentinel_authority() -> String {
+    "login.microsoftonline.com".to_string()
+}
+
+fn default_remote_path() -> String {
+    "{file}".to_string()
+}
+
+fn default_schedule_max_events() -> usize {
+    10_000
+}
+
+// Default value function for lookback_events
+fn default_lookback_events() -> usize {
+    1000
+}
+
+impl Config {
+    pub fn checkpoint_path(&self) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+        Ok(match &self.checkpoint_file {
+            Some(path) => path.into(),
+            None => std::env::current_exe()?
+                .parent()
+                .ok_or("executable has no parent directory")?
+                .join("checkpoints.json"),
+        })
+    }
+
+    pub fn state_path(&self) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+        Ok(match &self.state_file {
+            Some(path) => path.into(),
+            None => std::env::current_exe()?
+                .parent()
+                .ok_or("executable has no parent directory")?
+                .join("state.redb"),
+        })
+    }
+}
+
+// Where to load the configuration from, plus command-line overrides
+// that take precedence over both the file and environment variables
+#[derive(Clone, Default)]
+pub struct Source {
+    // --config; None means config.yaml next to the executable
+    // "-" reads YAML from stdin, http(s):// URLs are fetched on every load
+    pub path: Option<String>,
+
+    // --config-auth; Authorization header value sent when fetching a URL
+    pub auth: Option<String>,
+
+    // --profile; entry of the file's profiles section merged over the rest
+    pub profile: Option<String>,
+
+    // --channels; replaces the configured channel list when not empty
+    pub channels: Vec<String>,
+
+    // --output; replaces output_file
+    pub output: Option<String>,
+
+    // --event-id, --level, --provider and --data as XPath; replaces query
+    pub query: Option<String>,
+}
+
+// Default: config.yaml next to the executable
+pub fn default_path() -> std::io::Result<PathBuf> {
+    let exe_dir = std::env::current_exe()?.parent().unwrap().to_path_buf();
+    Ok(exe_dir.join("config.yaml"))
+}
+
+// Any failure here is a configuration error, whatever its cause
+pub fn load(source: &Source) -> Result<Config, Box<dyn std::error::Error>> {
+    build(source).map_err(|e| Fatal::new(Kind::Config, e).into())
+}
+
+fn build(source: &Source) -> Result<Config, Box<dyn std::error::Error>> {
     // Determine config file path
-    let config_path = match path {
+    let config_path = match source.path.clone() {
         Some(p) => p,
-        None => {
-            // Default: look for config.yaml next to the executable
-            let exe_dir = std::env::current_exe()?.parent().unwrap().to_path_buf();
-            exe_dir.join("config.yaml").to_string_lossy().to_string()
-        }
+        None => default_path()?.to_string_lossy().to_string(),
     };
 
+    let registry = RegistrySource::new(REGISTRY_KEY);
+    let policy = RegistrySource::new(POLICY_KEY);
+    let managed = policy_keys();
+
+    // With channels given on the command line or in the registry the default
+    // config.yaml is optional, so those setups need no file at all
+    let required = source.path.is_some()
+        || (source.channels.is_empty() && !registry.exists() && !policy.exists());
+
     // Build configuration from multiple sources (similar to viper in Go)
-    let settings = ConfigBuilder::builder()
-        // Source 1: Load from YAML file
-        // This reads config.yaml and parses it into a key-value map
-        .add_source(File::with_name(&config_path))
-        // Source 2: Load from environment variables
+    // Source 1: Settings from the registry (lowest priority)
+    let builder = ConfigBuilder::builder().add_source(registry);
+
+    // Source 2: Load YAML from stdin, a URL or (usually) a file
+    // This parses the YAML into a key-value map
+    let builder = if config_path == "-" {
+        builder.add_source(File::from_str(stdin_config()?, FileFormat::Yaml))
+    } else if is_url(&config_path) {
+        let yaml = fetch(&config_path, source.auth.as_deref())?;
+        builder.add_source(File::from_str(&yaml, FileFormat::Yaml))
+    } else {
+        builder.add_source(File::with_name(&config_path).required(required))
+    };
+
+    // Included files and the selected profile are merged over sources 1-2 and
+    // under everything else, so the layers below are added on top as defaults
+    let mut base = builder.build()?.collect()?;
+    // Relative include paths are resolved against the config file's folder
+    let dir = if config_path == "-" || is_url(&config_path) {
+        PathBuf::from(".")
+    } else {
+        Path::new(&config_path)
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default()
+    };
+    include(&mut base, &dir)?;
+    let profiles = base.remove("profiles");
+    if let Some(name) = &source.profile {
+        let profile = profiles
+            .and_then(|p| p.into_table().ok())
+            .and_then(|mut p| p.remove(name))
+            .ok_or_else(|| format!("config has no profile named '{}'", name))?;
+        merge(&mut base, profile.into_table()?, false);
+    }
+    let mut builder = ConfigBuilder::builder();
+    for (key, value) in base {
+        builder = builder.set_default(key, value)?;
+    }
+
+    let mut builder = builder
+        // Source 3: Load from environment variables
         // Looks for env vars like WINEVENTLOG_BATCH_SIZE, WINEVENTLOG_OUTPUT_FILE
         // The separator("_") means nested fields use underscores
         // Environment variables override file values (higher priority)
         .add_source(Environment::with_prefix("WINEVENTLOG").separator("_"))
-        // Build the final merged configuration
-        // This creates a config::Config (generic key-value map)
-        .build()?;
+        // Source 4: Group Policy, overriding the file and environment
+        .add_source(policy);
+
+    // Source 5: Command-line overrides, except for policy-managed settings
+    let cli_overrides = [
+        (
+            "channels",
+            (!source.channels.is_empty()).then(|| source.channels.clone().into()),
+        ),
+        ("output_file", source.output.clone().map(Value::from)),
+        ("query", source.query.clone().map(Value::from)),
+    ];
+    let mut query_flags = false;
+    for (key, value) in cli_overrides {
+        let Some(value) = value else { continue };
+        if managed.iter().any(|k| k == key) {
+            log::warn!(
+                "Ignoring command-line {}: it is managed by Group Policy",
+                key
+            );
+        } else {
+            query_flags |= key == "query";
+            builder = builder.set_override(key, value)?;
+        }
+    }
+
+    // Build the final merged configuration
+    // This creates a config::Config (generic key-value map)
+    let settings = builder.build()?;
+
+    // Resolve ${env:NAME} and dpapi:<base64> secret references in any value
+    let mut root = Value::new(None, ValueKind::Table(settings.collect()?));
+    // Taken while secret references are unresolved, so the hash that gets
+    // logged can't be used to check guesses of the secrets
+    let fingerprint = crate::identity::config_hash(root.clone().try_deserialize()?);
+    crate::secrets::resolve(&mut root)?;
 
     // Deserialize the generic config::Config into our specific Config struct
     // This is where serde's magic happens:
@@ -58,5 +1333,189 @@ pub fn load(path: Option<String>) -> Result<Config, Box<dyn std::error::Error>>
     // 3. Converts types (string -> String, array -> Vec, etc.)
     // 4. Applies defaults for missing optional fields
     // 5. Returns error if required fields are missing
-    Ok(settings.try_deserialize()?)
+    let mut config: Config = root.try_deserialize()?;
+    config.fingerprint = fingerprint;
+    if query_flags {
+        replace_channel_queries(&mut config)?;
+    }
+    validate(&config)?;
+    Ok(config)
+}
+
+// The query flags win over the channels' own queries too, like any other
+// command-line override. A structured query names its own channels, which
+// the flags' query couldn't be read from in its place.
+fn replace_channel_queries(config: &mut Config) -> Result<(), String> {
+    for channel in &mut config.channels {
+        match channel.query.as_deref() {
+            Some(query) if crate::query::is_structured(query) => {
+                return Err(format!(
+                    "channel '{}' has a structured query, which --event-id, --level, \
+                     --provider and --data can't replace; select it with --channels",
+                    channel.name
+                ));
+            }
+            Some(_) => {
+                log::warn!(
+                    "The query flags replace the query configured for channel '{}'",
+                    channel.name
+                );
+                channel.query = None;
+            }
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+// Checks the types alone can't express
+fn validate(config: &Config) -> Result<(), String> {
+    // It names its own channels, so every channel entry would read the same
+    // ones and write their events once for each entry
+    if config
+        .query
+        .as_deref()
+        .is_some_and(crate::query::is_structured)
+    {
+        return Err(
+            "query: a structured <QueryList> query reads the channels it names; \
+             give it as the query of a channels entry instead"
+                .to_string(),
+        );
+    }
+    Ok(())
+}
+
+// Deep-merges `overlay` into `base`: tables are merged key by key, lists
+// (such as channels) appended to with `append_lists` and replaced otherwise,
+// anything else is replaced
+fn merge(
+    base: &mut config::Map<String, Value>,
+    overlay: config::Map<String, Value>,
+    append_lists: bool,
+) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key).map(|v| &mut v.kind), value.kind) {
+            (Some(ValueKind::Table(existing)), ValueKind::Table(table)) => {
+                merge(existing, table, append_lists)
+            }
+            (Some(ValueKind::Array(existing)), ValueKind::Array(items)) if append_lists => {
+                existing.extend(items)
+            }
+            (_, kind) => {
+                base.insert(key, Value::new(None, kind));
+            }
+        }
+    }
+}
+
+// Merges the YAML files named by the `include` key (a path or a list, with
+// wildcards allowed in the file name) into `table`, each pattern's matches in
+// name order. Included files can't include further files.
+fn include(
+    table: &mut config::Map<String, Value>,
+    dir: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(patterns) = table.remove("include") else {
+        return Ok(());
+    };
+    let patterns = match patterns.kind {
+        ValueKind::Array(items) => items
+            .into_iter()
+            .map(|v| v.into_string())
+            .collect::<Result<Vec<_>, _>>()?,
+        kind => vec![Value::new(None, kind).into_string()?],
+    };
+
+    for pattern in patterns {
+        for path in expand(&dir.join(&pattern))? {
+            let mut included = ConfigBuilder::builder()
+                .add_source(File::from(path.as_path()).format(FileFormat::Yaml))
+                .build()
+                .map_err(|e| format!("cannot include {}: {}", path.display(), e))?
+                .collect()?;
+            if included.remove("include").is_some() {
+                log::warn!("Ignoring include in included file {}", path.display());
+            }
+            merge(table, included, true);
+        }
+    }
+    Ok(())
+}
+
+// A path without wildcards is returned as is (and must exist); otherwise the
+// files in its folder whose names match, which may be none
+fn expand(pattern: &Path) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let name = pattern
+        .file_name()
+        .map(|n| n.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if !name.contains(['*', '?']) {
+        return Ok(vec![pattern.to_path_buf()]);
+    }
+
+    let dir = pattern.parent().unwrap_or(Path::new("."));
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("cannot read {}: {}", dir.display(), e).into()),
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(Result::ok)
+        .map(|e| e.path())
+        .filter(|p| p.is_file())
+        .filter(|p| {
+            p.file_name()
+                .is_some_and(|n| glob_match(&name, &n.to_string_lossy().to_lowercase()))
+        })
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+/// Top-level settings managed by Group Policy, which can't be changed by the
+/// config file, environment variables or command-line flags.
+pub fn policy_keys() -> Vec<String> {
+    let mut keys: Vec<String> = RegistrySource::new(POLICY_KEY)
+        .collect()
+        .map(|table| table.into_keys().collect())
+        .unwrap_or_default();
+    keys.sort();
+    keys
+}
+
+// True for configs that are fetched rather than read from disk
+pub fn is_url(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+fn stdin_config() -> Result<&'static str, Box<dyn std::error::Error>> {
+    if let Some(yaml) = STDIN_CONFIG.get() {
+        return Ok(yaml);
+    }
+    let mut yaml = String::new();
+    std::io::stdin().read_to_string(&mut yaml)?;
+    Ok(STDIN_CONFIG.get_or_init(|| yaml))
+}
+
+#[cfg(not(feature = "remote-config"))]
+fn fetch(url: &str, _auth: Option<&str>) -> Result<String, Box<dyn std::error::Error>> {
+    Err(format!(
+        "cannot fetch config from {}: built without the 'remote-config' feature",
+        url
+    )
+    .into())
+}
+
+#[cfg(feature = "remote-config")]
+fn fetch(url: &str, auth: Option<&str>) -> Result<String, Box<dyn std::error::Error>> {
+    let mut request = crate::http_client::agent(Duration::from_secs(30)).get(url);
+    if let Some(auth) = auth {
+        request = request.header("Authorization", auth);
+    }
+    let yaml = request
+        .call()
+        .and_then(|response| response.into_body().read_to_string())
+        .map_err(|e| format!("cannot fetch config from {}: {}", url, e))?;
+    Ok(yaml)
 }
@@ -0,0 +1,27 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use windows::Win32::Foundation::{FALSE, TRUE};
+use windows::Win32::System::Console::{CTRL_BREAK_EVENT, SetConsoleCtrlHandler};
+use windows::core::BOOL;
+
+static BREAK_PRESSED: AtomicBool = AtomicBool::new(false);
+
+/// Makes Ctrl+Break request a status report instead of terminating. Must be
+/// installed after the Ctrl+C handler: console handlers run last-added first.
+pub fn watch_break() -> windows::core::Result<()> {
+    unsafe { SetConsoleCtrlHandler(Some(on_control), true) }
+}
+
+/// Whether Ctrl+Break was pressed since the last call.
+pub fn take_break() -> bool {
+    BREAK_PRESSED.swap(false, Ordering::SeqCst)
+}
+
+unsafe extern "system" fn on_control(control: u32) -> BOOL {
+    if control == CTRL_BREAK_EVENT {
+        BREAK_PRESSED.store(true, Ordering::SeqCst);
+        TRUE
+    } else {
+        // Let the next handler (Ctrl+C shutdown) deal with it
+        FALSE
+    }
+}
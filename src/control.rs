@@ -0,0 +1,177 @@
+use crate::config;
+use log::{error, info, warn};
+use serde_json::{Value as JsonValue, json};
+use std::io::{BufRead, BufReader, Write};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::Duration;
+use windows::Win32::Foundation::{CloseHandle, ERROR_PIPE_CONNECTED, INVALID_HANDLE_VALUE};
+use windows::Win32::Security::Authorization::{
+    ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1,
+};
+use windows::Win32::Security::{PSECURITY_DESCRIPTOR, SECURITY_ATTRIBUTES};
+use windows::Win32::Storage::FileSystem::{
+    FlushFileBuffers, PIPE_ACCESS_DUPLEX, ReadFile, WriteFile,
+};
+use windows::Win32::System::Pipes::*;
+use windows::core::{HSTRING, PCWSTR};
+
+pub const DEFAULT_PIPE: &str = r"\\.\pipe\rs-wineventlog";
+
+// Only SYSTEM and (elevated) Administrators may connect
+const PIPE_SDDL: &str = "D:P(A;;GA;;;SY)(A;;GA;;;BA)";
+
+pub enum Command {
+    Status,
+    Reload,
+    Pause(String),
+    Resume(Option<String>),
+    Flush,
+    Rotate,
+}
+
+/// A command received over the pipe, answered by the monitor supervisor.
+pub struct Request {
+    pub command: Command,
+    pub reply: Sender<JsonValue>,
+}
+
+impl Command {
+    fn parse(line: &str) -> Result<Command, String> {
+        let mut parts = line.split_whitespace();
+        let verb = parts.next().unwrap_or("");
+        let arg = parts.collect::<Vec<_>>().join(" ");
+        match (verb, arg.is_empty()) {
+            ("status", true) => Ok(Command::Status),
+            ("reload", true) => Ok(Command::Reload),
+            ("pause", false) => Ok(Command::Pause(arg)),
+            ("resume", true) => Ok(Command::Resume(None)),
+            ("resume", false) => Ok(Command::Resume(Some(arg))),
+            ("flush", true) => Ok(Command::Flush),
+            ("rotate", true) => Ok(Command::Rotate),
+            ("pause", true) => Err("usage: pause <channel>".to_string()),
+            _ => Err(format!("unknown command '{}'", line.trim())),
+        }
+    }
+}
+
+pub fn ok(result: JsonValue) -> JsonValue {
+    json!({ "ok": true, "result": result })
+}
+
+pub fn err(message: impl Into<String>) -> JsonValue {
+    json!({ "ok": false, "error": message.into() })
+}
+
+/// Serves control commands on `pipe_name`, one client at a time, forwarding
+/// them to the supervisor over `requests`.
+pub fn serve(pipe_name: String, source: config::Source, requests: Sender<Request>) {
+    thread::spawn(move || {
+        let wide: Vec<u16> = pipe_name.encode_utf16().chain(std::iter::once(0)).collect();
+        // Kept for as long as the pipe is served
+        let mut descriptor = PSECURITY_DESCRIPTOR::default();
+        if let Err(e) = unsafe {
+            ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                &HSTRING::from(PIPE_SDDL),
+                SDDL_REVISION_1,
+                &mut descriptor,
+                None,
+            )
+        } {
+            error!("Failed to secure control pipe {}: {}", pipe_name, e);
+            return;
+        }
+        let attributes = SECURITY_ATTRIBUTES {
+            nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+            lpSecurityDescriptor: descriptor.0,
+            bInheritHandle: false.into(),
+        };
+        info!("Control API listening on {}", pipe_name);
+
+        loop {
+            let pipe = unsafe {
+                CreateNamedPipeW(
+                    PCWSTR(wide.as_ptr()),
+                    PIPE_ACCESS_DUPLEX,
+                    PIPE_TYPE_MESSAGE
+                        | PIPE_READMODE_MESSAGE
+                        | PIPE_WAIT
+                        | PIPE_REJECT_REMOTE_CLIENTS,
+                    // One client at a time
+                    1,
+                    4096,
+                    4096,
+                    0,
+                    Some(&attributes),
+                )
+            };
+            if pipe == INVALID_HANDLE_VALUE {
+                error!("Failed to create control pipe {}", pipe_name);
+                return;
+            }
+
+            unsafe {
+                if let Err(e) = ConnectNamedPipe(pipe, None)
+                    && e.code() != ERROR_PIPE_CONNECTED.to_hresult()
+                {
+                    warn!("Control pipe connection failed: {}", e);
+                    let _ = CloseHandle(pipe);
+                    continue;
+                }
+
+                let mut buffer = [0u8; 4096];
+                let mut read = 0u32;
+                let response = if ReadFile(pipe, Some(&mut buffer), Some(&mut read), None).is_ok() {
+                    let line = String::from_utf8_lossy(&buffer[..read as usize]);
+                    handle(line.trim(), &source, &requests)
+                } else {
+                    err("failed to read command")
+                };
+
+                let mut body = response.to_string();
+                body.push('\n');
+                let _ = WriteFile(pipe, Some(body.as_bytes()), None, None);
+                let _ = FlushFileBuffers(pipe);
+                let _ = DisconnectNamedPipe(pipe);
+                let _ = CloseHandle(pipe);
+            }
+        }
+    });
+}
+
+fn handle(line: &str, source: &config::Source, requests: &Sender<Request>) -> JsonValue {
+    let command = match Command::parse(line) {
+        Ok(c) => c,
+        Err(e) => return err(e),
+    };
+
+    // Refuse to tear down a working setup for a config that won't load
+    if let Command::Reload = command
+        && let Err(e) = config::load(source)
+    {
+        return err(format!("config is invalid, not reloading: {}", e));
+    }
+
+    let (reply, response) = mpsc::channel();
+    if requests.send(Request { command, reply }).is_err() {
+        return err("collector is shutting down");
+    }
+    response
+        .recv_timeout(Duration::from_secs(10))
+        .unwrap_or_else(|_| err("timed out waiting for the collector"))
+}
+
+/// Client side: sends a single command and prints the JSON response.
+pub fn send(pipe_name: &str, command: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut pipe = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(pipe_name)
+        .map_err(|e| format!("cannot open control pipe {}: {}", pipe_name, e))?;
+    pipe.write_all(command.join(" ").as_bytes())?;
+
+    let mut response = String::new();
+    BufReader::new(pipe).read_line(&mut response)?;
+    print!("{}", response);
+    Ok(())
+}
@@ -0,0 +1,324 @@
+use crate::fatal::Kind;
+use crate::output::Output;
+use crate::state::{self, State};
+use crate::stats::Stats;
+use log::{error, warn};
+use serde_json::{Map, Value as JsonValue, json};
+use std::backtrace::Backtrace;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::Write;
+use std::mem::ManuallyDrop;
+use std::os::windows::io::{AsRawHandle, FromRawHandle};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{Arc, Mutex, TryLockError, Weak};
+use std::time::{SystemTime, UNIX_EPOCH};
+use windows::Win32::System::Diagnostics::Debug::{EXCEPTION_POINTERS, SetUnhandledExceptionFilter};
+use windows::Win32::System::Threading::{GetCurrentProcess, GetCurrentThreadId, TerminateProcess};
+
+// Filter result that ends the process without the system's error dialog
+const EXCEPTION_EXECUTE_HANDLER: i32 = 1;
+
+// What a crash flushes and reports on: the current run's output, counters
+// and state, if it keeps any
+struct Watched {
+    output: Weak<Mutex<Output>>,
+    stats: Weak<Stats>,
+    state: Option<Arc<State>>,
+    // crashes.ndjson next to the state file, opened up front for the
+    // exception filter
+    reports: PathBuf,
+    file: Option<File>,
+}
+
+static WATCHED: Mutex<Option<Watched>> = Mutex::new(None);
+
+// The open crashes.ndjson, null until there is one. The exception filter
+// can't take locks or open files, so it writes to this handle only.
+static REPORTS: AtomicPtr<std::ffi::c_void> = AtomicPtr::new(std::ptr::null_mut());
+
+// How far each channel read from its checkpoint got with every event before
+// it written (not held back), which a panic saves as the checkpoint once the
+// output is flushed
+static REACHED: Mutex<BTreeMap<String, Reached>> = Mutex::new(BTreeMap::new());
+
+struct Reached {
+    state: Arc<State>,
+    bookmark: String,
+    high_water: Option<u64>,
+}
+
+/// Makes panics flush the output, save the bookmarks of the channels being
+/// read from their checkpoints and append a crash report, with each
+/// channel's last written and last saved EventRecordID, to crashes.ndjson
+/// next to the state file. A panic that ends the collector exits with code
+/// 70; one in a channel thread is reported and the thread restarted.
+///
+/// Unhandled exceptions (e.g. an access violation in a Windows API call)
+/// leave the process in no state to take locks or allocate, so they only
+/// append a short report through the already open crashes.ndjson. They go
+/// through the unhandled exception filter rather than a vectored handler,
+/// which would also see the first-chance exceptions Windows raises and
+/// handles itself, e.g. inside RPC.
+pub fn install() {
+    let default = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default(info);
+        let message = info
+            .payload()
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| info.payload().downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        record(json!({
+            "kind": "panic",
+            "message": message,
+            "location": info.location().map(|l| format!("{}:{}", l.file(), l.line())),
+            "thread": std::thread::current().name(),
+            "backtrace": Backtrace::force_capture().to_string(),
+        }));
+    }));
+    unsafe {
+        SetUnhandledExceptionFilter(Some(exception_filter));
+    }
+}
+
+/// Registers what the next crash flushes and reports on, replacing the
+/// previous run's on reload. `state_path` places the report even when the
+/// run keeps no state.
+pub fn watch(
+    output: &Arc<Mutex<Output>>,
+    stats: &Arc<Stats>,
+    state: Option<&Arc<State>>,
+    state_path: &Path,
+) {
+    let reports = state_path.with_file_name("crashes.ndjson");
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&reports)
+        .inspect_err(|e| warn!("Cannot open {}: {}", reports.display(), e))
+        .ok();
+    let handle = file
+        .as_ref()
+        .map_or(std::ptr::null_mut(), |f| f.as_raw_handle());
+    let mut watched = WATCHED.lock().unwrap_or_else(|e| e.into_inner());
+    REPORTS.store(handle, Ordering::SeqCst);
+    *watched = Some(Watched {
+        output: Arc::downgrade(output),
+        stats: Arc::downgrade(stats),
+        state: state.cloned(),
+        reports,
+        file,
+    });
+}
+
+/// Notes where a channel read from its checkpoint got to, with every event
+/// up to the bookmark written, for a panic to save before the read's own
+/// checkpoint.
+pub fn reached(channel: &str, state: &Arc<State>, bookmark: String, high_water: Option<u64>) {
+    REACHED.lock().unwrap_or_else(|e| e.into_inner()).insert(
+        channel.to_string(),
+        Reached {
+            state: Arc::clone(state),
+            bookmark,
+            high_water,
+        },
+    );
+}
+
+/// Forgets a channel's progress once its checkpoint is saved.
+pub fn settled(channel: &str) {
+    REACHED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(channel);
+}
+
+unsafe extern "system" fn exception_filter(info: *const EXCEPTION_POINTERS) -> i32 {
+    let (code, address) = unsafe {
+        info.as_ref()
+            .and_then(|i| i.ExceptionRecord.as_ref())
+            .map_or((0, std::ptr::null_mut()), |r| {
+                (r.ExceptionCode.0 as u32, r.ExceptionAddress)
+            })
+    };
+    let handle = REPORTS.load(Ordering::SeqCst);
+    if !handle.is_null() {
+        let mut line = Line::default();
+        let _ = writeln!(
+            line,
+            r#"{{"kind":"exception","message":"unhandled exception 0x{:08X} at {:?}","thread_id":{},"unix_time":{},"pid":{},"version":"{}"}}"#,
+            code,
+            address,
+            unsafe { GetCurrentThreadId() },
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            std::process::id(),
+            env!("CARGO_PKG_VERSION"),
+        );
+        // Not closed: the handle stays owned by WATCHED
+        let mut file = ManuallyDrop::new(unsafe { File::from_raw_handle(handle) });
+        let _ = file.write_all(line.as_bytes());
+    }
+    // The process is in no state to unwind or run exit handlers
+    unsafe {
+        let _ = TerminateProcess(GetCurrentProcess(), Kind::Crash.code().into());
+    }
+    EXCEPTION_EXECUTE_HANDLER
+}
+
+// A report line formatted on the stack; what doesn't fit is cut off
+struct Line {
+    buf: [u8; 512],
+    len: usize,
+}
+
+impl Default for Line {
+    fn default() -> Line {
+        Line {
+            buf: [0; 512],
+            len: 0,
+        }
+    }
+}
+
+impl Line {
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl std::fmt::Write for Line {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        let end = self.len + s.len();
+        if end > self.buf.len() {
+            return Err(std::fmt::Error);
+        }
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+fn record(mut report: JsonValue) {
+    // Held by the crashing thread itself, or the crash happened before
+    // there was anything to flush
+    let Ok(watched) = WATCHED.try_lock() else {
+        return;
+    };
+    let Some(watched) = watched.as_ref() else {
+        return;
+    };
+
+    // Not if the crashing thread was writing: the lock is its own
+    let flushed = watched
+        .output
+        .upgrade()
+        .is_some_and(|output| match output.try_lock() {
+            Ok(mut out) => out.flush().is_ok(),
+            Err(TryLockError::Poisoned(e)) => e.into_inner().flush().is_ok(),
+            Err(TryLockError::WouldBlock) => false,
+        });
+    // The events up to where each checkpointed read got are out, so the
+    // next run starts after them rather than at the last checkpoint
+    let checkpointed = if flushed { checkpoint() } else { Vec::new() };
+    // Events after the saved record are read again by the next --once or
+    // scheduled run
+    let mut channels = Map::new();
+    for (channel, counters) in watched
+        .stats
+        .upgrade()
+        .map(|s| s.snapshot())
+        .unwrap_or_default()
+    {
+        let saved = watched
+            .state
+            .as_ref()
+            .and_then(|state| state.bookmark(&channel))
+            .and_then(|xml| state::record_id(&xml));
+        channels.insert(
+            channel,
+            json!({ "last_written_record_id": counters.last_record(), "saved_record_id": saved }),
+        );
+    }
+    if let Some(obj) = report.as_object_mut() {
+        obj.insert("time".to_string(), json!(chrono::Utc::now().to_rfc3339()));
+        obj.insert("pid".to_string(), json!(std::process::id()));
+        obj.insert("version".to_string(), json!(env!("CARGO_PKG_VERSION")));
+        obj.insert("output_flushed".to_string(), json!(flushed));
+        obj.insert("checkpointed".to_string(), json!(checkpointed));
+        obj.insert("channels".to_string(), JsonValue::Object(channels));
+    }
+
+    let path = &watched.reports;
+    let Some(mut file) = watched.file.as_ref() else {
+        error!("Cannot write crash report to {}", path.display());
+        return;
+    };
+    match writeln!(file, "{}", report) {
+        Ok(()) => error!("Crash report written to {}", path.display()),
+        Err(e) => error!("Cannot write crash report to {}: {}", path.display(), e),
+    }
+}
+
+// Saves the bookmarks noted by `reached`; returns the channels saved
+fn checkpoint() -> Vec<String> {
+    let Ok(reached) = REACHED.try_lock() else {
+        return Vec::new();
+    };
+    reached
+        .iter()
+        .filter(
+            |(channel, r)| match r.state.set_bookmark(channel, &r.bookmark, r.high_water) {
+                Ok(()) => true,
+                Err(e) => {
+                    error!("Cannot save the checkpoint of {}: {}", channel, e);
+                    false
+                }
+            },
+        )
+        .map(|(channel, _)| channel.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_lines_stay_on_the_stack() {
+        let mut line = Line::default();
+        assert!(write!(line, "{{\"pid\":{}}}", 42).is_ok());
+        assert_eq!(line.as_bytes(), br#"{"pid":42}"#);
+        assert!(write!(line, "{}", "x".repeat(600)).is_err());
+        assert_eq!(line.as_bytes(), br#"{"pid":42}"#);
+    }
+
+    #[cfg(feature = "state")]
+    #[test]
+    fn panics_save_where_reads_got() {
+        let temp = |name: &str| {
+            let path = std::env::temp_dir().join(format!(
+                "rs-wineventlog-crash-{}-{}",
+                std::process::id(),
+                name
+            ));
+            let _ = std::fs::remove_file(&path);
+            path
+        };
+        let state = state::open(&temp("state.redb"), &temp("checkpoints.json")).unwrap();
+        let bookmark = "<BookmarkList><Bookmark Channel='Crash' RecordId='42' IsCurrent='true'/></BookmarkList>";
+        reached("Crash", &state, bookmark.to_string(), Some(42));
+        assert!(checkpoint().contains(&"Crash".to_string()));
+        assert_eq!(state.bookmark("Crash").as_deref(), Some(bookmark));
+        assert_eq!(state.high_water("Crash"), Some(42));
+
+        // Saved the regular way: nothing left for a panic
+        settled("Crash");
+        assert!(!checkpoint().contains(&"Crash".to_string()));
+    }
+}
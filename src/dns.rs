@@ -0,0 +1,347 @@
+use crate::hub;
+use serde_json::{Map, Value as JsonValue, json};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+// Followed by Audit or Analytical
+pub const SERVER_CHANNEL_PREFIX: &str = "Microsoft-Windows-DNSServer/";
+pub const CLIENT_CHANNEL: &str = "Microsoft-Windows-DNS-Client/Operational";
+
+const RECORD_TYPES: [(u64, &str); 28] = [
+    (1, "A"),
+    (2, "NS"),
+    (5, "CNAME"),
+    (6, "SOA"),
+    (12, "PTR"),
+    (13, "HINFO"),
+    (15, "MX"),
+    (16, "TXT"),
+    (17, "RP"),
+    (18, "AFSDB"),
+    (24, "SIG"),
+    (25, "KEY"),
+    (28, "AAAA"),
+    (29, "LOC"),
+    (33, "SRV"),
+    (35, "NAPTR"),
+    (39, "DNAME"),
+    (41, "OPT"),
+    (43, "DS"),
+    (46, "RRSIG"),
+    (47, "NSEC"),
+    (48, "DNSKEY"),
+    (50, "NSEC3"),
+    (64, "SVCB"),
+    (65, "HTTPS"),
+    (251, "IXFR"),
+    (252, "AXFR"),
+    (255, "ANY"),
+];
+
+const RESPONSE_CODES: [&str; 11] = [
+    "NOERROR", "FORMERR", "SERVFAIL", "NXDOMAIN", "NOTIMP", "REFUSED", "YXDOMAIN", "YXRRSET",
+    "NXRRSET", "NOTAUTH", "NOTZONE",
+];
+
+// Win32 DNS status codes the client logs, as response codes
+const CLIENT_STATUSES: [(u64, &str); 7] = [
+    (0, "NOERROR"),
+    (1460, "TIMEOUT"),
+    (9002, "SERVFAIL"),
+    (9003, "NXDOMAIN"),
+    (9004, "NOTIMP"),
+    (9005, "REFUSED"),
+    (9501, "NODATA"),
+];
+
+/// Adds `Dns` to DNS Server audit and analytical events and DNS Client
+/// events: the query name (without the trailing dot), record type and
+/// response code by name, the addresses involved, and for responses
+/// the answers decoded from the packet (server) or the query results
+/// (client). Other events are left alone.
+pub fn parse(event: &mut JsonValue) {
+    let Some(channel) = hub::channel(event) else {
+        return;
+    };
+    let server = channel
+        .to_ascii_lowercase()
+        .starts_with(&SERVER_CHANNEL_PREFIX.to_ascii_lowercase());
+    if !server && !channel.eq_ignore_ascii_case(CLIENT_CHANNEL) {
+        return;
+    }
+    let Some(data) = event.get("EventData").and_then(|d| d.as_object()) else {
+        return;
+    };
+    let dns = if server {
+        server_fields(data)
+    } else {
+        client_fields(data)
+    };
+    if !dns.is_empty()
+        && let Some(obj) = event.as_object_mut()
+    {
+        obj.insert("Dns".to_string(), JsonValue::Object(dns));
+    }
+}
+
+fn server_fields(data: &Map<String, JsonValue>) -> Map<String, JsonValue> {
+    let mut dns = Map::new();
+    // Analytical events name the query QNAME/QTYPE, audit events (record
+    // changes) NAME/TYPE
+    if let Some(name) = text(data, "QNAME").or_else(|| text(data, "NAME")) {
+        dns.insert("QueryName".to_string(), json!(domain(&name)));
+    }
+    if let Some(kind) = number(data, "QTYPE").or_else(|| number(data, "TYPE")) {
+        dns.insert("QueryType".to_string(), json!(record_type(kind)));
+    }
+    if let Some(code) = number(data, "RCODE") {
+        dns.insert("ResponseCode".to_string(), json!(response_code(code)));
+    }
+    if let Some(id) = number(data, "XID") {
+        dns.insert("TransactionId".to_string(), json!(id));
+    }
+    if let Some(tcp) = text(data, "TCP") {
+        let protocol = if tcp == "1" { "tcp" } else { "udp" };
+        dns.insert("Protocol".to_string(), json!(protocol));
+    }
+    // Source is the client for queries received, Destination the server
+    // asked for recursive queries sent
+    for (field, key) in [
+        ("Source", "SourceAddress"),
+        ("Destination", "DestinationAddress"),
+        ("InterfaceIP", "InterfaceAddress"),
+        ("Zone", "Zone"),
+        ("PolicyName", "Policy"),
+    ] {
+        if let Some(value) = text(data, field) {
+            dns.insert(key.to_string(), json!(value));
+        }
+    }
+    if let Some(answers) = text(data, "PacketData")
+        .and_then(|p| packet_answers(&p))
+        .filter(|a| !a.is_empty())
+    {
+        dns.insert("Answers".to_string(), JsonValue::Array(answers));
+    }
+    dns
+}
+
+fn client_fields(data: &Map<String, JsonValue>) -> Map<String, JsonValue> {
+    let mut dns = Map::new();
+    if let Some(name) = text(data, "QueryName") {
+        dns.insert("QueryName".to_string(), json!(domain(&name)));
+    }
+    if let Some(kind) = number(data, "QueryType") {
+        dns.insert("QueryType".to_string(), json!(record_type(kind)));
+    }
+    if let Some(status) = number(data, "QueryStatus").or_else(|| number(data, "Status")) {
+        let code = CLIENT_STATUSES
+            .iter()
+            .find(|(s, _)| *s == status)
+            .map_or_else(|| status.to_string(), |(_, name)| name.to_string());
+        dns.insert("ResponseCode".to_string(), json!(code));
+    }
+    if let Some(server) = text(data, "DnsServerIpAddress").or_else(|| text(data, "ServerList")) {
+        dns.insert("ServerAddress".to_string(), json!(server));
+    }
+    if let Some(results) = text(data, "QueryResults").filter(|r| r.contains(';')) {
+        dns.insert(
+            "Answers".to_string(),
+            JsonValue::Array(query_results(&results)),
+        );
+    }
+    dns
+}
+
+// "type:  5 edge.example.net;::ffff:93.184.216.34;" -> a CNAME answer and
+// an A answer
+fn query_results(results: &str) -> Vec<JsonValue> {
+    results
+        .split(';')
+        .map(str::trim)
+        .filter(|r| !r.is_empty())
+        .map(|result| match result.strip_prefix("type:") {
+            Some(rest) => {
+                let mut parts = rest.split_whitespace();
+                let kind = parts.next().and_then(|t| t.parse().ok()).unwrap_or(0);
+                json!({
+                    "Type": record_type(kind),
+                    "Data": domain(parts.next().unwrap_or_default()),
+                })
+            }
+            None => {
+                // IPv4 answers are written as IPv4-mapped IPv6 addresses
+                let address = result.strip_prefix("::ffff:").unwrap_or(result);
+                let kind = if address.contains(':') { "AAAA" } else { "A" };
+                json!({ "Type": kind, "Data": address })
+            }
+        })
+        .collect()
+}
+
+// The answer section of a DNS message given as hex, or None when it isn't
+// one (or doesn't parse)
+fn packet_answers(hex: &str) -> Option<Vec<JsonValue>> {
+    let hex = hex.trim_start_matches("0x");
+    let packet: Vec<u8> = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<_>>()?;
+    let questions = u16::from_be_bytes([*packet.get(4)?, *packet.get(5)?]);
+    let answers = u16::from_be_bytes([*packet.get(6)?, *packet.get(7)?]);
+
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = name(&packet, offset)?.1 + 4;
+    }
+    let mut parsed = Vec::new();
+    for _ in 0..answers {
+        let (owner, end) = name(&packet, offset)?;
+        let field = |at: usize| Some(u16::from_be_bytes([*packet.get(at)?, *packet.get(at + 1)?]));
+        let kind = field(end)? as u64;
+        let ttl = u32::from_be_bytes(packet.get(end + 4..end + 8)?.try_into().ok()?);
+        let length = field(end + 8)? as usize;
+        let start = end + 10;
+        let rdata = packet.get(start..start + length)?;
+        let value = match kind {
+            1 => Ipv4Addr::from(<[u8; 4]>::try_from(rdata).ok()?).to_string(),
+            28 => Ipv6Addr::from(<[u8; 16]>::try_from(rdata).ok()?).to_string(),
+            2 | 5 | 12 | 39 => name(&packet, start)?.0,
+            15 => format!("{} {}", field(start)?, name(&packet, start + 2)?.0),
+            16 => txt(rdata),
+            _ => rdata.iter().map(|b| format!("{:02x}", b)).collect(),
+        };
+        parsed.push(json!({
+            "Name": owner,
+            "Type": record_type(kind),
+            "Ttl": ttl,
+            "Data": value,
+        }));
+        offset = start + length;
+    }
+    Some(parsed)
+}
+
+// A possibly compressed name at `offset`, and where the record continues
+// after it
+fn name(packet: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Bounds the pointers followed, so a pointer loop can't hang
+    for _ in 0..128 {
+        let length = *packet.get(offset)? as usize;
+        if length == 0 {
+            return Some((labels.join("."), end.unwrap_or(offset + 1)));
+        }
+        if length & 0xC0 == 0xC0 {
+            end.get_or_insert(offset + 2);
+            offset = ((length & 0x3F) << 8) | *packet.get(offset + 1)? as usize;
+            continue;
+        }
+        let label = packet.get(offset + 1..offset + 1 + length)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        offset += 1 + length;
+    }
+    None
+}
+
+// Character strings, each preceded by its length
+fn txt(mut rdata: &[u8]) -> String {
+    let mut strings = Vec::new();
+    while let Some((&length, rest)) = rdata.split_first() {
+        let length = (length as usize).min(rest.len());
+        strings.push(String::from_utf8_lossy(&rest[..length]).into_owned());
+        rdata = &rest[length..];
+    }
+    strings.join("")
+}
+
+fn record_type(kind: u64) -> String {
+    RECORD_TYPES
+        .iter()
+        .find(|(t, _)| *t == kind)
+        .map_or_else(|| format!("TYPE{}", kind), |(_, name)| name.to_string())
+}
+
+fn response_code(code: u64) -> String {
+    RESPONSE_CODES
+        .get(code as usize)
+        .map_or_else(|| code.to_string(), |name| name.to_string())
+}
+
+// Queries end in the root's dot: "www.example.com."
+fn domain(name: &str) -> String {
+    name.trim().trim_end_matches('.').to_lowercase()
+}
+
+fn text(data: &Map<String, JsonValue>, key: &str) -> Option<String> {
+    let value = match data.get(key)? {
+        JsonValue::String(s) => s.trim().to_string(),
+        JsonValue::Number(n) => n.to_string(),
+        _ => return None,
+    };
+    (!value.is_empty()).then_some(value)
+}
+
+fn number(data: &Map<String, JsonValue>, key: &str) -> Option<u64> {
+    text(data, key)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A response to www.example.com A: a CNAME to web.example.com, whose
+    // name is compressed against the question, then its A record, owned
+    // through a pointer into the CNAME's data
+    const RESPONSE: &str = concat!(
+        "123481800001000200000000",
+        "03777777076578616d706c6503636f6d0000010001",
+        "c00c000500010000012c000603776562c010",
+        "c02d000100010000003c00045db8d822",
+    );
+
+    fn bytes(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn decodes_answers() {
+        assert_eq!(
+            packet_answers(RESPONSE).unwrap(),
+            [
+                json!({"Name": "www.example.com", "Type": "CNAME", "Ttl": 300, "Data": "web.example.com"}),
+                json!({"Name": "web.example.com", "Type": "A", "Ttl": 60, "Data": "93.184.216.34"}),
+            ]
+        );
+        assert_eq!(packet_answers(&format!("0x{}", RESPONSE)).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn truncated_packets_have_no_answers() {
+        assert_eq!(packet_answers(&RESPONSE[..RESPONSE.len() - 2]), None);
+        assert_eq!(packet_answers("1234"), None);
+        assert_eq!(packet_answers("not hex"), None);
+    }
+
+    #[test]
+    fn follows_name_pointers() {
+        let packet = bytes(RESPONSE);
+        assert_eq!(name(&packet, 12), Some(("www.example.com".to_string(), 29)));
+        // A pointer ends the name where it is, however far it leads
+        assert_eq!(name(&packet, 33), Some(("www.example.com".to_string(), 35)));
+        assert_eq!(name(&packet, 45), Some(("web.example.com".to_string(), 51)));
+    }
+
+    #[test]
+    fn pointer_loops_end() {
+        // Each pointer leads to the other
+        let packet = bytes("c002c000");
+        assert_eq!(name(&packet, 0), None);
+        // Past the end of the packet
+        assert_eq!(name(&bytes("03777777"), 0), None);
+        assert_eq!(name(&bytes("c0ff"), 0), None);
+    }
+}
@@ -0,0 +1,439 @@
+use crate::acl;
+use crate::config::{self, Config, Source};
+use crate::eventlog;
+use crate::output;
+use crate::privilege::Impersonation;
+use crate::query;
+use crate::state;
+use glob_match::glob_match;
+use serde::Serialize;
+use std::net::UdpSocket;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use windows::Win32::Foundation::{CloseHandle, E_ACCESSDENIED, HANDLE, LUID};
+use windows::Win32::Security::*;
+use windows::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+use windows::Win32::System::Threading::{GetCurrentProcess, OpenProcessToken};
+use windows::core::HSTRING;
+
+pub const DEFAULT_NTP_SERVER: &str = "time.windows.com";
+
+// Free space under which an output's disk is a warning, then a failure
+const LOW_DISK: u64 = 1 << 30;
+const FULL_DISK: u64 = 100 << 20;
+// Clock offset that is worth a warning; beyond the other Kerberos logons
+// fail and event times can't be correlated with other machines
+const SKEW_WARN: Duration = Duration::from_secs(2);
+const SKEW_FAIL: Duration = Duration::from_secs(300);
+// Seconds from the NTP epoch (1900) to the Unix epoch
+const NTP_EPOCH: u64 = 2_208_988_800;
+
+// ERROR_EVT_CHANNEL_NOT_FOUND as an HRESULT
+const CHANNEL_NOT_FOUND: i32 = 0x8007_3A9F_u32 as i32;
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+#[derive(Serialize)]
+struct Check {
+    name: String,
+    status: Status,
+    detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hint: Option<String>,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, status: Status, detail: impl Into<String>) -> Check {
+        Check {
+            name: name.into(),
+            status,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn hint(mut self, hint: impl Into<String>) -> Check {
+        self.hint = Some(hint.into());
+        self
+    }
+}
+
+/// Checks what most often keeps the collector from working: privileges,
+/// whether each configured channel can be read, whether the outputs can be
+/// reached, the state file, free disk space for file outputs and the clock.
+/// Prints a pass/warn/fail report with what to do about each problem, and
+/// fails when any check did.
+pub fn run(
+    source: &Source,
+    ntp_server: &str,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut checks = vec![elevation()];
+    match config::load(source) {
+        Ok(config) => {
+            checks.push(Check::new("Configuration", Status::Pass, "loaded"));
+            checks.extend(channels(&config));
+            checks.push(sink(&config));
+            checks.push(checkpoints(&config));
+            checks.extend(disk_space(&config));
+        }
+        Err(e) => checks.push(
+            Check::new("Configuration", Status::Fail, e.to_string())
+                .hint("Fix the configuration; the channel, output and state checks need it"),
+        ),
+    }
+    checks.push(clock(ntp_server));
+
+    let failed = checks.iter().filter(|c| c.status == Status::Fail).count();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&checks)?);
+    } else {
+        let width = checks.iter().map(|c| c.name.len()).max().unwrap_or(0);
+        for check in &checks {
+            let status = match check.status {
+                Status::Pass => "PASS",
+                Status::Warn => "WARN",
+                Status::Fail => "FAIL",
+            };
+            println!("{}  {:<width$}  {}", status, check.name, check.detail);
+            if let Some(hint) = &check.hint {
+                println!("      {:<width$}  -> {}", "", hint);
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(format!("{} of {} checks failed", failed, checks.len()).into());
+    }
+    Ok(())
+}
+
+fn elevation() -> Check {
+    let (elevated, security) = unsafe { token_rights() }.unwrap_or((false, false));
+    match (elevated, security) {
+        (true, true) => Check::new(
+            "Privileges",
+            Status::Pass,
+            "running elevated with SeSecurityPrivilege",
+        ),
+        (true, false) => Check::new(
+            "Privileges",
+            Status::Warn,
+            "running elevated without SeSecurityPrivilege",
+        )
+        .hint("The Security log needs it: check 'Manage auditing and security log' in the local security policy"),
+        (false, _) => Check::new("Privileges", Status::Warn, "not running elevated").hint(
+            "Fine when the account is in Event Log Readers; otherwise run elevated or as a service",
+        ),
+    }
+}
+
+// Whether the process token is elevated and holds SeSecurityPrivilege
+unsafe fn token_rights() -> windows::core::Result<(bool, bool)> {
+    unsafe {
+        let mut token = HANDLE::default();
+        OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token)?;
+
+        let mut elevation = TOKEN_ELEVATION::default();
+        let mut used = 0u32;
+        let elevated = GetTokenInformation(
+            token,
+            TokenElevation,
+            Some(&mut elevation as *mut _ as *mut _),
+            size_of::<TOKEN_ELEVATION>() as u32,
+            &mut used,
+        );
+
+        let _ = GetTokenInformation(token, TokenPrivileges, None, 0, &mut used);
+        // u64 words, so the buffer is aligned for TOKEN_PRIVILEGES
+        let mut buffer = vec![0u64; (used as usize).div_ceil(8).max(1)];
+        let privileges = GetTokenInformation(
+            token,
+            TokenPrivileges,
+            Some(buffer.as_mut_ptr() as *mut _),
+            used,
+            &mut used,
+        );
+        let _ = CloseHandle(token);
+        elevated?;
+        privileges?;
+
+        let mut security = LUID::default();
+        LookupPrivilegeValueW(None, SE_SECURITY_NAME, &mut security)?;
+        let privileges = &*(buffer.as_ptr() as *const TOKEN_PRIVILEGES);
+        let held = std::slice::from_raw_parts(
+            privileges.Privileges.as_ptr(),
+            privileges.PrivilegeCount as usize,
+        )
+        .iter()
+        .any(|p| p.Luid.LowPart == security.LowPart && p.Luid.HighPart == security.HighPart);
+        Ok((elevation.TokenIsElevated != 0, held))
+    }
+}
+
+// One check per configured channel, patterns expanded as monitoring does
+fn channels(config: &Config) -> Vec<Check> {
+    let available = match eventlog::available_channels() {
+        Ok(available) => available,
+        Err(e) => {
+            return vec![
+                Check::new(
+                    "Channels",
+                    Status::Fail,
+                    format!("cannot list channels: {}", e),
+                )
+                .hint("Check that the Windows Event Log service is running"),
+            ];
+        }
+    };
+    let mut checks = Vec::new();
+    for entry in &config.channels {
+        if let Some(query) = entry.query.as_deref().filter(|q| query::is_structured(q)) {
+            match query::select_paths(query) {
+                Ok(paths) => {
+                    for path in paths {
+                        checks.push(channel(&path, entry.run_as.as_ref()));
+                    }
+                }
+                Err(e) => checks.push(
+                    Check::new(format!("Query {}", entry.name), Status::Fail, e)
+                        .hint("Fix the <QueryList>, e.g. by building it in Event Viewer's custom view XML tab"),
+                ),
+            }
+            continue;
+        }
+        let names: Vec<&String> = if entry.name.contains('*') || entry.name.contains('?') {
+            available
+                .iter()
+                .filter(|ch| glob_match(&entry.name, ch))
+                .collect()
+        } else {
+            vec![&entry.name]
+        };
+        if names.is_empty() {
+            checks.push(
+                Check::new(
+                    format!("Channel {}", entry.name),
+                    Status::Warn,
+                    "pattern matches no channel",
+                )
+                .hint("list-channels shows the channels this machine has"),
+            );
+        }
+        for name in names {
+            checks.push(channel(name, entry.run_as.as_ref()));
+        }
+    }
+    checks
+}
+
+fn channel(name: &str, run_as: Option<&config::RunAsConfig>) -> Check {
+    let label = format!("Channel {}", name);
+    // Read as the configured account, like the channel's thread does
+    let _impersonation = match run_as.map(Impersonation::logon).transpose() {
+        Ok(impersonation) => impersonation,
+        Err(e) => {
+            return Check::new(label, Status::Fail, format!("run_as logon failed: {}", e))
+                .hint("Check the run_as password and that the account has the logon_type right");
+        }
+    };
+    let account = run_as.map_or(String::new(), |r| format!(" as {}", r.user));
+    match acl::can_read(name) {
+        Ok(()) => Check::new(label, Status::Pass, format!("readable{}", account)),
+        Err(e) if e.code() == E_ACCESSDENIED => {
+            Check::new(label, Status::Fail, format!("access denied{}", account)).hint(format!(
+                "Add the account to Event Log Readers or run elevated; channel-acl {} shows who may read it",
+                name
+            ))
+        }
+        Err(e) if e.code().0 == CHANNEL_NOT_FOUND => {
+            Check::new(label, Status::Fail, "channel does not exist")
+                .hint("list-channels shows the channels this machine has; install or enable the provider")
+        }
+        Err(e) => Check::new(label, Status::Fail, format!("cannot read: {}", e)),
+    }
+}
+
+// Probes every output without opening it, so nothing is created, rotated
+// or sent
+fn sink(config: &Config) -> Check {
+    let label = "Output";
+    let mut targets = vec![config.output_file.as_deref().unwrap_or("-")];
+    for route in &config.routes {
+        for target in &route.outputs {
+            if !targets.contains(&target.as_str()) {
+                targets.push(target);
+            }
+        }
+    }
+    let failed: Vec<String> = targets
+        .iter()
+        .filter_map(|target| output::probe(target, config).err())
+        .map(|e| e.to_string())
+        .collect();
+    if !failed.is_empty() {
+        return Check::new(label, Status::Fail, failed.join("; ")).hint(
+            "Check the address, credentials and firewall; a file output needs a writable directory",
+        );
+    }
+    let message = match targets.len() - 1 {
+        0 => format!("{} is reachable", targets[0]),
+        routed => format!("{} and {} route outputs are reachable", targets[0], routed),
+    };
+    Check::new(label, Status::Pass, message)
+}
+
+fn checkpoints(config: &Config) -> Check {
+    let label = "State file";
+    let path = match config.state_path() {
+        Ok(path) => path,
+        Err(e) => return Check::new(label, Status::Fail, e.to_string()),
+    };
+    if !path.exists() {
+        return Check::new(
+            label,
+            Status::Pass,
+            format!("{} not created yet, reading starts fresh", path.display()),
+        );
+    }
+    match state::check(&path) {
+        Ok(found) => Check::new(label, Status::Pass, format!("{}: {}", path.display(), found)),
+        Err(e) if e.to_string().contains("in use") => Check::new(label, Status::Warn, e.to_string()),
+        Err(e) => Check::new(label, Status::Fail, e.to_string()).hint(
+            "Move the file aside to start over; events up to each channel's high-water mark are lost with it",
+        ),
+    }
+}
+
+// Free space where file outputs and the state file are written, once per
+// directory
+fn disk_space(config: &Config) -> Vec<Check> {
+    let mut targets: Vec<&str> = config.output_file.iter().map(String::as_str).collect();
+    for route in &config.routes {
+        targets.extend(route.outputs.iter().map(String::as_str));
+    }
+    let mut dirs: Vec<PathBuf> = targets.into_iter().filter_map(file_dir).collect();
+    if let Ok(state) = config.state_path() {
+        dirs.extend(state.parent().map(Path::to_path_buf));
+    }
+    dirs.sort();
+    dirs.dedup();
+
+    let mut checks = Vec::new();
+    for dir in dirs {
+        let label = format!("Disk {}", dir.display());
+        let mut free = 0u64;
+        let result = unsafe {
+            GetDiskFreeSpaceExW(&HSTRING::from(dir.as_os_str()), Some(&mut free), None, None)
+        };
+        let check = match result {
+            Err(e) => Check::new(
+                label,
+                Status::Warn,
+                format!("cannot tell free space: {}", e),
+            ),
+            Ok(()) if free < FULL_DISK => {
+                Check::new(label, Status::Fail, format!("{} free", size(free)))
+            }
+            Ok(()) if free < LOW_DISK => {
+                Check::new(label, Status::Warn, format!("{} free", size(free)))
+            }
+            Ok(()) => Check::new(label, Status::Pass, format!("{} free", size(free))),
+        };
+        checks.push(match check.status {
+            Status::Pass => check,
+            _ => check.hint("Free up space, lower retention or write to another volume"),
+        });
+    }
+    checks
+}
+
+// The nearest existing directory a file output writes under; None for
+// stdout and network outputs
+fn file_dir(target: &str) -> Option<PathBuf> {
+    if target == "-" {
+        return None;
+    }
+    let path = output::file_path(target).ok()?;
+    // Partitioned paths: up to the first placeholder
+    let path = Path::new(path.split('{').next().unwrap_or(path));
+    let mut dir = if path.is_dir() { path } else { path.parent()? };
+    while !dir.is_dir() {
+        dir = dir.parent()?;
+    }
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    Some(dir.to_path_buf())
+}
+
+fn size(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1 << 30 => format!("{:.1} GiB", b as f64 / (1u64 << 30) as f64),
+        b => format!("{} MiB", b >> 20),
+    }
+}
+
+fn clock(server: &str) -> Check {
+    let label = "Clock";
+    match offset(server) {
+        Ok(offset) => {
+            let skew = offset.abs();
+            let detail = format!("{:+.3}s from {}", offset, server);
+            let hint = "Resynchronize with w32tm /resync and check the Windows Time service";
+            if Duration::from_secs_f64(skew) >= SKEW_FAIL {
+                Check::new(label, Status::Fail, detail).hint(hint)
+            } else if Duration::from_secs_f64(skew) >= SKEW_WARN {
+                Check::new(label, Status::Warn, detail).hint(hint)
+            } else {
+                Check::new(label, Status::Pass, detail)
+            }
+        }
+        Err(e) => Check::new(
+            label,
+            Status::Warn,
+            format!("cannot ask {} for the time: {}", server, e),
+        )
+        .hint("Pass --ntp-server with a time server this machine can reach (UDP 123)"),
+    }
+}
+
+// How far ahead of this machine's clock the server's is, in seconds, from
+// one SNTP request
+fn offset(server: &str) -> std::io::Result<f64> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+    socket.connect((server, 123))?;
+    // Version 4, client mode
+    let mut packet = [0u8; 48];
+    packet[0] = 0x23;
+    let sent = now();
+    socket.send(&packet)?;
+    let received = socket.recv(&mut packet)?;
+    let arrived = now();
+    if received < 48 {
+        return Err(std::io::Error::other("short NTP reply"));
+    }
+    // The server's transmit timestamp, against the middle of the round trip
+    let seconds = u32::from_be_bytes(packet[40..44].try_into().unwrap()) as f64;
+    let fraction = u32::from_be_bytes(packet[44..48].try_into().unwrap()) as f64 / 4_294_967_296.0;
+    if seconds == 0.0 {
+        return Err(std::io::Error::other("server is not synchronized"));
+    }
+    let server_time = seconds - NTP_EPOCH as f64 + fraction;
+    Ok(server_time - (sent + arrived) / 2.0)
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
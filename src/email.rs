@@ -0,0 +1,103 @@
+use crate::alert::{self, Alert};
+use crate::config::{EmailConfig, SmtpTls};
+use lettre::message::{Mailbox, header::ContentType};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Sends alert emails over SMTP: one per alert, or a digest of several.
+pub struct Email {
+    transport: SmtpTransport,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    config: EmailConfig,
+}
+
+impl Email {
+    pub fn new(config: &EmailConfig) -> Result<Email, String> {
+        let builder = match config.tls {
+            SmtpTls::Tls => SmtpTransport::relay(&config.server),
+            SmtpTls::Starttls => SmtpTransport::starttls_relay(&config.server),
+            SmtpTls::None => Ok(SmtpTransport::builder_dangerous(&config.server)),
+        }
+        .map_err(|e| format!("email.server '{}': {}", config.server, e))?;
+        let port = config.port.unwrap_or(match config.tls {
+            SmtpTls::Tls => 465,
+            SmtpTls::Starttls => 587,
+            SmtpTls::None => 25,
+        });
+        let mut builder = builder.port(port);
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        let mailbox = |address: &str| {
+            address
+                .parse::<Mailbox>()
+                .map_err(|e| format!("invalid email address '{}': {}", address, e))
+        };
+        if config.to.is_empty() {
+            return Err("email.to needs at least one recipient".to_string());
+        }
+        Ok(Email {
+            transport: builder.build(),
+            from: mailbox(&config.from)?,
+            to: config
+                .to
+                .iter()
+                .map(|a| mailbox(a))
+                .collect::<Result<_, _>>()?,
+            config: config.clone(),
+        })
+    }
+
+    /// Set when alerts are collected into digests.
+    pub fn digest(&self) -> Option<Duration> {
+        self.config.digest
+    }
+
+    /// Sends one alert on its own, or several as a digest noting the
+    /// `dropped` ones that didn't fit.
+    pub fn send(
+        &self,
+        alerts: &[Arc<Alert>],
+        dropped: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(first) = alerts.first() else {
+            return Ok(());
+        };
+        let (subject, body) = if alerts.len() == 1 && dropped == 0 {
+            (
+                alert::expand(&self.config.subject, first),
+                alert::expand(&self.config.body, first),
+            )
+        } else {
+            let count = (alerts.len() + dropped).to_string();
+            let mut body: Vec<String> = alerts
+                .iter()
+                .map(|a| format!("{}\n{}", a.rule, alert::expand(&self.config.body, a)))
+                .collect();
+            if dropped > 0 {
+                body.push(format!("...and {} more alerts", dropped));
+            }
+            (
+                alert::expand(
+                    &self.config.digest_subject.replace("{count}", &count),
+                    first,
+                ),
+                body.join("\n\n"),
+            )
+        };
+
+        let mut message = Message::builder()
+            .from(self.from.clone())
+            .subject(subject.replace(['\r', '\n'], " "))
+            .header(ContentType::TEXT_PLAIN);
+        for to in &self.to {
+            message = message.to(to.clone());
+        }
+        self.transport.send(&message.body(body)?)?;
+        Ok(())
+    }
+}
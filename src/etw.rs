@@ -0,0 +1,64 @@
+use std::time::Duration;
+use tracelogging as tlg;
+
+// Collect with e.g. `wpr -start` using a profile that enables
+// `*rs-wineventlog`, or `tracelog -guid *rs-wineventlog`
+tlg::define_provider!(PROVIDER, "rs-wineventlog");
+
+// Keywords, for filtering sessions to the events of interest
+const KW_PROCESSING: u64 = 0x1;
+const KW_LIFECYCLE: u64 = 0x2;
+
+/// Registers the collector's TraceLogging provider. Events are only built
+/// while an ETW session is listening, so this is cheap to leave on.
+pub fn register() {
+    // Safe for an EXE: the provider lives until the process exits
+    unsafe {
+        PROVIDER.register();
+    }
+}
+
+/// One drained subscription batch: how many events and how long rendering
+/// and writing them took.
+pub fn batch(channel: &str, events: u32, elapsed: Duration) {
+    tlg::write_event!(
+        PROVIDER,
+        "Batch",
+        level(Verbose),
+        keyword(KW_PROCESSING),
+        str8("Channel", channel),
+        u32("Events", &events),
+        u64("DurationMicros", &(elapsed.as_micros() as u64)),
+    );
+}
+
+pub fn write_error(channel: &str) {
+    tlg::write_event!(
+        PROVIDER,
+        "WriteError",
+        level(Error),
+        keyword(KW_PROCESSING),
+        str8("Channel", channel),
+    );
+}
+
+pub fn channel_restart(channel: &str, failures: u32, delay: Duration) {
+    tlg::write_event!(
+        PROVIDER,
+        "ChannelRestart",
+        level(Warning),
+        keyword(KW_LIFECYCLE),
+        str8("Channel", channel),
+        u32("Failures", &failures),
+        u64("DelayMillis", &(delay.as_millis() as u64)),
+    );
+}
+
+pub fn reload() {
+    tlg::write_event!(
+        PROVIDER,
+        "Reload",
+        level(Informational),
+        keyword(KW_LIFECYCLE),
+    );
+}
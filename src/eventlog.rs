@@ -1,24 +1,53 @@
-use crate::{output::Output, privilege, xml};
+use crate::alert::Alerts;
+use crate::checkpoint::Checkpoints;
+use crate::config::{
+    AccessDenied, ChannelConfig, Config, FlushConfig, Level, ParsersConfig, Priority, RenderMode,
+    ScheduleConfig,
+};
+use crate::control::{self, Command, Request};
+use crate::crash;
+use crate::evtapi::{EventLogApi, Metadata, Origin, Win32};
+use crate::fatal::{Fatal, Kind};
+use crate::filter::{EventFilter, MessageFilter};
+use crate::hub::{self, Hub};
+use crate::knowledge::KnowledgeBase;
+use crate::merge::Merger;
+use crate::metadata;
+use crate::overload::Shedder;
+use crate::scriptblock::ScriptBlocks;
+use crate::state::{self, State};
+use crate::stats::{self, ChannelStats, Stats};
+use crate::timestamp::{self, Timezone};
+use crate::{
+    cmdline, console, dns, etw, identity,
+    message::{self, Formatted},
+    output::Output,
+    privilege, publisher, query, securityalert, sysmon, xml,
+};
 use glob_match::glob_match;
 use log::{error, info, warn};
-use serde_json::Value as JsonValue;
-use std::io::Write;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
-use std::thread;
-use windows::Win32::Foundation::CloseHandle;
+use serde_json::{Value as JsonValue, json};
+use std::cell::OnceCell;
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 use windows::Win32::System::EventLog::*;
-use windows::Win32::System::Threading::{CreateEventW, ResetEvent, WaitForSingleObject};
-use windows::core::PCWSTR;
+use windows::Win32::System::Threading::{
+    GetCurrentThread, SetThreadPriority, THREAD_PRIORITY_ABOVE_NORMAL, THREAD_PRIORITY_BELOW_NORMAL,
+};
 
 pub fn list_channels() -> Result<(), Box<dyn std::error::Error>> {
-    for channel in get_available_channels()? {
+    for channel in available_channels()? {
         println!("{}", channel);
     }
     Ok(())
 }
 
-fn get_available_channels() -> Result<Vec<String>, Box<dyn std::error::Error>> {
+pub fn available_channels() -> Result<Vec<String>, Box<dyn std::error::Error>> {
     unsafe {
         let channel_enum = EvtOpenChannelEnum(None, 0)?;
         let mut channels = Vec::new();
@@ -37,21 +66,182 @@ fn get_available_channels() -> Result<Vec<String>, Box<dyn std::error::Error>> {
     }
 }
 
-pub fn monitor(
-    channels: &[String],
-    output: Output,
+/// Turns a disabled channel on, as `wevtutil sl <channel> /e:true` would;
+/// analytic and debug channels (e.g. Microsoft-Windows-DNSServer/Analytical)
+/// are off by default. Returns whether it was off.
+pub fn enable_channel(channel: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    unsafe {
+        let config = EvtOpenChannelConfig(None, &windows::core::HSTRING::from(channel), 0)
+            .map_err(|e| format!("cannot open channel '{}': {}", channel, e))?;
+        let mut value = EVT_VARIANT::default();
+        let mut used = 0u32;
+        let result = EvtGetChannelConfigProperty(
+            config,
+            EvtChannelConfigEnabled,
+            0,
+            std::mem::size_of::<EVT_VARIANT>() as u32,
+            Some(&mut value),
+            &mut used,
+        )
+        .and_then(|()| {
+            if value.Anonymous.BooleanVal.as_bool() {
+                return Ok(false);
+            }
+            value.Anonymous.BooleanVal = true.into();
+            EvtSetChannelConfigProperty(config, EvtChannelConfigEnabled, 0, &value)?;
+            EvtSaveChannelConfig(config, 0)?;
+            Ok(true)
+        });
+        let _ = EvtClose(config);
+        result.map_err(|e| format!("cannot enable channel '{}': {}", channel, e).into())
+    }
+}
+
+// How often counters are added to the totals in the state database
+const COUNTERS_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+// Watchdog backoff ceiling for restarting failed channel threads
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(60);
+// A restarted channel that stays up this long starts its backoff over
+const RESTART_RESET_AFTER: Duration = Duration::from_secs(300);
+
+struct Worker {
+    channel: String,
+    settings: Arc<ChannelConfig>,
+    handle: Option<JoinHandle<Result<(), Failure>>>,
+    started: Instant,
+    failures: u32,
+    restart_at: Option<Instant>,
+    paused: Arc<AtomicBool>,
+    // Collection lag is over the lag_alert threshold
+    lagging: bool,
+}
+
+// Why a channel thread ended, for the supervisor to act on
+enum Failure {
+    AccessDenied(String),
+    Error(String),
+}
+
+impl Failure {
+    fn new(e: Box<dyn std::error::Error>) -> Failure {
+        if is_access_denied(e.as_ref()) {
+            Failure::AccessDenied(e.to_string())
+        } else {
+            Failure::Error(e.to_string())
+        }
+    }
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Failure::AccessDenied(e) | Failure::Error(e) => f.write_str(e),
+        }
+    }
+}
+
+fn is_access_denied(e: &(dyn std::error::Error + 'static)) -> bool {
+    e.downcast_ref::<Fatal>()
+        .is_some_and(|f| f.kind == Kind::AccessDenied)
+}
+
+// Settings shared by every channel thread of one monitor run
+struct ChannelContext {
+    output: Arc<Mutex<Output>>,
     pretty: bool,
+    render: RenderMode,
+    typed_json: bool,
+    timezone: Timezone,
+    parsers: ParsersConfig,
+    // Set by parsers.knowledge_base and parsers.lookups
+    knowledge: Option<KnowledgeBase>,
+    // Parts of PowerShell script blocks waiting for the rest
+    script_blocks: Option<ScriptBlocks>,
     batch_size: usize,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let available = get_available_channels()?;
+    stop: Arc<AtomicBool>,
+    shutdown: Arc<AtomicBool>,
+    budget: Option<Arc<AtomicU64>>,
+    hub: Arc<Hub>,
+    // XPath query selecting the events read, unless a channel has its own
+    query: Option<String>,
+    // Set by the filter section
+    filter: Option<EventFilter>,
+    // Set by the channels' include_messages and exclude_messages
+    messages: Option<MessageFilter>,
+    // Locale IDs per provider, tried before the channel's own locales
+    provider_locales: HashMap<String, Vec<u32>>,
+    // Set by metadata_cache
+    metadata: Option<metadata::Cache>,
+    // The configured labels as a JSON object, None when there are none
+    labels: Option<JsonValue>,
+    // Set by max_events_per_sec
+    shedder: Option<Shedder>,
+    // Set by merge_window
+    merger: Option<Merger>,
+    // Set when alert rules are configured
+    alerts: Option<Alerts>,
+    // Fields kept or dropped in what the output writes
+    flush: FlushConfig,
+    // Only touched while holding the output lock
+    flush_state: Mutex<FlushState>,
+    // Set in scheduled mode, which polls from checkpoints instead of subscribing
+    schedule: Option<ScheduleConfig>,
+    checkpoints: Option<Checkpoints>,
+    // Where bookmarks and counters are kept between runs; only opened for
+    // checkpoints
+    state: Option<Arc<State>>,
+    // Set once a write fails; the run then ends with a sink error
+    output_failed: AtomicBool,
+    stats: Arc<Stats>,
+}
+
+// Events written since the output was last flushed
+struct FlushState {
+    pending: usize,
+    last: Instant,
+}
+
+const DEFAULT_SCRIPT_BLOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// State that outlives a single monitor run, i.e. survives config reloads.
+pub struct Runtime {
+    pub shutdown: Arc<AtomicBool>,
+    pub stats: Arc<Stats>,
+    pub hub: Arc<Hub>,
+    // Events left to write before shutting down (--max-events)
+    pub budget: Option<Arc<AtomicU64>>,
+}
+
+/// Why `monitor` returned.
+pub enum MonitorExit {
+    Shutdown,
+    Reload,
+}
+
+// The configured channels that exist, with glob patterns expanded, and
+// the entries with a structured query by name
+type Channels = Vec<(String, Arc<ChannelConfig>)>;
+
+fn resolve_channels(config: &Config) -> Result<Channels, Box<dyn std::error::Error>> {
+    let available = available_channels()?;
 
     let mut valid_channels = Vec::new();
-    for pattern in channels {
-        if pattern.contains('*') || pattern.contains('?') {
+    for entry in &config.channels {
+        let pattern = &entry.name;
+        let settings = Arc::new(entry.clone());
+        if let Some(query) = entry.query.as_deref().filter(|q| query::is_structured(q)) {
+            // Read as one subscription under the entry's name
+            let paths = query::select_paths(query)
+                .map_err(|e| Fatal::new(Kind::Config, format!("channel '{}': {}", pattern, e)))?;
+            for path in paths.iter().filter(|p| !available.contains(p)) {
+                warn!("Channel '{}' of query '{}' does not exist", path, pattern);
+            }
+            valid_channels.push((pattern.clone(), settings));
+        } else if pattern.contains('*') || pattern.contains('?') {
             let matches: Vec<_> = available
                 .iter()
                 .filter(|ch| glob_match(pattern, ch))
-                .cloned()
+                .map(|ch| (ch.clone(), Arc::clone(&settings)))
                 .collect();
             if matches.is_empty() {
                 warn!("No channels match pattern '{}'", pattern);
@@ -60,319 +250,1732 @@ pub fn monitor(
                 valid_channels.extend(matches);
             }
         } else if available.contains(pattern) {
-            valid_channels.push(pattern.clone());
+            valid_channels.push((pattern.clone(), settings));
         } else {
             warn!("Channel '{}' does not exist, skipping", pattern);
         }
     }
 
     if valid_channels.is_empty() {
-        return Err("No valid channels to subscribe to".into());
+        return Err(Fatal::new(Kind::NoChannels, "No valid channels to subscribe to").into());
     }
 
-    // Remove duplicates
-    valid_channels.sort();
-    valid_channels.dedup();
+    // Remove duplicates; a channel listed twice keeps its first entry's settings
+    valid_channels.sort_by(|a, b| a.0.cmp(&b.0));
+    valid_channels.dedup_by(|a, b| a.0 == b.0);
+
+    // A channel that can't be turned on (e.g. without administrator rights)
+    // would deliver nothing; the others are read regardless
+    let mut enabled = Vec::with_capacity(valid_channels.len());
+    for (name, settings) in valid_channels {
+        if settings.enable {
+            let channels = match settings
+                .query
+                .as_deref()
+                .filter(|q| query::is_structured(q))
+            {
+                Some(query) => query::select_paths(query)?,
+                None => vec![name.clone()],
+            };
+            let failed = channels
+                .iter()
+                .find_map(|channel| match enable_channel(channel) {
+                    Ok(true) => {
+                        info!("Enabled channel '{}'", channel);
+                        None
+                    }
+                    Ok(false) => None,
+                    Err(e) => Some(e),
+                });
+            if let Some(e) = failed {
+                warn!("Skipping channel '{}': {}", name, e);
+                continue;
+            }
+        }
+        enabled.push((name, settings));
+    }
+    if enabled.is_empty() {
+        return Err(Fatal::new(Kind::NoChannels, "No channel could be enabled").into());
+    }
+    let valid_channels = enabled;
+
+    let names: Vec<String> = valid_channels.iter().map(|(ch, _)| ch.clone()).collect();
+    identity::announce(config, &names);
+    Ok(valid_channels)
+}
+
+// How a context's events come in
+#[derive(Clone, Copy, PartialEq)]
+enum Pass {
+    // Read as they are logged
+    Live,
+    // Read from each channel's checkpoint on (--once)
+    CatchUp,
+    // Read back from an earlier run's output (reprocess)
+    Replay,
+}
+
+// A catch-up loads the state file even without a schedule. Live monitoring
+// keeps no state, so it doesn't hold the state file either and can run next
+// to a collector that does; neither does a replay, which reads no channel.
+fn context(
+    config: &Config,
+    output: &Arc<Mutex<Output>>,
+    pretty: bool,
+    runtime: &Runtime,
+    stop: &Arc<AtomicBool>,
+    pass: Pass,
+) -> Result<Arc<ChannelContext>, Box<dyn std::error::Error>> {
+    let state_path = config.state_path()?;
+    let state = (pass == Pass::CatchUp || pass == Pass::Live && config.schedule.is_some())
+        .then(|| state::open(&state_path, &config.checkpoint_path()?))
+        .transpose()?;
+    crash::watch(output, &runtime.stats, state.as_ref(), &state_path);
+    Ok(Arc::new(ChannelContext {
+        output: Arc::clone(output),
+        pretty,
+        render: config.render,
+        typed_json: config.typed_json,
+        timezone: config.timezone.clone(),
+        parsers: config.parsers.clone(),
+        knowledge: KnowledgeBase::new(&config.parsers),
+        script_blocks: config.parsers.script_blocks.then(|| {
+            ScriptBlocks::new(
+                config
+                    .parsers
+                    .script_block_timeout
+                    .unwrap_or(DEFAULT_SCRIPT_BLOCK_TIMEOUT),
+            )
+        }),
+        batch_size: config.batch_size,
+        stop: Arc::clone(stop),
+        shutdown: Arc::clone(&runtime.shutdown),
+        budget: runtime.budget.clone(),
+        hub: Arc::clone(&runtime.hub),
+        query: config.query.clone(),
+        filter: EventFilter::new(&config.filter, &config.channels)?,
+        messages: MessageFilter::new(&config.channels)?,
+        provider_locales: config
+            .provider_locales
+            .iter()
+            .map(|(provider, locales)| (provider.clone(), publisher::locale_ids(locales)))
+            .collect(),
+        metadata: config.metadata_cache.as_ref().map(metadata::Cache::new),
+        labels: (!config.labels.is_empty()).then(|| json!(config.labels)),
+        // --once catches up on a backlog, which the ceiling would mostly drop
+        shedder: config
+            .max_events_per_sec
+            .filter(|_| pass == Pass::Live)
+            .map(|ceiling| Shedder::new(ceiling, &config.channels)),
+        // --once reads one channel after the other, leaving nothing to merge
+        merger: config
+            .merge_window
+            .filter(|_| pass == Pass::Live)
+            .map(Merger::new),
+        alerts: Alerts::start(config)?,
+        flush: config.flush.clone(),
+        flush_state: Mutex::new(FlushState {
+            pending: 0,
+            last: Instant::now(),
+        }),
+        schedule: config.schedule.clone(),
+        checkpoints: state
+            .as_ref()
+            .map(|state| Checkpoints::new(Arc::clone(state))),
+        state,
+        output_failed: AtomicBool::new(false),
+        stats: Arc::clone(&runtime.stats),
+    }))
+}
+
+pub fn monitor(
+    config: &Config,
+    output: Output,
+    pretty: bool,
+    status_line: bool,
+    runtime: &Runtime,
+    requests: &Receiver<Request>,
+) -> Result<MonitorExit, Box<dyn std::error::Error>> {
+    let valid_channels = resolve_channels(config)?;
 
     let output = Arc::new(Mutex::new(output));
-    let shutdown = Arc::new(AtomicBool::new(false));
-    let mut handles = Vec::new();
-
-    // Set up Ctrl+C handler
-    let shutdown_signal = Arc::clone(&shutdown);
-    ctrlc::set_handler(move || {
-        info!("Received shutdown signal, stopping...");
-        shutdown_signal.store(true, Ordering::SeqCst);
-    })?;
+    let stats = &runtime.stats;
+    let shutdown = &runtime.shutdown;
+    // Stops this run's threads; set on shutdown and on reload
+    let stop = Arc::new(AtomicBool::new(false));
+    let ctx = context(config, &output, pretty, runtime, &stop, Pass::Live)?;
+
+    let status =
+        status_line.then(|| stats::spawn_status_line(Arc::clone(stats), Arc::clone(&stop)));
+
+    let spawn = |ch: &str, settings: &Arc<ChannelConfig>, paused: &Arc<AtomicBool>| {
+        let ch = ch.to_string();
+        let settings = Arc::clone(settings);
+        let ctx = Arc::clone(&ctx);
+        let counters = stats.channel(&ch);
+        let paused = Arc::clone(paused);
+        thread::spawn(move || {
+            monitor_channel(&Win32, &ch, &settings, ctx, counters, paused).map_err(Failure::new)
+        })
+    };
 
-    for ch in valid_channels {
-        let output = Arc::clone(&output);
-        let shutdown = Arc::clone(&shutdown);
-        let handle = thread::spawn(move || {
-            if let Err(e) = monitor_channel(&ch, output, pretty, shutdown, batch_size) {
-                error!("Error monitoring {}: {}", ch, e);
+    let mut workers: Vec<Worker> = valid_channels
+        .into_iter()
+        .map(|(ch, settings)| {
+            let paused = Arc::new(AtomicBool::new(false));
+            Worker {
+                handle: Some(spawn(&ch, &settings, &paused)),
+                channel: ch,
+                settings,
+                started: Instant::now(),
+                failures: 0,
+                restart_at: None,
+                paused,
+                lagging: false,
             }
-        });
-        handles.push(handle);
+        })
+        .collect();
+
+    let mut exit = MonitorExit::Shutdown;
+    // Set when a channel's access denied policy ends the run
+    let mut denied = None;
+    let mut counters_saved = Instant::now();
+
+    // Watchdog: restart channel threads that fail or panic, with backoff.
+    // Control requests are answered between checks.
+    'supervise: while !shutdown.load(Ordering::SeqCst) {
+        match requests.recv_timeout(Duration::from_millis(500)) {
+            Ok(Request {
+                command: Command::Reload,
+                reply,
+            }) => {
+                let _ = reply.send(control::ok(json!("reloading")));
+                exit = MonitorExit::Reload;
+                break;
+            }
+            Ok(request) => {
+                let response = handle_command(request.command, &workers, stats, &output);
+                let _ = request.reply.send(response);
+            }
+            Err(_) => {}
+        }
+
+        write_script_block_parts(&ctx, stats, false);
+        write_merged(&ctx, stats, false);
+        write_shedding_summary(&ctx, false);
+        if let Some(alerts) = &ctx.alerts {
+            alerts.report_suppressed(false);
+        }
+
+        if console::take_break() {
+            status_report(&workers, stats, ctx.checkpoints.as_ref());
+        }
+
+        // Events written before the output went quiet still reach it on time
+        flush_if_due(&mut lock_output(&output), &ctx, false);
+
+        if let Some(threshold) = config.lag_alert {
+            check_lag(&mut workers, stats, threshold);
+        }
+
+        if counters_saved.elapsed() >= COUNTERS_SAVE_INTERVAL {
+            save_counters(&ctx, stats);
+            counters_saved = Instant::now();
+        }
+
+        for w in workers.iter_mut() {
+            if w.handle.as_ref().is_some_and(|h| h.is_finished()) {
+                let failure = match w.handle.take().unwrap().join() {
+                    Ok(Ok(())) => None,
+                    Ok(Err(e)) => Some(e),
+                    Err(_) => Some(Failure::Error("channel thread panicked".to_string())),
+                };
+                if let Some(Failure::AccessDenied(e)) = &failure {
+                    match access_denied_policy(config, &w.settings) {
+                        AccessDenied::Skip => {
+                            error!("{}; continuing without it", e);
+                            continue;
+                        }
+                        AccessDenied::Retry => {}
+                        policy => {
+                            if policy == AccessDenied::Elevate {
+                                error!("Access denied — attempting to relaunch elevated");
+                                let _ = privilege::try_elevate();
+                            }
+                            denied = Some(Fatal::new(Kind::AccessDenied, e));
+                            break 'supervise;
+                        }
+                    }
+                }
+                if let Some(e) = failure {
+                    error!("Error monitoring {}: {}", w.channel, e);
+                    if w.started.elapsed() >= RESTART_RESET_AFTER {
+                        w.failures = 0;
+                    }
+                    let delay =
+                        Duration::from_secs(1 << w.failures.min(6)).min(RESTART_BACKOFF_MAX);
+                    w.failures += 1;
+                    warn!("Restarting {} in {}s", w.channel, delay.as_secs());
+                    etw::channel_restart(&w.channel, w.failures, delay);
+                    w.restart_at = Some(Instant::now() + delay);
+                }
+            }
+
+            if w.restart_at.is_some_and(|at| Instant::now() >= at) {
+                info!("Restarting monitor for {}", w.channel);
+                w.restart_at = None;
+                w.started = Instant::now();
+                w.handle = Some(spawn(&w.channel, &w.settings, &w.paused));
+            }
+        }
+
+        if workers
+            .iter()
+            .all(|w| w.handle.is_none() && w.restart_at.is_none())
+        {
+            break;
+        }
     }
 
-    for handle in handles {
-        let _ = handle.join();
+    stop.store(true, Ordering::SeqCst);
+
+    for w in workers {
+        if let Some(Ok(Err(e))) = w.handle.map(|h| h.join()) {
+            error!("Error monitoring {}: {}", w.channel, e);
+        }
+    }
+
+    if let Some(status) = status {
+        let _ = status.join();
+    }
+    write_script_block_parts(&ctx, stats, true);
+    write_merged(&ctx, stats, true);
+    write_shedding_summary(&ctx, true);
+    if let Some(alerts) = &ctx.alerts {
+        alerts.report_suppressed(true);
     }
 
     // Flush output before exiting
-    if let Ok(mut out) = output.lock() {
-        let _ = out.flush();
+    let mut out = lock_output(&output);
+    let _ = if ctx.flush.fsync {
+        out.sync()
+    } else {
+        out.flush()
+    };
+    drop(out);
+    save_counters(&ctx, stats);
+
+    if let Some(denied) = denied {
+        return Err(denied.into());
     }
+    if ctx.output_failed.load(Ordering::SeqCst) {
+        return Err(Fatal::new(Kind::Sink, "output is not writable").into());
+    }
+    if let MonitorExit::Shutdown = exit {
+        info!("Shutdown complete");
+    }
+    Ok(exit)
+}
 
-    info!("Shutdown complete");
-    Ok(())
+// Warns once when a channel falls further behind than `threshold`, and
+// again when it has caught up
+fn check_lag(workers: &mut [Worker], stats: &Stats, threshold: Duration) {
+    for w in workers.iter_mut() {
+        let lag = stats.channel(&w.channel).collection_lag();
+        if !w.lagging && lag > threshold {
+            warn!(
+                "{} is falling behind: newest written event is {}s old (lag_alert {}s)",
+                w.channel,
+                lag.as_secs(),
+                threshold.as_secs()
+            );
+            w.lagging = true;
+        } else if w.lagging && lag <= threshold {
+            info!("{} has caught up ({}s behind)", w.channel, lag.as_secs());
+            w.lagging = false;
+        }
+    }
 }
 
-fn monitor_channel(
+// Ctrl+Break: a snapshot of every channel on stderr, monitoring carries on
+fn status_report(workers: &[Worker], stats: &Stats, checkpoints: Option<&Checkpoints>) {
+    eprintln!(
+        "--- status: {} event(s), {} queued, {} write error(s) ---",
+        stats.total(),
+        stats.queued(),
+        stats.write_errors()
+    );
+    for w in workers {
+        let counters = stats.channel(&w.channel);
+        let state = if w.handle.is_none() {
+            "stopped"
+        } else if w.paused.load(Ordering::SeqCst) {
+            "paused"
+        } else {
+            "running"
+        };
+        // The last record written, else where the saved checkpoint points
+        let position = counters
+            .last_record()
+            .or_else(|| checkpoints.and_then(|c| c.record_id(&w.channel)))
+            .map_or("-".to_string(), |id| id.to_string());
+        eprintln!(
+            "{:<50} {:<8} events={} queued={} lag={}ms collection_lag={}ms write_errors={} restarts={} record={}",
+            w.channel,
+            state,
+            counters.events(),
+            counters.queued(),
+            counters.lag().as_millis(),
+            counters.collection_lag().as_millis(),
+            counters.write_errors(),
+            w.failures,
+            position
+        );
+    }
+}
+
+fn handle_command(
+    command: Command,
+    workers: &[Worker],
+    stats: &Stats,
+    output: &Mutex<Output>,
+) -> JsonValue {
+    let find = |name: &str| {
+        workers
+            .iter()
+            .find(|w| w.channel.eq_ignore_ascii_case(name))
+    };
+
+    match command {
+        Command::Status => {
+            let channels: Vec<JsonValue> = workers
+                .iter()
+                .map(|w| {
+                    let counters = stats.channel(&w.channel);
+                    json!({
+                        "channel": w.channel,
+                        "running": w.handle.is_some(),
+                        "paused": w.paused.load(Ordering::SeqCst),
+                        "restarts": w.failures,
+                        "events": counters.events(),
+                        "lag_ms": counters.lag().as_millis() as u64,
+                        "collection_lag_ms": counters.collection_lag().as_millis() as u64,
+                        "lagging": w.lagging,
+                        "write_errors": counters.write_errors(),
+                        "dropped": counters.dropped(),
+                    })
+                })
+                .collect();
+            control::ok(json!({ "total": stats.total(), "channels": channels }))
+        }
+        Command::Pause(name) => match find(&name) {
+            Some(w) => {
+                w.paused.store(true, Ordering::SeqCst);
+                info!("Paused {}", w.channel);
+                control::ok(json!({ "paused": w.channel }))
+            }
+            None => control::err(format!("not monitoring channel '{}'", name)),
+        },
+        Command::Resume(Some(name)) => match find(&name) {
+            Some(w) => {
+                w.paused.store(false, Ordering::SeqCst);
+                info!("Resumed {}", w.channel);
+                control::ok(json!({ "resumed": w.channel }))
+            }
+            None => control::err(format!("not monitoring channel '{}'", name)),
+        },
+        Command::Resume(None) => {
+            for w in workers {
+                w.paused.store(false, Ordering::SeqCst);
+            }
+            info!("Resumed all channels");
+            control::ok(json!({ "resumed": "all" }))
+        }
+        Command::Flush => match lock_output(output).flush() {
+            Ok(()) => control::ok(json!("flushed")),
+            Err(e) => control::err(e.to_string()),
+        },
+        Command::Rotate => match lock_output(output).rotate() {
+            Ok(rotated) => {
+                info!("Rotated output to {}", rotated.display());
+                control::ok(json!({ "rotated": rotated }))
+            }
+            Err(e) => control::err(e.to_string()),
+        },
+        // Handled by the supervisor loop itself
+        Command::Reload => control::err("reload must be handled by the supervisor"),
+    }
+}
+
+fn monitor_channel<A: EventLogApi>(
+    api: &A,
     channel: &str,
-    output: Arc<Mutex<Output>>,
-    pretty: bool,
-    shutdown: Arc<AtomicBool>,
-    batch_size: usize,
+    settings: &ChannelConfig,
+    ctx: Arc<ChannelContext>,
+    counters: Arc<ChannelStats>,
+    paused: Arc<AtomicBool>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    // Create manual-reset event (TRUE for manual reset)
-    let signal = unsafe { CreateEventW(None, true, true, None)? };
-    let wide: Vec<u16> = channel.encode_utf16().chain(std::iter::once(0)).collect();
+    let locales = publisher::locale_ids(&settings.locales);
+    set_thread_priority(channel, settings.priority);
 
-    let subscription = unsafe {
-        match EvtSubscribe(
-            None,
-            Some(signal),
-            PCWSTR(wide.as_ptr()),
-            PCWSTR::null(),
-            None,
-            None,
-            None,
-            EvtSubscribeToFutureEvents.0 as u32,
-        ) {
-            Ok(h) => {
+    // Held for the whole thread: rendering also runs under this identity
+    let _impersonation = impersonate(channel, settings)?;
+
+    if let (Some(schedule), Some(checkpoints)) = (&ctx.schedule, &ctx.checkpoints) {
+        return poll_channel(
+            api,
+            channel,
+            settings,
+            schedule,
+            checkpoints,
+            &ctx,
+            &counters,
+            &paused,
+            &locales,
+        );
+    }
+
+    let subscription =
+        match api.subscribe(channel, channel_query(channel, settings, &ctx).as_deref()) {
+            Ok(subscription) => {
                 info!("Subscribed to: {}", channel);
-                h
+                subscription
             }
-            Err(e) => {
-                // Silently skip unsupported channels (Analytic/Debug)
-                if e.code() == windows::Win32::Foundation::ERROR_NOT_SUPPORTED.to_hresult() {
+            Err(e) => return open_failed(e, channel, settings),
+        };
+
+    while !ctx.stop.load(Ordering::SeqCst) {
+        // Leave events queued in the subscription until resumed
+        if paused.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(500));
+            continue;
+        }
+
+        // Wait with 1 second timeout to check shutdown flag
+        if !api.wait(&subscription, 1000) {
+            continue;
+        }
+
+        // Drain all available events; use short timeout to avoid blocking
+        // on shutdown
+        loop {
+            let events = api.next(&subscription, ctx.batch_size, 100);
+            if events.is_empty() {
+                break;
+            }
+            let read_at = Instant::now();
+            for (i, event) in events.iter().enumerate() {
+                counters.set_queued((events.len() - i) as u64);
+                if !deliver(api, event, channel, &ctx, &locales, &counters, read_at) {
                     return Ok(());
                 }
-                if e.code() == windows::Win32::Foundation::E_ACCESSDENIED {
-                    error!("Access denied — attempting to relaunch elevated");
-                    let _ = privilege::try_elevate();
-                    std::process::exit(1);
-                }
-                return Err(e.into());
             }
+            counters.set_queued(0);
+            etw::batch(channel, events.len() as u32, read_at.elapsed());
         }
-    };
+    }
 
-    while !shutdown.load(Ordering::SeqCst) {
-        unsafe {
-            // Wait for signal with 1 second timeout to check shutdown flag
-            let wait_result = WaitForSingleObject(signal, 1000);
+    info!("Stopped monitoring: {}", channel);
+    Ok(())
+}
 
-            // WAIT_OBJECT_0 = 0, anything else is timeout or error
-            if wait_result.0 != 0 {
-                continue;
-            }
+fn impersonate(
+    channel: &str,
+    settings: &ChannelConfig,
+) -> Result<Option<privilege::Impersonation>, Box<dyn std::error::Error>> {
+    let Some(run_as) = &settings.run_as else {
+        return Ok(None);
+    };
+    let impersonation = privilege::Impersonation::logon(run_as)?;
+    info!("Reading {} as {}", channel, run_as.user);
+    Ok(Some(impersonation))
+}
 
-            // Manually reset the event
-            let _ = ResetEvent(signal);
-
-            // Drain all available events
-            loop {
-                let mut events = vec![EVT_HANDLE::default(); batch_size];
-                let mut returned = 0u32;
-                let events_slice =
-                    std::slice::from_raw_parts_mut(events.as_mut_ptr() as *mut isize, events.len());
-
-                // Use short timeout to avoid blocking on shutdown
-                if EvtNext(subscription, events_slice, 100, 0, &mut returned).is_ok()
-                    && returned > 0
-                {
-                    for i in 0..returned as usize {
-                        if let Some(json) = render_event(events[i], pretty) {
-                            if let Ok(mut out) = output.lock() {
-                                if writeln!(*out, "{}", json).is_err() {
-                                    error!("Failed to write event, output may be closed");
-                                    let _ = EvtClose(events[i]);
-                                    let _ = EvtClose(subscription);
-                                    let _ = CloseHandle(signal);
-                                    return Ok(());
-                                }
-                                let _ = out.flush();
-                            }
+/// One-shot mode: reads every event after each channel's checkpoint (from
+/// the oldest when there is none), one channel after the other, and saves
+/// the checkpoints. Returns the number of events read per channel.
+pub fn drain(
+    config: &Config,
+    output: Output,
+    pretty: bool,
+    runtime: &Runtime,
+) -> Result<Vec<(String, usize)>, Box<dyn std::error::Error>> {
+    let channels = resolve_channels(config)?;
+    let output = Arc::new(Mutex::new(output));
+    let ctx = context(
+        config,
+        &output,
+        pretty,
+        runtime,
+        &runtime.shutdown,
+        Pass::CatchUp,
+    )?;
+    let checkpoints = ctx.checkpoints.as_ref().unwrap();
+
+    let mut summary = Vec::new();
+    let mut denied = None;
+    for (channel, settings) in &channels {
+        let _impersonation = impersonate(channel, settings)?;
+        let counters = runtime.stats.channel(channel);
+        let locales = publisher::locale_ids(&settings.locales);
+        let read = match read_since_checkpoint(
+            &Win32,
+            channel,
+            settings,
+            checkpoints,
+            None,
+            &ctx,
+            &counters,
+            &locales,
+        ) {
+            Err(e) if is_access_denied(e.as_ref()) => {
+                match access_denied_policy(config, settings) {
+                    // The next run tries again
+                    AccessDenied::Skip | AccessDenied::Retry => {
+                        error!("{}; skipping it", e);
+                        continue;
+                    }
+                    policy => {
+                        if policy == AccessDenied::Elevate {
+                            error!("Access denied — attempting to relaunch elevated");
+                            let _ = privilege::try_elevate();
                         }
-                        let _ = EvtClose(events[i]);
+                        denied = Some(e);
+                        break;
                     }
-                } else {
-                    // No more events, break out of drain loop
-                    break;
                 }
             }
+            read => read?,
+        };
+        match read {
+            Some(count) => summary.push((channel.clone(), count)),
+            // Stopped by --max-events; the checkpoint covers what was written
+            None if ctx.budget.is_some() && ctx.shutdown.load(Ordering::SeqCst) => break,
+            None => return Err(Fatal::new(Kind::Sink, "output is not writable").into()),
+        }
+        if ctx.shutdown.load(Ordering::SeqCst) {
+            break;
         }
     }
 
-    // Clean up handles
-    unsafe {
-        let _ = EvtClose(subscription);
-        let _ = CloseHandle(signal);
+    write_script_block_parts(&ctx, &runtime.stats, true);
+    if let Some(alerts) = &ctx.alerts {
+        alerts.report_suppressed(true);
+    }
+    lock_output(&output).flush()?;
+    save_counters(&ctx, &runtime.stats);
+    if let Some(denied) = denied {
+        return Err(denied);
     }
+    Ok(summary)
+}
 
-    info!("Stopped monitoring: {}", channel);
-    Ok(())
+/// Lines of a `reprocess` input and what became of them.
+#[derive(Default)]
+pub struct Reprocessed {
+    pub written: usize,
+    pub filtered: usize,
+    pub invalid: usize,
 }
 
-unsafe fn render_event(event: EVT_HANDLE, pretty: bool) -> Option<String> {
-    unsafe {
-        let mut used = 0u32;
-        let _ = EvtRender(
-            None,
-            event,
-            EvtRenderEventXml.0 as u32,
-            0,
-            None,
-            &mut used,
-            &mut 0,
-        );
-        let mut buffer = vec![0u16; (used / 2) as usize + 1];
+/// Runs events written by an earlier run (NDJSON, without flatten or
+/// key_case) through the current filter, the channels' EventID lists,
+/// min_level and message filters, parsers, time zone, labels and output
+/// again, e.g. to re-ship a day of events after fixing a mapping.
+/// Alert rules aren't checked again: they fired when the events were first
+/// collected. Lines that aren't JSON objects are skipped with a warning.
+pub fn reprocess(
+    mut config: Config,
+    output: Output,
+    pretty: bool,
+    runtime: &Runtime,
+    input: impl BufRead,
+) -> Result<Reprocessed, Box<dyn std::error::Error>> {
+    config.alerts.clear();
+    let output = Arc::new(Mutex::new(output));
+    let ctx = context(
+        &config,
+        &output,
+        pretty,
+        runtime,
+        &runtime.shutdown,
+        Pass::Replay,
+    )?;
 
-        if EvtRender(
-            None,
-            event,
-            EvtRenderEventXml.0 as u32,
-            used,
-            Some(buffer.as_mut_ptr() as *mut _),
-            &mut used,
-            &mut 0,
-        )
-        .is_ok()
+    let mut result = Reprocessed::default();
+    for (n, line) in input.lines().enumerate() {
+        if ctx.shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut v = match serde_json::from_str::<JsonValue>(&line) {
+            Ok(v) if v.is_object() => v,
+            _ => {
+                warn!("Line {}: not a JSON object, skipped", n + 1);
+                result.invalid += 1;
+                continue;
+            }
+        };
+        if let Some(filter) = &ctx.filter {
+            let text = |v: Option<&JsonValue>| v.and_then(JsonValue::as_str).map(str::to_string);
+            let origin = Origin {
+                user_id: text(v.pointer("/Security/@UserID")),
+                computer: text(v.get("Computer")),
+            };
+            if !filter.admit(hub::channel(&v).unwrap_or_default(), &origin) {
+                result.filtered += 1;
+                continue;
+            }
+        }
+        if let Some(messages) = &ctx.messages
+            && !messages.admit(&v)
         {
-            let len = buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len());
-            let xml = String::from_utf16_lossy(&buffer[..len]);
-            let mut v = xml::parse_to_json(&xml)?;
-
-            // Get provider name from parsed JSON
-            let provider_name = v
-                .get("Provider")
-                .and_then(|p| p.get("@Name"))
-                .and_then(|n| n.as_str())
-                .map(|s| s.to_string());
-
-            enrich_metadata(event, &mut v);
-
-            // Add friendly message with provider metadata
-            if let Some(prov) = provider_name {
-                if let Some(msg) = format_event_message(event, &prov) {
-                    if let Some(obj) = v.as_object_mut() {
-                        obj.insert("Message".to_string(), JsonValue::String(msg));
-                    }
-                }
+            result.filtered += 1;
+            continue;
+        }
+        if channel_settings(&config.channels, &v).is_some_and(|settings| suppressed(&v, settings)) {
+            result.filtered += 1;
+            continue;
+        }
+        if let Some(obj) = v.as_object_mut() {
+            // A file output with hash_chain links the lines anew
+            obj.remove("ChainHash");
+        }
+        timestamp::relocalize(&mut v, &ctx.timezone);
+        parse(&mut v, &ctx);
+        let channel = hub::channel(&v).unwrap_or_default().to_string();
+        let counters = runtime.stats.channel(&channel);
+        if !emit(v, &channel, &ctx, &counters, Instant::now()) {
+            return Err(Fatal::new(Kind::Sink, "output is not writable").into());
+        }
+        result.written += 1;
+    }
+
+    lock_output(&output).flush()?;
+    Ok(result)
+}
+
+// Adds this process's counts to the totals kept between runs
+fn save_counters(ctx: &ChannelContext, stats: &Stats) {
+    let Some(state) = &ctx.state else {
+        return;
+    };
+    if let Err(e) = state.save_counters(stats) {
+        warn!("Failed to save counters to the state database: {}", e);
+    }
+}
+
+// Decides what a failure to subscribe to or query a channel means
+fn open_failed(
+    e: windows::core::Error,
+    channel: &str,
+    settings: &ChannelConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Analytic and debug channels can only be queried, not subscribed to
+    if e.code() == windows::Win32::Foundation::ERROR_NOT_SUPPORTED.to_hresult() {
+        warn!(
+            "Channel '{}' can't be read live (an analytic or debug channel); \
+             poll it with schedule: instead",
+            channel
+        );
+        return Ok(());
+    }
+    // ...and only while they are disabled: an enabled one is still writing
+    // its trace file, which only an ETW session can read meanwhile
+    if e.code()
+        == windows::Win32::Foundation::ERROR_EVT_INVALID_OPERATION_OVER_ENABLED_DIRECT_CHANNEL
+            .to_hresult()
+    {
+        warn!(
+            "Channel '{}' is an enabled analytic or debug channel, which can't be read \
+             until it is disabled again (wevtutil sl /e:false); skipping it",
+            channel
+        );
+        return Ok(());
+    }
+    if e.code() == windows::Win32::Foundation::E_ACCESSDENIED {
+        let message = match &settings.run_as {
+            Some(run_as) => format!("{} cannot read {}: access denied", run_as.user, channel),
+            None => format!("cannot read {}: access denied", channel),
+        };
+        return Err(Fatal::new(Kind::AccessDenied, message).into());
+    }
+    Err(e.into())
+}
+
+// Relaunching elevated can't help a channel read as a run_as account, so
+// that one is retried instead
+fn access_denied_policy(config: &Config, settings: &ChannelConfig) -> AccessDenied {
+    match config.on_access_denied {
+        AccessDenied::Elevate if settings.run_as.is_some() => AccessDenied::Retry,
+        policy => policy,
+    }
+}
+
+// Renders one event and hands it to the hub and the output. Returns false
+// when the output can't be written anymore or the event budget is used up.
+fn deliver<A: EventLogApi>(
+    api: &A,
+    event: &A::Event,
+    channel: &str,
+    ctx: &ChannelContext,
+    locales: &[u32],
+    counters: &ChannelStats,
+    read_at: Instant,
+) -> bool {
+    // Filtered out before anything is rendered; not counted as dropped
+    if let Some(filter) = &ctx.filter
+        && !filter.admit(channel, &api.origin(event))
+    {
+        return true;
+    }
+    if let Some(shedder) = &ctx.shedder
+        && !shedder.admit(channel)
+    {
+        counters.drop_event();
+        return true;
+    }
+
+    // Spent already: stop before rendering one more
+    if ctx
+        .budget
+        .as_ref()
+        .is_some_and(|budget| budget.load(Ordering::SeqCst) == 0)
+    {
+        ctx.shutdown.store(true, Ordering::SeqCst);
+        return false;
+    }
+
+    let Some(mut v) = render_event(api, event, ctx, locales) else {
+        return true;
+    };
+    // Only known once the message is rendered; not counted as dropped either
+    if let Some(messages) = &ctx.messages
+        && !messages.admit(&v)
+    {
+        return true;
+    }
+    // Checked again: another channel may have spent the budget meanwhile
+    if let Some(budget) = &ctx.budget {
+        match budget.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)) {
+            // The last one: write it, then have everything shut down
+            Ok(1) => ctx.shutdown.store(true, Ordering::SeqCst),
+            Ok(_) => {}
+            Err(_) => {
+                ctx.shutdown.store(true, Ordering::SeqCst);
+                return false;
             }
+        }
+    }
+    timestamp::localize(&mut v, &ctx.timezone);
+    parse(&mut v, ctx);
+    // When the batch holding the event was read, for latency analysis
+    let received_at =
+        chrono::Utc::now() - chrono::Duration::from_std(read_at.elapsed()).unwrap_or_default();
+    if let Some(obj) = v.as_object_mut() {
+        let received_at = timestamp::format(received_at, &ctx.timezone);
+        xml::insert_after(
+            obj,
+            "TimeCreated",
+            "received_at",
+            JsonValue::String(received_at),
+        );
+    }
+    let v = match &ctx.script_blocks {
+        Some(blocks) => match blocks.add(v) {
+            Some(v) => v,
+            // Held back until the rest of its script block arrives
+            None => return true,
+        },
+        None => v,
+    };
+    match &ctx.merger {
+        // Written by the supervisor once the merge window has passed
+        Some(merger) => {
+            merger.add(v, channel, read_at);
+            true
+        }
+        None => emit(v, channel, ctx, counters, read_at),
+    }
+}
 
-            if pretty {
-                serde_json::to_string_pretty(&v).ok()
-            } else {
-                serde_json::to_string(&v).ok()
+// The configured channel-specific parsers
+fn parse(v: &mut JsonValue, ctx: &ChannelContext) {
+    if ctx.parsers.sysmon {
+        sysmon::parse(v);
+    }
+    if ctx.parsers.command_line {
+        cmdline::parse(v);
+    }
+    if ctx.parsers.dns {
+        dns::parse(v);
+    }
+    if ctx.parsers.security_alerts {
+        securityalert::parse(v);
+    }
+    if let Some(knowledge) = &ctx.knowledge {
+        knowledge.enrich(v);
+    }
+}
+
+fn write_merged(ctx: &ChannelContext, stats: &Stats, all: bool) {
+    let Some(merger) = &ctx.merger else {
+        return;
+    };
+    for held in merger.release(all) {
+        let counters = stats.channel(&held.channel);
+        emit(held.event, &held.channel, ctx, &counters, held.read_at);
+    }
+}
+
+// Script block parts whose block didn't complete in time (or, at the end of
+// a run, at all) are written on their own
+fn write_script_block_parts(ctx: &ChannelContext, stats: &Stats, all: bool) {
+    let Some(blocks) = &ctx.script_blocks else {
+        return;
+    };
+    for part in blocks.expired(all) {
+        let channel = hub::channel(&part).unwrap_or_default().to_string();
+        let counters = stats.channel(&channel);
+        emit(part, &channel, ctx, &counters, Instant::now());
+    }
+}
+
+// Reports events dropped by max_events_per_sec in the output itself, so
+// whoever reads it can tell the gap from a quiet host
+fn write_shedding_summary(ctx: &ChannelContext, now: bool) {
+    let Some(summary) = ctx
+        .shedder
+        .as_ref()
+        .and_then(|s| s.summary(now, &ctx.timezone))
+    else {
+        return;
+    };
+    warn!("Events per second over max_events_per_sec: {}", summary);
+    if lock_output(&ctx.output)
+        .write_event(&summary, &to_json(&summary, ctx.pretty))
+        .is_err()
+    {
+        error!("Failed to write load shedding summary");
+    }
+}
+
+// Final touches, then hands the event to the hub and the output. Returns
+// false when the output can't be written anymore.
+fn emit(
+    mut v: JsonValue,
+    channel: &str,
+    ctx: &ChannelContext,
+    counters: &ChannelStats,
+    read_at: Instant,
+) -> bool {
+    if ctx.typed_json {
+        // What render_event typed from the template is left as it is
+        let provider = hub::provider(&v).map(str::to_string);
+        let template = provider.as_deref().and_then(|p| publisher::template(&v, p));
+        let cached = template
+            .is_none()
+            .then(|| ctx.metadata.as_ref()?.get(provider.as_deref()?))
+            .flatten();
+        let typed = match (&template, cached.as_ref().and_then(|c| c.event(&v))) {
+            (Some(template), _) => &template.names[..],
+            (None, Some(cached_event)) => &cached_event.template[..],
+            (None, None) => &[][..],
+        };
+        xml::coerce_types(&mut v, typed);
+    }
+    if let (Some(labels), Some(obj)) = (&ctx.labels, v.as_object_mut()) {
+        obj.insert("Labels".to_string(), labels.clone());
+    }
+    let created = hub::time_created(&v).and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok());
+    ctx.hub.publish(&v);
+    if let Some(alerts) = &ctx.alerts {
+        alerts.check(&v);
+    }
+    // Each output shapes the event the way its sink or route asks for
+    let json = to_json(&v, ctx.pretty);
+    let mut out = lock_output(&ctx.output);
+    if out.write_event(&v, &json).is_err() {
+        error!("Failed to write event, output may be closed");
+        ctx.output_failed.store(true, Ordering::SeqCst);
+        counters.write_error();
+        etw::write_error(channel);
+        counters.set_queued(0);
+        return false;
+    }
+    flush_if_due(&mut out, ctx, true);
+    counters.record(read_at.elapsed());
+    if let Some(created) = created {
+        let behind = chrono::Utc::now().signed_duration_since(created);
+        counters.set_collection_lag(behind.to_std().unwrap_or_default());
+    }
+    if let Some(id) = hub::record_id(&v) {
+        counters.set_last_record(id);
+    }
+    true
+}
+
+// A panic while writing poisons the lock but leaves the output usable (at
+// worst with a torn line); carrying on beats skipping every later event
+fn lock_output(output: &Mutex<Output>) -> MutexGuard<'_, Output> {
+    output.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// High priority channels get to the output first when threads compete for it
+fn set_thread_priority(channel: &str, priority: Priority) {
+    let level = match priority {
+        Priority::High => THREAD_PRIORITY_ABOVE_NORMAL,
+        Priority::Normal => return,
+        Priority::Low => THREAD_PRIORITY_BELOW_NORMAL,
+    };
+    if let Err(e) = unsafe { SetThreadPriority(GetCurrentThread(), level) } {
+        warn!("Failed to set thread priority for {}: {}", channel, e);
+    }
+}
+
+// Scheduled mode: wake every interval, read what the channel gained since
+// its checkpoint, then sleep again
+#[allow(clippy::too_many_arguments)]
+fn poll_channel<A: EventLogApi>(
+    api: &A,
+    channel: &str,
+    settings: &ChannelConfig,
+    schedule: &ScheduleConfig,
+    checkpoints: &Checkpoints,
+    ctx: &ChannelContext,
+    counters: &ChannelStats,
+    paused: &AtomicBool,
+    locales: &[u32],
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!(
+        "Polling {} every {}s",
+        channel,
+        schedule.interval().as_secs()
+    );
+    while !ctx.stop.load(Ordering::SeqCst) {
+        if !paused.load(Ordering::SeqCst) {
+            let read = read_since_checkpoint(
+                api,
+                channel,
+                settings,
+                checkpoints,
+                Some(schedule.max_events),
+                ctx,
+                counters,
+                locales,
+            )?;
+            match read {
+                Some(count) => info!("Polled {}: {} event(s)", channel, count),
+                // Stopped by --max-events
+                None if ctx.budget.is_some() && ctx.shutdown.load(Ordering::SeqCst) => break,
+                // Output failed; the supervisor ends the run with a sink error
+                None => break,
             }
-        } else {
-            None
+        }
+
+        let wake = Instant::now() + schedule.interval();
+        while !ctx.stop.load(Ordering::SeqCst) && Instant::now() < wake {
+            thread::sleep(Duration::from_millis(500));
         }
     }
+
+    info!("Stopped polling: {}", channel);
+    Ok(())
 }
 
-unsafe fn enrich_metadata(event: EVT_HANDLE, json: &mut JsonValue) {
-    unsafe {
-        if let Some(obj) = json.as_object_mut() {
-            for (key, flag) in [
-                ("Keywords", EvtFormatMessageKeyword),
-                ("Level", EvtFormatMessageLevel),
-                ("Task", EvtFormatMessageTask),
-                ("Opcode", EvtFormatMessageOpcode),
-            ] {
-                if obj.contains_key(key) {
-                    if let Some(s) = format_message(event, flag) {
-                        obj.insert(key.to_string(), JsonValue::String(s));
-                    }
+// The channels entry an event was read by: the first naming its channel,
+// or reading it with a structured query
+fn channel_settings<'a>(
+    channels: &'a [ChannelConfig],
+    event: &JsonValue,
+) -> Option<&'a ChannelConfig> {
+    let channel = hub::channel(event)?.to_lowercase();
+    channels.iter().find(|settings| {
+        match settings
+            .query
+            .as_deref()
+            .filter(|q| query::is_structured(q))
+        {
+            Some(structured) => query::select_paths(structured).is_ok_and(|paths| {
+                paths
+                    .iter()
+                    .any(|p| glob_match(&p.to_lowercase(), &channel))
+            }),
+            None => glob_match(&settings.name.to_lowercase(), &channel),
+        }
+    })
+}
+
+// Whether an event is one the Event Log leaves out for a channel's EventID
+// lists and min_level (see channel_query), which a replay has to do itself.
+// Levels beyond Verbose count as Verbose, as in the suppress query.
+fn suppressed(event: &JsonValue, settings: &ChannelConfig) -> bool {
+    let id = hub::event_id(event);
+    (!settings.include_event_ids.is_empty()
+        && !id.is_some_and(|id| settings.include_event_ids.contains(&id)))
+        || id.is_some_and(|id| settings.exclude_event_ids.contains(&id))
+        || settings
+            .min_level
+            .is_some_and(|min| hub::level(event).unwrap_or(Level::Verbose) > min)
+}
+
+// A channel's own query wins over the global one; its EventID lists and
+// min_level narrow either down. An entry with a structured query is read
+// with that, `channel` only naming it.
+fn channel_query(channel: &str, settings: &ChannelConfig, ctx: &ChannelContext) -> Option<String> {
+    let base = settings.query.as_deref().or(ctx.query.as_deref());
+    let mut suppress =
+        query::event_id_suppress(&settings.include_event_ids, &settings.exclude_event_ids);
+    suppress.extend(settings.min_level.and_then(query::level_suppress));
+    query::channel_query(channel, base, &suppress)
+}
+
+/// Reads the events after a channel's checkpoint (from the oldest when it
+/// has none), at most `limit`, then flushes the output and moves the
+/// checkpoint past them. Returns how many events were read, or None when
+/// the output failed.
+#[allow(clippy::too_many_arguments)]
+fn read_since_checkpoint<A: EventLogApi>(
+    api: &A,
+    channel: &str,
+    settings: &ChannelConfig,
+    checkpoints: &Checkpoints,
+    limit: Option<usize>,
+    ctx: &ChannelContext,
+    counters: &ChannelStats,
+    locales: &[u32],
+) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+    let query = match api.query(channel, channel_query(channel, settings, ctx).as_deref()) {
+        Ok(query) => query,
+        Err(e) => return open_failed(e, channel, settings).map(|_| Some(0)),
+    };
+
+    let saved = checkpoints.get(channel);
+    let (bookmark, resumed) = match api.bookmark(saved.as_deref()) {
+        Ok(bookmark) if saved.is_none() => (bookmark, false),
+        Ok(bookmark) => match api.seek(&query, &bookmark) {
+            Ok(()) => (bookmark, true),
+            Err(e) => {
+                // The bookmarked event was cleared from the log; start over from the oldest
+                warn!(
+                    "Checkpoint for {} is no longer valid ({}), reading from the oldest event",
+                    channel, e
+                );
+                (bookmark, false)
+            }
+        },
+        Err(e) => {
+            warn!(
+                "Checkpoint for {} is corrupt ({}), reading from the oldest event",
+                channel, e
+            );
+            (api.bookmark(None)?, false)
+        }
+    };
+    // Reading from the oldest event again: what was written before is
+    // skipped up to the high-water mark, unless the log's numbering has
+    // started over since (it was deleted and recreated). A structured query
+    // reads several channels, whose EventRecordIDs can't be compared.
+    let structured = settings.query.as_deref().is_some_and(query::is_structured);
+    let written = (!resumed && !structured)
+        .then(|| checkpoints.high_water(channel))
+        .flatten()
+        .filter(|&high_water| {
+            let newest = api.newest_record_id(channel);
+            if newest.is_some_and(|newest| newest < high_water) {
+                warn!(
+                    "{} ends before the last event written (EventRecordID {}), reading all of it",
+                    channel, high_water
+                );
+                return false;
+            }
+            true
+        });
+
+    let limit = limit.unwrap_or(usize::MAX);
+    let mut read = 0;
+    let mut skipped = 0;
+    let mut newest = None;
+    let mut delivered = true;
+    while delivered && read < limit && !ctx.stop.load(Ordering::SeqCst) {
+        // Empty at the end of the log
+        let events = api.next(&query, ctx.batch_size.min(limit - read), 1000);
+        if events.is_empty() {
+            break;
+        }
+
+        let read_at = Instant::now();
+        for event in &events {
+            let record_id = api.record_id(event);
+            if written.is_some_and(|high_water| record_id.is_some_and(|id| id <= high_water)) {
+                skipped += 1;
+            } else {
+                delivered = deliver(api, event, channel, ctx, locales, counters, read_at);
+                if !delivered {
+                    break;
                 }
             }
+            let _ = api.update_bookmark(&bookmark, event);
+            newest = record_id.or(newest);
+            read += 1;
+        }
+        // Where a panic checkpoints: everything read so far is written
+        let held = ctx.merger.as_ref().is_some_and(|m| !m.is_empty())
+            || ctx.script_blocks.as_ref().is_some_and(|b| !b.is_empty());
+        if delivered
+            && !held
+            && let Ok(xml) = api.bookmark_xml(&bookmark)
+        {
+            checkpoints.reached(channel, xml, newest.filter(|_| !structured));
         }
+        etw::batch(channel, events.len() as u32, read_at.elapsed());
     }
+    drop(query);
+    if skipped > 0 {
+        info!(
+            "Skipped {} event(s) of {} already written by an earlier run",
+            skipped, channel
+        );
+    }
+
+    // The checkpoint must never get ahead of what reached the output, so
+    // events held back for the merge window or the rest of their script
+    // block are written first; the next run would start after them
+    write_script_block_parts(ctx, &ctx.stats, true);
+    write_merged(ctx, &ctx.stats, true);
+    let mut out = lock_output(&ctx.output);
+    if ctx.flush.fsync {
+        out.sync()?;
+    } else {
+        out.flush()?;
+    }
+    drop(out);
+    if read > 0 {
+        let newest = newest.filter(|_| !structured);
+        checkpoints.set(channel, api.bookmark_xml(&bookmark)?, newest)?;
+    }
+    Ok(delivered.then_some(read))
 }
 
-unsafe fn format_message(event: EVT_HANDLE, format_id: EVT_FORMAT_MESSAGE_FLAGS) -> Option<String> {
-    unsafe {
-        let mut buffer_size = 0u32;
-        let _ = EvtFormatMessage(
-            None,
-            Some(event),
-            0,
-            None,
-            format_id.0 as u32,
-            None,
-            &mut buffer_size,
-        );
-        if buffer_size == 0 {
+// Applies the flush policy; `wrote` counts one more event written since the
+// last flush
+fn flush_if_due(out: &mut Output, ctx: &ChannelContext, wrote: bool) {
+    // Network and arrow outputs write on their own batch limits instead, and
+    // a share that dropped keeps being retried
+    let sent = out.send_if_due();
+    if let Some(result) = sent {
+        if let Err(e) = result {
+            warn!("Failed to send batch to output: {}", e);
+        }
+        return;
+    }
+
+    let mut state = ctx.flush_state.lock().unwrap();
+    if wrote {
+        state.pending += 1;
+    }
+    if state.pending == 0 {
+        return;
+    }
+
+    let policy = &ctx.flush;
+    if !policy.due(state.pending, state.last.elapsed()) {
+        return;
+    }
+
+    let result = if policy.fsync {
+        out.sync()
+    } else {
+        out.flush()
+    };
+    if let Err(e) = result {
+        warn!("Failed to flush output: {}", e);
+    }
+    state.pending = 0;
+    state.last = Instant::now();
+}
+
+fn to_json(v: &JsonValue, pretty: bool) -> String {
+    if pretty {
+        serde_json::to_string_pretty(v).unwrap_or_default()
+    } else {
+        v.to_string()
+    }
+}
+
+fn render_event<A: EventLogApi>(
+    api: &A,
+    event: &A::Event,
+    ctx: &ChannelContext,
+    channel_locales: &[u32],
+) -> Option<JsonValue> {
+    let mut v = match ctx.render {
+        RenderMode::Values => render_values(api, event),
+        RenderMode::Xml => None,
+    }
+    .or_else(|| {
+        let xml = api.render_xml(event)?;
+        // AppLocker's decisions are only in UserData
+        let mut v = if ctx.parsers.security_alerts {
+            xml::parse_with_user_data(&xml)
+        } else {
+            xml::parse_to_json(&xml)
+        }?;
+        set_time_created(api, event, &mut v);
+        Some(v)
+    })?;
+
+    // Get provider name from parsed JSON
+    let provider_name = v
+        .get("Provider")
+        .and_then(|p| p.get("@Name"))
+        .and_then(|n| n.as_str())
+        .map(|s| s.to_string());
+
+    // Only consulted where the provider's own metadata can't be had here,
+    // and then looked up once per event
+    let cache = OnceCell::new();
+    let cached = || {
+        cache
+            .get_or_init(|| {
+                let (provider, cache) = provider_name.as_deref().zip(ctx.metadata.as_ref())?;
+                cache.get(provider)
+            })
+            .as_deref()
+    };
+    enrich_metadata(api, event, &mut v, cached);
+
+    // Add friendly message with provider metadata
+    let mut formatted = None;
+    if let Some(prov) = provider_name.as_deref() {
+        let template = publisher::template(&v, prov);
+        let (names, types) = match &template {
+            Some(template) => (&template.names[..], &template.types[..]),
+            None => match cached().and_then(|c| c.event(&v)) {
+                Some(cached) => (&cached.template[..], &cached.types[..]),
+                None => (&[][..], &[][..]),
+            },
+        };
+        publisher::apply_template(&mut v, names);
+        if ctx.typed_json {
+            publisher::type_event_data(&mut v, names, types);
+        }
+        let locales = ctx
+            .provider_locales
+            .get(prov)
+            .map_or(channel_locales, |l| l);
+        formatted = format_event_message(api, event, prov, locales).or_else(|| {
+            let message = cached()?.event(&v)?.message.clone()?;
+            Some(Formatted::Partial(message))
+        });
+    }
+    let msg = message::complete(&v, formatted);
+    if let Some(obj) = v.as_object_mut() {
+        obj.insert("Message".to_string(), JsonValue::String(msg));
+    }
+    Some(v)
+}
+
+// The event from its values, where that gives what its XML would: EventData
+// of manifest events is only known by name when the publisher's template
+// names it, so UserData events and ones without a template go through XML
+fn render_values<A: EventLogApi>(api: &A, event: &A::Event) -> Option<JsonValue> {
+    let mut v = api.render_values(event)?;
+    if v.get("EventIDQualifiers").is_none() {
+        let provider = hub::provider(&v)?.to_string();
+        publisher::name_event_data(&mut v, &provider);
+        if v.pointer("/EventData/Data").is_some() {
             return None;
         }
+    }
+    Some(v)
+}
 
-        let mut buffer = vec![0u16; buffer_size as usize];
-        if EvtFormatMessage(
-            None,
-            Some(event),
-            0,
-            None,
-            format_id.0 as u32,
-            Some(&mut buffer),
-            &mut buffer_size,
-        )
-        .is_ok()
+// Rewrites TimeCreated from the event's FILETIME so all seven fractional
+// digits survive, whatever precision the rendered XML used
+fn set_time_created<A: EventLogApi>(api: &A, event: &A::Event, json: &mut JsonValue) {
+    if let Some(time) = api.time_created(event).and_then(timestamp::from_filetime)
+        && let Some(created) = json.pointer_mut("/TimeCreated/@SystemTime")
+    {
+        *created = JsonValue::String(timestamp::format(time, &Timezone::Utc));
+    }
+}
+
+fn enrich_metadata<'a, A: EventLogApi>(
+    api: &A,
+    event: &A::Event,
+    json: &mut JsonValue,
+    cached: impl Fn() -> Option<&'a metadata::Provider>,
+) {
+    let Some(obj) = json.as_object_mut() else {
+        return;
+    };
+    // Opcode first: a cached opcode name is looked up with the raw Task
+    for (key, metadata) in [
+        ("Opcode", Metadata::Opcode),
+        ("Task", Metadata::Task),
+        ("Level", Metadata::Level),
+    ] {
+        if obj.contains_key(key)
+            && let Some(s) = api
+                .format(event, metadata)
+                .and_then(|names| names.into_iter().next())
+                .or_else(|| cached()?.name(metadata, obj))
         {
-            let len = buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len());
-            Some(String::from_utf16_lossy(&buffer[..len]))
-        } else {
-            None
+            obj.insert(key.to_string(), JsonValue::String(s));
+        }
+    }
+
+    // Keywords is a bit mask: emit one name per set bit, as resolved by
+    // the publisher, and keep the raw mask next to it
+    if let Some(index) = obj.keys().position(|k| k == "Keywords") {
+        let names = api
+            .format(event, Metadata::Keywords)
+            .or_else(|| Some(cached()?.keywords(obj)))
+            .unwrap_or_default();
+        let mask = obj.insert("Keywords".to_string(), JsonValue::from(names));
+        if let Some(mask) = mask {
+            obj.shift_insert(index + 1, "KeywordsMask".to_string(), mask);
         }
     }
 }
 
-unsafe fn format_event_message(event: EVT_HANDLE, provider_name: &str) -> Option<String> {
-    unsafe {
-        let provider_wide: Vec<u16> = provider_name
-            .encode_utf16()
-            .chain(std::iter::once(0))
-            .collect();
+// Tries each preferred locale in order, then the system default (0)
+fn format_event_message<A: EventLogApi>(
+    api: &A,
+    event: &A::Event,
+    provider_name: &str,
+    locales: &[u32],
+) -> Option<Formatted> {
+    locales
+        .iter()
+        .chain(std::iter::once(&0))
+        .find_map(|&locale| api.message(event, provider_name, locale))
+}
 
-        // Open provider metadata
-        let metadata =
-            match EvtOpenPublisherMetadata(None, PCWSTR(provider_wide.as_ptr()), None, 0, 0) {
-                Ok(m) => m,
-                Err(_) => return None,
-            };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evtapi::mock::Mock;
 
-        // Format message with provider metadata
-        let mut msg_buffer_size = 0u32;
-        let _ = EvtFormatMessage(
-            Some(metadata),
-            Some(event),
-            0,
-            None,
-            EvtFormatMessageEvent.0 as u32,
-            None,
-            &mut msg_buffer_size,
+    const CHANNEL: &str = "Test";
+
+    fn event(record: u64) -> String {
+        format!(
+            r#"<Event xmlns="http://schemas.microsoft.com/win/2004/08/events/event"><System><Provider Name="rs-wineventlog-test"/><EventID>100</EventID><Level>4</Level><TimeCreated SystemTime="2024-05-01T12:34:56.1234567Z"/><EventRecordID>{}</EventRecordID><Channel>Test</Channel><Computer>HOST</Computer></System><EventData><Data Name="Value">{}</Data></EventData></Event>"#,
+            record, record
+        )
+    }
+
+    // A --once style context writing nowhere; events are read back from
+    // the hub's lookback buffer
+    fn context(name: &str) -> (Arc<ChannelContext>, Arc<Hub>) {
+        configured(name, json!({}))
+    }
+
+    // The same with more settings from `extra`
+    fn configured(name: &str, extra: JsonValue) -> (Arc<ChannelContext>, Arc<Hub>) {
+        let path = std::env::temp_dir().join(format!(
+            "rs-wineventlog-{}-{}.redb",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let mut config = json!({
+            "channels": [CHANNEL],
+            "state_file": path,
+        });
+        if let (Some(config), Some(extra)) = (config.as_object_mut(), extra.as_object()) {
+            config.extend(extra.clone());
+        }
+        let config: Config = serde_json::from_value(config).unwrap();
+        let runtime = Runtime {
+            shutdown: Arc::new(AtomicBool::new(false)),
+            stats: Arc::new(Stats::default()),
+            hub: Arc::new(Hub::new(100)),
+            budget: None,
+        };
+        let output = Arc::new(Mutex::new(Output::discard()));
+        let ctx = super::context(
+            &config,
+            &output,
+            false,
+            &runtime,
+            &runtime.shutdown,
+            Pass::CatchUp,
         );
+        (ctx.unwrap(), runtime.hub)
+    }
 
-        if msg_buffer_size == 0 {
-            let _ = EvtClose(metadata);
-            return None;
+    fn read(api: &Mock, ctx: &ChannelContext, limit: Option<usize>) -> Option<usize> {
+        read_since_checkpoint(
+            api,
+            CHANNEL,
+            &ChannelConfig::default(),
+            ctx.checkpoints.as_ref().unwrap(),
+            limit,
+            ctx,
+            &ChannelStats::default(),
+            &[],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn resumes_from_checkpoint() {
+        let api = Mock::default();
+        for record in 1..=3 {
+            api.add(CHANNEL, &event(record));
         }
+        let (ctx, hub) = context("resume");
 
-        let mut msg_buffer = vec![0u16; msg_buffer_size as usize];
-        let result = if EvtFormatMessage(
-            Some(metadata),
-            Some(event),
-            0,
+        assert_eq!(read(&api, &ctx, None), Some(3));
+        api.add(CHANNEL, &event(4));
+        assert_eq!(read(&api, &ctx, None), Some(1));
+        assert_eq!(read(&api, &ctx, None), Some(0));
+
+        let records: Vec<u64> = hub
+            .recent()
+            .iter()
+            .filter_map(|e| hub::record_id(e))
+            .collect();
+        assert_eq!(records, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn skips_written_events_after_losing_checkpoint() {
+        let api = Mock::default();
+        for record in 1..=3 {
+            api.add(CHANNEL, &event(record));
+        }
+        let (ctx, hub) = context("high-water");
+        let checkpoints = ctx.checkpoints.as_ref().unwrap();
+
+        assert_eq!(read(&api, &ctx, None), Some(3));
+        checkpoints
+            .set(CHANNEL, "corrupt".to_string(), None)
+            .unwrap();
+        api.add(CHANNEL, &event(4));
+        assert_eq!(read(&api, &ctx, None), Some(4));
+        assert_eq!(checkpoints.high_water(CHANNEL), Some(4));
+
+        let records: Vec<u64> = hub
+            .recent()
+            .iter()
+            .filter_map(|e| hub::record_id(e))
+            .collect();
+        assert_eq!(records, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn stops_at_limit() {
+        let api = Mock::default();
+        for record in 1..=5 {
+            api.add(CHANNEL, &event(record));
+        }
+        let (ctx, _hub) = context("limit");
+
+        assert_eq!(read(&api, &ctx, Some(2)), Some(2));
+        assert_eq!(read(&api, &ctx, Some(2)), Some(2));
+        assert_eq!(read(&api, &ctx, None), Some(1));
+    }
+
+    #[test]
+    fn renders_events() {
+        let api = Mock::default();
+        api.add(CHANNEL, &event(7));
+        let (ctx, hub) = context("render");
+
+        assert_eq!(read(&api, &ctx, None), Some(1));
+        let recent = hub.recent();
+        let event = recent.first().unwrap();
+        assert_eq!(hub::event_id(event), Some(100));
+        assert_eq!(event["EventData"]["Value"], "7");
+        assert!(event.get("received_at").is_some());
+        assert!(!event["Message"].as_str().unwrap_or_default().is_empty());
+    }
+
+    #[test]
+    fn writes_held_script_block_parts_before_checkpoint() {
+        const POWERSHELL: &str = "Microsoft-Windows-PowerShell/Operational";
+        let api = Mock::default();
+        api.add(
+            POWERSHELL,
+            &format!(
+                r#"<Event xmlns="http://schemas.microsoft.com/win/2004/08/events/event"><System><Provider Name="Microsoft-Windows-PowerShell"/><EventID>4104</EventID><Level>5</Level><TimeCreated SystemTime="2024-05-01T12:34:56.1234567Z"/><EventRecordID>1</EventRecordID><Channel>{}</Channel><Computer>HOST</Computer></System><EventData><Data Name="MessageNumber">1</Data><Data Name="MessageTotal">2</Data><Data Name="ScriptBlockText">Get-</Data><Data Name="ScriptBlockId">b1</Data></EventData></Event>"#,
+                POWERSHELL
+            ),
+        );
+        let (ctx, hub) = configured(
+            "script-blocks",
+            json!({ "parsers": { "script_blocks": true } }),
+        );
+        let checkpoints = ctx.checkpoints.as_ref().unwrap();
+
+        let read = read_since_checkpoint(
+            &api,
+            POWERSHELL,
+            &ChannelConfig::default(),
+            checkpoints,
             None,
-            EvtFormatMessageEvent.0 as u32,
-            Some(&mut msg_buffer),
-            &mut msg_buffer_size,
+            &ctx,
+            &ChannelStats::default(),
+            &[],
         )
-        .is_ok()
-        {
-            let len = msg_buffer
-                .iter()
-                .position(|&c| c == 0)
-                .unwrap_or(msg_buffer.len());
-            Some(String::from_utf16_lossy(&msg_buffer[..len]))
-        } else {
-            None
+        .unwrap();
+        assert_eq!(read, Some(1));
+        // The other part never came; this one is written as it is
+        assert_eq!(checkpoints.get(POWERSHELL).as_deref(), Some("1"));
+        assert_eq!(hub.recent().len(), 1);
+    }
+
+    #[test]
+    fn access_denied_is_reported() {
+        let api = Mock::default();
+        api.add(CHANNEL, &event(1));
+        api.deny(CHANNEL);
+        let (ctx, _hub) = context("denied");
+
+        let err = read_since_checkpoint(
+            &api,
+            CHANNEL,
+            &ChannelConfig::default(),
+            ctx.checkpoints.as_ref().unwrap(),
+            None,
+            &ctx,
+            &ChannelStats::default(),
+            &[],
+        )
+        .unwrap_err();
+        assert!(is_access_denied(err.as_ref()));
+    }
+
+    #[test]
+    fn reprocess_applies_event_id_lists_and_min_level() {
+        let config: Config = serde_json::from_value(json!({
+            "channels": [
+                {"name": "Security", "include_event_ids": [4624, 4625]},
+                {"name": "System", "min_level": "warning", "exclude_event_ids": [7040]},
+            ],
+        }))
+        .unwrap();
+        let runtime = Runtime {
+            shutdown: Arc::new(AtomicBool::new(false)),
+            stats: Arc::new(Stats::default()),
+            hub: Arc::new(Hub::new(100)),
+            budget: None,
         };
+        let input = [
+            json!({"Channel": "Security", "EventID": 4624, "Level": "Information"}),
+            json!({"Channel": "Security", "EventID": 4672, "Level": "Information"}),
+            json!({"Channel": "System", "EventID": 7036, "Level": "Information"}),
+            json!({"Channel": "System", "EventID": 7031, "Level": "Error"}),
+            json!({"Channel": "System", "EventID": 7040, "Level": "Error"}),
+            json!({"Channel": "Application", "EventID": 1000, "Level": 0}),
+        ]
+        .map(|event| event.to_string())
+        .join("\n");
+
+        let result =
+            reprocess(config, Output::discard(), false, &runtime, input.as_bytes()).unwrap();
+        assert_eq!((result.written, result.filtered), (3, 3));
+        let written: Vec<Option<u32>> = runtime
+            .hub
+            .recent()
+            .iter()
+            .map(|event| hub::event_id(event))
+            .collect();
+        assert_eq!(written, [Some(4624), Some(7031), Some(1000)]);
+    }
 
-        let _ = EvtClose(metadata);
-        result
+    #[test]
+    fn values_render_like_xml() {
+        let api = Mock::default();
+        api.add(
+            CHANNEL,
+            r#"<Event xmlns="http://schemas.microsoft.com/win/2004/08/events/event"><System><Provider Name="rs-wineventlog-test" EventSourceName="rs-wineventlog-test"/><EventID Qualifiers="16384">7036</EventID><Version>0</Version><Level>4</Level><Task>0</Task><Opcode>0</Opcode><Keywords>0x80000000000000</Keywords><TimeCreated SystemTime="2024-05-01T12:34:56.1234567Z"/><EventRecordID>1</EventRecordID><Correlation ActivityID="{9E0B0FA2-1B2C-4D5E-8F90-A1B2C3D4E5F6}"/><Execution ProcessID="700" ThreadID="9000"/><Channel>Test</Channel><Computer>HOST</Computer><Security UserID="S-1-5-18"/></System><EventData><Data>Windows Update</Data><Data></Data><Binary>7700750061007500</Binary></EventData></Event>"#,
+        );
+        let results = api.query(CHANNEL, None).unwrap();
+        let event = api.next(&results, 1, 0).pop().unwrap();
+        let (xml, _) = context("render-xml");
+        let (values, _) = configured("render-values", json!({ "render": "values" }));
+
+        assert!(api.render_values(&event).is_some());
+        let from_xml = render_event(&api, &event, &xml, &[]).unwrap();
+        let from_values = render_event(&api, &event, &values, &[]).unwrap();
+        assert_eq!(from_values["EventData"]["Binary"], "7700750061007500");
+        assert_eq!(from_values.to_string(), from_xml.to_string());
+    }
+
+    // Polls `done` for up to five seconds
+    fn eventually(done: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            if Instant::now() > deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+
+    #[test]
+    fn monitors_subscription_until_stopped() {
+        let api = Mock::default();
+        // Already there when subscribing: not read live
+        api.add(CHANNEL, &event(1));
+        let (ctx, hub) = context("monitor");
+
+        thread::scope(|scope| {
+            let monitor = scope.spawn(|| {
+                monitor_channel(
+                    &api,
+                    CHANNEL,
+                    &ChannelConfig::default(),
+                    Arc::clone(&ctx),
+                    Arc::new(ChannelStats::default()),
+                    Arc::new(AtomicBool::new(false)),
+                )
+                .map_err(|e| e.to_string())
+            });
+            assert!(eventually(|| api.subscribers(CHANNEL) == 1));
+            api.add(CHANNEL, &event(2));
+            assert!(eventually(|| hub.recent().len() == 1));
+            api.add(CHANNEL, &event(3));
+            api.add(CHANNEL, &event(4));
+            assert!(eventually(|| hub.recent().len() == 3));
+
+            ctx.stop.store(true, Ordering::SeqCst);
+            assert_eq!(monitor.join().unwrap(), Ok(()));
+        });
+        let records: Vec<u64> = hub
+            .recent()
+            .iter()
+            .filter_map(|e| hub::record_id(e))
+            .collect();
+        assert_eq!(records, [2, 3, 4]);
+        assert_eq!(api.subscribers(CHANNEL), 0);
+    }
+
+    #[test]
+    fn monitor_reports_denied_subscription() {
+        let api = Mock::default();
+        api.add(CHANNEL, &event(1));
+        api.deny(CHANNEL);
+        let (ctx, _hub) = context("monitor-denied");
+
+        let err = monitor_channel(
+            &api,
+            CHANNEL,
+            &ChannelConfig::default(),
+            ctx,
+            Arc::new(ChannelStats::default()),
+            Arc::new(AtomicBool::new(false)),
+        )
+        .unwrap_err();
+        assert!(is_access_denied(err.as_ref()));
     }
 }
@@ -0,0 +1,1000 @@
+use crate::checkpoint::Bookmark;
+use crate::message::Formatted;
+use crate::timestamp::{self, Timezone};
+use crate::xml;
+use chrono::{TimeZone, Utc};
+use serde_json::{Value as JsonValue, json};
+use windows::Win32::Foundation::{
+    CloseHandle, ERROR_EVT_UNRESOLVED_PARAMETER_INSERT, ERROR_EVT_UNRESOLVED_VALUE_INSERT, HANDLE,
+    HLOCAL, LocalFree,
+};
+use windows::Win32::Security::Authorization::ConvertSidToStringSidW;
+use windows::Win32::System::EventLog::*;
+use windows::Win32::System::Threading::{CreateEventW, ResetEvent, WaitForSingleObject};
+use windows::core::{HSTRING, PCWSTR, PWSTR};
+
+type JsonMap = serde_json::Map<String, JsonValue>;
+
+/// Publisher metadata an event's System values can be formatted with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Metadata {
+    Level,
+    Task,
+    Opcode,
+    Keywords,
+}
+
+/// Who and where an event comes from.
+#[derive(Debug, Default)]
+pub struct Origin {
+    pub user_id: Option<String>,
+    pub computer: Option<String>,
+}
+
+/// The Event Log calls the collection loops make: subscribing, querying,
+/// reading and rendering events, and bookmarks. `Win32` is the real thing;
+/// tests run the loops against `mock::Mock`.
+pub trait EventLogApi: Sync {
+    /// A subscription or query.
+    type Results;
+    /// One event, released when dropped.
+    type Event;
+    type Bookmark;
+
+    /// Subscribes to the events a channel gets from now on, only those
+    /// matching `query` (XPath, or a structured <QueryList>) if given.
+    fn subscribe(&self, channel: &str, query: Option<&str>)
+    -> windows::core::Result<Self::Results>;
+
+    /// Opens a channel for reading from its oldest event, only those
+    /// matching `query` (XPath, or a structured <QueryList>) if given.
+    fn query(&self, channel: &str, query: Option<&str>) -> windows::core::Result<Self::Results>;
+
+    /// The EventRecordID of a channel's newest event; None when the channel
+    /// is empty (or can't be opened).
+    fn newest_record_id(&self, channel: &str) -> Option<u64>;
+
+    /// Waits up to `timeout_ms` for a subscription to get events. True when
+    /// there may be some to read.
+    fn wait(&self, results: &Self::Results, timeout_ms: u32) -> bool;
+
+    /// Up to `max` events, waiting at most `timeout_ms` for them. Empty when
+    /// there are none left for now (or at the end of a query).
+    fn next(&self, results: &Self::Results, max: usize, timeout_ms: u32) -> Vec<Self::Event>;
+
+    fn render_xml(&self, event: &Self::Event) -> Option<String>;
+
+    /// The event's System values and its EventData values (unnamed, in a
+    /// `Data` array) in the shape `xml::parse_to_json` gives them, read
+    /// without rendering XML. None when a value can't be rendered that way.
+    fn render_values(&self, event: &Self::Event) -> Option<JsonValue>;
+
+    /// The names the publisher gives the event's values; one per set bit
+    /// for keywords, one otherwise.
+    fn format(&self, event: &Self::Event, metadata: Metadata) -> Option<Vec<String>>;
+
+    /// The event's message in `locale` (0 for the system default).
+    fn message(&self, event: &Self::Event, provider: &str, locale: u32) -> Option<Formatted>;
+
+    /// TimeCreated as a FILETIME.
+    fn time_created(&self, event: &Self::Event) -> Option<u64>;
+
+    /// EventRecordID.
+    fn record_id(&self, event: &Self::Event) -> Option<u64>;
+
+    /// Security/@UserID (as an S-1-... string) and Computer, rendered on
+    /// their own so events can be filtered on them before anything else is
+    /// done with them.
+    fn origin(&self, event: &Self::Event) -> Origin;
+
+    /// A bookmark restored from its XML, or a fresh one.
+    fn bookmark(&self, xml: Option<&str>) -> windows::core::Result<Self::Bookmark>;
+
+    /// Moves a query to the event after the bookmarked one.
+    fn seek(&self, results: &Self::Results, bookmark: &Self::Bookmark)
+    -> windows::core::Result<()>;
+
+    fn update_bookmark(
+        &self,
+        bookmark: &Self::Bookmark,
+        event: &Self::Event,
+    ) -> windows::core::Result<()>;
+
+    fn bookmark_xml(&self, bookmark: &Self::Bookmark) -> windows::core::Result<String>;
+}
+
+/// The wevtapi.dll implementation.
+pub struct Win32;
+
+/// An Event Log handle, closed when dropped.
+pub struct Handle(pub EVT_HANDLE);
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        let _ = unsafe { EvtClose(self.0) };
+    }
+}
+
+/// A subscription with the event it signals, or a query.
+pub struct Results {
+    handle: Handle,
+    signal: Option<HANDLE>,
+}
+
+impl Drop for Results {
+    fn drop(&mut self) {
+        if let Some(signal) = self.signal {
+            let _ = unsafe { CloseHandle(signal) };
+        }
+    }
+}
+
+impl EventLogApi for Win32 {
+    type Results = Results;
+    type Event = Handle;
+    type Bookmark = Bookmark;
+
+    fn subscribe(&self, channel: &str, query: Option<&str>) -> windows::core::Result<Results> {
+        // Manual reset, initially set so events already waiting are read
+        let signal = unsafe { CreateEventW(None, true, true, None)? };
+        let channel = HSTRING::from(channel);
+        let structured = query.is_some_and(crate::query::is_structured);
+        let query = query.map(HSTRING::from);
+        let handle = unsafe {
+            EvtSubscribe(
+                None,
+                Some(signal),
+                // A structured query names its channels itself
+                if structured {
+                    PCWSTR::null()
+                } else {
+                    PCWSTR(channel.as_ptr())
+                },
+                query
+                    .as_ref()
+                    .map_or(PCWSTR::null(), |q| PCWSTR(q.as_ptr())),
+                None,
+                None,
+                None,
+                EvtSubscribeToFutureEvents.0,
+            )
+        };
+        match handle {
+            Ok(handle) => Ok(Results {
+                handle: Handle(handle),
+                signal: Some(signal),
+            }),
+            Err(e) => {
+                let _ = unsafe { CloseHandle(signal) };
+                Err(e)
+            }
+        }
+    }
+
+    fn query(&self, channel: &str, query: Option<&str>) -> windows::core::Result<Results> {
+        let channel = HSTRING::from(channel);
+        let structured = query.is_some_and(crate::query::is_structured);
+        let query = query.map(HSTRING::from);
+        let handle = unsafe {
+            EvtQuery(
+                None,
+                if structured {
+                    PCWSTR::null()
+                } else {
+                    PCWSTR(channel.as_ptr())
+                },
+                query
+                    .as_ref()
+                    .map_or(PCWSTR::null(), |q| PCWSTR(q.as_ptr())),
+                EvtQueryChannelPath.0 | EvtQueryForwardDirection.0,
+            )?
+        };
+        Ok(Results {
+            handle: Handle(handle),
+            signal: None,
+        })
+    }
+
+    fn newest_record_id(&self, channel: &str) -> Option<u64> {
+        unsafe {
+            let log = Handle(EvtOpenLog(None, &HSTRING::from(channel), EvtOpenChannelPath.0).ok()?);
+            let property = |id: EVT_LOG_PROPERTY_ID| {
+                let mut value = EVT_VARIANT::default();
+                let mut used = 0u32;
+                EvtGetLogInfo(
+                    log.0,
+                    id,
+                    std::mem::size_of::<EVT_VARIANT>() as u32,
+                    Some(&mut value),
+                    &mut used,
+                )
+                .ok()?;
+                (value.Type == EvtVarTypeUInt64.0 as u32).then_some(value.Anonymous.UInt64Val)
+            };
+            let oldest = property(EvtLogOldestRecordNumber)?;
+            let count = property(EvtLogNumberOfLogRecords)?;
+            (count > 0).then(|| oldest + count - 1)
+        }
+    }
+
+    fn wait(&self, results: &Results, timeout_ms: u32) -> bool {
+        let Some(signal) = results.signal else {
+            return true;
+        };
+        unsafe {
+            // WAIT_OBJECT_0 = 0, anything else is timeout or error
+            if WaitForSingleObject(signal, timeout_ms).0 != 0 {
+                return false;
+            }
+            let _ = ResetEvent(signal);
+        }
+        true
+    }
+
+    fn next(&self, results: &Results, max: usize, timeout_ms: u32) -> Vec<Handle> {
+        let mut events = vec![EVT_HANDLE::default(); max];
+        let mut returned = 0u32;
+        let slice =
+            unsafe { std::slice::from_raw_parts_mut(events.as_mut_ptr() as *mut isize, max) };
+        // Fails with ERROR_NO_MORE_ITEMS when there are none
+        if unsafe { EvtNext(results.handle.0, slice, timeout_ms, 0, &mut returned) }.is_err() {
+            return Vec::new();
+        }
+        events.truncate(returned as usize);
+        events.into_iter().map(Handle).collect()
+    }
+
+    fn render_xml(&self, event: &Handle) -> Option<String> {
+        unsafe {
+            let mut used = 0u32;
+            let _ = EvtRender(
+                None,
+                event.0,
+                EvtRenderEventXml.0,
+                0,
+                None,
+                &mut used,
+                &mut 0,
+            );
+            let mut buffer = vec![0u16; (used / 2) as usize + 1];
+            EvtRender(
+                None,
+                event.0,
+                EvtRenderEventXml.0,
+                used,
+                Some(buffer.as_mut_ptr() as *mut _),
+                &mut used,
+                &mut 0,
+            )
+            .ok()?;
+            let len = buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len());
+            Some(String::from_utf16_lossy(&buffer[..len]))
+        }
+    }
+
+    fn render_values(&self, event: &Handle) -> Option<JsonValue> {
+        VALUE_CONTEXTS.with(|contexts| unsafe {
+            let contexts = contexts.as_ref()?;
+            let system = values(&contexts.system, event)?;
+            let system: Vec<_> = variants(&system).iter().map(|v| text(v)).collect();
+            let user = values(&contexts.user, event)?;
+            let user = variants(&user)
+                .iter()
+                .map(|v| text(v))
+                .collect::<Option<Vec<_>>>()?;
+            let binary = values(&contexts.binary, event)?;
+            let binary = variants(&binary).first().and_then(|v| text(v));
+            values_json(&system, user, binary)
+        })
+    }
+
+    fn format(&self, event: &Handle, metadata: Metadata) -> Option<Vec<String>> {
+        let flags = match metadata {
+            Metadata::Level => EvtFormatMessageLevel,
+            Metadata::Task => EvtFormatMessageTask,
+            Metadata::Opcode => EvtFormatMessageOpcode,
+            Metadata::Keywords => EvtFormatMessageKeyword,
+        };
+        unsafe {
+            let mut buffer_size = 0u32;
+            let _ = EvtFormatMessage(
+                None,
+                Some(event.0),
+                0,
+                None,
+                flags.0,
+                None,
+                &mut buffer_size,
+            );
+            if buffer_size == 0 {
+                return None;
+            }
+
+            let mut buffer = vec![0u16; buffer_size as usize];
+            EvtFormatMessage(
+                None,
+                Some(event.0),
+                0,
+                None,
+                flags.0,
+                Some(&mut buffer),
+                &mut buffer_size,
+            )
+            .ok()?;
+            // Keyword lookups return a list of NUL-separated strings
+            Some(
+                buffer
+                    .split(|&c| c == 0)
+                    .filter(|s| !s.is_empty())
+                    .map(String::from_utf16_lossy)
+                    .collect(),
+            )
+        }
+    }
+
+    fn message(&self, event: &Handle, provider: &str, locale: u32) -> Option<Formatted> {
+        let provider = HSTRING::from(provider);
+        unsafe {
+            let metadata = Handle(
+                EvtOpenPublisherMetadata(None, PCWSTR(provider.as_ptr()), None, locale, 0).ok()?,
+            );
+
+            let mut size = 0u32;
+            let _ = EvtFormatMessage(
+                Some(metadata.0),
+                Some(event.0),
+                0,
+                None,
+                EvtFormatMessageEvent.0,
+                None,
+                &mut size,
+            );
+            if size == 0 {
+                return None;
+            }
+
+            let mut buffer = vec![0u16; size as usize];
+            let formatted = match EvtFormatMessage(
+                Some(metadata.0),
+                Some(event.0),
+                0,
+                None,
+                EvtFormatMessageEvent.0,
+                Some(&mut buffer),
+                &mut size,
+            ) {
+                Ok(()) => Formatted::Complete,
+                // Unresolved inserts still produce the message, with %N left in it
+                Err(e)
+                    if e.code() == ERROR_EVT_UNRESOLVED_VALUE_INSERT.to_hresult()
+                        || e.code() == ERROR_EVT_UNRESOLVED_PARAMETER_INSERT.to_hresult() =>
+                {
+                    Formatted::Partial
+                }
+                Err(_) => return None,
+            };
+            let len = buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len());
+            Some(formatted(String::from_utf16_lossy(&buffer[..len])))
+        }
+    }
+
+    fn time_created(&self, event: &Handle) -> Option<u64> {
+        TIME_CONTEXT.with(|context| unsafe {
+            let context = context.0.as_ref()?;
+            let mut value = EVT_VARIANT::default();
+            let (mut used, mut count) = (0u32, 0u32);
+            EvtRender(
+                Some(context.0),
+                event.0,
+                EvtRenderEventValues.0,
+                std::mem::size_of::<EVT_VARIANT>() as u32,
+                Some(&mut value as *mut EVT_VARIANT as *mut _),
+                &mut used,
+                &mut count,
+            )
+            .ok()?;
+            (value.Type == EvtVarTypeFileTime.0 as u32).then_some(value.Anonymous.FileTimeVal)
+        })
+    }
+
+    fn record_id(&self, event: &Handle) -> Option<u64> {
+        RECORD_CONTEXT.with(|context| unsafe {
+            let context = context.0.as_ref()?;
+            let mut value = EVT_VARIANT::default();
+            let (mut used, mut count) = (0u32, 0u32);
+            EvtRender(
+                Some(context.0),
+                event.0,
+                EvtRenderEventValues.0,
+                std::mem::size_of::<EVT_VARIANT>() as u32,
+                Some(&mut value as *mut EVT_VARIANT as *mut _),
+                &mut used,
+                &mut count,
+            )
+            .ok()?;
+            (value.Type == EvtVarTypeUInt64.0 as u32).then_some(value.Anonymous.UInt64Val)
+        })
+    }
+
+    fn origin(&self, event: &Handle) -> Origin {
+        ORIGIN_CONTEXT.with(|context| unsafe {
+            let Some(values) = context.0.as_ref().and_then(|c| values(c, event)) else {
+                return Origin::default();
+            };
+            let values = variants(&values);
+            Origin {
+                // Events without a user have a null value
+                user_id: values
+                    .first()
+                    .filter(|v| v.Type == EvtVarTypeSid.0 as u32)
+                    .and_then(|v| text(v)),
+                computer: values
+                    .get(1)
+                    .and_then(|v| text(v))
+                    .filter(|c| !c.is_empty()),
+            }
+        })
+    }
+
+    fn bookmark(&self, xml: Option<&str>) -> windows::core::Result<Bookmark> {
+        Bookmark::new(xml)
+    }
+
+    fn seek(&self, results: &Results, bookmark: &Bookmark) -> windows::core::Result<()> {
+        unsafe {
+            EvtSeek(
+                results.handle.0,
+                1,
+                Some(bookmark.handle()),
+                None,
+                EvtSeekRelativeToBookmark.0,
+            )
+        }
+    }
+
+    fn update_bookmark(&self, bookmark: &Bookmark, event: &Handle) -> windows::core::Result<()> {
+        bookmark.update(event.0)
+    }
+
+    fn bookmark_xml(&self, bookmark: &Bookmark) -> windows::core::Result<String> {
+        bookmark.to_xml()
+    }
+}
+
+// Render context selecting just TimeCreated, one per channel thread
+struct TimeContext(Option<Handle>);
+
+thread_local! {
+    static TIME_CONTEXT: TimeContext = TimeContext(unsafe {
+        let path = windows::core::w!("Event/System/TimeCreated/@SystemTime");
+        EvtCreateRenderContext(Some(&[path]), EvtRenderContextValues.0)
+            .ok()
+            .map(Handle)
+    });
+}
+
+// Render context selecting just EventRecordID, one per channel thread
+struct RecordContext(Option<Handle>);
+
+thread_local! {
+    static RECORD_CONTEXT: RecordContext = RecordContext(unsafe {
+        let path = windows::core::w!("Event/System/EventRecordID");
+        EvtCreateRenderContext(Some(&[path]), EvtRenderContextValues.0)
+            .ok()
+            .map(Handle)
+    });
+}
+
+// Render context selecting just the user SID and Computer, one per channel
+// thread
+struct OriginContext(Option<Handle>);
+
+thread_local! {
+    static ORIGIN_CONTEXT: OriginContext = OriginContext(unsafe {
+        let paths = [
+            windows::core::w!("Event/System/Security/@UserID"),
+            windows::core::w!("Event/System/Computer"),
+        ];
+        EvtCreateRenderContext(Some(&paths), EvtRenderContextValues.0)
+            .ok()
+            .map(Handle)
+    });
+}
+
+// Render contexts selecting all System values, all EventData/UserData
+// values and a classic event's Binary, one set per channel thread
+struct ValueContexts {
+    system: Handle,
+    user: Handle,
+    binary: Handle,
+}
+
+thread_local! {
+    static VALUE_CONTEXTS: Option<ValueContexts> = unsafe {
+        let system = EvtCreateRenderContext(None, EvtRenderContextSystem.0).ok()?;
+        let system = Handle(system);
+        let user = EvtCreateRenderContext(None, EvtRenderContextUser.0).ok()?;
+        let path = windows::core::w!("Event/EventData/Binary");
+        let binary = EvtCreateRenderContext(Some(&[path]), EvtRenderContextValues.0).ok()?;
+        Some(ValueContexts {
+            system,
+            user: Handle(user),
+            binary: Handle(binary),
+        })
+    };
+}
+
+// The values a render context selects; the buffer is u64 words so it is
+// aligned for EVT_VARIANT
+unsafe fn values(context: &Handle, event: &Handle) -> Option<(Vec<u64>, usize)> {
+    unsafe {
+        let (mut used, mut count) = (0u32, 0u32);
+        let _ = EvtRender(
+            Some(context.0),
+            event.0,
+            EvtRenderEventValues.0,
+            0,
+            None,
+            &mut used,
+            &mut count,
+        );
+        let mut buffer = vec![0u64; (used as usize).div_ceil(8)];
+        EvtRender(
+            Some(context.0),
+            event.0,
+            EvtRenderEventValues.0,
+            used,
+            Some(buffer.as_mut_ptr() as *mut _),
+            &mut used,
+            &mut count,
+        )
+        .ok()?;
+        Some((buffer, count as usize))
+    }
+}
+
+unsafe fn variants(values: &(Vec<u64>, usize)) -> &[EVT_VARIANT] {
+    unsafe { std::slice::from_raw_parts(values.0.as_ptr() as *const EVT_VARIANT, values.1) }
+}
+
+// System values (as text, indexed by property ID) keyed and formatted the
+// way the rendered XML has them: attributes as "@Name" members, numbers as
+// decimal strings. The user values and Binary go in
+// EventData as XML's unnamed Data and Binary do.
+fn values_json(
+    system: &[Option<String>],
+    user: Vec<String>,
+    binary: Option<String>,
+) -> Option<JsonValue> {
+    let get = |id: EVT_SYSTEM_PROPERTY_ID| {
+        system
+            .get(id.0 as usize)
+            .cloned()
+            .flatten()
+            .filter(|t| !t.is_empty())
+            .map(JsonValue::String)
+    };
+    let mut event = JsonMap::new();
+
+    let mut provider = JsonMap::new();
+    provider.insert("@Name".to_string(), get(EvtSystemProviderName)?);
+    if let Some(guid) = get(EvtSystemProviderGuid) {
+        provider.insert("@Guid".to_string(), guid);
+    }
+    // Only classic (EventLog API) events have qualifiers; their source is
+    // the provider name
+    let qualifiers = get(EvtSystemQualifiers);
+    if qualifiers.is_some() {
+        provider.insert("@EventSourceName".to_string(), provider["@Name"].clone());
+    }
+    event.insert("Provider".to_string(), JsonValue::Object(provider));
+    event.insert("EventID".to_string(), get(EvtSystemEventID)?);
+    if let Some(qualifiers) = qualifiers {
+        event.insert("EventIDQualifiers".to_string(), qualifiers);
+    }
+    for (key, id) in [
+        ("Version", EvtSystemVersion),
+        ("Level", EvtSystemLevel),
+        ("Task", EvtSystemTask),
+        ("Opcode", EvtSystemOpcode),
+        ("Keywords", EvtSystemKeywords),
+    ] {
+        if let Some(value) = get(id) {
+            event.insert(key.to_string(), value);
+        }
+    }
+    if let Some(time) = get(EvtSystemTimeCreated) {
+        event.insert("TimeCreated".to_string(), json!({ "@SystemTime": time }));
+    }
+    event.insert("EventRecordID".to_string(), get(EvtSystemEventRecordId)?);
+
+    let mut correlation = JsonMap::new();
+    let mut ids = Vec::new();
+    for (key, id) in [
+        ("ActivityID", EvtSystemActivityID),
+        ("RelatedActivityID", EvtSystemRelatedActivityID),
+    ] {
+        if let Some(guid) = get(id) {
+            ids.push((key, guid.as_str().map(xml::normalize_guid)));
+            correlation.insert(format!("@{}", key), guid);
+        }
+    }
+    event.insert("Correlation".to_string(), JsonValue::Object(correlation));
+    for (key, guid) in ids {
+        event.insert(key.to_string(), json!(guid));
+    }
+
+    let mut execution = JsonMap::new();
+    for (key, id) in [
+        ("@ProcessID", EvtSystemProcessID),
+        ("@ThreadID", EvtSystemThreadID),
+    ] {
+        if let Some(value) = get(id) {
+            execution.insert(key.to_string(), value);
+        }
+    }
+    event.insert("Execution".to_string(), JsonValue::Object(execution));
+    for (key, id) in [
+        ("Channel", EvtSystemChannel),
+        ("Computer", EvtSystemComputer),
+    ] {
+        if let Some(value) = get(id) {
+            event.insert(key.to_string(), value);
+        }
+    }
+    let mut security = JsonMap::new();
+    if let Some(sid) = get(EvtSystemUserID) {
+        security.insert("@UserID".to_string(), sid);
+    }
+    event.insert("Security".to_string(), JsonValue::Object(security));
+
+    let mut data = JsonMap::new();
+    if let Some(binary) = binary.filter(|b| !b.is_empty()) {
+        data.insert("Binary".to_string(), JsonValue::String(binary));
+    }
+    if !user.is_empty() {
+        data.insert("Data".to_string(), json!(user));
+    }
+    event.insert("EventData".to_string(), JsonValue::Object(data));
+    Some(JsonValue::Object(event))
+}
+
+// A value as the rendered XML writes it ("" for a missing one). None for
+// arrays, handles and embedded XML, which only XML rendering gets right.
+#[allow(non_upper_case_globals)]
+unsafe fn text(value: &EVT_VARIANT) -> Option<String> {
+    if value.Type & EVT_VARIANT_TYPE_ARRAY != 0 {
+        return None;
+    }
+    let v = &value.Anonymous;
+    let text = unsafe {
+        match EVT_VARIANT_TYPE(value.Type as i32) {
+            EvtVarTypeNull => String::new(),
+            EvtVarTypeString => v.StringVal.to_string().ok()?.trim().to_string(),
+            EvtVarTypeAnsiString => v.AnsiStringVal.to_string().ok()?.trim().to_string(),
+            EvtVarTypeSByte => v.SByteVal.to_string(),
+            EvtVarTypeByte => v.ByteVal.to_string(),
+            EvtVarTypeInt16 => v.Int16Val.to_string(),
+            EvtVarTypeUInt16 => v.UInt16Val.to_string(),
+            EvtVarTypeInt32 => v.Int32Val.to_string(),
+            EvtVarTypeUInt32 => v.UInt32Val.to_string(),
+            EvtVarTypeInt64 => v.Int64Val.to_string(),
+            EvtVarTypeUInt64 => v.UInt64Val.to_string(),
+            EvtVarTypeSingle => v.SingleVal.to_string(),
+            EvtVarTypeDouble => v.DoubleVal.to_string(),
+            EvtVarTypeBoolean => v.BooleanVal.as_bool().to_string(),
+            EvtVarTypeHexInt32 => format!("0x{:x}", v.UInt32Val),
+            EvtVarTypeHexInt64 => format!("0x{:x}", v.UInt64Val),
+            // Pointers
+            EvtVarTypeSizeT => format!("0x{:x}", v.SizeTVal),
+            EvtVarTypeGuid => {
+                let g = v.GuidVal.as_ref()?;
+                format!(
+                    "{{{:08X}-{:04X}-{:04X}-{}-{}}}",
+                    g.data1,
+                    g.data2,
+                    g.data3,
+                    hex(&g.data4[..2]),
+                    hex(&g.data4[2..])
+                )
+            }
+            EvtVarTypeFileTime => {
+                timestamp::format(timestamp::from_filetime(v.FileTimeVal)?, &Timezone::Utc)
+            }
+            EvtVarTypeSysTime => {
+                let t = v.SysTimeVal.as_ref()?;
+                let time = Utc
+                    .with_ymd_and_hms(
+                        t.wYear.into(),
+                        t.wMonth.into(),
+                        t.wDay.into(),
+                        t.wHour.into(),
+                        t.wMinute.into(),
+                        t.wSecond.into(),
+                    )
+                    .single()?
+                    + chrono::Duration::milliseconds(t.wMilliseconds.into());
+                timestamp::format(time, &Timezone::Utc)
+            }
+            EvtVarTypeSid => {
+                let mut sid = PWSTR::null();
+                ConvertSidToStringSidW(v.SidVal, &mut sid).ok()?;
+                let text = sid.to_string().ok();
+                let _ = LocalFree(Some(HLOCAL(sid.0 as _)));
+                text?
+            }
+            EvtVarTypeBinary => hex(std::slice::from_raw_parts(
+                v.BinaryVal,
+                value.Count as usize,
+            )),
+            _ => return None,
+        }
+    };
+    Some(text)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+#[cfg(test)]
+pub mod mock {
+    use super::{EventLogApi, Formatted, Metadata, Origin, values_json};
+    use serde_json::Value as JsonValue;
+    use std::collections::{HashMap, VecDeque};
+    use std::sync::{Arc, Condvar, Mutex, Weak};
+    use std::time::Duration;
+    use windows::Win32::Foundation::{E_ACCESSDENIED, E_INVALIDARG, ERROR_NOT_FOUND};
+    use windows::Win32::System::EventLog::*;
+
+    /// Channels held in memory as lists of event XML. Events get record IDs
+    /// from 1 in the order they were added; bookmarks are those IDs.
+    /// Subscriptions get the events added after they were made.
+    #[derive(Default)]
+    pub struct Mock {
+        channels: Mutex<HashMap<String, Vec<String>>>,
+        subscriptions: Mutex<Vec<(String, Weak<Results>)>>,
+        denied: Mutex<Vec<String>>,
+    }
+
+    pub struct Event {
+        pub record_id: u64,
+        pub xml: String,
+    }
+
+    /// A query's events, or those a subscription has been given and not yet
+    /// read; `signal` is notified as they are added.
+    #[derive(Default)]
+    pub struct Results {
+        events: Mutex<VecDeque<Event>>,
+        signal: Condvar,
+    }
+
+    impl Mock {
+        pub fn add(&self, channel: &str, xml: &str) {
+            let mut channels = self.channels.lock().unwrap();
+            let events = channels.entry(channel.to_string()).or_default();
+            events.push(xml.to_string());
+            let record_id = events.len() as u64;
+            let mut subscriptions = self.subscriptions.lock().unwrap();
+            subscriptions.retain(|(_, results)| results.strong_count() > 0);
+            for results in subscriptions
+                .iter()
+                .filter(|(c, _)| c == channel)
+                .filter_map(|(_, results)| results.upgrade())
+            {
+                results.events.lock().unwrap().push_back(Event {
+                    record_id,
+                    xml: xml.to_string(),
+                });
+                results.signal.notify_all();
+            }
+        }
+
+        /// Opening the channel fails with access denied from now on.
+        pub fn deny(&self, channel: &str) {
+            self.denied.lock().unwrap().push(channel.to_string());
+        }
+
+        /// How many subscriptions to the channel are open.
+        pub fn subscribers(&self, channel: &str) -> usize {
+            self.subscriptions
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(c, results)| c == channel && results.strong_count() > 0)
+                .count()
+        }
+
+        fn open(&self, channel: &str) -> windows::core::Result<Vec<Event>> {
+            if self.denied.lock().unwrap().iter().any(|c| c == channel) {
+                return Err(E_ACCESSDENIED.into());
+            }
+            let channels = self.channels.lock().unwrap();
+            let events = channels
+                .get(channel)
+                .ok_or_else(|| windows::core::Error::from(ERROR_NOT_FOUND.to_hresult()))?;
+            Ok(events
+                .iter()
+                .enumerate()
+                .map(|(i, xml)| Event {
+                    record_id: i as u64 + 1,
+                    xml: xml.clone(),
+                })
+                .collect())
+        }
+    }
+
+    impl EventLogApi for Mock {
+        type Results = Arc<Results>;
+        type Event = Event;
+        type Bookmark = Mutex<u64>;
+
+        // Queries aren't evaluated; every event matches
+        fn subscribe(
+            &self,
+            channel: &str,
+            _query: Option<&str>,
+        ) -> windows::core::Result<Self::Results> {
+            self.open(channel)?;
+            let results = Arc::new(Results::default());
+            self.subscriptions
+                .lock()
+                .unwrap()
+                .push((channel.to_string(), Arc::downgrade(&results)));
+            Ok(results)
+        }
+
+        fn query(
+            &self,
+            channel: &str,
+            _query: Option<&str>,
+        ) -> windows::core::Result<Self::Results> {
+            Ok(Arc::new(Results {
+                events: Mutex::new(self.open(channel)?.into()),
+                signal: Condvar::new(),
+            }))
+        }
+
+        fn newest_record_id(&self, channel: &str) -> Option<u64> {
+            let channels = self.channels.lock().unwrap();
+            let count = channels.get(channel)?.len() as u64;
+            (count > 0).then_some(count)
+        }
+
+        fn wait(&self, results: &Self::Results, timeout_ms: u32) -> bool {
+            let events = results.events.lock().unwrap();
+            let timeout = Duration::from_millis(timeout_ms.into());
+            let (events, _) = results
+                .signal
+                .wait_timeout_while(events, timeout, |events| events.is_empty())
+                .unwrap();
+            !events.is_empty()
+        }
+
+        fn next(&self, results: &Self::Results, max: usize, _timeout_ms: u32) -> Vec<Event> {
+            let mut events = results.events.lock().unwrap();
+            let count = max.min(events.len());
+            events.drain(..count).collect()
+        }
+
+        fn render_xml(&self, event: &Event) -> Option<String> {
+            Some(event.xml.clone())
+        }
+
+        // The values EvtRender would give, read from the XML: System's by
+        // property ID, the EventData or UserData leaves, and Binary
+        fn render_values(&self, event: &Event) -> Option<JsonValue> {
+            let doc = roxmltree::Document::parse(&event.xml).ok()?;
+            let find = |name| doc.descendants().find(|n| n.has_tag_name(name));
+            let text = |name| find(name)?.text().map(str::to_string);
+            let attribute = |name, attribute| find(name)?.attribute(attribute).map(str::to_string);
+            let mut system = vec![None; EvtSystemPropertyIdEND.0 as usize];
+            for (id, value) in [
+                (EvtSystemProviderName, attribute("Provider", "Name")),
+                (EvtSystemProviderGuid, attribute("Provider", "Guid")),
+                (EvtSystemEventID, text("EventID")),
+                (EvtSystemQualifiers, attribute("EventID", "Qualifiers")),
+                (EvtSystemLevel, text("Level")),
+                (EvtSystemTask, text("Task")),
+                (EvtSystemOpcode, text("Opcode")),
+                (EvtSystemKeywords, text("Keywords")),
+                (EvtSystemTimeCreated, attribute("TimeCreated", "SystemTime")),
+                (EvtSystemEventRecordId, text("EventRecordID")),
+                (EvtSystemActivityID, attribute("Correlation", "ActivityID")),
+                (
+                    EvtSystemRelatedActivityID,
+                    attribute("Correlation", "RelatedActivityID"),
+                ),
+                (EvtSystemProcessID, attribute("Execution", "ProcessID")),
+                (EvtSystemThreadID, attribute("Execution", "ThreadID")),
+                (EvtSystemChannel, text("Channel")),
+                (EvtSystemComputer, text("Computer")),
+                (EvtSystemUserID, attribute("Security", "UserID")),
+                (EvtSystemVersion, text("Version")),
+            ] {
+                system[id.0 as usize] = value;
+            }
+            let user = find("EventData")
+                .or_else(|| find("UserData"))
+                .map(|data| {
+                    data.descendants()
+                        .skip(1)
+                        .filter(|n| {
+                            n.is_element()
+                                && !n.has_tag_name("Binary")
+                                && !n.children().any(|c| c.is_element())
+                        })
+                        .map(|n| n.text().unwrap_or_default().to_string())
+                        .collect()
+                })
+                .unwrap_or_default();
+            values_json(&system, user, text("Binary"))
+        }
+
+        fn format(&self, _event: &Event, _metadata: Metadata) -> Option<Vec<String>> {
+            None
+        }
+
+        fn message(&self, _event: &Event, _provider: &str, _locale: u32) -> Option<Formatted> {
+            None
+        }
+
+        fn time_created(&self, _event: &Event) -> Option<u64> {
+            None
+        }
+
+        fn record_id(&self, event: &Event) -> Option<u64> {
+            Some(event.record_id)
+        }
+
+        fn origin(&self, event: &Event) -> Origin {
+            let Ok(doc) = roxmltree::Document::parse(&event.xml) else {
+                return Origin::default();
+            };
+            let find = |name| doc.descendants().find(|n| n.has_tag_name(name));
+            Origin {
+                user_id: find("Security")
+                    .and_then(|n| n.attribute("UserID"))
+                    .map(str::to_string),
+                computer: find("Computer").and_then(|n| n.text()).map(str::to_string),
+            }
+        }
+
+        // Bookmarks are the record ID as text
+        fn bookmark(&self, xml: Option<&str>) -> windows::core::Result<Mutex<u64>> {
+            match xml.map(str::parse) {
+                Some(Ok(record_id)) => Ok(Mutex::new(record_id)),
+                Some(Err(_)) => Err(E_INVALIDARG.into()),
+                None => Ok(Mutex::new(0)),
+            }
+        }
+
+        fn seek(
+            &self,
+            results: &Self::Results,
+            bookmark: &Mutex<u64>,
+        ) -> windows::core::Result<()> {
+            let after = *bookmark.lock().unwrap();
+            results
+                .events
+                .lock()
+                .unwrap()
+                .retain(|e| e.record_id > after);
+            Ok(())
+        }
+
+        fn update_bookmark(
+            &self,
+            bookmark: &Mutex<u64>,
+            event: &Event,
+        ) -> windows::core::Result<()> {
+            *bookmark.lock().unwrap() = event.record_id;
+            Ok(())
+        }
+
+        fn bookmark_xml(&self, bookmark: &Mutex<u64>) -> windows::core::Result<String> {
+            Ok(bookmark.lock().unwrap().to_string())
+        }
+    }
+}
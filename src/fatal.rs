@@ -0,0 +1,83 @@
+use serde_json::json;
+use std::error::Error;
+use std::fmt;
+
+/// Why the collector gave up. Each kind has its own exit code (following
+/// BSD sysexits) so wrappers and schedulers can tell them apart; anything
+/// else exits with 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Config,
+    NoChannels,
+    AccessDenied,
+    Sink,
+    Crash,
+}
+
+impl Kind {
+    pub fn code(self) -> u8 {
+        match self {
+            Kind::Config => 78,       // EX_CONFIG
+            Kind::NoChannels => 69,   // EX_UNAVAILABLE
+            Kind::AccessDenied => 77, // EX_NOPERM
+            Kind::Sink => 74,         // EX_IOERR
+            Kind::Crash => 70,        // EX_SOFTWARE
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Config => "config",
+            Kind::NoChannels => "no_channels",
+            Kind::AccessDenied => "access_denied",
+            Kind::Sink => "sink",
+            Kind::Crash => "crash",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Fatal {
+    pub kind: Kind,
+    message: String,
+}
+
+impl Fatal {
+    pub fn new(kind: Kind, message: impl fmt::Display) -> Self {
+        Fatal {
+            kind,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Fatal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for Fatal {}
+
+/// Exit code for an error that ended the run.
+pub fn code(e: &(dyn Error + 'static)) -> u8 {
+    e.downcast_ref::<Fatal>().map_or(1, |f| f.kind.code())
+}
+
+/// Writes the final error object on stderr and returns the exit code, e.g.
+/// `{"error":{"kind":"config","code":78,"message":"..."}}`.
+pub fn report(e: &(dyn Error + 'static)) -> u8 {
+    let kind = e.downcast_ref::<Fatal>().map(|f| f.kind);
+    let code = code(e);
+    eprintln!(
+        "{}",
+        json!({
+            "error": {
+                "kind": kind.map_or("error", Kind::name),
+                "code": code,
+                "message": e.to_string(),
+            }
+        })
+    );
+    code
+}
@@ -0,0 +1,255 @@
+use crate::config::{KeyCase, RouteConfig, SinkConfig};
+use glob_match::glob_match;
+use serde_json::{Map, Value as JsonValue};
+
+/// What an output writes of each event: the configured fields, with keys
+/// renamed and nested objects flattened if asked. The default output takes
+/// the `sink:` settings, a route's outputs the route's own where it sets
+/// them.
+#[derive(Clone, PartialEq)]
+pub struct Shape {
+    fields: Option<FieldFilter>,
+    flatten: bool,
+    key_case: KeyCase,
+}
+
+impl Shape {
+    /// The default output's shape; None when it writes events as they are.
+    pub fn of_sink(sink: &SinkConfig) -> Option<Shape> {
+        Shape::new(
+            FieldFilter::new(&sink.include_fields, &sink.exclude_fields),
+            sink.flatten,
+            sink.key_case,
+        )
+    }
+
+    /// The shape of a route's outputs: its own settings, the `sink:` ones
+    /// for those it leaves out.
+    pub fn of_route(route: &RouteConfig, sink: &SinkConfig) -> Option<Shape> {
+        Shape::new(
+            FieldFilter::new(
+                route
+                    .include_fields
+                    .as_ref()
+                    .unwrap_or(&sink.include_fields),
+                route
+                    .exclude_fields
+                    .as_ref()
+                    .unwrap_or(&sink.exclude_fields),
+            ),
+            route.flatten.unwrap_or(sink.flatten),
+            route.key_case.unwrap_or(sink.key_case),
+        )
+    }
+
+    fn new(fields: Option<FieldFilter>, flatten: bool, key_case: KeyCase) -> Option<Shape> {
+        (fields.is_some() || flatten || key_case != KeyCase::Original).then_some(Shape {
+            fields,
+            flatten,
+            key_case,
+        })
+    }
+
+    pub fn apply(&self, event: &JsonValue) -> JsonValue {
+        // Field paths name the original keys
+        let mut shaped = match &self.fields {
+            Some(fields) => fields.apply(event),
+            None => event.clone(),
+        };
+        if self.key_case != KeyCase::Original {
+            rename_keys(&mut shaped, self.key_case);
+        }
+        if self.flatten {
+            flatten(&shaped)
+        } else {
+            shaped
+        }
+    }
+}
+
+/// The sink's `include_fields` / `exclude_fields`: dotted paths into the
+/// event (`EventData.ScriptBlockText`), each segment a case-insensitive glob
+/// (`EventData.*Hash*`). With includes only the matching fields (and their
+/// parents) are kept; excludes are then removed from what is left.
+#[derive(Clone, PartialEq)]
+pub struct FieldFilter {
+    include: Vec<Vec<String>>,
+    exclude: Vec<Vec<String>>,
+}
+
+impl FieldFilter {
+    /// None when neither list is set, so events are written untouched.
+    pub fn new(include: &[String], exclude: &[String]) -> Option<FieldFilter> {
+        if include.is_empty() && exclude.is_empty() {
+            return None;
+        }
+        let paths = |list: &[String]| {
+            list.iter()
+                .filter(|p| !p.trim().is_empty())
+                .map(|p| p.trim().split('.').map(str::to_lowercase).collect())
+                .collect()
+        };
+        Some(FieldFilter {
+            include: paths(include),
+            exclude: paths(exclude),
+        })
+    }
+
+    pub fn apply(&self, event: &JsonValue) -> JsonValue {
+        let Some(obj) = event.as_object() else {
+            return event.clone();
+        };
+        let mut kept = if self.include.is_empty() {
+            obj.clone()
+        } else {
+            let paths: Vec<&[String]> = self.include.iter().map(Vec::as_slice).collect();
+            include(obj, &paths)
+        };
+        for path in &self.exclude {
+            exclude(&mut kept, path);
+        }
+        JsonValue::Object(kept)
+    }
+}
+
+// Keeps a field whole when a path ends at it, and descends into objects that
+// a longer path continues through
+fn include(obj: &Map<String, JsonValue>, paths: &[&[String]]) -> Map<String, JsonValue> {
+    let mut kept = Map::new();
+    for (key, value) in obj {
+        let name = key.to_lowercase();
+        let matching: Vec<&[String]> = paths
+            .iter()
+            .filter(|p| glob_match(&p[0], &name))
+            .copied()
+            .collect();
+        if matching.iter().any(|p| p.len() == 1) {
+            kept.insert(key.clone(), value.clone());
+        } else if let Some(child) = value.as_object() {
+            let rest: Vec<&[String]> = matching.iter().map(|p| &p[1..]).collect();
+            let child = include(child, &rest);
+            if !child.is_empty() {
+                kept.insert(key.clone(), JsonValue::Object(child));
+            }
+        }
+    }
+    kept
+}
+
+fn exclude(obj: &mut Map<String, JsonValue>, path: &[String]) {
+    let Some((first, rest)) = path.split_first() else {
+        return;
+    };
+    if rest.is_empty() {
+        obj.retain(|key, _| !glob_match(first, &key.to_lowercase()));
+        return;
+    }
+    for (key, value) in obj.iter_mut() {
+        if glob_match(first, &key.to_lowercase())
+            && let Some(child) = value.as_object_mut()
+        {
+            exclude(child, rest);
+        }
+    }
+}
+
+/// Flattens nested objects into one level of dotted keys:
+/// `{"EventData": {"TargetUserName": "x"}}` becomes
+/// `{"EventData.TargetUserName": "x"}`. Arrays stay values.
+pub fn flatten(event: &JsonValue) -> JsonValue {
+    let mut flat = Map::new();
+    if let Some(obj) = event.as_object() {
+        flatten_into(&mut flat, "", obj);
+    }
+    JsonValue::Object(flat)
+}
+
+fn flatten_into(flat: &mut Map<String, JsonValue>, prefix: &str, obj: &Map<String, JsonValue>) {
+    for (key, value) in obj {
+        let key = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        match value {
+            JsonValue::Object(child) => flatten_into(flat, &key, child),
+            _ => {
+                flat.insert(key, value.clone());
+            }
+        }
+    }
+}
+
+/// Renames every key, in nested objects too, to `case`: `EventRecordID`
+/// becomes `event_record_id` or `eventRecordId`, `@SystemTime` becomes
+/// `system_time` or `systemTime`.
+pub fn rename_keys(value: &mut JsonValue, case: KeyCase) {
+    match value {
+        JsonValue::Object(obj) => {
+            let renamed = std::mem::take(obj)
+                .into_iter()
+                .map(|(key, mut value)| {
+                    rename_keys(&mut value, case);
+                    (convert(&key, case), value)
+                })
+                .collect();
+            *obj = renamed;
+        }
+        JsonValue::Array(items) => {
+            for item in items {
+                rename_keys(item, case);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn convert(key: &str, case: KeyCase) -> String {
+    let words = words(key);
+    match case {
+        KeyCase::Original => key.to_string(),
+        KeyCase::SnakeCase => words.join("_"),
+        KeyCase::CamelCase => {
+            let mut out = String::new();
+            for (i, word) in words.iter().enumerate() {
+                let mut chars = word.chars();
+                if i > 0
+                    && let Some(first) = chars.next()
+                {
+                    out.extend(first.to_uppercase());
+                }
+                out.push_str(chars.as_str());
+            }
+            out
+        }
+    }
+}
+
+// Lowercase words of a key: a new word starts after a separator, at an
+// uppercase letter following a lowercase one or a digit, and at the last
+// capital of an acronym (IPAddress -> ip, address; EventID -> event, id)
+fn words(key: &str) -> Vec<String> {
+    let chars: Vec<char> = key.chars().collect();
+    let mut words = Vec::new();
+    let mut word = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if !c.is_alphanumeric() {
+            if !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+            continue;
+        }
+        if c.is_uppercase() && !word.is_empty() {
+            let prev = chars[i - 1];
+            let next_lower = chars.get(i + 1).is_some_and(|n| n.is_lowercase());
+            if prev.is_lowercase() || prev.is_ascii_digit() || (prev.is_uppercase() && next_lower) {
+                words.push(std::mem::take(&mut word));
+            }
+        }
+        word.extend(c.to_lowercase());
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
@@ -0,0 +1,472 @@
+use crate::config::{ChannelConfig, FilterConfig};
+use crate::evtapi::Origin;
+use crate::{hub, query};
+use glob_match::glob_match;
+use log::warn;
+use regex::RegexSet;
+use serde_json::Value as JsonValue;
+use std::collections::HashSet;
+use windows::Win32::Foundation::{HLOCAL, LocalFree};
+use windows::Win32::Security::Authorization::ConvertSidToStringSidW;
+use windows::Win32::Security::{LookupAccountNameW, PSID, SID_NAME_USE};
+use windows::core::{HSTRING, PCWSTR, PWSTR};
+
+/// The `filter` section and the channels' own `filter`s: which events are
+/// dropped as soon as they are read. A channel takes the filter of the
+/// first entry naming it that has one, else the section's. Accounts are
+/// resolved to SIDs and computer lists read once, so an event is matched on
+/// its SID and Computer alone, before its XML or message is rendered.
+pub struct EventFilter {
+    global: Option<OriginRules>,
+    // Lowercased channel names and patterns, and their rules
+    entries: Vec<(Vec<String>, OriginRules)>,
+}
+
+struct OriginRules {
+    include_users: HashSet<String>,
+    exclude_users: HashSet<String>,
+    include_computers: Vec<String>,
+    exclude_computers: Vec<String>,
+}
+
+impl EventFilter {
+    /// None when nothing is filtered. Fails on a list that can't be read,
+    /// rather than silently keeping (or dropping) everything.
+    pub fn new(
+        config: &FilterConfig,
+        channels: &[ChannelConfig],
+    ) -> Result<Option<EventFilter>, Box<dyn std::error::Error>> {
+        let mut entries = Vec::new();
+        for channel in channels {
+            let Some(filter) = &channel.filter else {
+                continue;
+            };
+            // A structured query's subscription goes by the entry's name;
+            // reprocessed events by the channels it reads
+            let mut names = vec![channel.name.clone()];
+            if let Some(query) = channel.query.as_deref().filter(|q| query::is_structured(q)) {
+                names.extend(query::select_paths(query)?);
+            }
+            let rules = OriginRules::new(filter)
+                .map_err(|e| format!("channel '{}': filter: {}", channel.name, e))?;
+            entries.push((names.iter().map(|n| n.to_lowercase()).collect(), rules));
+        }
+        let global = if is_empty(config) {
+            None
+        } else {
+            Some(OriginRules::new(config).map_err(|e| format!("filter: {}", e))?)
+        };
+        if global.is_none() && entries.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(EventFilter { global, entries }))
+    }
+
+    /// Whether an event of `channel` from `origin` is kept.
+    pub fn admit(&self, channel: &str, origin: &Origin) -> bool {
+        let channel = channel.to_lowercase();
+        let rules = self
+            .entries
+            .iter()
+            .find(|(channels, _)| channels.iter().any(|p| glob_match(p, &channel)))
+            .map(|(_, rules)| rules)
+            .or(self.global.as_ref());
+        rules.is_none_or(|rules| rules.admit(origin))
+    }
+}
+
+fn is_empty(config: &FilterConfig) -> bool {
+    config.include_users.is_empty()
+        && config.exclude_users.is_empty()
+        && config.include_computers.is_empty()
+        && config.exclude_computers.is_empty()
+}
+
+impl OriginRules {
+    fn new(config: &FilterConfig) -> Result<OriginRules, Box<dyn std::error::Error>> {
+        Ok(OriginRules {
+            include_users: sids(&config.include_users),
+            exclude_users: sids(&config.exclude_users),
+            include_computers: computers(&config.include_computers)?,
+            exclude_computers: computers(&config.exclude_computers)?,
+        })
+    }
+
+    fn admit(&self, origin: &Origin) -> bool {
+        let user = origin.user_id.as_deref().map(str::to_uppercase);
+        let computer = origin.computer.as_deref().map(str::to_lowercase);
+        let computer_matches = |patterns: &[String]| {
+            computer
+                .as_deref()
+                .is_some_and(|c| patterns.iter().any(|p| computer_match(p, c)))
+        };
+        if !self.include_users.is_empty()
+            && !user
+                .as_ref()
+                .is_some_and(|u| self.include_users.contains(u))
+        {
+            return false;
+        }
+        if !self.include_computers.is_empty() && !computer_matches(&self.include_computers) {
+            return false;
+        }
+        !user.is_some_and(|u| self.exclude_users.contains(&u))
+            && !computer_matches(&self.exclude_computers)
+    }
+}
+
+/// The channels' `include_messages` and `exclude_messages`: regexes the
+/// rendered Message must match (any of them), or must not, for an event to
+/// be written. An event without a message is matched as an empty one. A
+/// channel takes these from the first entry naming it that has any, so a
+/// catch-all `*` entry without them doesn't hide a later entry's.
+pub struct MessageFilter {
+    entries: Vec<MessageRules>,
+}
+
+struct MessageRules {
+    // Lowercased channel names and patterns the entry applies to
+    channels: Vec<String>,
+    include: RegexSet,
+    exclude: RegexSet,
+}
+
+impl MessageFilter {
+    /// None when no channel filters messages. Fails on a regex that doesn't
+    /// compile.
+    pub fn new(
+        channels: &[ChannelConfig],
+    ) -> Result<Option<MessageFilter>, Box<dyn std::error::Error>> {
+        if channels
+            .iter()
+            .all(|c| c.include_messages.is_empty() && c.exclude_messages.is_empty())
+        {
+            return Ok(None);
+        }
+        let mut entries = Vec::new();
+        for channel in channels {
+            if channel.include_messages.is_empty() && channel.exclude_messages.is_empty() {
+                continue;
+            }
+            let set = |patterns: &[String]| {
+                RegexSet::new(patterns).map_err(|e| {
+                    format!("channel '{}': invalid message regex: {}", channel.name, e)
+                })
+            };
+            // A structured query's rules apply to the channels it reads
+            let names = match channel.query.as_deref().filter(|q| query::is_structured(q)) {
+                Some(query) => query::select_paths(query)?,
+                None => vec![channel.name.clone()],
+            };
+            entries.push(MessageRules {
+                channels: names.iter().map(|n| n.to_lowercase()).collect(),
+                include: set(&channel.include_messages)?,
+                exclude: set(&channel.exclude_messages)?,
+            });
+        }
+        Ok(Some(MessageFilter { entries }))
+    }
+
+    /// Whether a rendered event is kept.
+    pub fn admit(&self, event: &JsonValue) -> bool {
+        let channel = hub::channel(event).unwrap_or_default().to_lowercase();
+        let Some(rules) = self
+            .entries
+            .iter()
+            .find(|e| e.channels.iter().any(|p| glob_match(p, &channel)))
+        else {
+            return true;
+        };
+        let message = event
+            .get("Message")
+            .and_then(JsonValue::as_str)
+            .unwrap_or_default();
+        (rules.include.is_empty() || rules.include.is_match(message))
+            && !rules.exclude.is_match(message)
+    }
+}
+
+/// Whether an event is of one of `channels` (names or globs), from one of
+/// `providers` and has one of `event_ids`; an empty list matches anything.
+/// Routes and alert rules pick their events this way.
+pub fn event_matches(
+    event: &JsonValue,
+    channels: &[String],
+    providers: &[String],
+    event_ids: &[u32],
+) -> bool {
+    let channel = hub::channel(event).unwrap_or_default().to_lowercase();
+    (channels.is_empty()
+        || channels
+            .iter()
+            .any(|pattern| glob_match(&pattern.to_lowercase(), &channel)))
+        && (providers.is_empty()
+            || hub::provider(event)
+                .is_some_and(|p| providers.iter().any(|x| x.eq_ignore_ascii_case(p))))
+        && (event_ids.is_empty() || hub::event_id(event).is_some_and(|id| event_ids.contains(&id)))
+}
+
+/// Whether a lowercased Computer matches a lowercased pattern; a pattern
+/// without a domain also matches the host part of an FQDN, so "dc01"
+/// matches "dc01.corp.example.com".
+pub fn computer_match(pattern: &str, computer: &str) -> bool {
+    glob_match(pattern, computer)
+        || (!pattern.contains('.')
+            && computer
+                .split_once('.')
+                .is_some_and(|(host, _)| glob_match(pattern, host)))
+}
+
+/// Lowercased Computer patterns, with file:<path> entries replaced by the
+/// names in the file (blank lines and # comments skipped).
+pub fn computers(entries: &[String]) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut patterns = Vec::new();
+    for entry in entries {
+        let entry = entry.trim();
+        match entry.strip_prefix("file:") {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .map_err(|e| format!("cannot read {}: {}", path, e))?;
+                patterns.extend(
+                    text.lines()
+                        .map(str::trim)
+                        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                        .map(str::to_lowercase),
+                );
+            }
+            None if !entry.is_empty() => patterns.push(entry.to_lowercase()),
+            None => {}
+        }
+    }
+    Ok(patterns)
+}
+
+// Uppercased SIDs of the accounts. An account that doesn't resolve (e.g. a
+// domain account while the domain can't be reached) is kept as given
+fn sids(accounts: &[String]) -> HashSet<String> {
+    accounts
+        .iter()
+        .map(|account| {
+            let account = account.trim();
+            if account.to_uppercase().starts_with("S-1-") {
+                return account.to_uppercase();
+            }
+            lookup(account).unwrap_or_else(|| {
+                warn!(
+                    "filter: cannot resolve account '{}', matching it as given",
+                    account
+                );
+                account.to_uppercase()
+            })
+        })
+        .collect()
+}
+
+// The S-1-... form of an account's SID
+fn lookup(account: &str) -> Option<String> {
+    unsafe {
+        let name = HSTRING::from(account);
+        let mut sid = vec![0u8; 68];
+        let mut domain = [0u16; 256];
+        let mut sid_len = sid.len() as u32;
+        let mut domain_len = domain.len() as u32;
+        let mut usage = SID_NAME_USE::default();
+        LookupAccountNameW(
+            PCWSTR::null(),
+            &name,
+            Some(PSID(sid.as_mut_ptr() as _)),
+            &mut sid_len,
+            Some(PWSTR(domain.as_mut_ptr())),
+            &mut domain_len,
+            &mut usage,
+        )
+        .ok()?;
+
+        let mut text = PWSTR::null();
+        ConvertSidToStringSidW(PSID(sid.as_mut_ptr() as _), &mut text).ok()?;
+        let result = text.to_string().ok();
+        let _ = LocalFree(Some(HLOCAL(text.0 as _)));
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn messages(channels: JsonValue) -> MessageFilter {
+        let channels: Vec<ChannelConfig> = serde_json::from_value(channels).unwrap();
+        MessageFilter::new(&channels).unwrap().unwrap()
+    }
+
+    fn event(channel: &str, message: Option<&str>) -> JsonValue {
+        let mut event = json!({ "Channel": channel });
+        if let Some(message) = message {
+            event["Message"] = json!(message);
+        }
+        event
+    }
+
+    #[test]
+    fn include_keeps_only_matching_messages() {
+        let filter = messages(
+            json!([{ "name": "Application", "include_messages": ["(?i)error", "failed"] }]),
+        );
+        assert!(filter.admit(&event("Application", Some("An ERROR occurred"))));
+        assert!(filter.admit(&event("Application", Some("Backup failed"))));
+        assert!(!filter.admit(&event("Application", Some("Backup completed"))));
+        // Other channels aren't filtered
+        assert!(filter.admit(&event("System", Some("Backup completed"))));
+    }
+
+    #[test]
+    fn exclude_drops_matching_messages() {
+        let filter = messages(json!([{
+            "name": "Application",
+            "include_messages": ["Backup"],
+            "exclude_messages": ["completed"],
+        }]));
+        assert!(filter.admit(&event("application", Some("Backup failed"))));
+        assert!(!filter.admit(&event("Application", Some("Backup completed"))));
+    }
+
+    #[test]
+    fn events_without_a_message_match_as_empty() {
+        let filter = messages(json!([
+            { "name": "Application", "include_messages": [".+"] },
+            { "name": "System", "exclude_messages": ["^$"] },
+            { "name": "Setup", "exclude_messages": ["noise"] },
+        ]));
+        assert!(!filter.admit(&event("Application", None)));
+        assert!(!filter.admit(&event("System", None)));
+        assert!(filter.admit(&event("Setup", None)));
+    }
+
+    #[test]
+    fn entries_without_rules_dont_hide_later_ones() {
+        let filter = messages(json!([
+            { "name": "*" },
+            { "name": "Application", "exclude_messages": ["noise"] },
+        ]));
+        assert!(!filter.admit(&event("Application", Some("more noise"))));
+        assert!(filter.admit(&event("System", Some("more noise"))));
+    }
+
+    #[test]
+    fn no_rules_no_filter() {
+        let channels: Vec<ChannelConfig> =
+            serde_json::from_value(json!([{ "name": "Application" }])).unwrap();
+        assert!(MessageFilter::new(&channels).unwrap().is_none());
+        assert!(
+            EventFilter::new(&FilterConfig::default(), &channels)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    fn origins(filter: JsonValue, channels: JsonValue) -> EventFilter {
+        let filter: FilterConfig = serde_json::from_value(filter).unwrap();
+        let channels: Vec<ChannelConfig> = serde_json::from_value(channels).unwrap();
+        EventFilter::new(&filter, &channels).unwrap().unwrap()
+    }
+
+    fn origin(user_id: Option<&str>, computer: Option<&str>) -> Origin {
+        Origin {
+            user_id: user_id.map(str::to_string),
+            computer: computer.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn admits_users_by_sid() {
+        let filter = origins(
+            json!({ "include_users": ["s-1-5-21-1-2-3-1001", "S-1-5-18"] }),
+            json!([]),
+        );
+        assert!(filter.admit("Security", &origin(Some("S-1-5-18"), None)));
+        assert!(filter.admit("Security", &origin(Some("S-1-5-21-1-2-3-1001"), None)));
+        assert!(!filter.admit("Security", &origin(Some("S-1-5-19"), None)));
+        // Only the users' events, so none without a user
+        assert!(!filter.admit("Security", &origin(None, None)));
+
+        let filter = origins(json!({ "exclude_users": ["S-1-5-18"] }), json!([]));
+        assert!(!filter.admit("Security", &origin(Some("S-1-5-18"), None)));
+        assert!(filter.admit("Security", &origin(Some("S-1-5-19"), None)));
+        assert!(filter.admit("Security", &origin(None, None)));
+    }
+
+    #[test]
+    fn admits_computers_by_pattern() {
+        let filter = origins(
+            json!({
+                "include_computers": ["dc*.corp.example.com", "ws01"],
+                "exclude_computers": ["dc09*"],
+            }),
+            json!([]),
+        );
+        let from = |computer| origin(None, Some(computer));
+        assert!(filter.admit("ForwardedEvents", &from("DC01.corp.example.com")));
+        assert!(filter.admit("ForwardedEvents", &from("ws01.corp.example.com")));
+        assert!(!filter.admit("ForwardedEvents", &from("dc09.corp.example.com")));
+        assert!(!filter.admit("ForwardedEvents", &from("ws02.corp.example.com")));
+        assert!(!filter.admit("ForwardedEvents", &origin(None, None)));
+    }
+
+    #[test]
+    fn channels_filter_on_their_own() {
+        let filter = origins(
+            json!({ "exclude_users": ["S-1-5-18"] }),
+            json!([
+                { "name": "Security" },
+                { "name": "ForwardedEvents", "filter": { "include_computers": ["branch*"] } },
+            ]),
+        );
+        let system = |computer| origin(Some("S-1-5-18"), Some(computer));
+        assert!(!filter.admit("Security", &system("dc01")));
+        // The channel's filter replaces the section's
+        assert!(filter.admit("forwardedevents", &system("branch01")));
+        assert!(!filter.admit("ForwardedEvents", &system("hq01")));
+
+        // Without a section, other channels aren't filtered
+        let filter = origins(
+            json!({}),
+            json!([{ "name": "ForwardedEvents", "filter": { "exclude_users": ["S-1-5-18"] } }]),
+        );
+        assert!(filter.admit("Security", &system("dc01")));
+        assert!(!filter.admit("ForwardedEvents", &system("dc01")));
+    }
+
+    #[test]
+    fn keeps_accounts_that_dont_resolve() {
+        let filter = origins(
+            json!({ "exclude_users": ["NO-SUCH-DOMAIN\\no-such-user"] }),
+            json!([]),
+        );
+        assert!(!filter.admit(
+            "Security",
+            &origin(Some("no-such-domain\\NO-SUCH-USER"), None)
+        ));
+        assert!(filter.admit("Security", &origin(Some("S-1-5-18"), None)));
+    }
+
+    #[test]
+    fn matches_channels_providers_and_event_ids() {
+        let event = json!({
+            "Channel": "Security",
+            "Provider": { "@Name": "Microsoft-Windows-Security-Auditing" },
+            "EventID": 4625,
+        });
+        let names = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert!(event_matches(&event, &[], &[], &[]));
+        assert!(event_matches(
+            &event,
+            &names(&["sec*"]),
+            &names(&["microsoft-windows-security-auditing"]),
+            &[4624, 4625],
+        ));
+        assert!(!event_matches(&event, &names(&["System"]), &[], &[]));
+        assert!(!event_matches(&event, &[], &names(&["Schannel"]), &[]));
+        assert!(!event_matches(&event, &[], &[], &[4624]));
+    }
+}
@@ -0,0 +1,152 @@
+use crate::config::GrpcConfig;
+use crate::hub::{self, Hub, StreamFilter};
+use crate::stats::Stats;
+use log::{error, info};
+use serde_json::Value as JsonValue;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+
+mod proto {
+    tonic::include_proto!("wineventlog.v1");
+}
+
+use proto::event_log_server::{EventLog, EventLogServer};
+
+// Events buffered per subscriber before it starts losing events
+const SUBSCRIBER_BUFFER: usize = 1024;
+
+// How often a subscription without events checks for its client
+const CLOSED_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Service {
+    hub: Arc<Hub>,
+    stats: Arc<Stats>,
+}
+
+#[tonic::async_trait]
+impl EventLog for Service {
+    type SubscribeStream = ReceiverStream<Result<proto::Event, Status>>;
+
+    async fn subscribe(
+        &self,
+        request: Request<proto::SubscribeRequest>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        let request = request.into_inner();
+        let filter = StreamFilter {
+            channels: request.channels,
+            event_ids: request.event_ids,
+            providers: request.providers,
+        };
+        let (backlog, events) =
+            self.hub
+                .subscribe(SUBSCRIBER_BUFFER, &filter, request.history as usize);
+        let (tx, rx) = mpsc::channel(SUBSCRIBER_BUFFER);
+
+        // The hub is synchronous; bridge it on a blocking thread which ends
+        // once the client has gone away, whether or not events come
+        tokio::task::spawn_blocking(move || {
+            for event in backlog {
+                if tx.blocking_send(Ok(to_proto(&event))).is_err() {
+                    return;
+                }
+            }
+            while !tx.is_closed() {
+                let event = match events.recv_timeout(CLOSED_CHECK_INTERVAL) {
+                    Ok(event) => event,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+                if filter.matches(&event) && tx.blocking_send(Ok(to_proto(&event))).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn get_stats(
+        &self,
+        _request: Request<proto::GetStatsRequest>,
+    ) -> Result<Response<proto::Stats>, Status> {
+        let channels: Vec<proto::ChannelStats> = self
+            .stats
+            .snapshot()
+            .into_iter()
+            .map(|(channel, s)| proto::ChannelStats {
+                channel,
+                events: s.events(),
+                lag_ms: s.lag().as_millis() as u64,
+                collection_lag_ms: s.collection_lag().as_millis() as u64,
+            })
+            .collect();
+        Ok(Response::new(proto::Stats {
+            total: channels.iter().map(|c| c.events).sum(),
+            channels,
+        }))
+    }
+}
+
+fn to_proto(event: &JsonValue) -> proto::Event {
+    proto::Event {
+        channel: hub::channel(event).unwrap_or_default().to_string(),
+        event_id: hub::event_id(event).unwrap_or_default(),
+        provider: hub::provider(event).unwrap_or_default().to_string(),
+        time_created: hub::time_created(event).unwrap_or_default().to_string(),
+        json: event.to_string(),
+    }
+}
+
+/// Starts the gRPC server on its own runtime thread.
+pub fn serve(
+    config: &GrpcConfig,
+    hub: Arc<Hub>,
+    stats: Arc<Stats>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let addr: SocketAddr = config.listen.parse()?;
+
+    #[allow(unused_mut)]
+    let mut builder = Server::builder();
+    let tls = config.tls_cert.is_some() || config.tls_key.is_some();
+    #[cfg(feature = "grpc-tls")]
+    if tls {
+        let (Some(cert), Some(key)) = (&config.tls_cert, &config.tls_key) else {
+            return Err("gRPC TLS needs both tls_cert and tls_key".into());
+        };
+        let identity =
+            tonic::transport::Identity::from_pem(std::fs::read(cert)?, std::fs::read(key)?);
+        builder =
+            builder.tls_config(tonic::transport::ServerTlsConfig::new().identity(identity))?;
+    }
+    #[cfg(not(feature = "grpc-tls"))]
+    if tls {
+        return Err("gRPC TLS configured but built without the 'grpc-tls' feature".into());
+    }
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .thread_name("grpc")
+        .enable_all()
+        .build()?;
+    let router = builder.add_service(EventLogServer::new(Service { hub, stats }));
+
+    thread::spawn(move || {
+        if let Err(e) = runtime.block_on(router.serve(addr)) {
+            error!("gRPC server stopped: {}", e);
+        }
+    });
+
+    info!(
+        "gRPC API listening on {}{}",
+        addr,
+        if tls { " (TLS)" } else { "" }
+    );
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use crate::hub::{self, Hub, StreamFilter};
+use chrono::{DateTime, Duration, Utc};
+use log::info;
+use serde_json::json;
+use std::sync::Arc;
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Serves `GET /events` over the hub's buffer of recent events.
+///
+/// Query parameters: `channel`, `event_id`, `provider` (as for the live
+/// streams), `since` (RFC 3339 timestamp or a relative age such as `10m`,
+/// `2h`, `1d`) and `limit` (newest N matches).
+pub fn serve(listen: &str, hub: Arc<Hub>) -> Result<(), Box<dyn std::error::Error>> {
+    let server = Server::http(listen).map_err(|e| format!("cannot listen on {}: {}", listen, e))?;
+    info!("REST API listening on http://{}", listen);
+
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let (status, body) = handle(&request, &hub);
+            let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+            let response = Response::from_string(body)
+                .with_status_code(status)
+                .with_header(content_type);
+            let _ = request.respond(response);
+        }
+    });
+    Ok(())
+}
+
+fn handle(request: &Request, hub: &Hub) -> (u16, String) {
+    let url = request.url();
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+
+    if *request.method() != Method::Get {
+        return error(405, "method not allowed");
+    }
+    if path != "/events" {
+        return error(404, "not found");
+    }
+
+    let filter = StreamFilter::from_query(query);
+    let mut since = None;
+    let mut limit = None;
+    for (key, value) in hub::query_pairs(query) {
+        match key.as_str() {
+            "since" => match parse_since(&value, Utc::now()) {
+                Ok(t) => since = Some(t),
+                Err(e) => return error(400, &e),
+            },
+            "limit" => match value.parse::<usize>() {
+                Ok(n) => limit = Some(n),
+                Err(_) => return error(400, "limit must be a number"),
+            },
+            _ => {}
+        }
+    }
+
+    let recent = hub.recent();
+    let mut events: Vec<_> = recent
+        .iter()
+        .filter(|e| filter.matches(e))
+        .filter(|e| match since {
+            Some(since) => hub::time_created(e)
+                .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+                .is_some_and(|t| t >= since),
+            None => true,
+        })
+        .map(|e| e.as_ref())
+        .collect();
+    if let Some(limit) = limit {
+        events.drain(..events.len().saturating_sub(limit));
+    }
+
+    (200, serde_json::to_string(&events).unwrap_or_default())
+}
+
+/// Accepts an RFC 3339 timestamp or an age like `30s`, `10m`, `2h`, `7d`.
+fn parse_since(value: &str, now: DateTime<Utc>) -> Result<DateTime<Utc>, String> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(value) {
+        return Ok(t.with_timezone(&Utc));
+    }
+
+    let invalid = || format!("invalid since '{}': expected RFC 3339 or e.g. 10m", value);
+    let unit = value.chars().last().ok_or_else(invalid)?;
+    let amount: i64 = value[..value.len() - unit.len_utf8()]
+        .parse()
+        .map_err(|_| invalid())?;
+    let age = match unit {
+        's' => Duration::seconds(amount),
+        'm' => Duration::minutes(amount),
+        'h' => Duration::hours(amount),
+        'd' => Duration::days(amount),
+        _ => return Err(invalid()),
+    };
+    Ok(now - age)
+}
+
+fn error(status: u16, message: &str) -> (u16, String) {
+    (status, json!({ "error": message }).to_string())
+}
@@ -0,0 +1,62 @@
+#[cfg(feature = "sentinel")]
+use flate2::{Compression, write::GzEncoder};
+#[cfg(feature = "sentinel")]
+use std::io::{self, Write};
+use std::time::Duration;
+use ureq::Agent;
+use ureq::tls::{TlsConfig, TlsProvider};
+
+/// Agent for outbound HTTP(S) requests. TLS goes through SChannel (native-tls)
+/// so the Windows certificate store decides what is trusted.
+pub fn agent(timeout: Duration) -> Agent {
+    Agent::config_builder()
+        .tls_config(
+            TlsConfig::builder()
+                .provider(TlsProvider::NativeTls)
+                .build(),
+        )
+        .timeout_global(Some(timeout))
+        .build()
+        .into()
+}
+
+/// A request body and its Content-Encoding: gzip-compressed when it's at
+/// least `threshold` bytes, otherwise as it is.
+#[cfg(feature = "sentinel")]
+pub fn encode(
+    body: String,
+    threshold: Option<usize>,
+) -> io::Result<(Vec<u8>, Option<&'static str>)> {
+    if threshold.is_none_or(|threshold| body.len() < threshold) {
+        return Ok((body.into_bytes(), None));
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body.as_bytes())?;
+    Ok((encoder.finish()?, Some("gzip")))
+}
+
+#[cfg(all(test, feature = "sentinel"))]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn gzips_bodies_from_threshold() {
+        let body = "[{\"EventID\":4624}]".to_string();
+        assert_eq!(
+            encode(body.clone(), None).unwrap(),
+            (body.clone().into_bytes(), None)
+        );
+        let small = encode(body.clone(), Some(body.len() + 1)).unwrap();
+        assert_eq!(small, (body.clone().into_bytes(), None));
+
+        let (gzipped, encoding) = encode(body.clone(), Some(body.len())).unwrap();
+        assert_eq!(encoding, Some("gzip"));
+        let mut text = String::new();
+        GzDecoder::new(&gzipped[..])
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, body);
+    }
+}
@@ -0,0 +1,214 @@
+use crate::config::Level;
+use serde_json::Value as JsonValue;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+
+// An event tagged with a global sequence number, to merge channels in order
+type Buffered = (u64, Arc<JsonValue>);
+
+/// Fans rendered events out to live consumers (gRPC, WebSocket, ...) and
+/// keeps the last N events of every channel as lookback history. A consumer
+/// that falls behind loses events instead of stalling collection.
+pub struct Hub {
+    subscribers: Mutex<Vec<SyncSender<Arc<JsonValue>>>>,
+    recent: Mutex<HashMap<String, VecDeque<Buffered>>>,
+    lookback: usize,
+    sequence: AtomicU64,
+}
+
+impl Hub {
+    /// `lookback` is the number of events kept per channel; 0 disables it.
+    pub fn new(lookback: usize) -> Hub {
+        Hub {
+            subscribers: Mutex::new(Vec::new()),
+            recent: Mutex::new(HashMap::new()),
+            lookback,
+            sequence: AtomicU64::new(0),
+        }
+    }
+
+    /// The buffered events of all channels, oldest first.
+    pub fn recent(&self) -> Vec<Arc<JsonValue>> {
+        let recent = self.recent.lock().unwrap();
+        let mut events: Vec<&Buffered> = recent.values().flatten().collect();
+        events.sort_by_key(|(seq, _)| *seq);
+        events.into_iter().map(|(_, e)| Arc::clone(e)).collect()
+    }
+
+    /// Registers a live consumer. Also returns up to `history` of the most
+    /// recent buffered events matching `filter` (oldest first), taken
+    /// atomically with the subscription so nothing is missed or repeated.
+    pub fn subscribe(
+        &self,
+        capacity: usize,
+        filter: &StreamFilter,
+        history: usize,
+    ) -> (Vec<Arc<JsonValue>>, Receiver<Arc<JsonValue>>) {
+        let (tx, rx) = mpsc::sync_channel(capacity);
+        let mut subscribers = self.subscribers.lock().unwrap();
+
+        let mut backlog = Vec::new();
+        if history > 0 {
+            backlog = self.recent();
+            backlog.retain(|e| filter.matches(e));
+            backlog.drain(..backlog.len().saturating_sub(history));
+        }
+
+        subscribers.push(tx);
+        (backlog, rx)
+    }
+
+    pub fn publish(&self, event: &JsonValue) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.is_empty() && self.lookback == 0 {
+            return;
+        }
+        let event = Arc::new(event.clone());
+
+        if self.lookback > 0 {
+            let seq = self.sequence.fetch_add(1, Ordering::Relaxed);
+            let mut recent = self.recent.lock().unwrap();
+            let buffer = recent
+                .entry(channel(&event).unwrap_or_default().to_string())
+                .or_default();
+            if buffer.len() == self.lookback {
+                buffer.pop_front();
+            }
+            buffer.push_back((seq, Arc::clone(&event)));
+        }
+
+        subscribers.retain(|tx| match tx.try_send(Arc::clone(&event)) {
+            Ok(()) | Err(TrySendError::Full(_)) => true,
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+}
+
+/// Consumer-side selection of events. Empty lists match everything.
+#[derive(Default)]
+pub struct StreamFilter {
+    pub channels: Vec<String>,
+    pub event_ids: Vec<u32>,
+    pub providers: Vec<String>,
+}
+
+impl StreamFilter {
+    /// Builds a filter from URL query parameters. Values may be repeated or
+    /// comma-separated: `?channel=Security&event_id=4624,4625&provider=...`.
+    /// Unknown parameters are ignored.
+    #[cfg(any(feature = "api", feature = "websocket"))]
+    pub fn from_query(query: &str) -> StreamFilter {
+        let mut filter = StreamFilter::default();
+        for (key, value) in query_pairs(query) {
+            let values = value.split(',').map(str::trim).filter(|v| !v.is_empty());
+            match key.as_str() {
+                "channel" => filter.channels.extend(values.map(str::to_string)),
+                "provider" => filter.providers.extend(values.map(str::to_string)),
+                "event_id" => filter
+                    .event_ids
+                    .extend(values.filter_map(|v| v.parse::<u32>().ok())),
+                _ => {}
+            }
+        }
+        filter
+    }
+
+    pub fn matches(&self, event: &JsonValue) -> bool {
+        let any = |list: &[String], value: Option<&str>| {
+            list.is_empty() || value.is_some_and(|v| list.iter().any(|x| x.eq_ignore_ascii_case(v)))
+        };
+        any(&self.channels, channel(event))
+            && any(&self.providers, provider(event))
+            && (self.event_ids.is_empty()
+                || event_id(event).is_some_and(|id| self.event_ids.contains(&id)))
+    }
+}
+
+pub fn channel(event: &JsonValue) -> Option<&str> {
+    event.get("Channel").and_then(|c| c.as_str())
+}
+
+pub fn provider(event: &JsonValue) -> Option<&str> {
+    event
+        .get("Provider")
+        .and_then(|p| p.get("@Name"))
+        .and_then(|n| n.as_str())
+}
+
+pub fn event_id(event: &JsonValue) -> Option<u32> {
+    match event.get("EventID")? {
+        JsonValue::Number(n) => n.as_u64().map(|n| n as u32),
+        JsonValue::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+// The rendered name when the provider supplies one (in English), otherwise
+// the raw number; 0 (LogAlways) counts as Information
+pub fn level(event: &JsonValue) -> Option<Level> {
+    let level = match event.get("Level")? {
+        JsonValue::String(s) => s.to_lowercase(),
+        other => other.to_string(),
+    };
+    Some(match level.as_str() {
+        "1" | "critical" => Level::Critical,
+        "2" | "error" => Level::Error,
+        "3" | "warning" => Level::Warning,
+        "0" | "4" | "information" => Level::Information,
+        "5" | "verbose" => Level::Verbose,
+        _ => return None,
+    })
+}
+
+pub fn record_id(event: &JsonValue) -> Option<u64> {
+    match event.get("EventRecordID")? {
+        JsonValue::Number(n) => n.as_u64(),
+        JsonValue::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+pub fn time_created(event: &JsonValue) -> Option<&str> {
+    event
+        .get("TimeCreated")
+        .and_then(|t| t.get("@SystemTime"))
+        .and_then(|t| t.as_str())
+}
+
+/// Splits and percent-decodes `a=1&b=2` style query strings.
+#[cfg(any(feature = "api", feature = "websocket"))]
+pub fn query_pairs(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect()
+}
+
+#[cfg(any(feature = "api", feature = "websocket"))]
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 2;
+            }
+            (b'+', _) => out.push(b' '),
+            (b, _) => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
@@ -0,0 +1,102 @@
+use crate::built_info;
+use crate::config::Config;
+use log::{info, warn};
+use serde_json::{Value as JsonValue, json};
+use sha2::{Digest, Sha256};
+use windows::Win32::System::EventLog::{
+    DeregisterEventSource, EVENTLOG_INFORMATION_TYPE, RegisterEventSourceW, ReportEventW,
+};
+use windows::core::{HSTRING, PCWSTR};
+
+// Event ID of the startup record in the Application log
+const STARTUP_EVENT_ID: u32 = 1000;
+
+/// Version and build details, printed by `--version --json` and included in
+/// the startup record.
+pub fn build_info() -> JsonValue {
+    json!({
+        "name": "rs-wineventlog",
+        "version": option_env!("BUILD_VERSION").unwrap_or(built_info::PKG_VERSION),
+        "git_commit": built_info::GIT_COMMIT_HASH,
+        "git_dirty": built_info::GIT_DIRTY,
+        "built_at": built_info::BUILT_TIME_UTC,
+        "target": built_info::TARGET,
+        "rustc": built_info::RUSTC_VERSION,
+        "features": built_info::FEATURES_LOWERCASE,
+    })
+}
+
+/// SHA-256 of the merged configuration settings as written: every source
+/// applied, but `${env:...}` and `dpapi:` secret references left as they
+/// are. Keys are sorted first, so the hash doesn't depend on source order.
+pub fn config_hash(mut settings: JsonValue) -> String {
+    sort_keys(&mut settings);
+    let canonical = serde_json::to_vec(&settings).unwrap_or_default();
+    format!("{:x}", Sha256::digest(canonical))
+}
+
+fn sort_keys(value: &mut JsonValue) {
+    match value {
+        JsonValue::Object(map) => {
+            map.sort_keys();
+            map.values_mut().for_each(sort_keys);
+        }
+        JsonValue::Array(items) => items.iter_mut().for_each(sort_keys),
+        _ => {}
+    }
+}
+
+/// Logs what is running and with which configuration, and optionally writes
+/// the same record to the Application event log.
+pub fn announce(config: &Config, channels: &[String]) {
+    let mut record = build_info();
+    record["config_sha256"] = json!(config.fingerprint);
+    record["channels"] = json!(channels);
+    record["labels"] = json!(config.labels);
+    record["pid"] = json!(std::process::id());
+
+    info!("Starting collector: {}", record);
+    if config.startup_event
+        && let Err(e) = report_event(&record.to_string())
+    {
+        warn!("Failed to write startup event: {}", e);
+    }
+}
+
+fn report_event(message: &str) -> windows::core::Result<()> {
+    unsafe {
+        let source = RegisterEventSourceW(PCWSTR::null(), &HSTRING::from("rs-wineventlog"))?;
+        let message = HSTRING::from(message);
+        let result = ReportEventW(
+            source,
+            EVENTLOG_INFORMATION_TYPE,
+            0,
+            STARTUP_EVENT_ID,
+            None,
+            0,
+            Some(&[PCWSTR(message.as_ptr())]),
+            None,
+        );
+        let _ = DeregisterEventSource(source);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_hash_ignores_key_order() {
+        let hash = config_hash(json!({
+            "channels": ["Security"],
+            "email": { "password": "${env:SMTP_PASSWORD}", "to": ["soc@example.com"] },
+        }));
+        let reordered = config_hash(json!({
+            "email": { "to": ["soc@example.com"], "password": "${env:SMTP_PASSWORD}" },
+            "channels": ["Security"],
+        }));
+        assert_eq!(hash, reordered);
+        assert_ne!(hash, config_hash(json!({ "channels": ["Security"] })));
+    }
+}
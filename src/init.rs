@@ -0,0 +1,144 @@
+use crate::acl;
+use crate::eventlog;
+use std::fmt::Write;
+use std::path::Path;
+use windows::Win32::Foundation::E_ACCESSDENIED;
+
+// Offered in this order when the machine has them
+const CHANNELS: [(&str, &str); 12] = [
+    ("Security", "logons, account and policy changes"),
+    ("System", "services, drivers, restarts"),
+    ("Application", "application errors and events"),
+    (
+        "Microsoft-Windows-Sysmon/Operational",
+        "Sysmon process, network and file activity",
+    ),
+    (
+        "Microsoft-Windows-PowerShell/Operational",
+        "PowerShell script blocks",
+    ),
+    ("Windows PowerShell", "PowerShell engine start and stop"),
+    (
+        "Microsoft-Windows-Windows Defender/Operational",
+        "Defender detections",
+    ),
+    (
+        "Microsoft-Windows-TaskScheduler/Operational",
+        "scheduled task runs",
+    ),
+    (
+        "Microsoft-Windows-TerminalServices-LocalSessionManager/Operational",
+        "RDP sessions",
+    ),
+    (
+        "Microsoft-Windows-Bits-Client/Operational",
+        "BITS transfers",
+    ),
+    ("Microsoft-Windows-WMI-Activity/Operational", "WMI activity"),
+    (
+        "Microsoft-Windows-AppLocker/EXE and DLL",
+        "AppLocker decisions",
+    ),
+];
+
+/// Writes a commented starter config to `path`, listing the commonly
+/// collected channels this machine has. Channels the current account can't
+/// read are left commented out.
+pub fn run(path: &Path, force: bool) -> Result<(), Box<dyn std::error::Error>> {
+    if path.exists() && !force {
+        return Err(format!(
+            "{} already exists (use --force to replace it)",
+            path.display()
+        )
+        .into());
+    }
+
+    let available = eventlog::available_channels()?;
+    let mut channels = String::new();
+    let (mut readable, mut denied) = (0, 0);
+    for (name, about) in CHANNELS {
+        if !available.iter().any(|c| c.eq_ignore_ascii_case(name)) {
+            continue;
+        }
+        match acl::can_read(name) {
+            Ok(()) => {
+                readable += 1;
+                writeln!(channels, "  - {}  # {}", quote(name), about)?;
+            }
+            Err(e) if e.code() == E_ACCESSDENIED => {
+                denied += 1;
+                writeln!(
+                    channels,
+                    "  # - {}  # {}; access denied for this account (see channel-acl)",
+                    quote(name),
+                    about
+                )?;
+            }
+            // Present but not usable, e.g. a disabled analytic channel
+            Err(_) => {}
+        }
+    }
+    if readable == 0 {
+        channels.push_str("  - Application\n");
+    }
+
+    std::fs::write(path, template(&channels))?;
+    println!(
+        "Wrote {} with {} channels ({} more need other rights)",
+        path.display(),
+        readable,
+        denied
+    );
+    println!(
+        "Check it with: rs-wineventlog --config {} validate-config",
+        path.display()
+    );
+    Ok(())
+}
+
+// Names with YAML-significant characters are written quoted
+fn quote(name: &str) -> String {
+    if name.contains([':', '#']) {
+        format!("'{}'", name.replace('\'', "''"))
+    } else {
+        name.to_string()
+    }
+}
+
+fn template(channels: &str) -> String {
+    format!(
+        "# rs-wineventlog configuration, generated by `rs-wineventlog init`.
+# Every setting is described in the README.
+
+# Channels to collect. Names may be globs, e.g. Microsoft-Windows-*/Operational;
+# `rs-wineventlog list-channels` shows all channels on this machine.
+channels:
+{channels}
+# Where events go: a path, file://<path>, tcp://<host>:<port>,
+# lumberjack[+tls]://<host>:<port> or - for stdout (default)
+# output_file: C:\\ProgramData\\rs-wineventlog\\events.ndjson
+
+# When written events are flushed (default: after every event)
+# flush:
+#   every_n_events: 500
+#   interval_ms: 1000
+
+# Where read positions are kept between runs (default: next to the executable)
+# state_file: C:\\ProgramData\\rs-wineventlog\\state.redb
+
+# Emit numeric fields as JSON numbers and true/false as booleans
+# typed_json: true
+
+# Channel-specific parsing
+# parsers:
+#   sysmon: true
+#   command_line: true
+#   script_blocks: true
+#   knowledge_base: true
+
+# Labels added to every event
+# labels:
+#   role: workstation
+"
+    )
+}
@@ -0,0 +1,219 @@
+use log::info;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// Written after each record; a record without it was torn by a crash
+const COMMIT: &[u8; 4] = b"CMIT";
+
+/// Write-ahead journal for a file output. Every event is appended here, as
+/// `[u32 length][line][COMMIT]`, before it goes to the output file; each
+/// flush of the output empties it again. The header holds the output's
+/// length at that flush, so after a crash the output is cut back to it and
+/// the committed records are replayed: a torn line never survives a restart.
+pub struct Journal {
+    file: File,
+    // Records appended since the last commit
+    pending: usize,
+}
+
+impl Journal {
+    /// Opens the journal next to `output`, first repairing the output from
+    /// a journal left behind by a crash.
+    pub fn open(output: &Path) -> io::Result<Journal> {
+        let mut name = output.as_os_str().to_owned();
+        name.push(".journal");
+        let path = PathBuf::from(name);
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        if data.len() >= 8 {
+            let base = u64::from_le_bytes(data[..8].try_into().unwrap());
+            replay(output, base, &records(&data[8..]))?;
+        }
+
+        let mut journal = Journal { file, pending: 0 };
+        journal.rebase(&OpenOptions::new().create(true).append(true).open(output)?)?;
+        Ok(journal)
+    }
+
+    pub fn append(&mut self, line: &[u8]) -> io::Result<()> {
+        // One write, so the record is either fully in the page cache or not
+        let mut record = Vec::with_capacity(line.len() + 8);
+        record.extend_from_slice(&(line.len() as u32).to_le_bytes());
+        record.extend_from_slice(line);
+        record.extend_from_slice(COMMIT);
+        self.file.write_all(&record)?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// Empties the journal once everything in it has reached `output`.
+    pub fn commit(&mut self, output: &File) -> io::Result<()> {
+        if self.pending == 0 {
+            return Ok(());
+        }
+        self.rebase(output)
+    }
+
+    /// Empties the journal and records `output`'s current length as its
+    /// base, whether or not anything is pending, e.g. once the output was
+    /// rotated to a new file.
+    pub fn rebase(&mut self, output: &File) -> io::Result<()> {
+        let base = output.metadata()?.len();
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&base.to_le_bytes())?;
+        self.pending = 0;
+        Ok(())
+    }
+}
+
+// Committed records in order; parsing stops at the first torn one
+fn records(mut data: &[u8]) -> Vec<&[u8]> {
+    let mut records = Vec::new();
+    while data.len() >= 4 {
+        let len = u32::from_le_bytes(data[..4].try_into().unwrap()) as usize;
+        let Some(record) = data.get(4..4 + len + COMMIT.len()) else {
+            break;
+        };
+        if &record[len..] != COMMIT {
+            break;
+        }
+        records.push(&record[..len]);
+        data = &data[4 + len + COMMIT.len()..];
+    }
+    records
+}
+
+fn replay(output: &Path, base: u64, records: &[&[u8]]) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(output)?;
+    let len = file.metadata()?.len();
+    if len == base && records.is_empty() {
+        return Ok(());
+    }
+    // Everything past the base came from journaled records, possibly torn
+    if len > base {
+        file.set_len(base)?;
+    }
+    file.seek(SeekFrom::End(0))?;
+    for record in records {
+        file.write_all(record)?;
+    }
+    file.sync_data()?;
+    info!(
+        "Recovered {} event(s) into {} from its journal",
+        records.len(),
+        output.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "rs-wineventlog-journal-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_file(&path);
+        let mut journal = path.as_os_str().to_owned();
+        journal.push(".journal");
+        let _ = std::fs::remove_file(journal);
+        path
+    }
+
+    fn output(path: &Path) -> File {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap()
+    }
+
+    // Journals then writes `line` the way FileSink does
+    fn write(journal: &mut Journal, out: &mut File, line: &[u8]) {
+        journal.append(line).unwrap();
+        out.write_all(line).unwrap();
+    }
+
+    #[test]
+    fn committed_lines_stay_as_written() {
+        let path = temp("commit.ndjson");
+        let mut journal = Journal::open(&path).unwrap();
+        let mut out = output(&path);
+        write(&mut journal, &mut out, b"{\"a\":1}\n");
+        journal.commit(&out).unwrap();
+        drop(journal);
+
+        Journal::open(&path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"{\"a\":1}\n");
+    }
+
+    #[test]
+    fn replays_uncommitted_lines_over_a_torn_one() {
+        let path = temp("replay.ndjson");
+        let mut journal = Journal::open(&path).unwrap();
+        let mut out = output(&path);
+        write(&mut journal, &mut out, b"{\"a\":1}\n");
+        journal.commit(&out).unwrap();
+        journal.append(b"{\"b\":2}\n").unwrap();
+        // Crashed partway through writing the journaled line
+        out.write_all(b"{\"b\"").unwrap();
+        drop(journal);
+
+        Journal::open(&path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"{\"a\":1}\n{\"b\":2}\n");
+    }
+
+    #[test]
+    fn drops_torn_journal_records() {
+        let path = temp("torn.ndjson");
+        let mut journal = Journal::open(&path).unwrap();
+        let out = output(&path);
+        journal.commit(&out).unwrap();
+        // A record cut short of its commit marker
+        journal.file.write_all(&9u32.to_le_bytes()).unwrap();
+        journal.file.write_all(b"{\"c\"").unwrap();
+        drop(journal);
+
+        Journal::open(&path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"");
+    }
+
+    #[test]
+    fn rebases_onto_a_rotated_file() {
+        let path = temp("rotate.ndjson");
+        let rotated = temp("rotate.ndjson.1");
+        let mut journal = Journal::open(&path).unwrap();
+        let mut out = output(&path);
+        write(&mut journal, &mut out, b"{\"a\":1}\n{\"a\":2}\n");
+        journal.commit(&out).unwrap();
+
+        // Rotated after a flush: nothing pending, but the base must move
+        drop(out);
+        std::fs::rename(&path, &rotated).unwrap();
+        let mut out = output(&path);
+        journal.rebase(&out).unwrap();
+        write(&mut journal, &mut out, b"{\"b\":1}\n");
+        drop(journal);
+
+        Journal::open(&path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"{\"b\":1}\n");
+        assert_eq!(std::fs::read(&rotated).unwrap(), b"{\"a\":1}\n{\"a\":2}\n");
+        let _ = std::fs::remove_file(rotated);
+    }
+}
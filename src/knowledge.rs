@@ -0,0 +1,392 @@
+use crate::config::{LookupConfig, ParsersConfig};
+use crate::{hub, xml};
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+
+const SECURITY_AUDITING: &str = "Microsoft-Windows-Security-Auditing";
+
+const LOGON_TYPES: &[(&str, &str)] = &[
+    ("0", "System"),
+    ("2", "Interactive"),
+    ("3", "Network"),
+    ("4", "Batch"),
+    ("5", "Service"),
+    ("7", "Unlock"),
+    ("8", "NetworkCleartext"),
+    ("9", "NewCredentials"),
+    ("10", "RemoteInteractive"),
+    ("11", "CachedInteractive"),
+    ("12", "CachedRemoteInteractive"),
+    ("13", "CachedUnlock"),
+];
+
+// NTSTATUS codes of failed NTLM and interactive logons (4625, 4776)
+const LOGON_STATUS: &[(&str, &str)] = &[
+    ("0x0", "Success"),
+    ("0xC0000064", "User name does not exist"),
+    (
+        "0xC000006A",
+        "User name is correct but the password is wrong",
+    ),
+    ("0xC000006D", "Bad user name or password"),
+    ("0xC000006E", "Account restriction prevents the logon"),
+    ("0xC000006F", "Logon outside the allowed hours"),
+    ("0xC0000070", "Logon from a workstation that isn't allowed"),
+    ("0xC0000071", "Password has expired"),
+    ("0xC0000072", "Account is disabled"),
+    ("0xC00000DC", "Server was in the wrong state"),
+    ("0xC0000133", "Clock out of sync with the domain controller"),
+    (
+        "0xC000015B",
+        "Logon type not granted to the user on this machine",
+    ),
+    (
+        "0xC000018C",
+        "Trust relationship between the domains failed",
+    ),
+    ("0xC0000192", "Netlogon service is not started"),
+    ("0xC0000193", "Account has expired"),
+    ("0xC0000224", "User must change the password at next logon"),
+    ("0xC0000225", "Windows error, not a security risk"),
+    ("0xC0000234", "Account is locked out"),
+    ("0xC00002EE", "An error occurred during logon"),
+    (
+        "0xC0000371",
+        "Local account store has no secret for the account",
+    ),
+    (
+        "0xC0000413",
+        "Authentication firewall: not allowed to authenticate to this machine",
+    ),
+];
+
+// Insertion strings of 4625's FailureReason
+const FAILURE_REASONS: &[(&str, &str)] = &[
+    ("%%2304", "An error occurred during logon"),
+    ("%%2305", "The user account has expired"),
+    ("%%2306", "The NetLogon component is not active"),
+    ("%%2307", "Account locked out"),
+    (
+        "%%2308",
+        "The user has not been granted the requested logon type on this machine",
+    ),
+    ("%%2309", "The password has expired"),
+    ("%%2310", "Account currently disabled"),
+    ("%%2311", "Account logon time restriction violation"),
+    ("%%2312", "User not allowed to log on at this computer"),
+    ("%%2313", "Unknown user name or bad password"),
+];
+
+// Kerberos result codes (RFC 4120) of 4768, 4769 and 4771
+const KERBEROS_STATUS: &[(&str, &str)] = &[
+    ("0x0", "Success"),
+    ("0x6", "Client not found in the Kerberos database"),
+    ("0x7", "Server not found in the Kerberos database"),
+    ("0x9", "Client or server has no key"),
+    ("0xC", "KDC policy rejects the request"),
+    ("0xE", "Encryption type not supported"),
+    (
+        "0x10",
+        "Pre-authentication data not supported, e.g. smart card logon",
+    ),
+    (
+        "0x12",
+        "Client credentials revoked: account disabled, expired or locked out",
+    ),
+    ("0x17", "Password has expired"),
+    ("0x18", "Pre-authentication failed: bad password"),
+    ("0x1B", "Server principal is valid for user-to-user only"),
+    ("0x1F", "Integrity check on a decrypted field failed"),
+    ("0x20", "Ticket expired"),
+    ("0x25", "Clock skew too great"),
+    ("0x29", "Message stream modified"),
+    ("0x3C", "Generic error"),
+    ("0x44", "Wrong realm"),
+];
+
+const TICKET_ENCRYPTION_TYPES: &[(&str, &str)] = &[
+    ("0x1", "DES-CBC-CRC"),
+    ("0x3", "DES-CBC-MD5"),
+    ("0x11", "AES128-CTS-HMAC-SHA1-96"),
+    ("0x12", "AES256-CTS-HMAC-SHA1-96"),
+    ("0x17", "RC4-HMAC"),
+    ("0x18", "RC4-HMAC-EXP"),
+    ("0xFFFFFFFF", "Failure"),
+];
+
+// Security-Auditing EventIDs, coded field, added field, values
+type Builtin = (
+    &'static [u32],
+    &'static str,
+    &'static str,
+    &'static [(&'static str, &'static str)],
+);
+
+const BUILTIN: &[Builtin] = &[
+    (
+        &[4624, 4625, 4634],
+        "LogonType",
+        "LogonTypeName",
+        LOGON_TYPES,
+    ),
+    (&[4625], "Status", "StatusText", LOGON_STATUS),
+    (&[4625], "SubStatus", "SubStatusText", LOGON_STATUS),
+    (
+        &[4625],
+        "FailureReason",
+        "FailureReasonText",
+        FAILURE_REASONS,
+    ),
+    (&[4776], "Status", "FailureReasonText", LOGON_STATUS),
+    (
+        &[4768, 4769, 4771],
+        "Status",
+        "FailureReasonText",
+        KERBEROS_STATUS,
+    ),
+    (
+        &[4768, 4769],
+        "TicketEncryptionType",
+        "TicketEncryptionTypeName",
+        TICKET_ENCRYPTION_TYPES,
+    ),
+];
+
+/// Decodes coded EventData fields into readable ones added next to them:
+/// the built-in table of well-known Security events (`knowledge_base`),
+/// then the configured `lookups`.
+pub struct KnowledgeBase {
+    lookups: Vec<Lookup>,
+}
+
+struct Lookup {
+    event_ids: Vec<u32>,
+    providers: Vec<String>,
+    field: String,
+    name: String,
+    // By normalized code
+    values: HashMap<String, String>,
+}
+
+impl Lookup {
+    fn matches(&self, event: &JsonValue) -> bool {
+        (self.event_ids.is_empty()
+            || hub::event_id(event).is_some_and(|id| self.event_ids.contains(&id)))
+            && (self.providers.is_empty()
+                || hub::provider(event)
+                    .is_some_and(|p| self.providers.iter().any(|x| x.eq_ignore_ascii_case(p))))
+    }
+}
+
+impl KnowledgeBase {
+    /// None when the built-in table is off and there are no lookups.
+    pub fn new(config: &ParsersConfig) -> Option<KnowledgeBase> {
+        let mut lookups: Vec<Lookup> = Vec::new();
+        if config.knowledge_base {
+            for (event_ids, field, name, values) in BUILTIN {
+                lookups.push(Lookup {
+                    event_ids: event_ids.to_vec(),
+                    providers: vec![SECURITY_AUDITING.to_string()],
+                    field: field.to_string(),
+                    name: name.to_string(),
+                    values: values
+                        .iter()
+                        .map(|(code, text)| (normalize(code), text.to_string()))
+                        .collect(),
+                });
+            }
+        }
+        for lookup in &config.lookups {
+            add(&mut lookups, lookup);
+        }
+        (!lookups.is_empty()).then_some(KnowledgeBase { lookups })
+    }
+
+    pub fn enrich(&self, event: &mut JsonValue) {
+        let mut decoded = Vec::new();
+        // Configured lookups first, so theirs is the field added when a
+        // built-in one adds the same
+        for lookup in self.lookups.iter().rev().filter(|l| l.matches(event)) {
+            let Some(value) = event.get("EventData").and_then(|d| d.get(&lookup.field)) else {
+                continue;
+            };
+            let code = match value {
+                JsonValue::String(s) => normalize(s),
+                JsonValue::Number(n) => n.to_string(),
+                _ => continue,
+            };
+            if let Some(text) = lookup.values.get(&code) {
+                decoded.push((&lookup.field, &lookup.name, text));
+            }
+        }
+        let Some(data) = event
+            .get_mut("EventData")
+            .and_then(JsonValue::as_object_mut)
+        else {
+            return;
+        };
+        for (field, name, text) in decoded {
+            // Nor does a field the event already has get replaced
+            if !data.contains_key(name.as_str()) {
+                xml::insert_after(data, field, name, JsonValue::String(text.clone()));
+            }
+        }
+    }
+}
+
+// A configured lookup joins a built-in one for the same events and field,
+// its values replacing those of the same code
+fn add(lookups: &mut Vec<Lookup>, config: &LookupConfig) {
+    let name = config
+        .name
+        .clone()
+        .unwrap_or_else(|| format!("{}Text", config.field));
+    let values = config
+        .values
+        .iter()
+        .map(|(code, text)| (normalize(code), text.clone()));
+    if let Some(existing) = lookups.iter_mut().find(|l| {
+        l.field == config.field
+            && l.name == name
+            && l.event_ids == config.event_ids
+            && (config.providers.is_empty()
+                || l.providers.len() == config.providers.len()
+                    && l.providers
+                        .iter()
+                        .zip(&config.providers)
+                        .all(|(a, b)| a.eq_ignore_ascii_case(b)))
+    }) {
+        existing.values.extend(values);
+        return;
+    }
+    lookups.push(Lookup {
+        event_ids: config.event_ids.clone(),
+        providers: config.providers.clone(),
+        field: config.field.clone(),
+        name,
+        values: values.collect(),
+    });
+}
+
+// Numbers in hex (0x...) or decimal become decimal, anything else lowercase
+fn normalize(code: &str) -> String {
+    let code = code.trim();
+    let number = match code.strip_prefix("0x").or_else(|| code.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => code.parse::<u64>().ok(),
+    };
+    number.map_or_else(|| code.to_lowercase(), |n| n.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn knowledge(parsers: JsonValue) -> KnowledgeBase {
+        KnowledgeBase::new(&serde_json::from_value(parsers).unwrap()).unwrap()
+    }
+
+    fn security(id: u32, data: JsonValue) -> JsonValue {
+        json!({
+            "Provider": { "@Name": SECURITY_AUDITING },
+            "EventID": id,
+            "EventData": data,
+        })
+    }
+
+    #[test]
+    fn decodes_codes_in_hex_or_decimal() {
+        let knowledge = knowledge(json!({ "knowledge_base": true }));
+        let mut event = security(
+            4625,
+            json!({
+                "LogonType": "3",
+                "Status": "0xc000006a",
+                "SubStatus": "3221225572",
+                "IpAddress": "10.0.0.1",
+            }),
+        );
+        knowledge.enrich(&mut event);
+        assert_eq!(
+            event["EventData"],
+            json!({
+                "LogonType": "3",
+                "LogonTypeName": "Network",
+                "Status": "0xc000006a",
+                "StatusText": "User name is correct but the password is wrong",
+                "SubStatus": "3221225572",
+                "SubStatusText": "User name does not exist",
+                "IpAddress": "10.0.0.1",
+            })
+        );
+        // Typed by typed_json
+        let mut event = security(4624, json!({ "LogonType": 10 }));
+        knowledge.enrich(&mut event);
+        assert_eq!(event["EventData"]["LogonTypeName"], "RemoteInteractive");
+    }
+
+    #[test]
+    fn user_lookups_override_builtins() {
+        let knowledge = knowledge(json!({
+            "knowledge_base": true,
+            "lookups": [{
+                "event_ids": [4624, 4625, 4634],
+                "field": "LogonType",
+                "name": "LogonTypeName",
+                "values": { "0x3": "Network logon" },
+            }],
+        }));
+        let mut event = security(4624, json!({ "LogonType": "3" }));
+        knowledge.enrich(&mut event);
+        assert_eq!(event["EventData"]["LogonTypeName"], "Network logon");
+        // The built-in codes it doesn't list still decode
+        let mut event = security(4624, json!({ "LogonType": "2" }));
+        knowledge.enrich(&mut event);
+        assert_eq!(event["EventData"]["LogonTypeName"], "Interactive");
+    }
+
+    #[test]
+    fn lookups_of_their_own() {
+        let knowledge = knowledge(json!({
+            "lookups": [{
+                "providers": ["MyApp"],
+                "field": "Result",
+                "values": { "E_DENIED": "Access denied", "0x10": "Retry" },
+            }],
+        }));
+        let mut event = json!({
+            "Provider": { "@Name": "myapp" },
+            "EventID": 1,
+            "EventData": { "Result": "e_denied" },
+        });
+        knowledge.enrich(&mut event);
+        assert_eq!(event["EventData"]["ResultText"], "Access denied");
+        let mut event = json!({
+            "Provider": { "@Name": "MyApp" },
+            "EventData": { "Result": "16" },
+        });
+        knowledge.enrich(&mut event);
+        assert_eq!(event["EventData"]["ResultText"], "Retry");
+        // Built-ins are off
+        let mut event = security(4624, json!({ "LogonType": "3" }));
+        knowledge.enrich(&mut event);
+        assert!(event["EventData"].get("LogonTypeName").is_none());
+    }
+
+    #[test]
+    fn never_overwrites_fields() {
+        let knowledge = knowledge(json!({ "knowledge_base": true }));
+        let mut event = security(
+            4624,
+            json!({ "LogonType": "3", "LogonTypeName": "from the event" }),
+        );
+        knowledge.enrich(&mut event);
+        assert_eq!(event["EventData"]["LogonTypeName"], "from the event");
+    }
+
+    #[test]
+    fn nothing_to_look_up() {
+        assert!(KnowledgeBase::new(&Default::default()).is_none());
+    }
+}
@@ -0,0 +1,13 @@
+//! Typed access to the events rs-wineventlog writes, for Rust programs that
+//! consume its output (files, TCP, the REST API or the WebSocket stream).
+//!
+//! ```no_run
+//! use wineventlog::record::EventRecord;
+//!
+//! let line = std::fs::read_to_string("events.json").unwrap();
+//! for event in line.lines().filter_map(|l| l.parse::<EventRecord>().ok()) {
+//!     println!("{} {}", event.system.event_id, event.system.time_created);
+//! }
+//! ```
+
+pub mod record;
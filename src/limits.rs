@@ -0,0 +1,100 @@
+use crate::config::{CpuPriority, LimitsConfig};
+use log::info;
+use std::ffi::c_void;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use windows::Win32::Foundation::HANDLE;
+use windows::Win32::System::JobObjects::*;
+use windows::Win32::System::Threading::{
+    BELOW_NORMAL_PRIORITY_CLASS, GetCurrentProcess, IDLE_PRIORITY_CLASS, NORMAL_PRIORITY_CLASS,
+    PROCESS_MODE_BACKGROUND_BEGIN, PROCESS_MODE_BACKGROUND_END, SetPriorityClass,
+};
+
+// A process can't leave its job, so reloads change the limits of this one
+static JOB: Mutex<Option<isize>> = Mutex::new(None);
+static BACKGROUND: AtomicBool = AtomicBool::new(false);
+
+/// Applies the `limits` section to this process: memory and CPU caps via a
+/// Job Object, created on first use, and its CPU and background priority.
+/// Called again on reload; removed limits are lifted.
+pub fn apply(limits: &LimitsConfig) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(percent) = limits.cpu_percent
+        && !(1..=100).contains(&percent)
+    {
+        return Err("limits.cpu_percent must be between 1 and 100".into());
+    }
+    let mut job = JOB.lock().unwrap();
+    let capped = limits.memory.is_some() || limits.cpu_percent.is_some();
+    unsafe {
+        if job.is_none() && capped {
+            let created = CreateJobObjectW(None, windows::core::PCWSTR::null())?;
+            AssignProcessToJobObject(created, GetCurrentProcess())
+                .map_err(|e| format!("cannot put the process in a Job Object: {}", e))?;
+            *job = Some(created.0 as isize);
+        }
+        if let Some(handle) = *job {
+            let handle = HANDLE(handle as *mut c_void);
+            let mut memory = JOBOBJECT_EXTENDED_LIMIT_INFORMATION::default();
+            if let Some(bytes) = limits.memory {
+                memory.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_PROCESS_MEMORY;
+                memory.ProcessMemoryLimit = bytes as usize;
+            }
+            SetInformationJobObject(
+                handle,
+                JobObjectExtendedLimitInformation,
+                &memory as *const _ as *const c_void,
+                size_of_val(&memory) as u32,
+            )?;
+
+            let mut cpu = JOBOBJECT_CPU_RATE_CONTROL_INFORMATION::default();
+            if let Some(percent) = limits.cpu_percent {
+                cpu.ControlFlags =
+                    JOB_OBJECT_CPU_RATE_CONTROL_ENABLE | JOB_OBJECT_CPU_RATE_CONTROL_HARD_CAP;
+                // In hundredths of a percent
+                cpu.Anonymous.CpuRate = percent * 100;
+            }
+            SetInformationJobObject(
+                handle,
+                JobObjectCpuRateControlInformation,
+                &cpu as *const _ as *const c_void,
+                size_of_val(&cpu) as u32,
+            )?;
+        }
+
+        SetPriorityClass(
+            GetCurrentProcess(),
+            match limits.cpu_priority {
+                CpuPriority::Normal => NORMAL_PRIORITY_CLASS,
+                CpuPriority::Low => BELOW_NORMAL_PRIORITY_CLASS,
+                CpuPriority::Idle => IDLE_PRIORITY_CLASS,
+            },
+        )?;
+        // Entering background mode twice, or leaving it when not in it, fails
+        if limits.background != BACKGROUND.load(Ordering::SeqCst) {
+            SetPriorityClass(
+                GetCurrentProcess(),
+                if limits.background {
+                    PROCESS_MODE_BACKGROUND_BEGIN
+                } else {
+                    PROCESS_MODE_BACKGROUND_END
+                },
+            )?;
+            BACKGROUND.store(limits.background, Ordering::SeqCst);
+        }
+    }
+
+    if capped || limits.cpu_priority != CpuPriority::Normal || limits.background {
+        info!(
+            "Limits: memory={} cpu_percent={} cpu_priority={} background={}",
+            limits
+                .memory
+                .map_or("-".to_string(), |b| format!("{}MB", b >> 20)),
+            limits
+                .cpu_percent
+                .map_or("-".to_string(), |p| p.to_string()),
+            format!("{:?}", limits.cpu_priority).to_lowercase(),
+            limits.background
+        );
+    }
+    Ok(())
+}
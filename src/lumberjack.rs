@@ -0,0 +1,244 @@
+use crate::config::{Config, Framing, SinkConfig};
+use crate::output::{Record, Sink};
+use native_tls::{Certificate, TlsConnector, TlsStream};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+// Frame types of the Lumberjack v2 protocol spoken by Logstash's beats input
+const VERSION: u8 = b'2';
+const WINDOW: u8 = b'W';
+const JSON: u8 = b'J';
+const ACK: u8 = b'A';
+
+// How long to wait for the receiver to acknowledge a window
+const ACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Used when only batch_max_events is set
+const DEFAULT_WINDOW_INTERVAL: Duration = Duration::from_millis(1000);
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.read(buf),
+            Stream::Tls(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.write(buf),
+            Stream::Tls(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(s) => s.flush(),
+            Stream::Tls(s) => s.flush(),
+        }
+    }
+}
+
+/// Sends events to a Logstash beats input. Events are sent in windows of up
+/// to `batch_max_events`, and a window only counts as written once the
+/// receiver acknowledged all of it, so checkpoints saved after a flush never
+/// cover unacknowledged events.
+pub struct Lumberjack {
+    addr: String,
+    tls: Option<TlsConnector>,
+    stream: Option<Stream>,
+    window: Vec<String>,
+    oldest: Instant,
+    max_events: usize,
+    max_interval: Duration,
+}
+
+impl Lumberjack {
+    /// Connects to `addr` (host:port), over TLS when `tls` is set. The
+    /// receiver's certificate must chain to a trusted root or `sink.tls_ca`.
+    pub fn connect(addr: &str, tls: bool, sink: &SinkConfig) -> Result<Lumberjack, String> {
+        let tls = if tls {
+            let mut builder = TlsConnector::builder();
+            if let Some(path) = &sink.tls_ca {
+                let pem = std::fs::read(path)
+                    .map_err(|e| format!("cannot read tls_ca {}: {}", path, e))?;
+                let ca = Certificate::from_pem(&pem)
+                    .map_err(|e| format!("invalid tls_ca {}: {}", path, e))?;
+                builder.add_root_certificate(ca);
+            }
+            Some(builder.build().map_err(|e| e.to_string())?)
+        } else {
+            None
+        };
+
+        let mut output = Lumberjack {
+            addr: addr.to_string(),
+            tls,
+            stream: None,
+            window: Vec::new(),
+            oldest: Instant::now(),
+            max_events: sink.batch_max_events.unwrap_or(1).max(1),
+            max_interval: sink
+                .batch_max_interval_ms
+                .map_or(DEFAULT_WINDOW_INTERVAL, Duration::from_millis),
+        };
+        // Fail at startup rather than on the first event
+        output.stream = Some(output.open().map_err(|e| e.to_string())?);
+        Ok(output)
+    }
+
+    pub fn write_event(&mut self, line: &str) -> io::Result<()> {
+        if self.window.is_empty() {
+            self.oldest = Instant::now();
+        }
+        self.window.push(line.to_string());
+        if self.window.len() >= self.max_events {
+            self.send()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Sends the pending window and waits for its acknowledgement. On failure
+    /// the window is resent once over a new connection; if that fails too
+    /// the events stay pending.
+    pub fn send(&mut self) -> io::Result<()> {
+        if self.window.is_empty() {
+            return Ok(());
+        }
+        let frames = self.frames()?;
+        if self.exchange(&frames).is_err() {
+            self.stream = Some(self.open()?);
+            if let Err(e) = self.exchange(&frames) {
+                self.stream = None;
+                return Err(e);
+            }
+        }
+        self.window.clear();
+        Ok(())
+    }
+
+    fn open(&self) -> io::Result<Stream> {
+        let tcp = TcpStream::connect(&self.addr)?;
+        tcp.set_read_timeout(Some(ACK_TIMEOUT))?;
+        let Some(connector) = &self.tls else {
+            return Ok(Stream::Plain(tcp));
+        };
+        let host = self
+            .addr
+            .rsplit_once(':')
+            .map_or(self.addr.as_str(), |(host, _)| host);
+        connector
+            .connect(host, tcp)
+            .map(|s| Stream::Tls(Box::new(s)))
+            .map_err(|e| {
+                io::Error::other(format!("TLS handshake with {} failed: {}", self.addr, e))
+            })
+    }
+
+    // A window frame announcing the event count, then one JSON frame per
+    // event numbered from 1
+    fn frames(&self) -> io::Result<Vec<u8>> {
+        let too_large = || io::Error::new(io::ErrorKind::InvalidData, "event too large");
+        let mut frames = vec![VERSION, WINDOW];
+        frames.extend_from_slice(&(self.window.len() as u32).to_be_bytes());
+        for (i, event) in self.window.iter().enumerate() {
+            let len = u32::try_from(event.len()).map_err(|_| too_large())?;
+            frames.extend_from_slice(&[VERSION, JSON]);
+            frames.extend_from_slice(&(i as u32 + 1).to_be_bytes());
+            frames.extend_from_slice(&len.to_be_bytes());
+            frames.extend_from_slice(event.as_bytes());
+        }
+        Ok(frames)
+    }
+
+    // Writes the frames and reads ACKs until the last sequence number is
+    // acknowledged; smaller ones are progress reports (or keepalives)
+    fn exchange(&mut self, frames: &[u8]) -> io::Result<()> {
+        let last = self.window.len() as u32;
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => return Err(io::Error::from(io::ErrorKind::NotConnected)),
+        };
+        stream.write_all(frames)?;
+        stream.flush()?;
+
+        loop {
+            let mut ack = [0u8; 6];
+            stream.read_exact(&mut ack)?;
+            if ack[0] != VERSION || ack[1] != ACK {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unexpected frame from beats receiver",
+                ));
+            }
+            if u32::from_be_bytes([ack[2], ack[3], ack[4], ack[5]]) >= last {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Sink for Lumberjack {
+    fn send_batch(&mut self, batch: &[Record]) -> io::Result<()> {
+        batch.iter().try_for_each(|r| self.write_event(r.line))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+
+    // Sends a partial window whose oldest event has waited long enough
+    fn send_if_due(&mut self) -> Option<io::Result<()>> {
+        if !self.window.is_empty() && self.oldest.elapsed() >= self.max_interval {
+            Some(self.send())
+        } else {
+            Some(Ok(()))
+        }
+    }
+
+    // The connection is dropped after a failed exchange and only opened
+    // again with the next window
+    fn healthcheck(&mut self) -> io::Result<()> {
+        if self.stream.is_none() {
+            self.stream = Some(self.open()?);
+        }
+        Ok(())
+    }
+}
+
+/// Opens a `lumberjack://<host>:<port>` or `lumberjack+tls://<host>:<port>`
+/// output.
+pub fn create(
+    target: &str,
+    _framing: Framing,
+    config: &Config,
+) -> Result<Box<dyn Sink>, Box<dyn std::error::Error>> {
+    let (addr, tls) = match target.strip_prefix("lumberjack+tls://") {
+        Some(addr) => (addr, true),
+        None => (
+            target.strip_prefix("lumberjack://").unwrap_or(target),
+            false,
+        ),
+    };
+    let lj = Lumberjack::connect(addr, tls, &config.sink)
+        .map_err(|e| format!("cannot connect to output {}: {}", target, e))?;
+    Ok(Box::new(lj))
+}
+
+/// Connects to a lumberjack output (with the TLS handshake for
+/// `lumberjack+tls://`) without sending anything.
+pub fn probe(target: &str, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let mut sink = create(target, Framing::Ndjson, config)?;
+    sink.healthcheck()
+        .map_err(|e| format!("cannot connect to output {}: {}", target, e))?;
+    Ok(())
+}
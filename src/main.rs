@@ -1,14 +1,91 @@
 #![cfg(windows)]
 
+mod acl;
+mod alert;
+#[cfg(feature = "arrow")]
+mod arrow;
+mod audit;
+mod chain;
+mod checkpoint;
+mod cmdline;
 mod config;
+mod console;
+mod control;
+mod crash;
+mod dns;
+mod doctor;
+#[cfg(feature = "email")]
+mod email;
+mod etw;
 mod eventlog;
+mod evtapi;
+mod fatal;
+mod fields;
+mod filter;
+#[cfg(feature = "grpc")]
+mod grpc;
+#[cfg(feature = "api")]
+mod http;
+#[cfg(any(
+    feature = "remote-config",
+    feature = "sentinel",
+    feature = "update",
+    feature = "webhook"
+))]
+mod http_client;
+mod hub;
+mod identity;
+mod init;
+mod journal;
+mod knowledge;
+mod limits;
+#[cfg(feature = "lumberjack")]
+mod lumberjack;
+mod merge;
+mod message;
+mod metadata;
 mod output;
+mod overload;
+mod perf;
 mod privilege;
+mod publisher;
+mod query;
+mod registry;
+mod report;
+mod route;
+mod scriptblock;
+mod secrets;
+mod securityalert;
+#[cfg(feature = "selftest")]
+mod selftest;
+#[cfg(feature = "sentinel")]
+mod sentinel;
+mod service;
+mod sftp;
+mod state;
+mod stats;
+mod sysmon;
+mod task;
+#[cfg(feature = "tcp")]
+mod tcp;
+mod timeline;
+mod timestamp;
+mod tui;
+#[cfg(feature = "update")]
+mod update;
+#[cfg(feature = "webhook")]
+mod webhook;
+#[cfg(feature = "websocket")]
+mod websocket;
+mod wec;
 mod xml;
 
-use clap::{CommandFactory, Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
 use clap_complete::{Shell, generate};
 use std::io;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, mpsc};
 
 pub mod built_info {
     include!(concat!(env!("OUT_DIR"), "/built.rs"));
@@ -21,14 +98,121 @@ pub struct Cli {
     #[command(subcommand)]
     pub command: Option<Commands>,
 
-    #[arg(short, long)]
+    #[arg(
+        short,
+        long,
+        help = "Config file path, - to read YAML from stdin, or an http(s):// URL"
+    )]
     pub config: Option<String>,
 
+    #[arg(
+        long,
+        env = "WINEVENTLOG_CONFIG_AUTH",
+        hide_env_values = true,
+        help = "Authorization header value sent when fetching the config from a URL"
+    )]
+    pub config_auth: Option<String>,
+
+    #[arg(
+        long,
+        help = "Apply this entry of the config's profiles section on top of the rest"
+    )]
+    pub profile: Option<String>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "Channels to monitor instead of the configured ones (repeatable or comma-separated)"
+    )]
+    pub channels: Vec<String>,
+
+    #[arg(
+        short,
+        long,
+        help = "Where to write events instead of the configured output: a path, file://<path>, tcp://<host>:<port>, or - for stdout"
+    )]
+    pub output: Option<String>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "Only read events with this EventID (repeatable or comma-separated)"
+    )]
+    pub event_id: Vec<u32>,
+
+    #[arg(
+        long,
+        value_parser = query::parse_levels,
+        help = "Only read events of this level, e.g. error, error,warning or warning+ (warning and more severe)"
+    )]
+    pub level: Option<query::Levels>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "Only read events of this provider (repeatable or comma-separated)"
+    )]
+    pub provider: Vec<String>,
+
+    #[arg(
+        long,
+        value_parser = query::parse_data,
+        help = "Only read events with this EventData value, e.g. TargetUserName=admin (repeatable)"
+    )]
+    pub data: Vec<(String, String)>,
+
     #[arg(short, long)]
     pub pretty_json: bool,
 
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = LogFormat::Auto,
+        help = "Format of the collector's own log lines on stderr"
+    )]
+    pub log_format: LogFormat,
+
+    #[arg(long, help = "Show a live throughput status line on stderr (TTY only)")]
+    pub status: bool,
+
+    #[arg(
+        long,
+        help = "Read all events since the last checkpoint (or the oldest), save checkpoints and exit"
+    )]
+    pub once: bool,
+
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Stop cleanly after writing this many events"
+    )]
+    pub max_events: Option<u64>,
+
+    #[arg(
+        long,
+        value_parser = config::parse_duration,
+        help = "Stop cleanly after this long, e.g. 90s, 15m or 1h"
+    )]
+    pub duration: Option<std::time::Duration>,
+
     #[arg(short, long)]
     version: bool,
+
+    #[arg(
+        long,
+        requires = "version",
+        help = "With --version, print build information as JSON"
+    )]
+    json: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum LogFormat {
+    /// Text on a terminal, JSON otherwise
+    Auto,
+    Text,
+    /// One JSON object per line
+    Json,
 }
 
 #[derive(Subcommand)]
@@ -36,38 +220,398 @@ pub enum Commands {
     #[command(about = "List available Windows Event Log channels")]
     ListChannels,
 
+    #[command(about = "Show who may access a channel and whether this process can read it")]
+    ChannelAcl {
+        #[arg(help = "Channel name, e.g. Security")]
+        channel: String,
+    },
+
+    #[command(
+        about = "Report which wanted Security EventIDs the audit policy keeps from being logged"
+    )]
+    AuditCoverage {
+        #[arg(
+            long,
+            value_delimiter = ',',
+            help = "EventID to check (repeatable or comma-separated); added to those of alert rules on Security"
+        )]
+        event_id: Vec<u32>,
+
+        #[arg(long, help = "Print JSON instead of a table")]
+        json: bool,
+    },
+
     #[command(about = "Generate shell completions")]
     Completions {
-        #[arg(help = "Shell to generate completions for")]
+        #[arg(help = "Shell to generate completions for, e.g. powershell, bash or zsh")]
         shell: Shell,
+
+        #[arg(
+            long,
+            help = "Write the completion script to this directory instead of stdout"
+        )]
+        dir: Option<std::path::PathBuf>,
+    },
+
+    #[command(about = "Generate man pages")]
+    Manpage {
+        #[arg(
+            long,
+            help = "Write a page per command and subcommand to this directory instead of the main page to stdout"
+        )]
+        dir: Option<std::path::PathBuf>,
+    },
+
+    #[command(about = "Write a starter config with the channels this machine has")]
+    Init {
+        #[arg(long, help = "Replace the config file if it exists")]
+        force: bool,
+    },
+
+    #[command(about = "Check the configuration and show policy-managed settings")]
+    ValidateConfig {
+        #[arg(long, help = "Also open the output and check that it takes events")]
+        check_output: bool,
+    },
+
+    #[command(
+        about = "Check privileges, channel access, outputs, the state file, disk space and the clock"
+    )]
+    Doctor {
+        #[arg(long, default_value = doctor::DEFAULT_NTP_SERVER, help = "Time server to measure clock skew against")]
+        ntp_server: String,
+
+        #[arg(long, help = "Print JSON instead of a report")]
+        json: bool,
+    },
+
+    #[command(about = "Count past events by provider and EventID, e.g. to decide what to filter")]
+    Report {
+        #[arg(
+            long,
+            required = true,
+            value_delimiter = ',',
+            help = "Channel to report on (repeatable or comma-separated)"
+        )]
+        channel: Vec<String>,
+
+        #[arg(
+            long,
+            default_value = "7d",
+            value_parser = config::parse_duration,
+            help = "How far back to look, e.g. 24h or 30d"
+        )]
+        since: std::time::Duration,
+
+        #[arg(long, help = "Print JSON instead of a table")]
+        json: bool,
     },
+
+    #[command(
+        about = "Write the events of channels and .evtx files in a time range as one sorted timeline"
+    )]
+    Timeline {
+        #[arg(
+            long,
+            value_delimiter = ',',
+            required_unless_present = "file",
+            help = "Channel to include (repeatable or comma-separated)"
+        )]
+        channel: Vec<String>,
+
+        #[arg(long, help = "Exported .evtx file to include (repeatable)")]
+        file: Vec<String>,
+
+        #[arg(
+            long,
+            conflicts_with = "from",
+            value_parser = config::parse_duration,
+            help = "Include the events of this long before --to, e.g. 24h (default) or 7d"
+        )]
+        since: Option<std::time::Duration>,
+
+        #[arg(long, value_parser = timeline::parse_time, help = "Start of the range, e.g. 2024-05-01T08:00:00Z")]
+        from: Option<chrono::DateTime<chrono::Utc>>,
+
+        #[arg(long, value_parser = timeline::parse_time, help = "End of the range (default: now)")]
+        to: Option<chrono::DateTime<chrono::Utc>>,
+
+        #[arg(long, value_enum, default_value_t = timeline::Format::Csv)]
+        format: timeline::Format,
+
+        #[arg(
+            long,
+            help = "Directory of provider metadata (see export-metadata) for providers not installed here"
+        )]
+        metadata_cache: Option<std::path::PathBuf>,
+
+        #[arg(
+            long,
+            default_value = "-",
+            help = "File to write the timeline to, - for stdout"
+        )]
+        out: String,
+    },
+
+    #[command(
+        about = "Save provider metadata so events can be rendered where the providers aren't installed"
+    )]
+    ExportMetadata {
+        #[arg(
+            long,
+            required = true,
+            help = "Directory to write one JSON file per provider to"
+        )]
+        dir: std::path::PathBuf,
+
+        #[arg(
+            long,
+            value_delimiter = ',',
+            help = "Provider to export (repeatable or comma-separated; default: all installed)"
+        )]
+        provider: Vec<String>,
+    },
+
+    #[command(
+        about = "Run events written by an earlier run through the current parsers, filter and output again"
+    )]
+    Reprocess {
+        #[arg(help = "NDJSON file written by this tool, - for stdin")]
+        file: String,
+    },
+
+    #[command(about = "Check the hash chain of files written with hash_chain enabled")]
+    Verify {
+        #[arg(required = true, help = "Files to check as one chain, oldest first")]
+        files: Vec<std::path::PathBuf>,
+
+        #[arg(
+            long,
+            help = "Last hash of the file before the first one, when it was rotated out"
+        )]
+        prev: Option<String>,
+    },
+
+    #[command(about = "Show the bookmarks and counters kept between runs")]
+    State {
+        #[arg(help = "State file to read, by default the configured state_file")]
+        file: Option<std::path::PathBuf>,
+    },
+
+    #[command(about = "Browse live events in an interactive terminal viewer")]
+    Tui,
+
+    #[command(about = "Install as a Windows service with automatic restart on failure")]
+    InstallService,
+
+    #[command(about = "Stop and remove the Windows service")]
+    UninstallService,
+
+    #[command(hide = true)]
+    RunService,
+
+    #[command(
+        about = "Register a Task Scheduler task that runs the collector at boot or on a schedule"
+    )]
+    InstallTask {
+        #[arg(
+            long,
+            value_parser = config::parse_duration,
+            help = "Run --once at this interval, e.g. 15m, instead of monitoring from boot"
+        )]
+        every: Option<std::time::Duration>,
+
+        #[arg(
+            long,
+            default_value = "SYSTEM",
+            help = "Account the task runs as (its password is prompted for, or read from stdin)"
+        )]
+        user: String,
+
+        #[arg(long, default_value = task::DEFAULT_NAME, help = "Task name")]
+        name: String,
+    },
+
+    #[command(about = "Stop and remove the Task Scheduler task")]
+    UninstallTask {
+        #[arg(long, default_value = task::DEFAULT_NAME, help = "Task name")]
+        name: String,
+    },
+
+    #[command(about = "Send a command to a running collector's control pipe")]
+    Ctl {
+        #[arg(long, default_value = control::DEFAULT_PIPE, help = "Control pipe name")]
+        pipe: String,
+
+        #[arg(
+            required = true,
+            help = "status | reload | pause <channel> | resume [channel] | flush | rotate"
+        )]
+        command: Vec<String>,
+    },
+
+    #[cfg(feature = "update")]
+    #[command(about = "Update to the latest release and restart the service if it is running")]
+    SelfUpdate {
+        #[arg(long, default_value = update::LATEST_RELEASE_URL, help = "GitHub-style release metadata URL")]
+        url: String,
+
+        #[arg(long, help = "Only report whether an update is available")]
+        check: bool,
+
+        #[arg(long, help = "Install the release even if it isn't newer")]
+        force: bool,
+
+        #[arg(
+            long,
+            help = "Install on checksums alone when the release is unsigned or cosign is missing"
+        )]
+        allow_unsigned: bool,
+    },
+
+    #[command(about = "Encrypt a secret with DPAPI for use as a dpapi: config value")]
+    Protect {
+        #[arg(help = "Secret to encrypt (read from stdin when omitted)")]
+        secret: Option<String>,
+
+        #[arg(
+            long,
+            help = "Only the current user can decrypt it (default: any account on this machine)"
+        )]
+        user: bool,
+    },
+
+    #[command(about = "Manage Windows Event Forwarding subscriptions on this collector")]
+    Wec {
+        #[command(subcommand)]
+        command: WecCommand,
+    },
+
+    #[cfg(feature = "selftest")]
+    #[command(
+        about = "Write test events to the Application log and check what the pipeline makes of them"
+    )]
+    SelfTest,
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+#[derive(Subcommand)]
+pub enum WecCommand {
+    #[command(about = "Create a source-initiated subscription, from flags or a wecutil XML file")]
+    Create {
+        #[arg(required_unless_present = "file", help = "Subscription name")]
+        name: Option<String>,
+
+        #[arg(
+            long,
+            conflicts_with_all = ["name", "channel", "query"],
+            help = "Subscription XML as wecutil cs takes it"
+        )]
+        file: Option<std::path::PathBuf>,
+
+        #[arg(
+            long,
+            value_delimiter = ',',
+            required_unless_present = "file",
+            help = "Channels forwarded to the subscription (comma-separated)"
+        )]
+        channel: Vec<String>,
+
+        #[arg(
+            long,
+            help = "XPath query selecting the events of each channel (default: all)"
+        )]
+        query: Option<String>,
+
+        #[arg(long, help = "Subscription description")]
+        description: Option<String>,
+
+        #[arg(
+            long,
+            help = "Log the forwarded events are written to (default: ForwardedEvents)"
+        )]
+        log_file: Option<String>,
+
+        #[arg(long, help = "Forward events already in the logs, not only new ones")]
+        read_existing: bool,
+
+        #[arg(
+            long,
+            help = "SDDL of the computers allowed to forward (default: Domain Computers)"
+        )]
+        allowed_sources: Option<String>,
+
+        #[arg(
+            long,
+            help = "Update a subscription of the same name instead of failing"
+        )]
+        replace: bool,
+    },
+
+    #[command(about = "List the subscriptions with their status and number of sources")]
+    List {
+        #[arg(long, help = "Print JSON instead of a table")]
+        json: bool,
+    },
+
+    #[command(about = "Delete a subscription")]
+    Delete {
+        #[arg(help = "Subscription name")]
+        name: String,
+    },
+}
+
+fn main() -> ExitCode {
+    crash::install();
+    match std::panic::catch_unwind(try_main) {
+        Ok(Ok(())) => ExitCode::SUCCESS,
+        Ok(Err(e)) => ExitCode::from(fatal::report(e.as_ref())),
+        // The panic hook has flushed the output and written the crash report
+        Err(_) => ExitCode::from(fatal::report(&fatal::Fatal::new(
+            fatal::Kind::Crash,
+            "the collector crashed, see crashes.ndjson next to the state file",
+        ))),
+    }
+}
+
+fn try_main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
 
     // Initialize logger
-    if atty::is(atty::Stream::Stderr) {
+    let json_logs = match cli.log_format {
+        LogFormat::Auto => !atty::is(atty::Stream::Stderr),
+        LogFormat::Text => false,
+        LogFormat::Json => true,
+    };
+    let mut logger =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+    // Log lines go around the status line rather than through it
+    if cli.status && atty::is(atty::Stream::Stderr) {
+        logger.target(env_logger::Target::Pipe(Box::new(stats::LogWriter)));
+    }
+    if !json_logs {
         // Human-readable format for interactive use (TTY)
-        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
-            .format_timestamp_millis()
-            .init();
+        logger.format_timestamp_millis().init();
     } else {
-        // JSON format (Not TTY)
-        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+        // JSON format, one object per line
+        logger
             .format(|buf, record| {
                 use std::io::Write;
-                writeln!(
-                    buf,
-                    r#"{{"timestamp":"{}","level":"{}","message":"{}"}}"#,
-                    chrono::Utc::now().to_rfc3339(),
-                    record.level(),
-                    record.args()
-                )
+                let line = serde_json::json!({
+                    "timestamp": chrono::Utc::now().to_rfc3339(),
+                    "level": record.level().as_str(),
+                    "target": record.target(),
+                    "message": record.args().to_string(),
+                });
+                writeln!(buf, "{}", line)
             })
             .init();
     }
 
+    if cli.version && cli.json {
+        println!("{}", identity::build_info());
+        return Ok(());
+    }
+
     if cli.version {
         let git_commit = built_info::GIT_COMMIT_HASH_SHORT;
         let release_ver = option_env!("BUILD_VERSION").unwrap_or(built_info::PKG_VERSION);
@@ -80,18 +624,382 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         return Ok(());
     }
 
+    #[cfg(feature = "update")]
+    update::cleanup();
+    etw::register();
+
+    let source = config::Source {
+        path: cli.config.clone(),
+        auth: cli.config_auth.clone(),
+        profile: cli.profile.clone(),
+        channels: cli.channels.clone(),
+        output: cli.output.clone(),
+        query: query::xpath(&cli.event_id, cli.level.as_ref(), &cli.provider, &cli.data)?,
+    };
+
     match cli.command {
-        Some(Commands::Completions { shell }) => {
+        Some(Commands::Completions { shell, dir }) => {
             let mut cmd = Cli::command();
-            generate(shell, &mut cmd, "rs-wineventlog", &mut io::stdout());
+            match dir {
+                Some(dir) => {
+                    std::fs::create_dir_all(&dir)?;
+                    let path = clap_complete::generate_to(shell, &mut cmd, "rs-wineventlog", dir)?;
+                    eprintln!("Wrote {}", path.display());
+                }
+                None => generate(shell, &mut cmd, "rs-wineventlog", &mut io::stdout()),
+            }
         }
+        Some(Commands::Manpage { dir }) => match dir {
+            Some(dir) => {
+                std::fs::create_dir_all(&dir)?;
+                clap_mangen::generate_to(Cli::command(), &dir)?;
+                eprintln!("Wrote man pages to {}", dir.display());
+            }
+            None => clap_mangen::Man::new(Cli::command()).render(&mut io::stdout())?,
+        },
         Some(Commands::ListChannels) => eventlog::list_channels()?,
+        Some(Commands::ChannelAcl { channel }) => acl::show(&channel)?,
+        Some(Commands::AuditCoverage { event_id, json }) => {
+            audit::coverage(&config::load(&source)?, &event_id, json)?
+        }
+        Some(Commands::Init { force }) => {
+            let path = match cli.config {
+                Some(p) if p == "-" || p.contains("://") => {
+                    return Err("init writes a file, --config must be a path".into());
+                }
+                Some(p) => std::path::PathBuf::from(p),
+                None => config::default_path()?,
+            };
+            init::run(&path, force)?
+        }
+        Some(Commands::ValidateConfig { check_output }) => {
+            let config = config::load(&source)?;
+            println!(
+                "Configuration is valid ({} channel entries)",
+                config.channels.len()
+            );
+            if check_output {
+                output::create(&config)?
+                    .healthcheck()
+                    .map_err(|e| format!("output is not healthy: {}", e))?;
+                println!(
+                    "Output {} is healthy",
+                    config.output_file.as_deref().unwrap_or("-")
+                );
+            }
+            let managed = config::policy_keys();
+            if !managed.is_empty() {
+                println!(
+                    "Managed by Group Policy (read-only): {}",
+                    managed.join(", ")
+                );
+            }
+        }
+        Some(Commands::Doctor { ntp_server, json }) => doctor::run(&source, &ntp_server, json)?,
+        Some(Commands::Report {
+            channel,
+            since,
+            json,
+        }) => report::run(&channel, since, json)?,
+        Some(Commands::Timeline {
+            channel,
+            file,
+            since,
+            from,
+            to,
+            format,
+            metadata_cache,
+            out,
+        }) => {
+            let to = to.unwrap_or_else(chrono::Utc::now);
+            let from = match from {
+                Some(from) => from,
+                None => to - since.unwrap_or(std::time::Duration::from_secs(24 * 3600)),
+            };
+            let sources: Vec<timeline::Source> = channel
+                .into_iter()
+                .map(timeline::Source::Channel)
+                .chain(file.into_iter().map(timeline::Source::File))
+                .collect();
+            let metadata = metadata_cache.map(metadata::Cache::new);
+            timeline::run(
+                &sources,
+                from,
+                to,
+                format,
+                metadata.as_ref(),
+                &mut timeline::output(&out)?,
+            )?
+        }
+        Some(Commands::ExportMetadata { dir, provider }) => metadata::export(&dir, &provider)?,
+        Some(Commands::Verify { files, prev }) => chain::verify(&files, prev.as_deref())?,
+        Some(Commands::Reprocess { file }) => reprocess(&source, &file, cli.pretty_json)?,
+        Some(Commands::State { file }) => {
+            let path = match file {
+                Some(path) => path,
+                None => config::load(&source)?.state_path()?,
+            };
+            state::show(&path)?
+        }
+        Some(Commands::Tui) => {
+            // Log lines on stderr would corrupt the screen
+            log::set_max_level(log::LevelFilter::Off);
+            tui::run(&source)?
+        }
+        Some(Commands::InstallService) => service::install(cli.config, cli.profile)?,
+        Some(Commands::UninstallService) => service::uninstall()?,
+        Some(Commands::RunService) => service::run(source)?,
+        Some(Commands::InstallTask { every, user, name }) => {
+            task::install(&name, every, &user, cli.config, cli.profile)?
+        }
+        Some(Commands::UninstallTask { name }) => task::uninstall(&name)?,
+        Some(Commands::Ctl { pipe, command }) => control::send(&pipe, &command)?,
+        #[cfg(feature = "update")]
+        Some(Commands::SelfUpdate {
+            url,
+            check,
+            force,
+            allow_unsigned,
+        }) => update::self_update(&url, check, force, allow_unsigned)?,
+        #[cfg(feature = "selftest")]
+        Some(Commands::SelfTest) => selftest::run()?,
+        Some(Commands::Protect { secret, user }) => {
+            let secret = match secret {
+                Some(s) => s,
+                None => {
+                    let mut s = String::new();
+                    io::stdin().read_line(&mut s)?;
+                    s.trim_end_matches(['\r', '\n']).to_string()
+                }
+            };
+            println!("{}", secrets::protect(&secret, !user)?);
+        }
+        Some(Commands::Wec { command }) => match command {
+            WecCommand::Create {
+                name,
+                file,
+                channel,
+                query,
+                description,
+                log_file,
+                read_existing,
+                allowed_sources,
+                replace,
+            } => {
+                let mut subscription = match file {
+                    Some(file) => wec::Subscription::from_xml(&std::fs::read_to_string(&file)?)?,
+                    None => wec::Subscription {
+                        name: name.unwrap_or_default(),
+                        query: Some(wec::query_list(&channel, query.as_deref())),
+                        enabled: Some(true),
+                        ..Default::default()
+                    },
+                };
+                // Flags override the file
+                subscription.description = description.or(subscription.description);
+                subscription.log_file = log_file.or(subscription.log_file);
+                subscription.allowed_sources = allowed_sources.or(subscription.allowed_sources);
+                if read_existing {
+                    subscription.read_existing_events = Some(true);
+                }
+                wec::create(&subscription, replace)?
+            }
+            WecCommand::List { json } => wec::list(json)?,
+            WecCommand::Delete { name } => wec::delete(&name)?,
+        },
         None => {
-            let config = config::load(cli.config)?;
-            let output = output::create(config.output_file.as_deref())?;
-            eventlog::monitor(&config.channels, output, cli.pretty_json, config.batch_size)?;
+            let status_line = cli.status && atty::is(atty::Stream::Stderr);
+            if cli.status && !status_line {
+                log::warn!("--status ignored: stderr is not a terminal");
+            }
+
+            // Set up Ctrl+C handler
+            let shutdown = Arc::new(AtomicBool::new(false));
+            let shutdown_signal = Arc::clone(&shutdown);
+            ctrlc::set_handler(move || {
+                log::info!("Received shutdown signal, stopping...");
+                shutdown_signal.store(true, Ordering::SeqCst);
+            })?;
+            if let Err(e) = console::watch_break() {
+                log::warn!("Ctrl+Break status reports unavailable: {}", e);
+            }
+
+            if let Some(duration) = cli.duration {
+                let shutdown = Arc::clone(&shutdown);
+                std::thread::spawn(move || {
+                    std::thread::sleep(duration);
+                    log::info!("--duration reached, stopping...");
+                    shutdown.store(true, Ordering::SeqCst);
+                });
+            }
+
+            if cli.once {
+                run_once(&source, cli.pretty_json, cli.max_events, shutdown)?;
+            } else {
+                run(
+                    source,
+                    cli.pretty_json,
+                    status_line,
+                    cli.max_events,
+                    shutdown,
+                )?;
+            }
         }
     }
 
     Ok(())
 }
+
+/// Reads what each channel gained since its checkpoint and exits, printing
+/// a summary on stderr; meant for Task Scheduler.
+fn run_once(
+    source: &config::Source,
+    pretty: bool,
+    max_events: Option<u64>,
+    shutdown: Arc<AtomicBool>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let config = config::load(source)?;
+    limits::apply(&config.limits)?;
+    let runtime = eventlog::Runtime {
+        shutdown,
+        stats: Arc::new(stats::Stats::default()),
+        hub: Arc::new(hub::Hub::new(0)),
+        budget: max_events.map(|n| Arc::new(AtomicU64::new(n))),
+    };
+    let output = open_output(&config)?;
+    let summary = eventlog::drain(&config, output, pretty, &runtime)?;
+
+    let total: usize = summary.iter().map(|(_, count)| count).sum();
+    eprintln!("Read {} event(s) from {} channel(s)", total, summary.len());
+    for (channel, count) in summary {
+        eprintln!("  {:<50} {}", channel, count);
+    }
+    Ok(())
+}
+
+/// Writes the events of `file` to the configured output again, printing a
+/// summary on stderr.
+fn reprocess(
+    source: &config::Source,
+    file: &str,
+    pretty: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let config = config::load(source)?;
+    let input: Box<dyn io::BufRead> = match file {
+        "-" => Box::new(io::stdin().lock()),
+        path => Box::new(io::BufReader::new(
+            std::fs::File::open(path).map_err(|e| format!("cannot open {}: {}", path, e))?,
+        )),
+    };
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let shutdown_signal = Arc::clone(&shutdown);
+    ctrlc::set_handler(move || shutdown_signal.store(true, Ordering::SeqCst))?;
+    let runtime = eventlog::Runtime {
+        shutdown,
+        stats: Arc::new(stats::Stats::default()),
+        hub: Arc::new(hub::Hub::new(0)),
+        budget: None,
+    };
+    let output = open_output(&config)?;
+    let result = eventlog::reprocess(config, output, pretty, &runtime, input)?;
+    eprintln!(
+        "Wrote {} event(s); {} filtered out, {} invalid line(s) skipped",
+        result.written, result.filtered, result.invalid
+    );
+    Ok(())
+}
+
+/// Loads the configuration and monitors the configured channels until
+/// `shutdown` is set, or `max_events` have been written. Shared by
+/// interactive and service mode.
+pub fn run(
+    source: config::Source,
+    pretty: bool,
+    status_line: bool,
+    max_events: Option<u64>,
+    shutdown: Arc<AtomicBool>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = config::load(&source)?;
+    limits::apply(&config.limits)?;
+
+    // Kept alive for the whole run so the supervisor's receiver never disconnects
+    let (requests, control_rx) = mpsc::channel();
+    if let Some(pipe) = &config.control_pipe {
+        control::serve(pipe.clone(), source.clone(), requests.clone());
+    }
+
+    let runtime = eventlog::Runtime {
+        shutdown,
+        stats: Arc::new(stats::Stats::default()),
+        // Only buffer recent events when something can ask for them
+        hub: Arc::new(hub::Hub::new(
+            if config.http_listen.is_some()
+                || config.websocket_listen.is_some()
+                || config.grpc.is_some()
+            {
+                config.lookback_events
+            } else {
+                0
+            },
+        )),
+        budget: max_events.map(|n| Arc::new(AtomicU64::new(n))),
+    };
+
+    perf::spawn(Arc::clone(&runtime.stats), Arc::clone(&runtime.shutdown));
+
+    if let Some(grpc) = &config.grpc {
+        #[cfg(feature = "grpc")]
+        grpc::serve(grpc, Arc::clone(&runtime.hub), Arc::clone(&runtime.stats))?;
+        #[cfg(not(feature = "grpc"))]
+        log::warn!(
+            "gRPC listener {} not started: built without the 'grpc' feature",
+            grpc.listen
+        );
+    }
+
+    if let Some(listen) = &config.websocket_listen {
+        #[cfg(feature = "websocket")]
+        websocket::serve(listen, Arc::clone(&runtime.hub))?;
+        #[cfg(not(feature = "websocket"))]
+        log::warn!(
+            "WebSocket listener {} not started: built without the 'websocket' feature",
+            listen
+        );
+    }
+
+    if let Some(listen) = &config.http_listen {
+        #[cfg(feature = "api")]
+        http::serve(listen, Arc::clone(&runtime.hub))?;
+        #[cfg(not(feature = "api"))]
+        log::warn!(
+            "REST API listener {} not started: built without the 'api' feature",
+            listen
+        );
+    }
+
+    loop {
+        let output = open_output(&config)?;
+        match eventlog::monitor(&config, output, pretty, status_line, &runtime, &control_rx)? {
+            eventlog::MonitorExit::Shutdown => return Ok(()),
+            eventlog::MonitorExit::Reload => {
+                log::info!("Reloading configuration");
+                etw::reload();
+                // The pipe checked the config, but it may have changed since
+                match config::load(&source) {
+                    Ok(reloaded) => {
+                        limits::apply(&reloaded.limits)?;
+                        config = reloaded;
+                    }
+                    Err(e) => log::error!(
+                        "Cannot reload the configuration, keeping the current one: {}",
+                        e
+                    ),
+                }
+            }
+        }
+    }
+}
+
+fn open_output(config: &config::Config) -> Result<output::Output, Box<dyn std::error::Error>> {
+    output::create(config).map_err(|e| fatal::Fatal::new(fatal::Kind::Sink, e).into())
+}
@@ -0,0 +1,168 @@
+use crate::hub;
+use serde_json::Value as JsonValue;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Holds events from all channels for up to `merge_window` and releases
+/// them ordered by TimeCreated. Events arriving further apart than the
+/// window may still come out of order.
+pub struct Merger {
+    window: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    // Keyed by (TimeCreated in ns, arrival number)
+    held: BTreeMap<(i64, u64), Held>,
+    // Keys in arrival order; some may have been released already
+    arrivals: VecDeque<(Instant, (i64, u64))>,
+    next: u64,
+}
+
+pub struct Held {
+    pub event: JsonValue,
+    pub channel: String,
+    pub read_at: Instant,
+}
+
+impl Merger {
+    pub fn new(window: Duration) -> Merger {
+        Merger {
+            window,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn add(&self, event: JsonValue, channel: &str, read_at: Instant) {
+        // Events without a usable TimeCreated sort as if created now
+        let created = hub::time_created(&event)
+            .and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok())
+            .and_then(|t| t.timestamp_nanos_opt())
+            .unwrap_or_else(|| chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default());
+        let mut state = self.state.lock().unwrap();
+        let key = (created, state.next);
+        state.next += 1;
+        state.held.insert(
+            key,
+            Held {
+                event,
+                channel: channel.to_string(),
+                read_at,
+            },
+        );
+        state.arrivals.push_back((Instant::now(), key));
+    }
+
+    /// Whether no event is waiting to be written.
+    pub fn is_empty(&self) -> bool {
+        self.state.lock().unwrap().held.is_empty()
+    }
+
+    /// Events due for writing, oldest TimeCreated first: while the longest
+    /// held event has waited out the window, everything created before it
+    /// goes too. With `all` set (shutdown) every held event is released.
+    pub fn release(&self, all: bool) -> Vec<Held> {
+        let mut state = self.state.lock().unwrap();
+        if all {
+            state.arrivals.clear();
+            return std::mem::take(&mut state.held).into_values().collect();
+        }
+        let mut released = Vec::new();
+        while let Some(&(arrived, key)) = state.arrivals.front() {
+            if !state.held.contains_key(&key) {
+                state.arrivals.pop_front();
+                continue;
+            }
+            if arrived.elapsed() < self.window {
+                break;
+            }
+            while let Some(entry) = state.held.first_entry() {
+                let done = *entry.key() == key;
+                released.push(entry.remove());
+                if done {
+                    break;
+                }
+            }
+            state.arrivals.pop_front();
+        }
+        released
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    // Event `id` created `second`s into the minute
+    fn add(merger: &Merger, id: u64, second: u32) {
+        let event = json!({
+            "TimeCreated": { "@SystemTime": format!("2024-05-01T12:34:{:02}.1234567Z", second) },
+            "EventRecordID": id,
+        });
+        merger.add(event, "Security", Instant::now());
+    }
+
+    // Makes the first `count` events to arrive look a window old
+    fn age(merger: &Merger, count: usize) {
+        let mut state = merger.state.lock().unwrap();
+        for (arrived, _) in state.arrivals.iter_mut().take(count) {
+            *arrived -= WINDOW;
+        }
+    }
+
+    fn ids(released: Vec<Held>) -> Vec<u64> {
+        released
+            .iter()
+            .map(|h| h.event["EventRecordID"].as_u64().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn holds_events_until_the_window_elapses() {
+        let merger = Merger::new(WINDOW);
+        add(&merger, 1, 10);
+        add(&merger, 2, 11);
+        assert!(merger.release(false).is_empty());
+        assert!(!merger.is_empty());
+
+        age(&merger, 2);
+        assert_eq!(ids(merger.release(false)), [1, 2]);
+        assert!(merger.is_empty());
+    }
+
+    #[test]
+    fn releases_in_time_created_order() {
+        let merger = Merger::new(WINDOW);
+        add(&merger, 2, 20);
+        add(&merger, 4, 40);
+        add(&merger, 1, 10);
+        add(&merger, 3, 20);
+        // Once the first arrival is due, what was created before it goes
+        // too, and events created at the same time keep their arrival order
+        age(&merger, 1);
+        assert_eq!(ids(merger.release(false)), [1, 2]);
+        assert!(merger.release(false).is_empty());
+
+        age(&merger, 4);
+        assert_eq!(ids(merger.release(false)), [3, 4]);
+        assert!(merger.is_empty());
+    }
+
+    #[test]
+    fn releases_everything_on_shutdown() {
+        let merger = Merger::new(WINDOW);
+        add(&merger, 3, 30);
+        add(&merger, 1, 10);
+        add(&merger, 2, 20);
+        assert_eq!(ids(merger.release(true)), [1, 2, 3]);
+        assert!(merger.is_empty());
+
+        age(&merger, 3);
+        assert!(merger.release(false).is_empty());
+    }
+}
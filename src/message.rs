@@ -0,0 +1,141 @@
+use crate::hub;
+use serde_json::Value as JsonValue;
+
+/// An event's message from its provider.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Formatted {
+    /// Formatted in full; a `%1` in it is part of the text
+    Complete(String),
+    /// With inserts left as `%1..%n`: Windows couldn't resolve them, or the
+    /// text is the publisher's message from the metadata cache
+    Partial(String),
+}
+
+impl Formatted {
+    pub fn text(&self) -> &str {
+        match self {
+            Formatted::Complete(text) | Formatted::Partial(text) => text,
+        }
+    }
+}
+
+/// Makes sure every event gets a Message. A partly formatted message gets
+/// its `%1..%n` inserts filled from EventData; when formatting failed
+/// altogether (e.g. the provider isn't installed on this machine) the EventData
+/// values are listed the way Event Viewer does.
+pub fn complete(event: &JsonValue, formatted: Option<Formatted>) -> String {
+    match formatted {
+        Some(Formatted::Complete(message)) if !message.trim().is_empty() => message,
+        Some(Formatted::Partial(message)) if !message.trim().is_empty() => {
+            substitute(&message, &event_data_values(event))
+        }
+        _ => {
+            let values = event_data_values(event);
+            let mut message = format!(
+                "The description for Event ID {} from source {} cannot be found.",
+                hub::event_id(event)
+                    .map(|id| id.to_string())
+                    .unwrap_or_default(),
+                hub::provider(event).unwrap_or("(unknown)")
+            );
+            if !values.is_empty() {
+                message.push_str(" The following information was included with the event:\n\n");
+                message.push_str(&values.join("\n"));
+            }
+            message
+        }
+    }
+}
+
+// Insert values in template order: the unnamed Data array, or the named fields
+fn event_data_values(event: &JsonValue) -> Vec<String> {
+    let values: Vec<&JsonValue> = match event.get("EventData") {
+        Some(JsonValue::Object(data)) => match data.get("Data") {
+            Some(JsonValue::Array(values)) => values.iter().collect(),
+            _ => data.values().collect(),
+        },
+        _ => Vec::new(),
+    };
+    values
+        .into_iter()
+        .map(|v| match v {
+            JsonValue::String(s) => s.clone(),
+            other => other.to_string(),
+        })
+        .collect()
+}
+
+// Replaces %1..%n with the matching value. %%n parameter inserts and
+// out-of-range numbers are left as they are.
+fn substitute(message: &str, values: &[String]) -> String {
+    let mut out = String::with_capacity(message.len());
+    let mut rest = message;
+    while let Some(pos) = rest.find('%') {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos + 1..];
+        if let Some(after) = rest.strip_prefix('%') {
+            out.push_str("%%");
+            rest = after;
+            continue;
+        }
+        let digits = rest.bytes().take_while(|b| b.is_ascii_digit()).count();
+        match rest[..digits]
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| values.get(n.checked_sub(1)?))
+        {
+            Some(value) => out.push_str(value),
+            None => {
+                out.push('%');
+                out.push_str(&rest[..digits]);
+            }
+        }
+        rest = &rest[digits..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn event() -> JsonValue {
+        json!({
+            "Provider": { "@Name": "Service Control Manager" },
+            "EventID": 7036,
+            "EventData": { "param1": "Windows Update", "param2": "running" },
+        })
+    }
+
+    #[test]
+    fn fills_inserts_of_partly_formatted_messages() {
+        let message = Formatted::Partial("The %1 service entered the %2 state. %%3 %9".to_string());
+        assert_eq!(
+            complete(&event(), Some(message)),
+            "The Windows Update service entered the running state. %%3 %9"
+        );
+    }
+
+    #[test]
+    fn keeps_formatted_messages_as_they_are() {
+        let message = Formatted::Complete("Process started: cmd.exe /c echo %1".to_string());
+        assert_eq!(
+            complete(&event(), Some(message)),
+            "Process started: cmd.exe /c echo %1"
+        );
+    }
+
+    #[test]
+    fn lists_event_data_without_a_message() {
+        let expected = "The description for Event ID 7036 from source Service Control Manager \
+                        cannot be found. The following information was included with the \
+                        event:\n\nWindows Update\nrunning";
+        assert_eq!(complete(&event(), None), expected);
+        assert_eq!(
+            complete(&event(), Some(Formatted::Complete(" ".to_string()))),
+            expected
+        );
+    }
+}
@@ -0,0 +1,416 @@
+use crate::evtapi::{Handle, Metadata};
+use crate::hub;
+use crate::publisher::{property, variant};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use windows::Win32::Foundation::{
+    ERROR_EVT_UNRESOLVED_PARAMETER_INSERT, ERROR_EVT_UNRESOLVED_VALUE_INSERT,
+};
+use windows::Win32::System::EventLog::*;
+use windows::core::{HSTRING, PCWSTR};
+
+type JsonMap = serde_json::Map<String, JsonValue>;
+
+// How long a provider found neither in the directory nor installed is taken
+// as missing, so a file added (or a provider installed) later is picked up
+const MISS_TTL: Duration = Duration::from_secs(300);
+
+/// What rendering needs from a provider's manifest: the names of its levels,
+/// tasks, opcodes and keywords, and each event's message and template.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Provider {
+    pub levels: BTreeMap<u32, String>,
+    pub tasks: BTreeMap<u32, String>,
+    // The opcode in the high word, the task it belongs to (0 for any) in
+    // the low word
+    pub opcodes: BTreeMap<u32, String>,
+    pub keywords: BTreeMap<u64, String>,
+    // By "<event ID>/<version>"
+    pub events: BTreeMap<String, Event>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Event {
+    // With %1..%n where the EventData values go
+    pub message: Option<String>,
+    pub template: Vec<String>,
+    // Input type of each template parameter
+    pub types: Vec<String>,
+}
+
+impl Provider {
+    /// The name of the event's Level, Task or Opcode.
+    pub fn name(&self, metadata: Metadata, event: &JsonMap) -> Option<String> {
+        let field = |key: &str| event.get(key).and_then(number);
+        match metadata {
+            Metadata::Level => self.levels.get(&(field("Level")? as u32)).cloned(),
+            Metadata::Task => self.tasks.get(&(field("Task")? as u32)).cloned(),
+            Metadata::Opcode => {
+                let opcode = (field("Opcode")? as u32) << 16;
+                let task = field("Task").unwrap_or(0) as u32;
+                self.opcodes
+                    .get(&(opcode | task))
+                    .or_else(|| self.opcodes.get(&opcode))
+                    .cloned()
+            }
+            Metadata::Keywords => None,
+        }
+    }
+
+    /// The names of the keywords set in the event's Keywords mask.
+    pub fn keywords(&self, event: &JsonMap) -> Vec<String> {
+        let mask = event.get("Keywords").and_then(number).unwrap_or(0);
+        self.keywords
+            .iter()
+            .filter(|(bits, _)| **bits != 0 && mask & **bits == **bits)
+            .map(|(_, name)| name.clone())
+            .collect()
+    }
+
+    pub fn event(&self, event: &JsonValue) -> Option<&Event> {
+        let version = event.get("Version").and_then(number).unwrap_or(0);
+        self.events
+            .get(&format!("{}/{}", hub::event_id(event)?, version))
+    }
+}
+
+// Levels and the like are strings in rendered events ("4", "0x8000..."),
+// numbers once typed_json converted them
+fn number(value: &JsonValue) -> Option<u64> {
+    match value {
+        JsonValue::Number(n) => n.as_u64(),
+        JsonValue::String(s) => match s.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => s.parse().ok(),
+        },
+        _ => None,
+    }
+}
+
+/// Provider metadata kept as one JSON file per provider in a directory, so
+/// events of providers that aren't installed here (from .evtx files or
+/// forwarded from other machines) still get their names and messages.
+/// Providers that are installed get their file written the first time they
+/// are looked up; `export` writes them all in one go.
+pub struct Cache {
+    dir: PathBuf,
+    // Err with the time of the lookup for providers neither cached nor
+    // installed, looked up again after MISS_TTL
+    providers: Mutex<HashMap<String, Result<Arc<Provider>, Instant>>>,
+}
+
+impl Cache {
+    pub fn new(dir: impl Into<PathBuf>) -> Cache {
+        Cache {
+            dir: dir.into(),
+            providers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, provider: &str) -> Option<Arc<Provider>> {
+        match self.providers.lock().unwrap().get(provider) {
+            Some(Ok(cached)) => return Some(cached.clone()),
+            Some(Err(missed)) if missed.elapsed() < MISS_TTL => return None,
+            _ => {}
+        }
+        // Read (and for installed providers, written) without holding the
+        // lock, so other channels' lookups don't wait on the disk
+        let path = file(&self.dir, provider);
+        let loaded = match std::fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text)
+                .map_err(|e| warn!("Ignoring {}: {}", path.display(), e))
+                .ok(),
+            Err(_) => {
+                read(provider).inspect(|metadata| match save(&self.dir, provider, metadata) {
+                    Ok(()) => info!("Cached the metadata of provider {}", provider),
+                    Err(e) => warn!("Cannot cache the metadata of provider {}: {}", provider, e),
+                })
+            }
+        }
+        .map(Arc::new);
+        self.providers.lock().unwrap().insert(
+            provider.to_string(),
+            loaded.clone().ok_or_else(Instant::now),
+        );
+        loaded
+    }
+}
+
+/// Writes the metadata of the given providers, or of every provider
+/// installed here when none are given, to `dir`.
+pub fn export(dir: &Path, providers: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let providers = if providers.is_empty() {
+        installed()?
+    } else {
+        providers.to_vec()
+    };
+    let mut written = 0;
+    for provider in &providers {
+        match read(provider) {
+            Some(metadata) => {
+                save(dir, provider, &metadata)?;
+                written += 1;
+            }
+            None => warn!("Provider {} is not installed here, skipping", provider),
+        }
+    }
+    println!(
+        "Wrote the metadata of {} providers to {}",
+        written,
+        dir.display()
+    );
+    Ok(())
+}
+
+fn save(dir: &Path, provider: &str, metadata: &Provider) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let path = file(dir, provider);
+    // Written aside and renamed, so readers never see half a file
+    let mut partial = path.clone().into_os_string();
+    partial.push(".tmp");
+    std::fs::write(&partial, serde_json::to_vec_pretty(metadata)?)?;
+    std::fs::rename(&partial, &path)
+}
+
+// Provider names may hold characters file names can't
+fn file(dir: &Path, provider: &str) -> PathBuf {
+    let name: String = provider
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            c => c,
+        })
+        .collect();
+    dir.join(format!("{}.json", name))
+}
+
+fn installed() -> windows::core::Result<Vec<String>> {
+    let mut providers = Vec::new();
+    unsafe {
+        let publishers = Handle(EvtOpenPublisherEnum(None, 0)?);
+        let mut buffer = vec![0u16; 512];
+        loop {
+            let mut used = 0u32;
+            if EvtNextPublisherId(publishers.0, Some(&mut buffer), &mut used).is_err() {
+                // A longer name than fits: grow the buffer and retry
+                if used as usize > buffer.len() {
+                    buffer.resize(used as usize, 0);
+                    continue;
+                }
+                break;
+            }
+            let len = buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len());
+            providers.push(String::from_utf16_lossy(&buffer[..len]));
+        }
+    }
+    Ok(providers)
+}
+
+// None when the provider isn't installed here
+fn read(provider: &str) -> Option<Provider> {
+    let name = HSTRING::from(provider);
+    unsafe {
+        let metadata =
+            Handle(EvtOpenPublisherMetadata(None, PCWSTR(name.as_ptr()), None, 0, 0).ok()?);
+        let mut out = Provider::default();
+        for (list, value, message, names) in [
+            (
+                EvtPublisherMetadataLevels,
+                EvtPublisherMetadataLevelValue,
+                EvtPublisherMetadataLevelMessageID,
+                &mut out.levels,
+            ),
+            (
+                EvtPublisherMetadataTasks,
+                EvtPublisherMetadataTaskValue,
+                EvtPublisherMetadataTaskMessageID,
+                &mut out.tasks,
+            ),
+            (
+                EvtPublisherMetadataOpcodes,
+                EvtPublisherMetadataOpcodeValue,
+                EvtPublisherMetadataOpcodeMessageID,
+                &mut out.opcodes,
+            ),
+        ] {
+            for (value, name) in names_of(&metadata, list, value, message) {
+                names.insert(value as u32, name);
+            }
+        }
+        out.keywords = names_of(
+            &metadata,
+            EvtPublisherMetadataKeywords,
+            EvtPublisherMetadataKeywordValue,
+            EvtPublisherMetadataKeywordMessageID,
+        )
+        .collect();
+
+        if let Ok(events) = EvtOpenEventMetadataEnum(metadata.0, 0) {
+            let events = Handle(events);
+            while let Ok(event) = EvtNextEventMetadata(events.0, 0) {
+                let event = Handle(event);
+                // The metadata ID carries qualifier bits above the 16-bit event ID
+                let (Some(id), Some(version)) = (
+                    property(event.0, EventMetadataEventID).map(|p| variant(&p).UInt32Val as u16),
+                    property(event.0, EventMetadataEventVersion).map(|p| variant(&p).ByteVal),
+                ) else {
+                    continue;
+                };
+                let message = property(event.0, EventMetadataEventMessageID)
+                    .and_then(|p| message_text(&metadata, variant(&p).UInt32Val));
+                let template = property(event.0, EventMetadataEventTemplate)
+                    .and_then(|p| variant(&p).StringVal.to_string().ok())
+                    .and_then(|t| crate::publisher::parse_template(&t))
+                    .unwrap_or_default();
+                out.events.insert(
+                    format!("{}/{}", id, version),
+                    Event {
+                        message,
+                        template: template.names,
+                        types: template.types,
+                    },
+                );
+            }
+        }
+        Some(out)
+    }
+}
+
+// The values and display names of one of the publisher's lists, e.g. its
+// levels; entries without a display name are left out
+unsafe fn names_of(
+    metadata: &Handle,
+    list: EVT_PUBLISHER_METADATA_PROPERTY_ID,
+    value: EVT_PUBLISHER_METADATA_PROPERTY_ID,
+    message: EVT_PUBLISHER_METADATA_PROPERTY_ID,
+) -> impl Iterator<Item = (u64, String)> {
+    let mut names = Vec::new();
+    unsafe {
+        let Some(array) = publisher_property(metadata, list) else {
+            return names.into_iter();
+        };
+        let array = Handle(variant(&array).EvtHandleVal);
+        let mut size = 0u32;
+        if EvtGetObjectArraySize(array.0.0, &mut size).is_err() {
+            return names.into_iter();
+        }
+        for index in 0..size {
+            let value = array_property(&array, value, index).map(|p| {
+                let v = &*(p.as_ptr() as *const EVT_VARIANT);
+                if v.Type == EvtVarTypeUInt64.0 as u32 {
+                    v.Anonymous.UInt64Val
+                } else {
+                    v.Anonymous.UInt32Val as u64
+                }
+            });
+            let name = array_property(&array, message, index)
+                .and_then(|p| message_text(metadata, variant(&p).UInt32Val));
+            if let (Some(value), Some(name)) = (value, name) {
+                names.push((value, name));
+            }
+        }
+    }
+    names.into_iter()
+}
+
+// Like `publisher::property`, for publisher metadata and its lists
+unsafe fn publisher_property(
+    metadata: &Handle,
+    id: EVT_PUBLISHER_METADATA_PROPERTY_ID,
+) -> Option<Vec<u64>> {
+    unsafe {
+        let mut used = 0u32;
+        let _ = EvtGetPublisherMetadataProperty(metadata.0, id, 0, 0, None, &mut used);
+        if used == 0 {
+            return None;
+        }
+        let mut buffer = vec![0u64; (used as usize).div_ceil(8)];
+        EvtGetPublisherMetadataProperty(
+            metadata.0,
+            id,
+            0,
+            used,
+            Some(buffer.as_mut_ptr() as *mut EVT_VARIANT),
+            &mut used,
+        )
+        .ok()?;
+        Some(buffer)
+    }
+}
+
+unsafe fn array_property(
+    array: &Handle,
+    id: EVT_PUBLISHER_METADATA_PROPERTY_ID,
+    index: u32,
+) -> Option<Vec<u64>> {
+    unsafe {
+        let mut used = 0u32;
+        let _ = EvtGetObjectArrayProperty(array.0.0, id.0 as u32, index, 0, 0, None, &mut used);
+        if used == 0 {
+            return None;
+        }
+        let mut buffer = vec![0u64; (used as usize).div_ceil(8)];
+        EvtGetObjectArrayProperty(
+            array.0.0,
+            id.0 as u32,
+            index,
+            0,
+            used,
+            Some(buffer.as_mut_ptr() as *mut EVT_VARIANT),
+            &mut used,
+        )
+        .ok()?;
+        Some(buffer)
+    }
+}
+
+// The text of one of the publisher's messages, inserts left as %1..%n
+unsafe fn message_text(metadata: &Handle, id: u32) -> Option<String> {
+    // -1 for "no message"
+    if id == u32::MAX {
+        return None;
+    }
+    unsafe {
+        let mut size = 0u32;
+        let _ = EvtFormatMessage(
+            Some(metadata.0),
+            None,
+            id,
+            None,
+            EvtFormatMessageId.0,
+            None,
+            &mut size,
+        );
+        if size == 0 {
+            return None;
+        }
+        let mut buffer = vec![0u16; size as usize];
+        match EvtFormatMessage(
+            Some(metadata.0),
+            None,
+            id,
+            None,
+            EvtFormatMessageId.0,
+            Some(&mut buffer),
+            &mut size,
+        ) {
+            Err(e)
+                if e.code() != ERROR_EVT_UNRESOLVED_VALUE_INSERT.to_hresult()
+                    && e.code() != ERROR_EVT_UNRESOLVED_PARAMETER_INSERT.to_hresult() =>
+            {
+                None
+            }
+            _ => {
+                let len = buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len());
+                Some(String::from_utf16_lossy(&buffer[..len])).filter(|m| !m.is_empty())
+            }
+        }
+    }
+}
@@ -1,30 +1,1149 @@
+use crate::chain::Chain;
+use crate::config::{Config, Framing, RetentionConfig};
+use crate::fields::Shape;
+use crate::hub;
+use crate::journal::Journal;
+use crate::route::Router;
+use crate::sftp::{self, Uploader};
+use log::{info, warn};
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
-use std::io::{self, Stdout, Write};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Stdout, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-pub enum Output {
-    File(File),
-    Stdout(Stdout),
+// Placeholders allowed in a templated output path
+const PLACEHOLDERS: [&str; 3] = ["{channel}", "{date}", "{hostname}"];
+
+// Schemes of the optional sinks and the cargo feature that builds each
+const FEATURE_SCHEMES: [(&str, &str); 5] = [
+    ("tcp://", "tcp"),
+    ("lumberjack://", "lumberjack"),
+    ("lumberjack+tls://", "lumberjack"),
+    ("arrow://", "arrow"),
+    ("sentinel://", "sentinel"),
+];
+
+/// One event for a sink: the event itself and the line it serializes to,
+/// after field selection and renaming for that sink (see `Shape`).
+pub struct Record<'a> {
+    pub event: &'a JsonValue,
+    pub line: &'a str,
+}
+
+/// Events delimited as a `Framing` asks, held until they are written out.
+/// Only a JSON array needs holding: it can't be written before it's closed.
+pub struct Framer {
+    framing: Framing,
+    batch: Vec<u8>,
+    events: usize,
 }
 
-impl Write for Output {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        match self {
-            Output::File(f) => f.write(buf),
-            Output::Stdout(s) => s.write(buf),
+impl Framer {
+    pub fn new(framing: Framing) -> Framer {
+        Framer {
+            framing,
+            batch: Vec::new(),
+            events: 0,
+        }
+    }
+
+    pub fn push(&mut self, line: &str) -> io::Result<()> {
+        match self.framing {
+            Framing::Ndjson => {
+                self.batch.extend_from_slice(line.as_bytes());
+                self.batch.push(b'\n');
+            }
+            Framing::JsonArray => {
+                self.batch.push(if self.events == 0 { b'[' } else { b',' });
+                self.batch.extend_from_slice(line.as_bytes());
+            }
+            Framing::LengthPrefixed => {
+                let len = u32::try_from(line.len())
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "event too large"))?;
+                self.batch.extend_from_slice(&len.to_be_bytes());
+                self.batch.extend_from_slice(line.as_bytes());
+            }
+        }
+        self.events += 1;
+        Ok(())
+    }
+
+    /// Events framed since they were last written.
+    #[cfg_attr(not(feature = "tcp"), allow(dead_code))]
+    pub fn events(&self) -> usize {
+        self.events
+    }
+
+    /// Whether events are held until a flush rather than written as they
+    /// come.
+    pub fn holds(&self) -> bool {
+        self.framing == Framing::JsonArray
+    }
+
+    /// Hands the held events, a JSON array closed, to `write` and forgets
+    /// them once it succeeded. On failure they stay held, and the array open.
+    pub fn write_with(&mut self, write: impl FnOnce(&[u8]) -> io::Result<()>) -> io::Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let len = self.batch.len();
+        if self.framing == Framing::JsonArray {
+            self.batch.extend_from_slice(b"]\n");
+        }
+        if let Err(e) = write(&self.batch) {
+            self.batch.truncate(len);
+            return Err(e);
         }
+        self.batch.clear();
+        self.events = 0;
+        Ok(())
+    }
+}
+
+/// A destination for events. Files, shares and stdout are built in; the
+/// network and encoded sinks are each behind a cargo feature and picked by
+/// the scheme of `output_file` or a route's output.
+pub trait Sink: Send {
+    /// Writes events, or buffers them until `flush`.
+    fn send_batch(&mut self, batch: &[Record]) -> io::Result<()>;
+
+    /// Writes out buffered events.
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// Flushes and, for files, forces the events to disk.
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+
+    /// Whether the destination can take events right now, e.g. that the
+    /// receiver is still connected.
+    fn healthcheck(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// For sinks that send on their own batch limits rather than the flush
+    /// policy: sends a partial batch that has waited long enough. None
+    /// leaves flushing to the flush policy.
+    fn send_if_due(&mut self) -> Option<io::Result<()>> {
+        None
+    }
+
+    /// Moves the current file aside with a timestamp suffix and starts a
+    /// fresh one. Returns the rotated file's path.
+    fn rotate(&mut self) -> io::Result<PathBuf> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "output is not a file, nothing to rotate",
+        ))
+    }
+}
+
+/// Opens a sink for an output target, given with its scheme, delimiting
+/// events by the framing where its protocol leaves that open.
+pub type Factory = fn(&str, Framing, &Config) -> Result<Box<dyn Sink>, Box<dyn std::error::Error>>;
+
+/// Checks that a sink could be opened for an output target, without writing
+/// to it (see `probe`).
+pub type Probe = fn(&str, &Config) -> Result<(), Box<dyn std::error::Error>>;
+
+// The optional sinks built in, by scheme
+const SINKS: &[(&str, Factory, Probe)] = &[
+    #[cfg(feature = "tcp")]
+    ("tcp://", crate::tcp::create, crate::tcp::probe),
+    #[cfg(feature = "lumberjack")]
+    (
+        "lumberjack://",
+        crate::lumberjack::create,
+        crate::lumberjack::probe,
+    ),
+    #[cfg(feature = "lumberjack")]
+    (
+        "lumberjack+tls://",
+        crate::lumberjack::create,
+        crate::lumberjack::probe,
+    ),
+    #[cfg(feature = "arrow")]
+    ("arrow://", crate::arrow::create, crate::arrow::probe),
+    #[cfg(feature = "sentinel")]
+    (
+        "sentinel://",
+        crate::sentinel::create,
+        crate::sentinel::probe,
+    ),
+];
+
+/// The sink events are written to.
+pub struct Output(Box<dyn Sink>);
+
+impl Output {
+    // Events are consumed elsewhere (e.g. the TUI reads them from the hub)
+    pub fn discard() -> Output {
+        Output(Box::new(Discard))
+    }
+
+    /// Writes one serialized event; `event` picks the file of a partitioned
+    /// output.
+    pub fn write_event(&mut self, event: &JsonValue, line: &str) -> io::Result<()> {
+        self.0.send_batch(&[Record { event, line }])
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.0.sync()
+    }
+
+    pub fn healthcheck(&mut self) -> io::Result<()> {
+        self.0.healthcheck()
+    }
+
+    pub fn send_if_due(&mut self) -> Option<io::Result<()>> {
+        self.0.send_if_due()
+    }
+
+    pub fn rotate(&mut self) -> io::Result<PathBuf> {
+        self.0.rotate()
+    }
+}
+
+/// A sink that writes events in its own shape; `line`s are shaped from the
+/// event before they reach it.
+struct Shaped {
+    sink: Box<dyn Sink>,
+    shape: Shape,
+}
+
+/// `sink` writing events in `shape`, or as they are without one.
+pub fn shaped(sink: Box<dyn Sink>, shape: Option<Shape>) -> Box<dyn Sink> {
+    match shape {
+        Some(shape) => Box::new(Shaped { sink, shape }),
+        None => sink,
+    }
+}
+
+impl Sink for Shaped {
+    fn send_batch(&mut self, batch: &[Record]) -> io::Result<()> {
+        let lines: Vec<String> = batch
+            .iter()
+            .map(|record| {
+                let shaped = self.shape.apply(record.event);
+                // Only --pretty-json lines span several lines; the shaped
+                // line keeps the form
+                if record.line.contains('\n') {
+                    serde_json::to_string_pretty(&shaped).unwrap_or_default()
+                } else {
+                    shaped.to_string()
+                }
+            })
+            .collect();
+        let records: Vec<Record> = batch
+            .iter()
+            .zip(&lines)
+            .map(|(record, line)| Record {
+                event: record.event,
+                line,
+            })
+            .collect();
+        self.sink.send_batch(&records)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        match self {
-            Output::File(f) => f.flush(),
-            Output::Stdout(s) => s.flush(),
+        self.sink.flush()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sink.sync()
+    }
+
+    fn healthcheck(&mut self) -> io::Result<()> {
+        self.sink.healthcheck()
+    }
+
+    fn send_if_due(&mut self) -> Option<io::Result<()>> {
+        self.sink.send_if_due()
+    }
+
+    fn rotate(&mut self) -> io::Result<PathBuf> {
+        self.sink.rotate()
+    }
+}
+
+struct Discard;
+
+impl Sink for Discard {
+    fn send_batch(&mut self, _batch: &[Record]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Sink for Stdout {
+    fn send_batch(&mut self, batch: &[Record]) -> io::Result<()> {
+        batch.iter().try_for_each(|r| writeln!(self, "{}", r.line))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Write::flush(self)
+    }
+}
+
+/// A local file, optionally journaled and hash-chained, that rotates and
+/// uploads rotated files.
+struct FileSink {
+    file: BufWriter<File>,
+    framer: Framer,
+    path: PathBuf,
+    retention: RetentionConfig,
+    journal: Option<Journal>,
+    chain: Option<Chain>,
+    upload: Option<Uploader>,
+}
+
+impl FileSink {
+    // Writes out the framed events, through the journal
+    fn write_framed(&mut self) -> io::Result<()> {
+        let (file, journal) = (&mut self.file, &mut self.journal);
+        self.framer.write_with(|bytes| {
+            if let Some(journal) = journal {
+                journal.append(bytes)?;
+            }
+            file.write_all(bytes)
+        })
+    }
+}
+
+impl Sink for FileSink {
+    fn send_batch(&mut self, batch: &[Record]) -> io::Result<()> {
+        for record in batch {
+            match &mut self.chain {
+                Some(chain) => self.framer.push(&chain.link(record.line))?,
+                None => self.framer.push(record.line)?,
+            }
+            if !self.framer.holds() {
+                self.write_framed()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_framed()?;
+        self.file.flush()?;
+        match &mut self.journal {
+            Some(journal) => journal.commit(self.file.get_ref()),
+            None => Ok(()),
+        }
+    }
+
+    // The journal may only be emptied once the events are on disk
+    fn sync(&mut self) -> io::Result<()> {
+        self.write_framed()?;
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        match &mut self.journal {
+            Some(journal) => journal.commit(self.file.get_ref()),
+            None => Ok(()),
+        }
+    }
+
+    fn healthcheck(&mut self) -> io::Result<()> {
+        self.file.get_ref().metadata().map(|_| ())
+    }
+
+    fn rotate(&mut self) -> io::Result<PathBuf> {
+        self.flush()?;
+        let rotated = rotated_path(&self.path);
+        std::fs::rename(&self.path, &rotated)?;
+        self.file = open_append(&self.path)?;
+        // The flush emptied the journal, but its base is still the old
+        // file's length
+        if let Some(journal) = &mut self.journal {
+            journal.rebase(self.file.get_ref())?;
+        }
+        if let Some(upload) = &self.upload {
+            upload.upload(rotated.clone());
+        }
+        prune(&self.path, &self.retention);
+        Ok(rotated)
+    }
+}
+
+impl Drop for FileSink {
+    fn drop(&mut self) {
+        // A JSON array still open would be lost
+        let _ = self.write_framed();
+    }
+}
+
+// events.log -> events.20240101T120000.log
+pub fn rotated_path(path: &Path) -> PathBuf {
+    let stamp = chrono::Local::now().format("%Y%m%dT%H%M%S");
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, stamp, ext.to_string_lossy()),
+        None => format!("{}.{}", stem, stamp),
+    };
+    path.with_file_name(name)
+}
+
+/// Deletes rotated copies of `path` beyond the retention limits, oldest
+/// first. Files still waiting for an sftp upload are kept.
+pub fn prune(path: &Path, retention: &RetentionConfig) {
+    if retention.max_files.is_none()
+        && retention.max_total_size.is_none()
+        && retention.max_age.is_none()
+    {
+        return;
+    }
+    let dir = match path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        Some(dir) => dir,
+        None => Path::new("."),
+    };
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+
+    // The timestamp suffix sorts chronologically, so newest first is reverse order
+    let mut rotated: Vec<(PathBuf, std::fs::Metadata)> = entries
+        .flatten()
+        .filter(|e| is_rotated(path, &e.path()))
+        .filter_map(|e| Some((e.path(), e.metadata().ok()?)))
+        .collect();
+    rotated.sort_by(|a, b| b.0.cmp(&a.0));
+    let uploading: Vec<_> = sftp::pending(path)
+        .iter()
+        .filter_map(|p| p.file_name().map(ToOwned::to_owned))
+        .collect();
+
+    let mut total = 0u64;
+    for (i, (file, meta)) in rotated.iter().enumerate() {
+        total += meta.len();
+        let too_many = retention.max_files.is_some_and(|max| i >= max);
+        let too_big = retention.max_total_size.is_some_and(|max| total > max);
+        let too_old = retention.max_age.is_some_and(|max| {
+            meta.modified()
+                .ok()
+                .and_then(|m| m.elapsed().ok())
+                .is_some_and(|age| age > max)
+        });
+        if (too_many || too_big || too_old)
+            && !file
+                .file_name()
+                .is_some_and(|n| uploading.iter().any(|u| u == n))
+        {
+            match std::fs::remove_file(file) {
+                Ok(()) => info!("Deleted rotated output {}", file.display()),
+                Err(e) => warn!("Cannot delete rotated output {}: {}", file.display(), e),
+            }
         }
     }
 }
 
-pub fn create(path: Option<&str>) -> Result<Output, Box<dyn std::error::Error>> {
-    Ok(match path {
-        Some(p) => Output::File(OpenOptions::new().create(true).append(true).open(p)?),
-        None => Output::Stdout(io::stdout()),
+// Matches the names produced by rotated_path for this output file
+fn is_rotated(path: &Path, candidate: &Path) -> bool {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let Some(name) = candidate.file_name().map(|n| n.to_string_lossy()) else {
+        return false;
+    };
+    let Some(rest) = name.strip_prefix(&format!("{}.", stem)) else {
+        return false;
+    };
+    let stamp = match path.extension() {
+        Some(ext) => rest.strip_suffix(&format!(".{}", ext.to_string_lossy())),
+        None => Some(rest),
+    };
+    stamp.is_some_and(|s| {
+        s.len() == 15
+            && s.char_indices()
+                .all(|(i, c)| if i == 8 { c == 'T' } else { c.is_ascii_digit() })
     })
 }
+
+// Buffered; the flush policy decides when events reach the file
+fn open_append(path: &Path) -> io::Result<BufWriter<File>> {
+    Ok(BufWriter::new(
+        OpenOptions::new().create(true).append(true).open(path)?,
+    ))
+}
+
+pub struct Partitioned {
+    template: String,
+    hostname: String,
+    framing: Framing,
+    // Files opened for the current date; closed when the date changes
+    files: HashMap<PathBuf, (BufWriter<File>, Framer)>,
+    date: String,
+}
+
+impl Partitioned {
+    fn new(template: &str, framing: Framing) -> Result<Partitioned, String> {
+        // Anything in braces that isn't a known placeholder is most likely a typo
+        let mut rest = template.to_string();
+        for placeholder in PLACEHOLDERS {
+            rest = rest.replace(placeholder, "");
+        }
+        if rest.contains('{') || rest.contains('}') {
+            return Err(format!(
+                "unsupported placeholder in output '{}': expected {}",
+                template,
+                PLACEHOLDERS.join(", ")
+            ));
+        }
+        Ok(Partitioned {
+            template: template.to_string(),
+            hostname: std::env::var("COMPUTERNAME").unwrap_or_else(|_| "localhost".to_string()),
+            framing,
+            files: HashMap::new(),
+            date: String::new(),
+        })
+    }
+
+    fn write_event(&mut self, event: &JsonValue, line: &str) -> io::Result<()> {
+        let date = chrono::Local::now().format("%Y-%m-%d").to_string();
+        if date != self.date {
+            self.flush()?;
+            self.files.clear();
+            self.date = date;
+        }
+
+        let path = PathBuf::from(
+            self.template
+                .replace(
+                    "{channel}",
+                    &file_name_safe(hub::channel(event).unwrap_or("unknown")),
+                )
+                .replace("{date}", &self.date)
+                .replace("{hostname}", &file_name_safe(&self.hostname)),
+        );
+        let file = match self.files.get_mut(&path) {
+            Some(file) => file,
+            None => {
+                if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+                    std::fs::create_dir_all(dir)?;
+                }
+                let file = open_append(&path)?;
+                self.files
+                    .entry(path)
+                    .or_insert((file, Framer::new(self.framing)))
+            }
+        };
+        let (file, framer) = file;
+        framer.push(line)?;
+        if framer.holds() {
+            return Ok(());
+        }
+        framer.write_with(|bytes| file.write_all(bytes))
+    }
+}
+
+impl Sink for Partitioned {
+    fn send_batch(&mut self, batch: &[Record]) -> io::Result<()> {
+        batch
+            .iter()
+            .try_for_each(|r| self.write_event(r.event, r.line))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.files.values_mut().try_for_each(|(file, framer)| {
+            framer.write_with(|bytes| file.write_all(bytes))?;
+            file.flush()
+        })
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.files
+            .values_mut()
+            .try_for_each(|(file, _)| file.get_ref().sync_data())
+    }
+
+    fn rotate(&mut self) -> io::Result<PathBuf> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "partitioned output is split by its path template, nothing to rotate",
+        ))
+    }
+}
+
+// Unsent bytes written to a connected share at once
+const SHARE_WRITE_CHUNK: usize = 64 * 1024;
+// Retries of an unreachable share back off up to this
+const SHARE_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A file output on a network share (UNC path) that rides out disconnects.
+/// While the share can't be written, events are held in memory and opening
+/// it again is retried with backoff. Once it has been unreachable for
+/// `fallback_after`, or the buffer is full, events go to a local fallback
+/// file instead, until the share is back. A flush (e.g. before a checkpoint
+/// is saved) moves events still held in memory to the fallback file too.
+pub struct Share {
+    path: PathBuf,
+    fallback_path: PathBuf,
+    // None while the share is unreachable
+    file: Option<File>,
+    fallback: Option<BufWriter<File>>,
+    framer: Framer,
+    // Events not written yet; the whole buffer while the share is down
+    unsent: Vec<u8>,
+    buffer_max: usize,
+    fallback_after: Duration,
+    down_since: Option<Instant>,
+    retry_at: Instant,
+    backoff: Duration,
+    retention: RetentionConfig,
+}
+
+impl Share {
+    fn open(
+        path: PathBuf,
+        framing: Framing,
+        config: &Config,
+    ) -> Result<Share, Box<dyn std::error::Error>> {
+        let fallback_path = match &config.share.fallback_file {
+            Some(fallback) => PathBuf::from(fallback),
+            None => std::env::current_exe()?
+                .parent()
+                .ok_or("executable has no parent directory")?
+                .join(path.file_name().unwrap_or_default()),
+        };
+        let mut share = Share {
+            path,
+            fallback_path,
+            file: None,
+            fallback: None,
+            framer: Framer::new(framing),
+            unsent: Vec::new(),
+            buffer_max: config.share.buffer_max.unwrap_or(64 << 20) as usize,
+            fallback_after: config
+                .share
+                .fallback_after
+                .unwrap_or(Duration::from_secs(300)),
+            down_since: Some(Instant::now()),
+            retry_at: Instant::now(),
+            backoff: Duration::from_secs(1),
+            retention: config.retention.clone(),
+        };
+        // An unreachable share at startup is an outage like any other
+        share.reconnect();
+        if share.file.is_some() {
+            prune(&share.path, &share.retention);
+        }
+        Ok(share)
+    }
+
+    pub fn is_down(&self) -> bool {
+        self.file.is_none()
+    }
+
+    fn write_event(&mut self, line: &str) -> io::Result<()> {
+        self.framer.push(line)?;
+        if self.framer.holds() {
+            return Ok(());
+        }
+        self.take_framed();
+        if self.unsent.len() >= SHARE_WRITE_CHUNK || self.is_down() {
+            self.send()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the unsent events to the share or, when it has been down long
+    /// enough (or the buffer is full), to the fallback file.
+    pub fn send(&mut self) -> io::Result<()> {
+        if self.is_down() && Instant::now() >= self.retry_at {
+            self.reconnect();
+        }
+        if let Some(file) = &mut self.file {
+            match file.write_all(&self.unsent) {
+                Ok(()) => {
+                    self.unsent.clear();
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!("Lost output share {}: {}", self.path.display(), e);
+                    self.file = None;
+                    self.down_since = Some(Instant::now());
+                    self.backoff = Duration::from_secs(1);
+                    self.retry_at = Instant::now() + self.backoff;
+                }
+            }
+        }
+
+        let down = self.down_since.map_or(Duration::ZERO, |t| t.elapsed());
+        if self.fallback.is_none()
+            && down < self.fallback_after
+            && self.unsent.len() < self.buffer_max
+        {
+            return Ok(());
+        }
+        self.send_fallback()
+    }
+
+    // Writes the unsent events to the fallback file, opening it if needed
+    fn send_fallback(&mut self) -> io::Result<()> {
+        let fallback = match &mut self.fallback {
+            Some(fallback) => fallback,
+            None => {
+                let down = self.down_since.map_or(Duration::ZERO, |t| t.elapsed());
+                warn!(
+                    "Output share {} unreachable for {}s, writing to {} until it's back",
+                    self.path.display(),
+                    down.as_secs(),
+                    self.fallback_path.display()
+                );
+                self.fallback.insert(open_append(&self.fallback_path)?)
+            }
+        };
+        fallback.write_all(&self.unsent)?;
+        fallback.flush()?;
+        self.unsent.clear();
+        Ok(())
+    }
+
+    fn reconnect(&mut self) {
+        match OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .and_then(|mut file| {
+                // A line torn by the disconnect is ended, so the events
+                // written again after it stay on lines of their own
+                if file.metadata()?.len() > 0 {
+                    let mut last = [0u8];
+                    file.seek(SeekFrom::End(-1))?;
+                    file.read_exact(&mut last)?;
+                    if last[0] != b'\n' {
+                        file.write_all(b"\n")?;
+                    }
+                }
+                Ok(file)
+            }) {
+            Ok(file) => {
+                if self.down_since.is_some() && self.fallback.is_some() {
+                    info!(
+                        "Output share {} is back; events of the outage are in {}",
+                        self.path.display(),
+                        self.fallback_path.display()
+                    );
+                }
+                self.file = Some(file);
+                self.fallback = None;
+                self.down_since = None;
+            }
+            Err(e) => {
+                if self.backoff == Duration::from_secs(1) {
+                    warn!("Cannot open output share {}: {}", self.path.display(), e);
+                }
+                self.down_since.get_or_insert_with(Instant::now);
+                self.retry_at = Instant::now() + self.backoff;
+                self.backoff = (self.backoff * 2).min(SHARE_MAX_BACKOFF);
+            }
+        }
+    }
+
+    // Moves the framed events to the unsent ones
+    fn take_framed(&mut self) {
+        let unsent = &mut self.unsent;
+        let _ = self.framer.write_with(|bytes| {
+            unsent.extend_from_slice(bytes);
+            Ok(())
+        });
+    }
+
+    // A checkpoint may follow a flush, so events only held in memory for
+    // the share would be lost with the process: they go to the fallback file
+    // now. The flush policy doesn't flush while the share is down.
+    fn flush(&mut self) -> io::Result<()> {
+        self.take_framed();
+        self.send()?;
+        if self.unsent.is_empty() {
+            return Ok(());
+        }
+        self.send_fallback()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()?;
+        match (&self.file, &self.fallback) {
+            (Some(file), _) => file.sync_data(),
+            (None, Some(fallback)) => fallback.get_ref().sync_data(),
+            (None, None) => Ok(()),
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<PathBuf> {
+        self.take_framed();
+        self.send()?;
+        if self.is_down() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "output share is unreachable, nothing to rotate",
+            ));
+        }
+        self.file = None;
+        let rotated = rotated_path(&self.path);
+        let result = std::fs::rename(&self.path, &rotated);
+        self.reconnect();
+        result?;
+        prune(&self.path, &self.retention);
+        Ok(rotated)
+    }
+}
+
+impl Sink for Share {
+    fn send_batch(&mut self, batch: &[Record]) -> io::Result<()> {
+        batch.iter().try_for_each(|r| self.write_event(r.line))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Share::flush(self)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Share::sync(self)
+    }
+
+    fn healthcheck(&mut self) -> io::Result<()> {
+        if self.is_down() {
+            self.send()?;
+        }
+        if self.is_down() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("output share {} is unreachable", self.path.display()),
+            ));
+        }
+        Ok(())
+    }
+
+    // Keeps retrying a share that dropped, even when no events come
+    fn send_if_due(&mut self) -> Option<io::Result<()>> {
+        self.is_down().then(|| self.send())
+    }
+
+    fn rotate(&mut self) -> io::Result<PathBuf> {
+        Share::rotate(self)
+    }
+}
+
+impl Drop for Share {
+    fn drop(&mut self) {
+        // Events still held for an unreachable share would be lost
+        let _ = Share::flush(self);
+    }
+}
+
+// \\server\share\... or //server/share/..., including \\?\UNC\server\share\...
+fn is_unc(path: &str) -> bool {
+    match path.strip_prefix(r"\\?\") {
+        Some(rest) => rest
+            .get(..4)
+            .is_some_and(|p| p.eq_ignore_ascii_case(r"UNC\")),
+        None => path.starts_with(r"\\") || path.starts_with("//"),
+    }
+}
+
+// Channel names like Microsoft-Windows-PowerShell/Operational contain path
+// separators; they become part of a single file or directory name
+fn file_name_safe(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '-',
+            c => c,
+        })
+        .collect()
+}
+
+/// Opens the output named by `output_file`, and the outputs of `routes`
+/// when there are any, each writing events in its shape.
+pub fn create(config: &Config) -> Result<Output, Box<dyn std::error::Error>> {
+    let sink = shaped(
+        open(
+            config.output_file.as_deref().unwrap_or("-"),
+            config.sink.framing,
+            config,
+        )?,
+        Shape::of_sink(&config.sink),
+    );
+    if config.routes.is_empty() {
+        return Ok(Output(sink));
+    }
+    Ok(Output(Box::new(Router::new(sink, config)?)))
+}
+
+/// Opens the sink for one target: a file path, `file://<path>`, `-` for
+/// stdout, or a target with the scheme of a built-in sink such as
+/// `tcp://<host>:<port>`. File paths may contain `{channel}`, `{date}` and
+/// `{hostname}` to partition events. `framing` delimits events of tcp://
+/// and file outputs. Rotated files beyond `retention` are pruned now and
+/// after every rotation.
+pub fn open(
+    target: &str,
+    framing: Framing,
+    config: &Config,
+) -> Result<Box<dyn Sink>, Box<dyn std::error::Error>> {
+    let file = target != "-" && (!target.contains("://") || target.starts_with("file://"));
+    if framing != Framing::Ndjson && !file && !target.starts_with("tcp://") {
+        warn!(
+            "framing only applies to tcp:// and file outputs, writing {} as NDJSON",
+            target
+        );
+    }
+    if config.hash_chain && !file {
+        warn!("hash_chain only applies to file outputs, ignoring");
+    }
+    if target == "-" {
+        return Ok(Box::new(io::stdout()));
+    }
+
+    if let Some((_, factory, _)) = SINKS.iter().find(|(scheme, ..)| target.starts_with(scheme)) {
+        return factory(target, framing, config);
+    }
+    let path = file_path(target)?;
+    if path.contains('{') {
+        if config.journal {
+            warn!("journal is not supported for partitioned output, ignoring");
+        }
+        if config.hash_chain {
+            warn!("hash_chain is not supported for partitioned output, ignoring");
+        }
+        if config.sftp.is_some() {
+            warn!("sftp uploads are not supported for partitioned output, ignoring");
+        }
+        return Ok(Box::new(Partitioned::new(path, framing)?));
+    }
+    if is_unc(path) {
+        if config.journal || config.hash_chain || config.sftp.is_some() {
+            warn!("journal, hash_chain and sftp are not supported for output on a share, ignoring");
+        }
+        return Ok(Box::new(Share::open(PathBuf::from(path), framing, config)?));
+    }
+    let path = PathBuf::from(path);
+    prune(&path, &config.retention);
+    // Opened first: it repairs the output before we append to it
+    let journal = if config.journal {
+        Some(Journal::open(&path)?)
+    } else {
+        None
+    };
+    // After the journal, so it continues from the repaired last line
+    let chain = match config.hash_chain {
+        true if framing != Framing::Ndjson => {
+            warn!("hash_chain needs ndjson framing, ignoring for {}", target);
+            None
+        }
+        true => Some(Chain::resume(&path)?),
+        false => None,
+    };
+    let upload = uploader(&path, config)?;
+    Ok(Box::new(FileSink {
+        file: open_append(&path)?,
+        framer: Framer::new(framing),
+        path,
+        retention: config.retention.clone(),
+        journal,
+        chain,
+        upload,
+    }))
+}
+
+/// The path of a file output target: a plain path or `file://<path>`. Fails
+/// for targets of other schemes, naming the feature for those of sinks that
+/// weren't built.
+pub fn file_path(target: &str) -> Result<&str, Box<dyn std::error::Error>> {
+    if let Some((scheme, feature)) = FEATURE_SCHEMES
+        .iter()
+        .find(|(scheme, _)| target.starts_with(scheme))
+        .filter(|(scheme, _)| !SINKS.iter().any(|(built, ..)| built == scheme))
+    {
+        return Err(format!(
+            "{} output requires a build with the '{}' feature",
+            scheme, feature
+        )
+        .into());
+    }
+    match target.strip_prefix("file://") {
+        // file:///C:/logs/out.ndjson -> C:/logs/out.ndjson
+        Some(p) if p.starts_with('/') && p.get(2..3) == Some(":") => Ok(&p[1..]),
+        Some(p) => Ok(p),
+        None if target.contains("://") => {
+            let mut schemes: Vec<&str> = SINKS.iter().map(|(scheme, ..)| *scheme).collect();
+            schemes.insert(0, "file://");
+            Err(format!(
+                "unsupported output '{}': expected a path, {} or -",
+                target,
+                schemes.join(", ")
+            )
+            .into())
+        }
+        None => Ok(target),
+    }
+}
+
+/// Checks that `target` could be opened as `open` would, without opening
+/// it: nothing is created, rotated, pruned or sent. Network sinks connect
+/// (or sign in) and let go again.
+pub fn probe(target: &str, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    if target == "-" {
+        return Ok(());
+    }
+    if let Some((_, _, probe)) = SINKS.iter().find(|(scheme, ..)| target.starts_with(scheme)) {
+        return probe(target, config);
+    }
+    probe_file(file_path(target)?).map_err(|e| format!("cannot write {}: {}", target, e).into())
+}
+
+/// Whether a file output could be written: an existing file is opened for
+/// appending (and closed untouched), otherwise its directory must exist.
+/// Partitioned paths create their directories, so only the part before the
+/// first placeholder is looked at.
+pub fn probe_file(path: &str) -> io::Result<()> {
+    if let Some((prefix, _)) = path.split_once('{') {
+        let prefix = Path::new(prefix);
+        return if prefix.is_relative() || prefix.ancestors().any(Path::is_dir) {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no such drive or share",
+            ))
+        };
+    }
+    let path = Path::new(path);
+    if path.exists() {
+        return OpenOptions::new().append(true).open(path).map(|_| ());
+    }
+    let dir = match path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        Some(dir) => dir,
+        None => Path::new("."),
+    };
+    if dir.is_dir() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("directory {} does not exist", dir.display()),
+        ))
+    }
+}
+
+// Uploads rotated files when an sftp: section is configured
+pub fn uploader(path: &Path, config: &Config) -> Result<Option<Uploader>, String> {
+    config
+        .sftp
+        .as_ref()
+        .map(|sftp| Uploader::start(path, sftp))
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // The file a file output of this framing holds after `events` were
+    // written and, when `flush`, flushed
+    fn framed(framing: Framing, events: &[JsonValue], flush: bool) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!(
+            "rs-wineventlog-framing-{}-{}.out",
+            std::process::id(),
+            events.len()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let config: Config =
+            serde_json::from_value(json!({ "channels": ["Application"] })).unwrap();
+        let mut sink = open(path.to_str().unwrap(), framing, &config).unwrap();
+        for event in events {
+            let line = event.to_string();
+            sink.send_batch(&[Record { event, line: &line }]).unwrap();
+        }
+        if flush {
+            sink.flush().unwrap();
+        }
+        // The file as it is before the sink is dropped
+        let data = std::fs::read(&path).unwrap();
+        drop(sink);
+        std::fs::remove_file(&path).unwrap();
+        data
+    }
+
+    #[test]
+    fn frames_file_events() {
+        let events = [json!({"EventID": 1}), json!({"EventID": 22})];
+        assert_eq!(
+            framed(Framing::Ndjson, &events, true),
+            b"{\"EventID\":1}\n{\"EventID\":22}\n"
+        );
+        assert_eq!(
+            framed(Framing::JsonArray, &events, true),
+            b"[{\"EventID\":1},{\"EventID\":22}]\n"
+        );
+        let mut prefixed = Vec::new();
+        for line in ["{\"EventID\":1}", "{\"EventID\":22}"] {
+            prefixed.extend_from_slice(&(line.len() as u32).to_be_bytes());
+            prefixed.extend_from_slice(line.as_bytes());
+        }
+        assert_eq!(framed(Framing::LengthPrefixed, &events, true), prefixed);
+    }
+
+    #[test]
+    fn json_arrays_wait_for_a_flush() {
+        let events = [json!({"EventID": 1})];
+        assert!(framed(Framing::JsonArray, &events, false).is_empty());
+    }
+
+    #[test]
+    fn failed_writes_keep_the_array_open() {
+        let mut framer = Framer::new(Framing::JsonArray);
+        framer.push("1").unwrap();
+        let failed = framer.write_with(|_| Err(io::Error::other("down")));
+        assert!(failed.is_err());
+        framer.push("2").unwrap();
+        let mut written = Vec::new();
+        framer
+            .write_with(|bytes| {
+                written.extend_from_slice(bytes);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(written, b"[1,2]\n");
+        assert_eq!(framer.events(), 0);
+    }
+
+    #[test]
+    fn prune_keeps_files_waiting_for_upload() {
+        let dir = std::env::temp_dir().join(format!("rs-wineventlog-prune-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("events.log");
+        let rotated: Vec<PathBuf> = ["20240101T000000", "20240102T000000", "20240103T000000"]
+            .iter()
+            .map(|stamp| dir.join(format!("events.{}.log", stamp)))
+            .collect();
+        for file in &rotated {
+            std::fs::write(file, "{}\n").unwrap();
+        }
+        std::fs::write(
+            dir.join("events.log.uploads"),
+            format!("{}\n", rotated[0].display()),
+        )
+        .unwrap();
+
+        let retention = RetentionConfig {
+            max_files: Some(1),
+            ..Default::default()
+        };
+        prune(&path, &retention);
+        let kept: Vec<bool> = rotated.iter().map(|f| f.exists()).collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(kept, [true, false, true]);
+    }
+}
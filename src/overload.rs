@@ -0,0 +1,221 @@
+use crate::config::ChannelConfig;
+use crate::timestamp::{self, Timezone};
+use chrono::{DateTime, Utc};
+use glob_match::glob_match;
+use serde_json::{Value as JsonValue, json};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Drops are reported at most this often while shedding goes on
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Enforces `max_events_per_sec` across all channels. Channels are ranked by
+/// the priority of their entry in `channels:`, then by its position; the
+/// first is kept longest. Each second, the demand of the second before
+/// decides how many ranks fit under the ceiling; channels ranked below those
+/// are dropped for the whole second, and at most the ceiling is admitted, so
+/// the same load always sheds the same channels.
+pub struct Shedder {
+    ceiling: u64,
+    // Each channels: entry's pattern and rank, in config order
+    patterns: Vec<(String, usize)>,
+    state: Mutex<State>,
+}
+
+struct State {
+    ranks: HashMap<String, usize>,
+    second: Instant,
+    admitted: u64,
+    // Events that arrived this second, per rank
+    demand: Vec<u64>,
+    // Ranks after this one are dropped this second
+    cutoff: usize,
+    // Per channel, since the last summary
+    dropped: BTreeMap<String, u64>,
+    since: DateTime<Utc>,
+    reported: Instant,
+}
+
+impl Shedder {
+    pub fn new(ceiling: u64, channels: &[ChannelConfig]) -> Shedder {
+        let mut order: Vec<usize> = (0..channels.len()).collect();
+        order.sort_by_key(|&i| channels[i].priority);
+        let mut patterns: Vec<(String, usize)> =
+            channels.iter().map(|c| (c.name.clone(), 0)).collect();
+        for (rank, i) in order.into_iter().enumerate() {
+            patterns[i].1 = rank;
+        }
+        Shedder {
+            ceiling,
+            state: Mutex::new(State {
+                ranks: HashMap::new(),
+                second: Instant::now(),
+                admitted: 0,
+                // Channels no pattern matches rank last
+                demand: vec![0; patterns.len() + 1],
+                cutoff: patterns.len(),
+                dropped: BTreeMap::new(),
+                since: Utc::now(),
+                reported: Instant::now(),
+            }),
+            patterns,
+        }
+    }
+
+    /// Whether an event of `channel` may be written; false counts it as dropped.
+    pub fn admit(&self, channel: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.second.elapsed() >= Duration::from_secs(1) {
+            state.next_second(self.ceiling);
+        }
+        let rank = *state
+            .ranks
+            .entry(channel.to_string())
+            .or_insert_with(|| rank(&self.patterns, channel));
+        state.demand[rank] += 1;
+        if rank <= state.cutoff && state.admitted < self.ceiling {
+            state.admitted += 1;
+            return true;
+        }
+        if state.dropped.is_empty() {
+            state.since = Utc::now();
+        }
+        *state.dropped.entry(channel.to_string()).or_default() += 1;
+        false
+    }
+
+    /// A record of the events dropped since the last one, every
+    /// SUMMARY_INTERVAL while dropping (or now, when `now` is set). None
+    /// when nothing was dropped.
+    pub fn summary(&self, now: bool, tz: &Timezone) -> Option<JsonValue> {
+        let mut state = self.state.lock().unwrap();
+        if state.dropped.is_empty() || (!now && state.reported.elapsed() < SUMMARY_INTERVAL) {
+            return None;
+        }
+        state.reported = Instant::now();
+        let dropped = std::mem::take(&mut state.dropped);
+        Some(json!({
+            "record_type": "load_shedding",
+            "since": timestamp::format(state.since, tz),
+            "until": timestamp::format(Utc::now(), tz),
+            "max_events_per_sec": self.ceiling,
+            "dropped_total": dropped.values().sum::<u64>(),
+            "dropped": dropped,
+        }))
+    }
+}
+
+impl State {
+    fn next_second(&mut self, ceiling: u64) {
+        let mut total = 0;
+        self.cutoff = self.demand.len();
+        for (rank, demand) in self.demand.iter().enumerate() {
+            total += demand;
+            if total > ceiling {
+                self.cutoff = rank;
+                break;
+            }
+        }
+        self.demand.iter_mut().for_each(|d| *d = 0);
+        self.admitted = 0;
+        self.second = Instant::now();
+    }
+}
+
+// Rank of the first channels: entry naming the channel, as resolve_channels
+// picks the entry whose settings a channel uses
+fn rank(patterns: &[(String, usize)], channel: &str) -> usize {
+    patterns
+        .iter()
+        .find(|(p, _)| p == channel || glob_match(p, channel))
+        .map_or(patterns.len(), |(_, rank)| *rank)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn shedder(ceiling: u64) -> Shedder {
+        let config: Config = serde_json::from_value(json!({
+            "channels": [
+                { "name": "Application", "priority": "low" },
+                "System",
+                { "name": "Security", "priority": "high" },
+                "Microsoft-Windows-*/Operational",
+            ],
+        }))
+        .unwrap();
+        Shedder::new(ceiling, &config.channels)
+    }
+
+    // Moves the shedder on to the next second
+    fn tick(shedder: &Shedder) {
+        let mut state = shedder.state.lock().unwrap();
+        state.second = Instant::now() - Duration::from_secs(1);
+    }
+
+    fn admit(shedder: &Shedder, channel: &str, events: usize) -> usize {
+        (0..events).filter(|_| shedder.admit(channel)).count()
+    }
+
+    #[test]
+    fn ranks_by_priority_then_position() {
+        let shedder = shedder(10);
+        let rank = |channel| rank(&shedder.patterns, channel);
+        assert_eq!(rank("Security"), 0);
+        assert_eq!(rank("System"), 1);
+        assert_eq!(rank("Microsoft-Windows-Sysmon/Operational"), 2);
+        assert_eq!(rank("Application"), 3);
+        assert_eq!(rank("Setup"), 4);
+    }
+
+    #[test]
+    fn drops_low_priority_channels_first() {
+        let shedder = shedder(10);
+        // The first second has no demand to go by: the ceiling alone applies
+        assert_eq!(admit(&shedder, "Application", 6), 6);
+        assert_eq!(admit(&shedder, "System", 6), 4);
+        assert_eq!(admit(&shedder, "Security", 6), 0);
+
+        // Security and System alone already exceed it, so Application gets
+        // nothing next second, however early it comes
+        tick(&shedder);
+        assert_eq!(admit(&shedder, "Application", 3), 0);
+        assert_eq!(admit(&shedder, "Security", 6), 6);
+        assert_eq!(admit(&shedder, "System", 6), 4);
+        assert_eq!(admit(&shedder, "Setup", 1), 0);
+
+        // Demand under the ceiling lets every channel through again
+        tick(&shedder);
+        assert_eq!(admit(&shedder, "Security", 2), 2);
+        tick(&shedder);
+        assert_eq!(admit(&shedder, "Application", 3), 3);
+        assert_eq!(admit(&shedder, "Setup", 1), 1);
+    }
+
+    #[test]
+    fn reports_drops_per_channel() {
+        let shedder = shedder(2);
+        let tz = Timezone::Utc;
+        assert_eq!(shedder.summary(true, &tz), None);
+
+        assert_eq!(admit(&shedder, "Security", 2), 2);
+        assert_eq!(admit(&shedder, "System", 3), 0);
+        assert_eq!(admit(&shedder, "Application", 1), 0);
+        // Not yet due, unless asked for now
+        assert_eq!(shedder.summary(false, &tz), None);
+        let summary = shedder.summary(true, &tz).unwrap();
+        assert_eq!(summary["record_type"], "load_shedding");
+        assert_eq!(summary["max_events_per_sec"], 2);
+        assert_eq!(summary["dropped_total"], 4);
+        assert_eq!(summary["dropped"], json!({ "Application": 1, "System": 3 }));
+
+        // Counts start over after each summary
+        assert_eq!(shedder.summary(true, &tz), None);
+        admit(&shedder, "System", 1);
+        let summary = shedder.summary(true, &tz).unwrap();
+        assert_eq!(summary["dropped"], json!({ "System": 1 }));
+    }
+}
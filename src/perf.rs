@@ -0,0 +1,173 @@
+use crate::stats::Stats;
+use log::{info, warn};
+use std::process::Command;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use windows::Win32::Foundation::HANDLE;
+use windows::Win32::System::Performance::*;
+use windows::core::{GUID, w};
+
+const PROVIDER_GUID: GUID = GUID::from_u128(0x5b0f3c2e_8f1d_4c6a_9e2b_7a4d1c3e9f60);
+const COUNTERSET_GUID: GUID = GUID::from_u128(0xa7e2d4b1_3c5f_4e8a_b9d6_2f1c0e8a7b43);
+
+// Counter types from winperf.h
+const PERF_COUNTER_BULK_COUNT: u32 = 0x1041_0500;
+const PERF_COUNTER_LARGE_RAWCOUNT: u32 = 0x0001_0100;
+
+// Counter IDs; must match the manifest below
+const EVENTS_PER_SEC: u32 = 1;
+const EVENTS_TOTAL: u32 = 2;
+const QUEUE_DEPTH: u32 = 3;
+const SINK_ERRORS: u32 = 4;
+const COLLECTION_LAG: u32 = 5;
+
+// Registered with `lodctr /m:` so PerfMon and agents know the counter names
+const MANIFEST: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<instrumentationManifest xmlns="http://schemas.microsoft.com/win/2004/08/events">
+  <instrumentation>
+    <counters xmlns="http://schemas.microsoft.com/win/2005/12/counters" schemaVersion="2.0">
+      <provider providerName="rs-wineventlog" providerType="userMode"
+                providerGuid="{5b0f3c2e-8f1d-4c6a-9e2b-7a4d1c3e9f60}"
+                applicationIdentity="{exe}">
+        <counterSet guid="{a7e2d4b1-3c5f-4e8a-b9d6-2f1c0e8a7b43}" uri="rs-wineventlog.Collector"
+                    name="rs-wineventlog" description="Windows Event Log collector" instances="single">
+          <counter id="1" uri="rs-wineventlog.Collector.EventsPerSec" name="Events/sec"
+                   description="Events written to the output per second"
+                   type="perf_counter_bulk_count" detailLevel="standard"/>
+          <counter id="2" uri="rs-wineventlog.Collector.EventsTotal" name="Events Total"
+                   description="Events written to the output since the collector started"
+                   type="perf_counter_large_rawcount" detailLevel="standard"/>
+          <counter id="3" uri="rs-wineventlog.Collector.QueueDepth" name="Queue Depth"
+                   description="Events read from channels but not yet written to the output"
+                   type="perf_counter_large_rawcount" detailLevel="standard"/>
+          <counter id="4" uri="rs-wineventlog.Collector.SinkErrors" name="Sink Errors"
+                   description="Failed writes to the output"
+                   type="perf_counter_large_rawcount" detailLevel="standard"/>
+          <counter id="5" uri="rs-wineventlog.Collector.CollectionLag" name="Collection Lag (ms)"
+                   description="Age of the newest written event in the channel furthest behind"
+                   type="perf_counter_large_rawcount" detailLevel="standard"/>
+        </counterSet>
+      </provider>
+    </counters>
+  </instrumentation>
+</instrumentationManifest>
+"#;
+
+// PerfSetCounterSetInfo takes the set header immediately followed by its counters
+#[repr(C)]
+struct CounterSetTemplate {
+    info: PERF_COUNTERSET_INFO,
+    counters: [PERF_COUNTER_INFO; 5],
+}
+
+/// Publishes the collector's counters to PerfLib, refreshed every second
+/// until shutdown. Without the registered manifest nobody can read them, so
+/// failures only warn.
+pub fn spawn(stats: Arc<Stats>, shutdown: Arc<AtomicBool>) {
+    thread::spawn(move || unsafe {
+        let mut provider = HANDLE::default();
+        if PerfStartProviderEx(&PROVIDER_GUID, None, &mut provider) != 0 {
+            warn!("Performance counters unavailable: cannot start provider");
+            return;
+        }
+
+        let counter = |id, kind, offset| PERF_COUNTER_INFO {
+            CounterId: id,
+            Type: kind,
+            Size: 8,
+            DetailLevel: PERF_DETAIL_NOVICE.0,
+            Offset: offset,
+            ..Default::default()
+        };
+        let mut template = CounterSetTemplate {
+            info: PERF_COUNTERSET_INFO {
+                CounterSetGuid: COUNTERSET_GUID,
+                ProviderGuid: PROVIDER_GUID,
+                NumCounters: 5,
+                InstanceType: PERF_COUNTERSET_SINGLE_INSTANCE,
+            },
+            counters: [
+                counter(EVENTS_PER_SEC, PERF_COUNTER_BULK_COUNT, 0),
+                counter(EVENTS_TOTAL, PERF_COUNTER_LARGE_RAWCOUNT, 8),
+                counter(QUEUE_DEPTH, PERF_COUNTER_LARGE_RAWCOUNT, 16),
+                counter(SINK_ERRORS, PERF_COUNTER_LARGE_RAWCOUNT, 24),
+                counter(COLLECTION_LAG, PERF_COUNTER_LARGE_RAWCOUNT, 32),
+            ],
+        };
+
+        let instance = if PerfSetCounterSetInfo(
+            provider,
+            &mut template.info,
+            std::mem::size_of::<CounterSetTemplate>() as u32,
+        ) == 0
+        {
+            PerfCreateInstance(provider, &COUNTERSET_GUID, w!("_Default"), 0)
+        } else {
+            std::ptr::null_mut()
+        };
+        if instance.is_null() {
+            warn!("Performance counters unavailable: cannot create counter set");
+            let _ = PerfStopProvider(provider);
+            return;
+        }
+
+        while !shutdown.load(Ordering::SeqCst) {
+            let total = stats.total();
+            // Events/sec is a rate PerfLib derives from the running total
+            PerfSetULongLongCounterValue(provider, instance, EVENTS_PER_SEC, total);
+            PerfSetULongLongCounterValue(provider, instance, EVENTS_TOTAL, total);
+            PerfSetULongLongCounterValue(provider, instance, QUEUE_DEPTH, stats.queued());
+            PerfSetULongLongCounterValue(provider, instance, SINK_ERRORS, stats.write_errors());
+            PerfSetULongLongCounterValue(
+                provider,
+                instance,
+                COLLECTION_LAG,
+                stats.collection_lag().as_millis() as u64,
+            );
+            thread::sleep(Duration::from_secs(1));
+        }
+
+        PerfDeleteInstance(provider, instance);
+        PerfStopProvider(provider);
+    });
+}
+
+/// Registers the counter manifest with `lodctr`, writing it next to the binary.
+pub fn register() -> Result<(), Box<dyn std::error::Error>> {
+    let exe = std::env::current_exe()?;
+    let manifest = exe.with_extension("man");
+    let name = exe
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    std::fs::write(&manifest, MANIFEST.replace("{exe}", &name))?;
+
+    let dir = exe.parent().ok_or("executable has no parent directory")?;
+    let status = Command::new("lodctr")
+        .arg(format!("/m:{}", manifest.display()))
+        .arg(dir)
+        .status()?;
+    if !status.success() {
+        return Err(format!("lodctr failed with {}", status).into());
+    }
+    info!(
+        "Registered performance counters from {}",
+        manifest.display()
+    );
+    Ok(())
+}
+
+/// Removes the counter manifest registered by `register`.
+pub fn unregister() -> Result<(), Box<dyn std::error::Error>> {
+    let manifest = std::env::current_exe()?.with_extension("man");
+    let status = Command::new("unlodctr")
+        .arg(format!("/m:{}", manifest.display()))
+        .status()?;
+    if !status.success() {
+        return Err(format!("unlodctr failed with {}", status).into());
+    }
+    let _ = std::fs::remove_file(&manifest);
+    Ok(())
+}
@@ -1,8 +1,11 @@
+use crate::config::{LogonType, RunAsConfig};
 use std::ffi::OsStr;
 use std::os::windows::prelude::OsStrExt;
+use windows::Win32::Foundation::{CloseHandle, HANDLE};
+use windows::Win32::Security::*;
 use windows::Win32::UI::Shell::ShellExecuteW;
 use windows::Win32::UI::WindowsAndMessaging::SW_SHOWNORMAL;
-use windows::core::PCWSTR;
+use windows::core::{HSTRING, PCWSTR};
 
 pub fn try_elevate() -> Result<(), Box<dyn std::error::Error>> {
     let exe = std::env::current_exe()?;
@@ -53,3 +56,55 @@ pub fn try_elevate() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 }
+
+/// The calling thread acts as another account until this is dropped.
+pub struct Impersonation {
+    token: HANDLE,
+}
+
+impl Impersonation {
+    pub fn logon(run_as: &RunAsConfig) -> Result<Impersonation, Box<dyn std::error::Error>> {
+        // DOMAIN\user, user@domain (UPN, no separate domain) or a local user
+        let (user, domain) = match run_as.user.split_once('\\') {
+            Some((domain, user)) => (user, Some(domain)),
+            None if run_as.user.contains('@') => (run_as.user.as_str(), None),
+            None => (run_as.user.as_str(), Some(".")),
+        };
+        let logon_type = match run_as.logon_type {
+            LogonType::Network => LOGON32_LOGON_NETWORK,
+            LogonType::Batch => LOGON32_LOGON_BATCH,
+            LogonType::Service => LOGON32_LOGON_SERVICE,
+            LogonType::Interactive => LOGON32_LOGON_INTERACTIVE,
+        };
+
+        let mut token = HANDLE::default();
+        unsafe {
+            let domain = domain.map(HSTRING::from);
+            LogonUserW(
+                &HSTRING::from(user),
+                domain
+                    .as_ref()
+                    .map_or(PCWSTR::null(), |d| PCWSTR(d.as_ptr())),
+                &HSTRING::from(run_as.password.as_str()),
+                logon_type,
+                LOGON32_PROVIDER_DEFAULT,
+                &mut token,
+            )
+            .map_err(|e| format!("cannot log on as {}: {}", run_as.user, e))?;
+            if let Err(e) = ImpersonateLoggedOnUser(token) {
+                let _ = CloseHandle(token);
+                return Err(format!("cannot impersonate {}: {}", run_as.user, e).into());
+            }
+        }
+        Ok(Impersonation { token })
+    }
+}
+
+impl Drop for Impersonation {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = RevertToSelf();
+            let _ = CloseHandle(self.token);
+        }
+    }
+}
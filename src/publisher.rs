@@ -0,0 +1,286 @@
+use crate::hub;
+use log::warn;
+use roxmltree::Document;
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use windows::Win32::Globalization::LocaleNameToLCID;
+use windows::Win32::System::EventLog::*;
+use windows::core::PCWSTR;
+
+// (provider, event ID, version) -> template; misses are cached too since
+// enumerating a publisher's events is expensive
+type Templates = HashMap<(String, u16, u8), Option<Arc<Template>>>;
+static TEMPLATES: OnceLock<Mutex<Templates>> = OnceLock::new();
+
+/// The parameters an event template declares, in order: their names and
+/// input types (`win:UInt32`, `win:Boolean`, ...).
+#[derive(Default)]
+pub struct Template {
+    pub names: Vec<String>,
+    pub types: Vec<String>,
+}
+
+/// The publisher's template for this event, if it has one.
+pub fn template(event: &JsonValue, provider: &str) -> Option<Arc<Template>> {
+    let id = hub::event_id(event)?;
+    let version = event
+        .get("Version")
+        .and_then(|v| v.as_str())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+
+    let key = (provider.to_string(), id as u16, version);
+    TEMPLATES
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .entry(key)
+        .or_insert_with(|| unsafe { event_template(provider, id as u16, version) }.map(Arc::new))
+        .clone()
+}
+
+/// Replaces an `EventData.Data` array of unnamed values with named fields
+/// when the publisher's template for this event declares the parameter names.
+pub fn name_event_data(event: &mut JsonValue, provider: &str) {
+    if !matches!(event.pointer("/EventData/Data"), Some(JsonValue::Array(_))) {
+        return;
+    }
+    if let Some(template) = template(event, provider) {
+        apply_template(event, &template.names);
+    }
+}
+
+/// Names an `EventData.Data` array after a template's parameter names,
+/// unless the template has fewer names than there are values.
+pub fn apply_template(event: &mut JsonValue, names: &[String]) {
+    let Some(data) = event.get_mut("EventData").and_then(|d| d.as_object_mut()) else {
+        return;
+    };
+    let fits =
+        matches!(data.get("Data"), Some(JsonValue::Array(values)) if values.len() <= names.len());
+    if names.is_empty() || !fits {
+        return;
+    }
+    if let Some(JsonValue::Array(values)) = data.remove("Data") {
+        for (name, value) in names.iter().zip(values) {
+            data.insert(name.clone(), value);
+        }
+    }
+}
+
+/// Turns the EventData values named in a template into the JSON types of
+/// their input types: integers and floats into numbers, booleans into
+/// booleans. Hex, strings, GUIDs, SIDs, times and values that don't parse
+/// as their type stay strings.
+pub fn type_event_data(event: &mut JsonValue, names: &[String], types: &[String]) {
+    let Some(data) = event.get_mut("EventData").and_then(|d| d.as_object_mut()) else {
+        return;
+    };
+    for (name, in_type) in names.iter().zip(types) {
+        let Some(value) = data.get_mut(name) else {
+            continue;
+        };
+        let Some(text) = value.as_str().map(str::trim) else {
+            continue;
+        };
+        let typed = match in_type.as_str() {
+            "win:Int8" | "win:Int16" | "win:Int32" | "win:Int64" => {
+                text.parse::<i64>().ok().map(JsonValue::from)
+            }
+            "win:UInt8" | "win:UInt16" | "win:UInt32" | "win:UInt64" => {
+                text.parse::<u64>().ok().map(JsonValue::from)
+            }
+            "win:Float" | "win:Double" => text
+                .parse::<f64>()
+                .ok()
+                .filter(|f| f.is_finite())
+                .map(JsonValue::from),
+            "win:Boolean" => match text {
+                "true" | "1" => Some(JsonValue::Bool(true)),
+                "false" | "0" => Some(JsonValue::Bool(false)),
+                _ => None,
+            },
+            _ => None,
+        };
+        if let Some(typed) = typed {
+            *value = typed;
+        }
+    }
+}
+
+/// Maps locale names like `de-DE` to the LCIDs the metadata API takes,
+/// skipping (and warning about) names Windows doesn't know.
+pub fn locale_ids(names: &[String]) -> Vec<u32> {
+    names
+        .iter()
+        .filter_map(|name| {
+            let wide: Vec<u16> = name.encode_utf16().chain(std::iter::once(0)).collect();
+            match unsafe { LocaleNameToLCID(PCWSTR(wide.as_ptr()), 0) } {
+                0 => {
+                    warn!("Unknown locale '{}', ignoring", name);
+                    None
+                }
+                lcid => Some(lcid),
+            }
+        })
+        .collect()
+}
+
+unsafe fn event_template(provider: &str, id: u16, version: u8) -> Option<Template> {
+    unsafe {
+        let provider_wide: Vec<u16> = provider.encode_utf16().chain(std::iter::once(0)).collect();
+        let metadata =
+            EvtOpenPublisherMetadata(None, PCWSTR(provider_wide.as_ptr()), None, 0, 0).ok()?;
+        let events = match EvtOpenEventMetadataEnum(metadata, 0) {
+            Ok(e) => e,
+            Err(_) => {
+                let _ = EvtClose(metadata);
+                return None;
+            }
+        };
+
+        let mut template = None;
+        while let Ok(event) = EvtNextEventMetadata(events, 0) {
+            // The metadata ID carries qualifier bits above the 16-bit event ID
+            let matches = property(event, EventMetadataEventID)
+                .is_some_and(|p| variant(&p).UInt32Val as u16 == id)
+                && property(event, EventMetadataEventVersion)
+                    .is_some_and(|p| variant(&p).ByteVal == version);
+            if matches {
+                template = property(event, EventMetadataEventTemplate)
+                    .and_then(|p| variant(&p).StringVal.to_string().ok());
+            }
+            let _ = EvtClose(event);
+            if matches {
+                break;
+            }
+        }
+
+        let _ = EvtClose(events);
+        let _ = EvtClose(metadata);
+        parse_template(&template?)
+    }
+}
+
+// <template><data name="SubjectUserSid" inType="win:SID" .../>...</template>
+pub fn parse_template(template: &str) -> Option<Template> {
+    let doc = Document::parse(template).ok()?;
+    let mut parsed = Template::default();
+    for data in doc
+        .root_element()
+        .children()
+        .filter(|n| n.has_tag_name("data"))
+    {
+        let Some(name) = data.attribute("name") else {
+            continue;
+        };
+        parsed.names.push(name.to_string());
+        parsed
+            .types
+            .push(data.attribute("inType").unwrap_or_default().to_string());
+    }
+    (!parsed.names.is_empty()).then_some(parsed)
+}
+
+/// An event metadata property, returned as u64 words so the buffer is
+/// aligned for EVT_VARIANT.
+pub unsafe fn property(event: EVT_HANDLE, id: EVT_EVENT_METADATA_PROPERTY_ID) -> Option<Vec<u64>> {
+    unsafe {
+        let mut used = 0u32;
+        let _ = EvtGetEventMetadataProperty(event, id, 0, 0, None, &mut used);
+        if used == 0 {
+            return None;
+        }
+        let mut buffer = vec![0u64; (used as usize).div_ceil(8)];
+        EvtGetEventMetadataProperty(
+            event,
+            id,
+            0,
+            used,
+            Some(buffer.as_mut_ptr() as *mut EVT_VARIANT),
+            &mut used,
+        )
+        .ok()?;
+        Some(buffer)
+    }
+}
+
+pub unsafe fn variant(buffer: &[u64]) -> &EVT_VARIANT_0 {
+    unsafe { &(*(buffer.as_ptr() as *const EVT_VARIANT)).Anonymous }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const TEMPLATE: &str = r#"<template xmlns="http://schemas.microsoft.com/win/2004/08/events"><data name="TargetUserSid" inType="win:SID" outType="xs:string"/><data name="LogonType" inType="win:UInt32" outType="xs:string"/><data name="Elevated" inType="win:Boolean" outType="xs:boolean"/><data name="Comment" inType="win:UnicodeString" outType="xs:string"/><data name="Delta" inType="win:Int32" outType="xs:int"/><data name="Ratio" inType="win:Double" outType="xs:double"/></template>"#;
+
+    fn names(template: &Template) -> Vec<&str> {
+        template.names.iter().map(String::as_str).collect()
+    }
+
+    #[test]
+    fn parses_template_names_and_types() {
+        let template = parse_template(TEMPLATE).unwrap();
+        assert_eq!(
+            names(&template),
+            [
+                "TargetUserSid",
+                "LogonType",
+                "Elevated",
+                "Comment",
+                "Delta",
+                "Ratio"
+            ]
+        );
+        assert_eq!(template.types[1], "win:UInt32");
+        assert_eq!(template.types[3], "win:UnicodeString");
+        assert!(parse_template("<template></template>").is_none());
+        assert!(parse_template("not xml").is_none());
+    }
+
+    #[test]
+    fn types_event_data_by_template() {
+        let template = parse_template(TEMPLATE).unwrap();
+        let mut event = json!({
+            "EventData": {
+                "Data": ["S-1-5-18", "3", "1", "true", "-5", "0.5"],
+            },
+        });
+        apply_template(&mut event, &template.names);
+        type_event_data(&mut event, &template.names, &template.types);
+        assert_eq!(
+            event["EventData"],
+            json!({
+                "TargetUserSid": "S-1-5-18",
+                "LogonType": 3,
+                "Elevated": true,
+                "Comment": "true",
+                "Delta": -5,
+                "Ratio": 0.5,
+            })
+        );
+    }
+
+    #[test]
+    fn keeps_values_that_dont_parse_as_their_type() {
+        let template = parse_template(TEMPLATE).unwrap();
+        let mut event = json!({
+            "EventData": { "LogonType": "%%2313", "Elevated": "%%1842", "Ratio": "NaN" },
+        });
+        type_event_data(&mut event, &template.names, &template.types);
+        assert_eq!(
+            event["EventData"],
+            json!({ "LogonType": "%%2313", "Elevated": "%%1842", "Ratio": "NaN" })
+        );
+    }
+
+    #[test]
+    fn leaves_data_unnamed_when_template_is_short() {
+        let mut event = json!({ "EventData": { "Data": ["a", "b"] } });
+        apply_template(&mut event, &["First".to_string()]);
+        assert_eq!(event["EventData"], json!({ "Data": ["a", "b"] }));
+    }
+}
@@ -0,0 +1,532 @@
+use crate::config::Level;
+use quick_xml::escape::escape;
+use roxmltree::Document;
+
+/// The Level values of `--level`: a level (`warning`), a list
+/// (`error,warning`) or a level with `+` for it and everything more severe
+/// (`warning+`).
+#[derive(Clone, Debug)]
+pub struct Levels(pub Vec<u8>);
+
+pub fn parse_levels(value: &str) -> Result<Levels, String> {
+    let mut levels = Vec::new();
+    for part in value.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (name, and_above) = match part.strip_suffix('+') {
+            Some(name) => (name, true),
+            None => (part, false),
+        };
+        let level = match name.to_lowercase().as_str() {
+            "critical" | "1" => Level::Critical,
+            "error" | "2" => Level::Error,
+            "warning" | "3" => Level::Warning,
+            "information" | "info" | "4" => Level::Information,
+            "verbose" | "5" => Level::Verbose,
+            _ => {
+                return Err(format!(
+                    "unknown level '{}': expected critical, error, warning, information or verbose",
+                    name
+                ));
+            }
+        };
+        for candidate in [
+            Level::Critical,
+            Level::Error,
+            Level::Warning,
+            Level::Information,
+            Level::Verbose,
+        ] {
+            if candidate == level || (and_above && candidate < level) {
+                levels.extend(values(candidate));
+            }
+        }
+    }
+    levels.sort();
+    levels.dedup();
+    if levels.is_empty() {
+        return Err("no level given".to_string());
+    }
+    Ok(Levels(levels))
+}
+
+// Level values as events carry them; LogAlways (0) shows as Information
+fn values(level: Level) -> &'static [u8] {
+    match level {
+        Level::Critical => &[1],
+        Level::Error => &[2],
+        Level::Warning => &[3],
+        Level::Information => &[0, 4],
+        Level::Verbose => &[5],
+    }
+}
+
+/// A `--data` condition: `Name=value`.
+pub fn parse_data(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((name, data)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), data.to_string()))
+        }
+        _ => Err(format!("expected Name=value, got '{}'", value)),
+    }
+}
+
+/// Compiles the query flags into an Event Log XPath query, e.g.
+/// `*[System[(EventID=4624 or EventID=4625) and (Level=2 or Level=3)] and
+/// EventData[Data[@Name='TargetUserName']='admin']]`. Values within a flag
+/// are alternatives, the flags all have to match; `--data` names given
+/// more than once are alternatives too. None when no flag is set.
+pub fn xpath(
+    event_ids: &[u32],
+    levels: Option<&Levels>,
+    providers: &[String],
+    data: &[(String, String)],
+) -> Result<Option<String>, String> {
+    let any = |terms: Vec<String>| format!("({})", terms.join(" or "));
+
+    let mut system = Vec::new();
+    if !providers.is_empty() {
+        let names = providers
+            .iter()
+            .map(|p| Ok(format!("@Name={}", literal(p)?)))
+            .collect::<Result<Vec<_>, String>>()?;
+        system.push(format!("Provider[{}]", names.join(" or ")));
+    }
+    if !event_ids.is_empty() {
+        system.push(any(event_ids
+            .iter()
+            .map(|id| format!("EventID={}", id))
+            .collect()));
+    }
+    if let Some(Levels(levels)) = levels {
+        system.push(any(levels.iter().map(|l| format!("Level={}", l)).collect()));
+    }
+
+    // Conditions on the same name are alternatives, in the order first given
+    let mut names: Vec<&str> = Vec::new();
+    for (name, _) in data {
+        if !names.contains(&name.as_str()) {
+            names.push(name);
+        }
+    }
+    let mut event_data = Vec::new();
+    for name in names {
+        let values = data
+            .iter()
+            .filter(|(n, _)| n == name)
+            .map(|(_, value)| {
+                Ok(format!(
+                    "Data[@Name={}]={}",
+                    literal(name)?,
+                    literal(value)?
+                ))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        event_data.push(any(values));
+    }
+
+    let mut parts = Vec::new();
+    if !system.is_empty() {
+        parts.push(format!("System[{}]", system.join(" and ")));
+    }
+    if !event_data.is_empty() {
+        parts.push(format!("EventData[{}]", event_data.join(" and ")));
+    }
+    Ok((!parts.is_empty()).then(|| format!("*[{}]", parts.join(" and "))))
+}
+
+// A quoted XPath string; XPath 1.0 has no escapes, so a value can't hold
+// both kinds of quote
+fn literal(value: &str) -> Result<String, String> {
+    if !value.contains('\'') {
+        Ok(format!("'{}'", value))
+    } else if !value.contains('"') {
+        Ok(format!("\"{}\"", value))
+    } else {
+        Err(format!(
+            "'{}' holds both ' and \", which an XPath query can't match",
+            value
+        ))
+    }
+}
+
+/// The query a channel is read with: `base` (every event when None) less
+/// the events any of the `suppress` XPath queries match. With something to
+/// suppress that is a structured <QueryList> query, which the Event Log
+/// applies as well; a structured `base` gets them for each channel it
+/// selects from.
+pub fn channel_query(channel: &str, base: Option<&str>, suppress: &[String]) -> Option<String> {
+    if suppress.is_empty() {
+        return base.map(str::to_string);
+    }
+    if let Some(base) = base.filter(|b| is_structured(b)) {
+        // Already checked when the channels were resolved
+        return add_suppress(base, suppress).ok();
+    }
+    let path = escape(channel);
+    let mut xml = format!(
+        "<QueryList><Query Id=\"0\" Path=\"{}\"><Select Path=\"{}\">{}</Select>",
+        path,
+        path,
+        escape(base.unwrap_or("*"))
+    );
+    for query in suppress {
+        xml.push_str(&format!(
+            "<Suppress Path=\"{}\">{}</Suppress>",
+            path,
+            escape(query)
+        ));
+    }
+    xml.push_str("</Query></QueryList>");
+    Some(xml)
+}
+
+/// Whether a query is a structured <QueryList> rather than XPath.
+pub fn is_structured(query: &str) -> bool {
+    query.trim_start().starts_with('<')
+}
+
+/// The channels a structured query selects from, each once. Fails on a
+/// query that isn't a <QueryList> or a Select without a channel.
+pub fn select_paths(query: &str) -> Result<Vec<String>, String> {
+    let doc = Document::parse(query).map_err(|e| format!("invalid structured query: {}", e))?;
+    if !doc.root_element().has_tag_name("QueryList") {
+        return Err("a structured query must be a <QueryList>".to_string());
+    }
+    let mut paths = Vec::new();
+    for query in doc.descendants().filter(|n| n.has_tag_name("Query")) {
+        for select in query.children().filter(|n| n.has_tag_name("Select")) {
+            let path = select
+                .attribute("Path")
+                .or(query.attribute("Path"))
+                .ok_or("a <Select> of the structured query names no channel (Path)")?;
+            if !paths.iter().any(|p: &String| p.eq_ignore_ascii_case(path)) {
+                paths.push(path.to_string());
+            }
+        }
+    }
+    if paths.is_empty() {
+        return Err("the structured query selects nothing".to_string());
+    }
+    Ok(paths)
+}
+
+// Adds the suppress queries to every <Query> of a structured query, once for
+// each channel it selects from
+fn add_suppress(base: &str, suppress: &[String]) -> Result<String, String> {
+    let doc = Document::parse(base).map_err(|e| e.to_string())?;
+    let mut xml = base.to_string();
+    let queries: Vec<_> = doc
+        .descendants()
+        .filter(|n| n.has_tag_name("Query"))
+        .collect();
+    // From the last, so the positions of the others stay the same
+    for query in queries.iter().rev() {
+        let mut paths: Vec<&str> = Vec::new();
+        for select in query.children().filter(|n| n.has_tag_name("Select")) {
+            if let Some(path) = select.attribute("Path").or(query.attribute("Path"))
+                && !paths.contains(&path)
+            {
+                paths.push(path);
+            }
+        }
+        let mut added = String::new();
+        for path in paths {
+            for query in suppress {
+                added.push_str(&format!(
+                    "<Suppress Path=\"{}\">{}</Suppress>",
+                    escape(path),
+                    escape(query)
+                ));
+            }
+        }
+        let end = query.range().end;
+        // Before </Query>, or replacing the / of an empty <Query ... />
+        match xml[..end].rfind("</") {
+            Some(close) if close > query.range().start => xml.insert_str(close, &added),
+            _ => {
+                let slash = xml[..end].rfind('/').ok_or("malformed <Query>")?;
+                xml.replace_range(slash..end, &format!(">{}</Query>", added));
+            }
+        }
+    }
+    Ok(xml)
+}
+
+/// Suppress queries for a channel's `include_event_ids` (everything else is
+/// dropped) and `exclude_event_ids`. Consecutive IDs become ranges, which
+/// keeps long lists within what the Event Log accepts in one query.
+pub fn event_id_suppress(include: &[u32], exclude: &[u32]) -> Vec<String> {
+    let mut suppress = Vec::new();
+    if !include.is_empty() {
+        let outside: Vec<String> = ranges(include)
+            .into_iter()
+            .map(|(first, last)| {
+                if first == last {
+                    format!("EventID!={}", first)
+                } else {
+                    format!("(EventID<{} or EventID>{})", first, last)
+                }
+            })
+            .collect();
+        suppress.push(format!("*[System[{}]]", outside.join(" and ")));
+    }
+    if !exclude.is_empty() {
+        let inside: Vec<String> = ranges(exclude)
+            .into_iter()
+            .map(|(first, last)| {
+                if first == last {
+                    format!("EventID={}", first)
+                } else {
+                    format!("(EventID>={} and EventID<={})", first, last)
+                }
+            })
+            .collect();
+        suppress.push(format!("*[System[{}]]", inside.join(" or ")));
+    }
+    suppress
+}
+
+/// The suppress query for a channel's `min_level`: events less severe than
+/// it, LogAlways (0) counting as Information and levels above Verbose as
+/// Verbose. None for Verbose, which keeps everything.
+pub fn level_suppress(min: Level) -> Option<String> {
+    let most = match min {
+        Level::Critical => 1,
+        Level::Error => 2,
+        Level::Warning => 3,
+        Level::Information => 4,
+        Level::Verbose => return None,
+    };
+    Some(if min < Level::Information {
+        format!("*[System[Level=0 or Level>{}]]", most)
+    } else {
+        format!("*[System[Level>{}]]", most)
+    })
+}
+
+// Sorted runs of consecutive IDs, as (first, last)
+fn ranges(ids: &[u32]) -> Vec<(u32, u32)> {
+    let mut ids = ids.to_vec();
+    ids.sort_unstable();
+    ids.dedup();
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for id in ids {
+        match ranges.last_mut() {
+            Some((_, last)) if *last + 1 == id => *last = id,
+            _ => ranges.push((id, id)),
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn levels_and_above() {
+        assert_eq!(parse_levels("warning+").unwrap().0, [1, 2, 3]);
+        assert_eq!(parse_levels("critical+").unwrap().0, [1]);
+        // LogAlways (0) counts as information
+        assert_eq!(parse_levels("info+").unwrap().0, [0, 1, 2, 3, 4]);
+        assert_eq!(parse_levels("Error, 3").unwrap().0, [2, 3]);
+        assert_eq!(parse_levels("verbose,error+").unwrap().0, [1, 2, 5]);
+        assert!(parse_levels("debug").is_err());
+        assert!(parse_levels(" , ").is_err());
+    }
+
+    #[test]
+    fn data_conditions() {
+        assert_eq!(
+            parse_data("TargetUserName=a=b").unwrap(),
+            ("TargetUserName".to_string(), "a=b".to_string())
+        );
+        assert!(parse_data("=admin").is_err());
+        assert!(parse_data("admin").is_err());
+    }
+
+    #[test]
+    fn literals_pick_the_other_quote() {
+        assert_eq!(literal("admin").unwrap(), "'admin'");
+        assert_eq!(literal("O'Brien").unwrap(), "\"O'Brien\"");
+        assert!(literal("'\"").is_err());
+    }
+
+    #[test]
+    fn xpath_without_flags_is_none() {
+        assert_eq!(xpath(&[], None, &[], &[]).unwrap(), None);
+    }
+
+    #[test]
+    fn xpath_combines_flags() {
+        let query = xpath(
+            &[4624, 4625],
+            Some(&parse_levels("error+").unwrap()),
+            &["Microsoft-Windows-Security-Auditing".to_string()],
+            &data(&[
+                ("TargetUserName", "admin"),
+                ("LogonType", "3"),
+                ("TargetUserName", "O'Brien"),
+            ]),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            query,
+            concat!(
+                "*[System[Provider[@Name='Microsoft-Windows-Security-Auditing'] and ",
+                "(EventID=4624 or EventID=4625) and (Level=1 or Level=2)] and ",
+                "EventData[(Data[@Name='TargetUserName']='admin' or ",
+                "Data[@Name='TargetUserName']=\"O'Brien\") and ",
+                "(Data[@Name='LogonType']='3')]]",
+            )
+        );
+    }
+
+    #[test]
+    fn xpath_data_alone() {
+        assert_eq!(
+            xpath(&[], None, &[], &data(&[("Image", "C:\\x.exe")]))
+                .unwrap()
+                .as_deref(),
+            Some("*[EventData[(Data[@Name='Image']='C:\\x.exe')]]")
+        );
+        assert!(xpath(&[], None, &[], &data(&[("Image", "'\"")])).is_err());
+    }
+
+    #[test]
+    fn select_paths_fall_back_to_the_query_path() {
+        let query = r#"<QueryList>
+            <Query Id="0" Path="Security">
+                <Select>*[System[EventID=4624]]</Select>
+                <Select Path="System">*</Select>
+            </Query>
+            <Query Id="1">
+                <Select Path="security">*</Select>
+                <Select Path="Application">*</Select>
+            </Query>
+        </QueryList>"#;
+        assert_eq!(
+            select_paths(query).unwrap(),
+            ["Security", "System", "Application"]
+        );
+    }
+
+    #[test]
+    fn select_paths_reject_what_names_no_channel() {
+        assert!(select_paths("<Query><Select Path=\"System\">*</Select></Query>").is_err());
+        assert!(select_paths("<QueryList><Query Id=\"0\"/></QueryList>").is_err());
+        assert!(
+            select_paths("<QueryList><Query Id=\"0\"><Select>*</Select></Query></QueryList>")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn suppress_is_added_to_every_query() {
+        let base = concat!(
+            r#"<QueryList><Query Id="0" Path="Security"><Select>*</Select>"#,
+            r#"<Select Path="System">*</Select></Query>"#,
+            r#"<Query Id="1"><Select Path="Application">*</Select></Query></QueryList>"#,
+        );
+        let suppress = ["*[System[Level>3]]".to_string()];
+        assert_eq!(
+            channel_query("logons", Some(base), &suppress).unwrap(),
+            concat!(
+                r#"<QueryList><Query Id="0" Path="Security"><Select>*</Select>"#,
+                r#"<Select Path="System">*</Select>"#,
+                r#"<Suppress Path="Security">*[System[Level&gt;3]]</Suppress>"#,
+                r#"<Suppress Path="System">*[System[Level&gt;3]]</Suppress></Query>"#,
+                r#"<Query Id="1"><Select Path="Application">*</Select>"#,
+                r#"<Suppress Path="Application">*[System[Level&gt;3]]</Suppress></Query></QueryList>"#,
+            )
+        );
+    }
+
+    #[test]
+    fn suppress_opens_an_empty_query() {
+        let base = r#"<QueryList><Query Id="0" Path="Security"/><Query Id="1"><Select Path="System">*</Select></Query></QueryList>"#;
+        let suppress = ["*[System[EventID=7036]]".to_string()];
+        assert_eq!(
+            channel_query("mixed", Some(base), &suppress).unwrap(),
+            concat!(
+                r#"<QueryList><Query Id="0" Path="Security"></Query>"#,
+                r#"<Query Id="1"><Select Path="System">*</Select>"#,
+                r#"<Suppress Path="System">*[System[EventID=7036]]</Suppress></Query></QueryList>"#,
+            )
+        );
+    }
+
+    #[test]
+    fn xpath_base_becomes_a_query_list_with_suppress() {
+        assert_eq!(
+            channel_query("System", Some("*[System[Level=2]]"), &[]).as_deref(),
+            Some("*[System[Level=2]]")
+        );
+        assert_eq!(channel_query("System", None, &[]), None);
+        let suppress = ["*[System[EventID=7036]]".to_string()];
+        assert_eq!(
+            channel_query("Microsoft-Windows-PowerShell/Operational", None, &suppress).unwrap(),
+            concat!(
+                r#"<QueryList><Query Id="0" Path="Microsoft-Windows-PowerShell/Operational">"#,
+                r#"<Select Path="Microsoft-Windows-PowerShell/Operational">*</Select>"#,
+                r#"<Suppress Path="Microsoft-Windows-PowerShell/Operational">*[System[EventID=7036]]</Suppress>"#,
+                r#"</Query></QueryList>"#,
+            )
+        );
+    }
+
+    #[test]
+    fn consecutive_ids_become_ranges() {
+        assert_eq!(ranges(&[]), []);
+        assert_eq!(
+            ranges(&[4625, 4624, 4634, 4624, 4626, 4672]),
+            [(4624, 4626), (4634, 4634), (4672, 4672)]
+        );
+    }
+
+    #[test]
+    fn event_ids_to_suppress() {
+        assert!(event_id_suppress(&[], &[]).is_empty());
+        assert_eq!(
+            event_id_suppress(&[4624, 4625, 4626, 4672], &[]),
+            ["*[System[(EventID<4624 or EventID>4626) and EventID!=4672]]"]
+        );
+        assert_eq!(
+            event_id_suppress(&[], &[7036, 7040, 7041, 7042]),
+            ["*[System[EventID=7036 or (EventID>=7040 and EventID<=7042)]]"]
+        );
+        // Included IDs can still be excluded
+        assert_eq!(
+            event_id_suppress(&[1, 2], &[2]),
+            [
+                "*[System[(EventID<1 or EventID>2)]]",
+                "*[System[EventID=2]]"
+            ]
+        );
+    }
+
+    #[test]
+    fn levels_to_suppress() {
+        assert_eq!(level_suppress(Level::Verbose), None);
+        assert_eq!(
+            level_suppress(Level::Information).as_deref(),
+            Some("*[System[Level>4]]")
+        );
+        // LogAlways (0) counts as Information, so goes below Warning
+        assert_eq!(
+            level_suppress(Level::Warning).as_deref(),
+            Some("*[System[Level=0 or Level>3]]")
+        );
+        assert_eq!(
+            level_suppress(Level::Critical).as_deref(),
+            Some("*[System[Level=0 or Level>1]]")
+        );
+    }
+}
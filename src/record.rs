@@ -0,0 +1,395 @@
+use chrono::{DateTime, FixedOffset};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value as JsonValue};
+use std::fmt::Display;
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// One event as written by rs-wineventlog: the `<System>` fields, the
+/// rendered `Message` and the `EventData`. Numbers are accepted both as JSON
+/// numbers and as strings, so output with and without `typed_json` parses.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EventRecord {
+    #[serde(flatten)]
+    pub system: System,
+    #[serde(rename = "Message", default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(rename = "EventData", default)]
+    pub event_data: EventData,
+    #[serde(rename = "Labels", default, skip_serializing_if = "Map::is_empty")]
+    pub labels: Map<String, JsonValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub received_at: Option<DateTime<FixedOffset>>,
+}
+
+impl TryFrom<&JsonValue> for EventRecord {
+    type Error = serde_json::Error;
+
+    fn try_from(event: &JsonValue) -> Result<Self, Self::Error> {
+        EventRecord::deserialize(event)
+    }
+}
+
+impl FromStr for EventRecord {
+    type Err = serde_json::Error;
+
+    /// Parses one line of NDJSON output.
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(line)
+    }
+}
+
+/// The event's `<System>` element. `Level`, `Task` and `Opcode` hold the
+/// names the publisher rendered, or the raw values when it had none.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct System {
+    pub provider: Provider,
+    #[serde(rename = "EventID", with = "number")]
+    pub event_id: u32,
+    #[serde(
+        rename = "EventIDQualifiers",
+        with = "optional_number",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub event_id_qualifiers: Option<u32>,
+    #[serde(
+        with = "optional_number",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub version: Option<u8>,
+    #[serde(
+        with = "optional_text",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub level: Option<String>,
+    #[serde(
+        with = "optional_text",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub task: Option<String>,
+    #[serde(
+        with = "optional_text",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub opcode: Option<String>,
+    #[serde(with = "keywords", default, skip_serializing_if = "Vec::is_empty")]
+    pub keywords: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keywords_mask: Option<String>,
+    #[serde(with = "system_time")]
+    pub time_created: DateTime<FixedOffset>,
+    #[serde(rename = "EventRecordID", with = "number")]
+    pub event_record_id: u64,
+    #[serde(
+        rename = "ActivityID",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub activity_id: Option<Uuid>,
+    #[serde(
+        rename = "RelatedActivityID",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub related_activity_id: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub execution: Option<Execution>,
+    pub channel: String,
+    pub computer: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub security: Option<Security>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Provider {
+    #[serde(rename = "@Name")]
+    pub name: String,
+    #[serde(rename = "@Guid", default, skip_serializing_if = "Option::is_none")]
+    pub guid: Option<Uuid>,
+    #[serde(
+        rename = "@EventSourceName",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub event_source_name: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Execution {
+    #[serde(rename = "@ProcessID", with = "number")]
+    pub process_id: u32,
+    #[serde(
+        rename = "@ThreadID",
+        with = "optional_number",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub thread_id: Option<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Security {
+    #[serde(rename = "@UserID", default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+}
+
+/// The event's `EventData`. Its fields depend on the provider and event ID,
+/// so they stay JSON values; the accessors cover the common lookups.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct EventData(pub Map<String, JsonValue>);
+
+impl EventData {
+    pub fn get(&self, name: &str) -> Option<&JsonValue> {
+        self.0.get(name)
+    }
+
+    pub fn str(&self, name: &str) -> Option<&str> {
+        self.0.get(name)?.as_str()
+    }
+
+    /// A field parsed as a number, whether it was written as one or not.
+    pub fn number<T: FromStr>(&self, name: &str) -> Option<T> {
+        match self.0.get(name)? {
+            JsonValue::Number(n) => n.to_string().parse().ok(),
+            JsonValue::String(s) => s.parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NumberOrText<T> {
+    Number(T),
+    Text(String),
+}
+
+// Numbers written as JSON numbers (typed_json) or as the strings in the XML
+mod number {
+    use super::*;
+
+    pub fn serialize<S: Serializer, T: Serialize>(value: &T, s: S) -> Result<S::Ok, S::Error> {
+        value.serialize(s)
+    }
+
+    pub fn deserialize<'de, D, T>(d: D) -> Result<T, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: Deserialize<'de> + FromStr,
+        T::Err: Display,
+    {
+        match NumberOrText::deserialize(d)? {
+            NumberOrText::Number(n) => Ok(n),
+            NumberOrText::Text(s) => s.trim().parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+mod optional_number {
+    use super::*;
+
+    pub fn serialize<S: Serializer, T: Serialize>(
+        value: &Option<T>,
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        value.serialize(s)
+    }
+
+    pub fn deserialize<'de, D, T>(d: D) -> Result<Option<T>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: Deserialize<'de> + FromStr,
+        T::Err: Display,
+    {
+        match Option::<NumberOrText<T>>::deserialize(d)? {
+            None => Ok(None),
+            Some(NumberOrText::Number(n)) => Ok(Some(n)),
+            Some(NumberOrText::Text(s)) if s.trim().is_empty() => Ok(None),
+            Some(NumberOrText::Text(s)) => {
+                s.trim().parse().map(Some).map_err(serde::de::Error::custom)
+            }
+        }
+    }
+}
+
+// Level/Task/Opcode: a rendered name, or the raw value as string or number
+mod optional_text {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &Option<String>, s: S) -> Result<S::Ok, S::Error> {
+        value.serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<String>, D::Error> {
+        Ok(match Option::<NumberOrText<u64>>::deserialize(d)? {
+            None => None,
+            Some(NumberOrText::Number(n)) => Some(n.to_string()),
+            Some(NumberOrText::Text(s)) => Some(s),
+        })
+    }
+}
+
+// An array of keyword names, or the raw mask when names weren't resolved
+mod keywords {
+    use super::*;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Keywords {
+        Names(Vec<String>),
+        Mask(String),
+    }
+
+    pub fn serialize<S: Serializer>(value: &[String], s: S) -> Result<S::Ok, S::Error> {
+        value.serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<String>, D::Error> {
+        Ok(match Option::<Keywords>::deserialize(d)? {
+            None => Vec::new(),
+            Some(Keywords::Names(names)) => names,
+            Some(Keywords::Mask(mask)) => vec![mask],
+        })
+    }
+}
+
+// "TimeCreated": {"@SystemTime": "2024-05-01T12:34:56.1234567Z"}
+mod system_time {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct TimeCreated {
+        #[serde(rename = "@SystemTime")]
+        system_time: DateTime<FixedOffset>,
+    }
+
+    pub fn serialize<S: Serializer>(
+        value: &DateTime<FixedOffset>,
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        TimeCreated {
+            system_time: *value,
+        }
+        .serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<DateTime<FixedOffset>, D::Error> {
+        TimeCreated::deserialize(d).map(|t| t.system_time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn event(typed: bool) -> JsonValue {
+        let number = |n: u64| {
+            if typed {
+                json!(n)
+            } else {
+                json!(n.to_string())
+            }
+        };
+        json!({
+            "Provider": {
+                "@Name": "Microsoft-Windows-Security-Auditing",
+                "@Guid": "{54849625-5478-4994-a5ba-3e3b0328c30d}",
+            },
+            "EventID": number(4624),
+            "Version": number(2),
+            "Level": "Information",
+            "Task": number(12544),
+            "Opcode": "Info",
+            "Keywords": ["Audit Success"],
+            "KeywordsMask": "0x8020000000000000",
+            "TimeCreated": { "@SystemTime": "2024-05-01T12:34:56.1234567Z" },
+            "EventRecordID": number(1234),
+            "ActivityID": "{6f3b5a8e-1c2d-4e5f-8a9b-0c1d2e3f4a5b}",
+            "Execution": { "@ProcessID": number(700), "@ThreadID": number(9000) },
+            "Channel": "Security",
+            "Computer": "dc01.corp.example.com",
+            "Message": "An account was successfully logged on.",
+            "EventData": { "TargetUserName": "alice", "LogonType": number(3) },
+        })
+    }
+
+    #[test]
+    fn reads_events_with_and_without_typed_json() {
+        let typed = EventRecord::try_from(&event(true)).unwrap();
+        let untyped = EventRecord::try_from(&event(false)).unwrap();
+        assert_eq!(typed.system, untyped.system);
+        assert_eq!(untyped.event_data.number::<u32>("LogonType"), Some(3));
+
+        let system = &typed.system;
+        assert_eq!(system.provider.name, "Microsoft-Windows-Security-Auditing");
+        assert_eq!(
+            system.provider.guid,
+            Some("54849625-5478-4994-a5ba-3e3b0328c30d".parse().unwrap())
+        );
+        assert_eq!((system.event_id, system.event_record_id), (4624, 1234));
+        assert_eq!(system.version, Some(2));
+        assert_eq!(system.level.as_deref(), Some("Information"));
+        assert_eq!(system.task.as_deref(), Some("12544"));
+        assert_eq!(system.keywords, ["Audit Success"]);
+        assert_eq!(
+            system.time_created.to_rfc3339(),
+            "2024-05-01T12:34:56.123456700+00:00"
+        );
+        assert_eq!(
+            system.activity_id,
+            Some("6f3b5a8e-1c2d-4e5f-8a9b-0c1d2e3f4a5b".parse().unwrap())
+        );
+        let execution = system.execution.as_ref().unwrap();
+        assert_eq!(
+            (execution.process_id, execution.thread_id),
+            (700, Some(9000))
+        );
+        assert_eq!(typed.event_data.str("TargetUserName"), Some("alice"));
+        assert_eq!(typed.event_data.number::<u32>("LogonType"), Some(3));
+        assert_eq!(typed.event_data.number::<u32>("TargetUserName"), None);
+    }
+
+    #[test]
+    fn parses_output_lines() {
+        let record: EventRecord = event(false).to_string().parse().unwrap();
+        assert_eq!(
+            record.message.as_deref(),
+            Some("An account was successfully logged on.")
+        );
+        assert!("not json".parse::<EventRecord>().is_err());
+        assert!("{}".parse::<EventRecord>().is_err());
+    }
+
+    #[test]
+    fn takes_raw_values_where_names_are_missing() {
+        let mut event = event(false);
+        event["Keywords"] = json!("0x8020000000000000");
+        event["Level"] = json!(4);
+        event["Version"] = json!("");
+        let record = EventRecord::try_from(&event).unwrap();
+        assert_eq!(record.system.keywords, ["0x8020000000000000"]);
+        assert_eq!(record.system.level.as_deref(), Some("4"));
+        assert_eq!(record.system.version, None);
+    }
+
+    #[test]
+    fn round_trips() {
+        let record = EventRecord::try_from(&event(true)).unwrap();
+        let written = serde_json::to_value(&record).unwrap();
+        assert_eq!(written["EventID"], 4624);
+        assert_eq!(
+            written["TimeCreated"]["@SystemTime"],
+            "2024-05-01T12:34:56.123456700Z"
+        );
+        assert!(written.get("Labels").is_none());
+        assert_eq!(EventRecord::try_from(&written).unwrap(), record);
+    }
+}
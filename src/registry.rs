@@ -0,0 +1,158 @@
+use config::{ConfigError, Map, Source, Value, ValueKind};
+use windows::Win32::Foundation::{ERROR_MORE_DATA, ERROR_SUCCESS};
+use windows::Win32::System::Registry::*;
+use windows::core::{PCWSTR, PWSTR};
+
+/// Config source backed by a key under HKLM. Value names are config keys:
+/// REG_SZ/REG_EXPAND_SZ become strings, REG_DWORD/REG_QWORD numbers and
+/// REG_MULTI_SZ lists (e.g. `channels`); subkeys become nested sections
+/// (e.g. a `grpc` subkey holding `listen`).
+#[derive(Clone, Debug)]
+pub struct RegistrySource {
+    path: String,
+}
+
+impl RegistrySource {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+        }
+    }
+
+    pub fn exists(&self) -> bool {
+        Key::open(HKEY_LOCAL_MACHINE, &self.path).is_some()
+    }
+}
+
+impl Source for RegistrySource {
+    fn clone_into_box(&self) -> Box<dyn Source + Send + Sync> {
+        Box::new(self.clone())
+    }
+
+    fn collect(&self) -> Result<Map<String, Value>, ConfigError> {
+        let origin = format!("HKLM\\{}", self.path);
+        Ok(match Key::open(HKEY_LOCAL_MACHINE, &self.path) {
+            Some(key) => read_table(&key, &origin),
+            None => Map::new(),
+        })
+    }
+}
+
+struct Key(HKEY);
+
+impl Key {
+    fn open(parent: HKEY, path: &str) -> Option<Key> {
+        let wide: Vec<u16> = path.encode_utf16().chain(std::iter::once(0)).collect();
+        let mut key = HKEY::default();
+        let status =
+            unsafe { RegOpenKeyExW(parent, PCWSTR(wide.as_ptr()), None, KEY_READ, &mut key) };
+        (status == ERROR_SUCCESS).then_some(Key(key))
+    }
+}
+
+impl Drop for Key {
+    fn drop(&mut self) {
+        let _ = unsafe { RegCloseKey(self.0) };
+    }
+}
+
+fn read_table(key: &Key, origin: &String) -> Map<String, Value> {
+    let mut table = Map::new();
+
+    let mut name = vec![0u16; 16384];
+    let mut data = vec![0u8; 1024];
+    let mut index = 0;
+    loop {
+        let mut name_len = name.len() as u32;
+        let mut data_len = data.len() as u32;
+        let mut kind = 0u32;
+        let status = unsafe {
+            RegEnumValueW(
+                key.0,
+                index,
+                Some(PWSTR(name.as_mut_ptr())),
+                &mut name_len,
+                None,
+                Some(&mut kind),
+                Some(data.as_mut_ptr()),
+                Some(&mut data_len),
+            )
+        };
+        if status == ERROR_MORE_DATA {
+            data.resize(data_len as usize, 0);
+            continue;
+        }
+        if status != ERROR_SUCCESS {
+            break;
+        }
+        index += 1;
+
+        let value = match REG_VALUE_TYPE(kind) {
+            REG_SZ | REG_EXPAND_SZ => ValueKind::String(
+                strings(&data[..data_len as usize])
+                    .into_iter()
+                    .next()
+                    .unwrap_or_default(),
+            ),
+            REG_MULTI_SZ => ValueKind::Array(
+                strings(&data[..data_len as usize])
+                    .into_iter()
+                    .map(|s| Value::new(Some(origin), ValueKind::String(s)))
+                    .collect(),
+            ),
+            REG_DWORD if data_len >= 4 => {
+                ValueKind::U64(u32::from_le_bytes(data[..4].try_into().unwrap()) as u64)
+            }
+            REG_QWORD if data_len >= 8 => {
+                ValueKind::U64(u64::from_le_bytes(data[..8].try_into().unwrap()))
+            }
+            _ => continue,
+        };
+        let key_name = String::from_utf16_lossy(&name[..name_len as usize]);
+        table.insert(key_name, Value::new(Some(origin), value));
+    }
+
+    let mut index = 0;
+    loop {
+        let mut name_len = name.len() as u32;
+        let status = unsafe {
+            RegEnumKeyExW(
+                key.0,
+                index,
+                Some(PWSTR(name.as_mut_ptr())),
+                &mut name_len,
+                None,
+                None,
+                None,
+                None,
+            )
+        };
+        if status != ERROR_SUCCESS {
+            break;
+        }
+        index += 1;
+
+        let sub_name = String::from_utf16_lossy(&name[..name_len as usize]);
+        if let Some(sub) = Key::open(key.0, &sub_name) {
+            let section = read_table(&sub, &format!("{}\\{}", origin, sub_name));
+            table.insert(
+                sub_name,
+                Value::new(Some(origin), ValueKind::Table(section)),
+            );
+        }
+    }
+
+    table
+}
+
+// REG_SZ data is NUL-terminated UTF-16, REG_MULTI_SZ a NUL-separated list
+fn strings(data: &[u8]) -> Vec<String> {
+    let wide: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    wide.split(|&c| c == 0)
+        .filter(|s| !s.is_empty())
+        .map(String::from_utf16_lossy)
+        .collect()
+}
@@ -0,0 +1,177 @@
+use crate::timestamp::{self, Timezone};
+use serde_json::json;
+use std::collections::HashMap;
+use std::time::Duration;
+use windows::Win32::System::EventLog::*;
+use windows::core::{HSTRING, w};
+
+// Events fetched per EvtNext call
+const BATCH: usize = 256;
+
+#[derive(Default)]
+struct Row {
+    count: u64,
+    // FILETIMEs
+    first: u64,
+    last: u64,
+}
+
+/// Counts the events of the last `since` in each channel by provider and
+/// EventID, with first and last seen times, most frequent first. Printed as
+/// a table, or as JSON with `as_json`.
+pub fn run(
+    channels: &[String],
+    since: Duration,
+    as_json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut report = Vec::new();
+    for channel in channels {
+        let counts = count(channel, since)
+            .map_err(|e| format!("cannot read channel '{}': {}", channel, e))?;
+        let mut rows: Vec<((String, u16), Row)> = counts.into_iter().collect();
+        rows.sort_by(|a, b| b.1.count.cmp(&a.1.count).then_with(|| a.0.cmp(&b.0)));
+        report.push((channel, rows));
+    }
+
+    let time = |filetime: u64| {
+        timestamp::from_filetime(filetime)
+            .map(|t| timestamp::format(t, &Timezone::Local))
+            .unwrap_or_default()
+    };
+    if as_json {
+        let channels: Vec<_> = report
+            .iter()
+            .map(|(channel, rows)| {
+                let events: Vec<_> = rows
+                    .iter()
+                    .map(|((provider, id), row)| {
+                        json!({
+                            "provider": provider,
+                            "event_id": id,
+                            "count": row.count,
+                            "first_seen": time(row.first),
+                            "last_seen": time(row.last),
+                        })
+                    })
+                    .collect();
+                json!({
+                    "channel": channel,
+                    "total": rows.iter().map(|(_, r)| r.count).sum::<u64>(),
+                    "events": events,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&channels)?);
+        return Ok(());
+    }
+
+    for (channel, rows) in &report {
+        let total: u64 = rows.iter().map(|(_, r)| r.count).sum();
+        println!("{} ({} events)", channel, total);
+        if rows.is_empty() {
+            println!();
+            continue;
+        }
+        let width = rows
+            .iter()
+            .map(|((p, _), _)| p.len())
+            .max()
+            .unwrap_or(0)
+            .max(8);
+        println!(
+            "{:>9}  {:>7}  {:<width$}  {:<33}  LAST SEEN",
+            "COUNT", "EVENTID", "PROVIDER", "FIRST SEEN"
+        );
+        for ((provider, id), row) in rows {
+            println!(
+                "{:>9}  {:>7}  {:<width$}  {:<33}  {}",
+                row.count,
+                id,
+                provider,
+                time(row.first),
+                time(row.last)
+            );
+        }
+        println!();
+    }
+    Ok(())
+}
+
+fn count(channel: &str, since: Duration) -> windows::core::Result<HashMap<(String, u16), Row>> {
+    let mut counts: HashMap<(String, u16), Row> = HashMap::new();
+    let query = format!(
+        "*[System[TimeCreated[timediff(@SystemTime) <= {}]]]",
+        since.as_millis()
+    );
+    unsafe {
+        let context = EvtCreateRenderContext(
+            Some(&[
+                w!("Event/System/Provider/@Name"),
+                w!("Event/System/EventID"),
+                w!("Event/System/TimeCreated/@SystemTime"),
+            ]),
+            EvtRenderContextValues.0,
+        )?;
+        let results = match EvtQuery(
+            None,
+            &HSTRING::from(channel),
+            &HSTRING::from(query),
+            EvtQueryChannelPath.0 | EvtQueryForwardDirection.0,
+        ) {
+            Ok(results) => results,
+            Err(e) => {
+                let _ = EvtClose(context);
+                return Err(e);
+            }
+        };
+
+        let mut events = [0isize; BATCH];
+        let mut returned = 0u32;
+        // Fails with ERROR_NO_MORE_ITEMS at the end
+        while EvtNext(results, &mut events, u32::MAX, 0, &mut returned).is_ok() {
+            for &event in &events[..returned as usize] {
+                let event = EVT_HANDLE(event);
+                if let Some((provider, id, time)) = values(context, event) {
+                    let row = counts.entry((provider, id)).or_default();
+                    if row.count == 0 || time < row.first {
+                        row.first = time;
+                    }
+                    row.last = row.last.max(time);
+                    row.count += 1;
+                }
+                let _ = EvtClose(event);
+            }
+        }
+        let _ = EvtClose(results);
+        let _ = EvtClose(context);
+    }
+    Ok(counts)
+}
+
+// Provider name, EventID and TimeCreated
+unsafe fn values(context: EVT_HANDLE, event: EVT_HANDLE) -> Option<(String, u16, u64)> {
+    unsafe {
+        // Room for the three variants and the provider name they point into
+        let mut buffer = [0u64; 128];
+        let (mut used, mut count) = (0u32, 0u32);
+        EvtRender(
+            Some(context),
+            event,
+            EvtRenderEventValues.0,
+            std::mem::size_of_val(&buffer) as u32,
+            Some(buffer.as_mut_ptr() as *mut _),
+            &mut used,
+            &mut count,
+        )
+        .ok()?;
+        let values = std::slice::from_raw_parts(buffer.as_ptr() as *const EVT_VARIANT, 3);
+        let provider = if values[0].Type == EvtVarTypeString.0 as u32 {
+            values[0].Anonymous.StringVal.to_string().ok()?
+        } else {
+            String::new()
+        };
+        let id = values[1].Anonymous.UInt16Val;
+        let time = values[2].Anonymous.FileTimeVal;
+        Some((provider, id, time))
+    }
+}
@@ -0,0 +1,294 @@
+use crate::config::{Config, FlushConfig, RouteConfig};
+use crate::fields::Shape;
+use crate::output::{self, Record, Sink};
+use crate::{filter, hub};
+use serde_json::Value as JsonValue;
+use std::io;
+use std::path::PathBuf;
+use std::time::Instant;
+
+/// Sends each event to the outputs of the routes it matches, or to the
+/// default output (`output_file`) when none does. Sinks that batch on their
+/// own keep doing so; the others follow the flush policy, each counting the
+/// events it was sent.
+pub struct Router {
+    routes: Vec<Route>,
+    // The default output first, then every other target routes name
+    targets: Vec<Target>,
+    flush: FlushConfig,
+}
+
+struct Route {
+    config: RouteConfig,
+    // computers, lowercased and with file: lists read
+    computers: Vec<String>,
+    // Indexes into targets
+    targets: Vec<usize>,
+}
+
+struct Target {
+    name: String,
+    sink: Box<dyn Sink>,
+    // Events written since the sink was last flushed, and when that was
+    pending: usize,
+    last: Instant,
+}
+
+impl Target {
+    fn new(name: &str, sink: Box<dyn Sink>) -> Target {
+        Target {
+            name: name.to_string(),
+            sink,
+            pending: 0,
+            last: Instant::now(),
+        }
+    }
+
+    fn flush(&mut self, fsync: bool) -> io::Result<()> {
+        self.pending = 0;
+        self.last = Instant::now();
+        let result = if fsync {
+            self.sink.sync()
+        } else {
+            self.sink.flush()
+        };
+        result.map_err(|e| self.error(e))
+    }
+
+    // Names the output that failed, the router has several
+    fn error(&self, e: io::Error) -> io::Error {
+        io::Error::new(e.kind(), format!("{}: {}", self.name, e))
+    }
+}
+
+impl Router {
+    /// Opens the outputs of every route, each writing events in its route's
+    /// shape and framing; `default` is the one opened for `output_file`. An
+    /// output named by routes that shape or frame events differently is an
+    /// error: it's opened once.
+    pub fn new(
+        default: Box<dyn Sink>,
+        config: &Config,
+    ) -> Result<Router, Box<dyn std::error::Error>> {
+        let mut targets = vec![Target::new(
+            config.output_file.as_deref().unwrap_or("-"),
+            default,
+        )];
+        // How each target shapes and frames events, and what set that
+        let mut shapes = vec![(
+            (Shape::of_sink(&config.sink), config.sink.framing),
+            "output_file".to_string(),
+        )];
+        let mut routes = Vec::new();
+        for route in &config.routes {
+            if route.outputs.is_empty() {
+                return Err(format!("route '{}' has no outputs", route.name).into());
+            }
+            let shape = Shape::of_route(route, &config.sink);
+            let framing = route.framing.unwrap_or(config.sink.framing);
+            let mut indexes = Vec::new();
+            for name in &route.outputs {
+                let index = match targets.iter().position(|t| t.name == *name) {
+                    Some(index) if shapes[index].0 != (shape.clone(), framing) => {
+                        return Err(format!(
+                            "route '{}': output {} is also written by {}, with other \
+                             include_fields, exclude_fields, flatten, key_case or framing",
+                            route.name, name, shapes[index].1
+                        )
+                        .into());
+                    }
+                    Some(index) => index,
+                    None => {
+                        let sink = output::open(name, framing, config)
+                            .map_err(|e| format!("route '{}': {}", route.name, e))?;
+                        targets.push(Target::new(name, output::shaped(sink, shape.clone())));
+                        shapes.push(((shape.clone(), framing), format!("route '{}'", route.name)));
+                        targets.len() - 1
+                    }
+                };
+                if !indexes.contains(&index) {
+                    indexes.push(index);
+                }
+            }
+            routes.push(Route {
+                config: route.clone(),
+                computers: filter::computers(&route.computers)
+                    .map_err(|e| format!("route '{}': {}", route.name, e))?,
+                targets: indexes,
+            });
+        }
+        Ok(Router {
+            routes,
+            targets,
+            flush: config.flush.clone(),
+        })
+    }
+
+    // The targets an event goes to, each once
+    fn destinations(&self, event: &JsonValue) -> Vec<usize> {
+        let mut chosen = Vec::new();
+        for route in &self.routes {
+            if !matches(route, event) {
+                continue;
+            }
+            for index in &route.targets {
+                if !chosen.contains(index) {
+                    chosen.push(*index);
+                }
+            }
+            if route.config.stop {
+                break;
+            }
+        }
+        if chosen.is_empty() {
+            chosen.push(0);
+        }
+        chosen
+    }
+
+    // Runs `f` on every target; the first error is returned once all ran
+    fn each(&mut self, mut f: impl FnMut(&mut Target) -> io::Result<()>) -> io::Result<()> {
+        let mut result = Ok(());
+        for target in &mut self.targets {
+            if let Err(e) = f(target)
+                && result.is_ok()
+            {
+                result = Err(e);
+            }
+        }
+        result
+    }
+}
+
+impl Sink for Router {
+    // An output that fails doesn't keep the event from the others
+    fn send_batch(&mut self, batch: &[Record]) -> io::Result<()> {
+        let mut result = Ok(());
+        for record in batch {
+            for index in self.destinations(record.event) {
+                let target = &mut self.targets[index];
+                match target.sink.send_batch(std::slice::from_ref(record)) {
+                    Ok(()) => target.pending += 1,
+                    Err(e) if result.is_ok() => result = Err(target.error(e)),
+                    Err(_) => {}
+                }
+            }
+        }
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.each(|t| t.flush(false))
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.each(|t| t.flush(true))
+    }
+
+    fn healthcheck(&mut self) -> io::Result<()> {
+        self.each(|t| t.sink.healthcheck().map_err(|e| t.error(e)))
+    }
+
+    // Always handles flushing itself: only the router knows how many events
+    // each of its outputs was sent
+    fn send_if_due(&mut self) -> Option<io::Result<()>> {
+        let flush = self.flush.clone();
+        Some(self.each(|t| match t.sink.send_if_due() {
+            Some(sent) => sent.map_err(|e| t.error(e)),
+            None if t.pending > 0 && flush.due(t.pending, t.last.elapsed()) => t.flush(flush.fsync),
+            None => Ok(()),
+        }))
+    }
+
+    // Rotates every file output; returns the first one's rotated path
+    fn rotate(&mut self) -> io::Result<PathBuf> {
+        let mut rotated = Vec::new();
+        self.each(|t| match t.sink.rotate() {
+            Ok(path) => {
+                rotated.push(path);
+                Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => Ok(()),
+            Err(e) => Err(t.error(e)),
+        })?;
+        rotated.into_iter().next().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "no output is a file, nothing to rotate",
+            )
+        })
+    }
+}
+
+fn matches(route: &Route, event: &JsonValue) -> bool {
+    let (computers, route) = (&route.computers, &route.config);
+    filter::event_matches(event, &route.channels, &route.providers, &route.event_ids)
+        && (computers.is_empty()
+            || event
+                .get("Computer")
+                .and_then(JsonValue::as_str)
+                .map(str::to_lowercase)
+                .is_some_and(|c| computers.iter().any(|p| filter::computer_match(p, &c))))
+        && route
+            .min_level
+            .is_none_or(|min| hub::level(event).is_some_and(|level| level <= min))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn route(config: JsonValue) -> Route {
+        let config: RouteConfig = serde_json::from_value(config).unwrap();
+        Route {
+            computers: filter::computers(&config.computers).unwrap(),
+            config,
+            targets: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn matches_computers() {
+        let route = route(json!({
+            "name": "branch",
+            "channels": ["ForwardedEvents"],
+            "computers": ["WS-*.branch.example.com", "dc01"],
+            "outputs": ["-"],
+        }));
+        let event = |computer: &str| json!({"Channel": "ForwardedEvents", "Computer": computer});
+        assert!(matches(&route, &event("ws-17.branch.example.com")));
+        assert!(matches(&route, &event("DC01.corp.example.com")));
+        assert!(!matches(&route, &event("ws-17.hq.example.com")));
+        assert!(!matches(&route, &json!({"Channel": "ForwardedEvents"})));
+    }
+
+    #[test]
+    fn reads_computer_lists() {
+        let path = std::env::temp_dir().join(format!("route-computers-{}.txt", std::process::id()));
+        std::fs::write(&path, "# branch\nWS-01\n\nws-02.branch.example.com\n").unwrap();
+        let route = route(json!({
+            "name": "branch",
+            "computers": [format!("file:{}", path.display())],
+            "outputs": ["-"],
+        }));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(route.computers, ["ws-01", "ws-02.branch.example.com"]);
+        assert!(matches(
+            &route,
+            &json!({"Computer": "ws-01.branch.example.com"})
+        ));
+        assert!(!matches(&route, &json!({"Computer": "ws-03"})));
+    }
+
+    #[test]
+    fn rejects_an_output_framed_two_ways() {
+        let config: Config = serde_json::from_value(json!({
+            "channels": ["Application"],
+            "routes": [{ "name": "arrays", "outputs": ["-"], "framing": "json_array" }],
+        }))
+        .unwrap();
+        let result = Router::new(Box::new(io::stdout()), &config);
+        assert!(result.is_err_and(|e| e.to_string().contains("framing")));
+    }
+}
@@ -0,0 +1,233 @@
+use crate::hub;
+use serde_json::Value as JsonValue;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const CHANNEL: &str = "Microsoft-Windows-PowerShell/Operational";
+const SCRIPT_BLOCK_EVENT: u32 = 4104;
+
+/// PowerShell logs long script blocks as several 4104 events sharing a
+/// ScriptBlockId, numbered by MessageNumber/MessageTotal. Parts are held back
+/// here until the last one arrives and then written as one event.
+pub struct ScriptBlocks {
+    pending: Mutex<HashMap<String, Pending>>,
+    timeout: Duration,
+}
+
+struct Pending {
+    parts: BTreeMap<u64, JsonValue>,
+    total: u64,
+    first_seen: Instant,
+}
+
+impl ScriptBlocks {
+    pub fn new(timeout: Duration) -> ScriptBlocks {
+        ScriptBlocks {
+            pending: Mutex::new(HashMap::new()),
+            timeout,
+        }
+    }
+
+    /// Returns the event to write: the event itself unless it is one part of
+    /// a multi-part script block, then nothing until its last part arrives,
+    /// which returns the reassembled block.
+    pub fn add(&self, event: JsonValue) -> Option<JsonValue> {
+        let Some((id, number, total)) = part(&event) else {
+            return Some(event);
+        };
+        let mut pending = self.pending.lock().unwrap();
+        let block = pending.entry(id.clone()).or_insert_with(|| Pending {
+            parts: BTreeMap::new(),
+            total,
+            first_seen: Instant::now(),
+        });
+        block.parts.insert(number, event);
+        if (block.parts.len() as u64) < block.total {
+            return None;
+        }
+        let block = pending.remove(&id)?;
+        Some(reassemble(block.parts.into_values().collect()))
+    }
+
+    /// Whether no incomplete block is waiting for parts.
+    pub fn is_empty(&self) -> bool {
+        self.pending.lock().unwrap().is_empty()
+    }
+
+    /// Takes the parts of blocks that stayed incomplete for longer than the
+    /// timeout, or of every incomplete block when `all` is set (shutdown),
+    /// so they are written as they are rather than lost.
+    pub fn expired(&self, all: bool) -> Vec<JsonValue> {
+        let mut pending = self.pending.lock().unwrap();
+        let expired: Vec<String> = pending
+            .iter()
+            .filter(|(_, b)| all || b.first_seen.elapsed() >= self.timeout)
+            .map(|(id, _)| id.clone())
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|id| pending.remove(&id))
+            .flat_map(|b| b.parts.into_values())
+            .collect()
+    }
+}
+
+// (ScriptBlockId, MessageNumber, MessageTotal) of a 4104 event that is split
+fn part(event: &JsonValue) -> Option<(String, u64, u64)> {
+    if hub::event_id(event) != Some(SCRIPT_BLOCK_EVENT)
+        || !hub::channel(event).is_some_and(|c| c.eq_ignore_ascii_case(CHANNEL))
+    {
+        return None;
+    }
+    let data = event.get("EventData")?;
+    let number = |key: &str| match data.get(key)? {
+        JsonValue::Number(n) => n.as_u64(),
+        JsonValue::String(s) => s.parse().ok(),
+        _ => None,
+    };
+    let (number, total) = (number("MessageNumber")?, number("MessageTotal")?);
+    if total <= 1 || number == 0 || number > total {
+        return None;
+    }
+    let id = data.get("ScriptBlockId")?.as_str()?.to_string();
+    Some((id, number, total))
+}
+
+// The first part carrying the whole text, shaped like a single-part event
+fn reassemble(parts: Vec<JsonValue>) -> JsonValue {
+    let count = parts.len();
+    let text: String = parts
+        .iter()
+        .filter_map(|p| p.pointer("/EventData/ScriptBlockText")?.as_str())
+        .collect();
+    let mut event = parts.into_iter().next().unwrap_or_default();
+
+    let Some(data) = event.get_mut("EventData").and_then(|d| d.as_object_mut()) else {
+        return event;
+    };
+    data.insert("MessageNumber".to_string(), JsonValue::from("1"));
+    data.insert("MessageTotal".to_string(), JsonValue::from("1"));
+    data.insert(
+        "ScriptBlockText".to_string(),
+        JsonValue::String(text.clone()),
+    );
+    data.insert("ReassembledParts".to_string(), JsonValue::from(count));
+    let id = data
+        .get("ScriptBlockId")
+        .and_then(|i| i.as_str())
+        .unwrap_or_default();
+    let path = data
+        .get("Path")
+        .and_then(|p| p.as_str())
+        .unwrap_or_default();
+    let message = format!(
+        "Creating Scriptblock text (1 of 1):\n{}\n\nScriptBlock ID: {}\nPath: {}",
+        text, id, path
+    );
+    if let Some(obj) = event.as_object_mut() {
+        obj.insert("Message".to_string(), JsonValue::String(message));
+    }
+    event
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn block_part(id: &str, number: u64, total: u64, text: &str) -> JsonValue {
+        json!({
+            "EventID": "4104",
+            "Channel": CHANNEL,
+            "EventData": {
+                "MessageNumber": number.to_string(),
+                "MessageTotal": total.to_string(),
+                "ScriptBlockText": text,
+                "ScriptBlockId": id,
+                "Path": "C:\\scripts\\a.ps1",
+            },
+        })
+    }
+
+    fn text(event: &JsonValue) -> &str {
+        event["EventData"]["ScriptBlockText"].as_str().unwrap()
+    }
+
+    #[test]
+    fn passes_other_events_through() {
+        let blocks = ScriptBlocks::new(Duration::from_secs(60));
+        let single = block_part("b1", 1, 1, "Get-Date");
+        assert_eq!(blocks.add(single.clone()), Some(single));
+        let mut other = block_part("b1", 1, 2, "Get-");
+        other["EventID"] = json!("4103");
+        assert_eq!(blocks.add(other.clone()), Some(other));
+        assert!(blocks.is_empty());
+    }
+
+    #[test]
+    fn reassembles_parts_in_order() {
+        let blocks = ScriptBlocks::new(Duration::from_secs(60));
+        assert_eq!(blocks.add(block_part("b1", 1, 3, "Get-")), None);
+        assert_eq!(blocks.add(block_part("b1", 2, 3, "Child")), None);
+        let event = blocks.add(block_part("b1", 3, 3, "Item")).unwrap();
+        assert_eq!(text(&event), "Get-ChildItem");
+        assert_eq!(event["EventData"]["MessageNumber"], "1");
+        assert_eq!(event["EventData"]["MessageTotal"], "1");
+        assert_eq!(event["EventData"]["ReassembledParts"], 3);
+        assert_eq!(
+            event["Message"],
+            "Creating Scriptblock text (1 of 1):\nGet-ChildItem\n\nScriptBlock ID: b1\nPath: C:\\scripts\\a.ps1"
+        );
+        assert!(blocks.is_empty());
+    }
+
+    #[test]
+    fn reassembles_parts_out_of_order() {
+        let blocks = ScriptBlocks::new(Duration::from_secs(60));
+        assert_eq!(blocks.add(block_part("b1", 3, 3, "Item")), None);
+        assert_eq!(blocks.add(block_part("b2", 2, 2, "Date")), None);
+        assert_eq!(blocks.add(block_part("b1", 1, 3, "Get-")), None);
+        assert_eq!(
+            text(&blocks.add(block_part("b2", 1, 2, "Get-")).unwrap()),
+            "Get-Date"
+        );
+        assert_eq!(
+            text(&blocks.add(block_part("b1", 2, 3, "Child")).unwrap()),
+            "Get-ChildItem"
+        );
+        assert!(blocks.is_empty());
+    }
+
+    #[test]
+    fn counts_a_repeated_part_once() {
+        let blocks = ScriptBlocks::new(Duration::from_secs(60));
+        assert_eq!(blocks.add(block_part("b1", 1, 2, "Get-")), None);
+        assert_eq!(blocks.add(block_part("b1", 1, 2, "Get-")), None);
+        let event = blocks.add(block_part("b1", 2, 2, "Date")).unwrap();
+        assert_eq!(text(&event), "Get-Date");
+        assert_eq!(event["EventData"]["ReassembledParts"], 2);
+    }
+
+    #[test]
+    fn gives_up_on_incomplete_blocks() {
+        let blocks = ScriptBlocks::new(Duration::from_secs(60));
+        blocks.add(block_part("b1", 2, 3, "Child"));
+        blocks.add(block_part("b1", 1, 3, "Get-"));
+        assert!(blocks.expired(false).is_empty());
+        // On shutdown the parts are written as they are, in order
+        let parts = blocks.expired(true);
+        assert_eq!(
+            parts.iter().map(text).collect::<Vec<_>>(),
+            ["Get-", "Child"]
+        );
+        assert!(blocks.is_empty());
+
+        let blocks = ScriptBlocks::new(Duration::ZERO);
+        blocks.add(block_part("b1", 1, 2, "Get-"));
+        assert_eq!(blocks.expired(false).len(), 1);
+        // A part after its block expired starts the block over
+        assert_eq!(blocks.add(block_part("b1", 2, 2, "Date")), None);
+        assert!(!blocks.is_empty());
+    }
+}
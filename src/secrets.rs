@@ -0,0 +1,139 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use config::{Value, ValueKind};
+use windows::Win32::Foundation::{HLOCAL, LocalFree};
+use windows::Win32::Security::Cryptography::{
+    CRYPT_INTEGER_BLOB, CRYPTPROTECT_LOCAL_MACHINE, CRYPTPROTECT_UI_FORBIDDEN, CryptProtectData,
+    CryptUnprotectData,
+};
+use windows::core::PCWSTR;
+
+/// Resolves references in every string of the loaded configuration:
+/// `${NAME}` (or `${env:NAME}`) anywhere in a value is replaced by that
+/// environment variable, `$${` stands for a literal `${`, and a whole value of
+/// the form `dpapi:<base64>` is decrypted with DPAPI.
+pub fn resolve(value: &mut Value) -> Result<(), String> {
+    resolve_at(value, &mut String::new())
+}
+
+// `path` is the key of the value being resolved, e.g. `channels[0].name`,
+// so errors can say where the bad reference is
+fn resolve_at(value: &mut Value, path: &mut String) -> Result<(), String> {
+    let len = path.len();
+    match &mut value.kind {
+        ValueKind::String(s) => {
+            *s = resolve_str(s).map_err(|e| format!("{} (in config key '{}')", e, path))?
+        }
+        ValueKind::Table(table) => {
+            for (key, v) in table.iter_mut() {
+                if !path.is_empty() {
+                    path.push('.');
+                }
+                path.push_str(key);
+                resolve_at(v, path)?;
+                path.truncate(len);
+            }
+        }
+        ValueKind::Array(items) => {
+            for (i, v) in items.iter_mut().enumerate() {
+                path.push_str(&format!("[{}]", i));
+                resolve_at(v, path)?;
+                path.truncate(len);
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn resolve_str(value: &str) -> Result<String, String> {
+    if let Some(blob) = value.strip_prefix("dpapi:") {
+        return unprotect(blob);
+    }
+
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        // $${ escapes a literal ${
+        if rest[..start].ends_with('$') {
+            out.push_str(&rest[..start - 1]);
+            out.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        out.push_str(&rest[..start]);
+        let reference = &rest[start + 2..];
+        let end = reference
+            .find('}')
+            .ok_or("unterminated ${...} reference in config")?;
+        let name = reference[..end]
+            .strip_prefix("env:")
+            .unwrap_or(&reference[..end]);
+        if name.is_empty() {
+            return Err("empty ${} reference in config".to_string());
+        }
+        let resolved = std::env::var(name)
+            .map_err(|_| format!("config references unset environment variable {}", name))?;
+        out.push_str(&resolved);
+        rest = &reference[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Encrypts `secret` for a `dpapi:` config value. Machine scope lets any
+/// account on this computer (e.g. the service's LocalSystem) decrypt it;
+/// user scope only the current user.
+pub fn protect(secret: &str, machine: bool) -> Result<String, Box<dyn std::error::Error>> {
+    let mut data = secret.as_bytes().to_vec();
+    let input = CRYPT_INTEGER_BLOB {
+        cbData: data.len() as u32,
+        pbData: data.as_mut_ptr(),
+    };
+    let mut flags = CRYPTPROTECT_UI_FORBIDDEN;
+    if machine {
+        flags |= CRYPTPROTECT_LOCAL_MACHINE;
+    }
+
+    let mut output = CRYPT_INTEGER_BLOB::default();
+    unsafe {
+        CryptProtectData(&input, PCWSTR::null(), None, None, None, flags, &mut output)?;
+        let encrypted = take_blob(output);
+        Ok(format!("dpapi:{}", BASE64.encode(encrypted)))
+    }
+}
+
+fn unprotect(blob: &str) -> Result<String, String> {
+    let mut data = BASE64
+        .decode(blob.trim())
+        .map_err(|e| format!("invalid dpapi: value in config: {}", e))?;
+    let input = CRYPT_INTEGER_BLOB {
+        cbData: data.len() as u32,
+        pbData: data.as_mut_ptr(),
+    };
+
+    let mut output = CRYPT_INTEGER_BLOB::default();
+    unsafe {
+        CryptUnprotectData(
+            &input,
+            None,
+            None,
+            None,
+            None,
+            CRYPTPROTECT_UI_FORBIDDEN,
+            &mut output,
+        )
+        .map_err(|e| format!("cannot decrypt dpapi: value in config: {}", e))?;
+        String::from_utf8(take_blob(output))
+            .map_err(|_| "decrypted dpapi: value is not UTF-8".to_string())
+    }
+}
+
+// Copies out and frees a blob allocated by DPAPI
+unsafe fn take_blob(blob: CRYPT_INTEGER_BLOB) -> Vec<u8> {
+    unsafe {
+        let bytes = std::slice::from_raw_parts(blob.pbData, blob.cbData as usize).to_vec();
+        let _ = LocalFree(Some(HLOCAL(blob.pbData as _)));
+        bytes
+    }
+}
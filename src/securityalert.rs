@@ -0,0 +1,402 @@
+use crate::{hub, xml};
+use serde_json::{Map, Value as JsonValue, json};
+
+pub const DEFENDER_CHANNEL: &str = "Microsoft-Windows-Windows Defender/Operational";
+// Followed by "EXE and DLL", "MSI and Script", "Packaged app-Deployment" or
+// "Packaged app-Execution"
+pub const APPLOCKER_CHANNEL_PREFIX: &str = "Microsoft-Windows-AppLocker/";
+
+// Defender event IDs and what happened
+const DEFENDER_EVENTS: [(u32, &str, &str); 14] = [
+    (1006, "MalwareDetected", "detected"),
+    (1007, "MalwareActionTaken", "remediated"),
+    (1008, "MalwareActionFailed", "failed"),
+    (1015, "SuspiciousBehavior", "detected"),
+    (1116, "MalwareDetected", "detected"),
+    (1117, "MalwareActionTaken", "remediated"),
+    (1118, "MalwareActionFailed", "failed"),
+    (1119, "MalwareActionFailed", "failed"),
+    (1121, "AttackSurfaceReduction", "blocked"),
+    (1122, "AttackSurfaceReduction", "audited"),
+    (1123, "ControlledFolderAccess", "blocked"),
+    (1124, "ControlledFolderAccess", "audited"),
+    (1125, "NetworkProtection", "audited"),
+    (1126, "NetworkProtection", "blocked"),
+];
+
+const SEVERITIES: [(u64, &str); 5] = [
+    (0, "Unknown"),
+    (1, "Low"),
+    (2, "Moderate"),
+    (4, "High"),
+    (5, "Severe"),
+];
+
+const ACTIONS: [(u64, &str); 7] = [
+    (1, "Clean"),
+    (2, "Quarantine"),
+    (3, "Remove"),
+    (6, "Allow"),
+    (8, "UserDefined"),
+    (9, "NoAction"),
+    (10, "Block"),
+];
+
+// Threat categories as in MSFT_MpThreatCatalog
+const CATEGORIES: [(u64, &str); 50] = [
+    (0, "Invalid"),
+    (1, "Adware"),
+    (2, "Spyware"),
+    (3, "PasswordStealer"),
+    (4, "TrojanDownloader"),
+    (5, "Worm"),
+    (6, "Backdoor"),
+    (7, "RemoteAccessTrojan"),
+    (8, "Trojan"),
+    (9, "EmailFlooder"),
+    (10, "Keylogger"),
+    (11, "Dialer"),
+    (12, "MonitoringSoftware"),
+    (13, "BrowserModifier"),
+    (14, "Cookie"),
+    (15, "BrowserPlugin"),
+    (16, "AolExploit"),
+    (17, "Nuker"),
+    (18, "SecurityDisabler"),
+    (19, "JokeProgram"),
+    (20, "HostileActiveXControl"),
+    (21, "SoftwareBundler"),
+    (22, "StealthNotifier"),
+    (23, "SettingsModifier"),
+    (24, "Toolbar"),
+    (25, "RemoteControlSoftware"),
+    (26, "TrojanFtp"),
+    (27, "PotentiallyUnwantedSoftware"),
+    (28, "IcqExploit"),
+    (29, "TrojanTelnet"),
+    (30, "Exploit"),
+    (31, "FileSharingProgram"),
+    (32, "MalwareCreationTool"),
+    (33, "RemoteControlSoftware"),
+    (34, "Tool"),
+    (36, "TrojanDenialOfService"),
+    (37, "TrojanDropper"),
+    (38, "TrojanMassMailer"),
+    (39, "TrojanMonitoringSoftware"),
+    (40, "TrojanProxyServer"),
+    (42, "Virus"),
+    (43, "Known"),
+    (44, "Unknown"),
+    (45, "Spp"),
+    (46, "Behavior"),
+    (47, "Vulnerability"),
+    (48, "Policy"),
+    (49, "EnterpriseUnwantedSoftware"),
+    (50, "Ransomware"),
+    (51, "AsrRule"),
+];
+
+/// Adds `SecurityAlert` to Defender detections and AppLocker decisions, the
+/// same shape for both:
+///
+/// ```text
+/// {"Source": "Defender", "Type": "MalwareDetected", "Outcome": "detected",
+///  "Severity": "Severe", "Threat": {"Id", "Name", "Category"}, "Action",
+///  "Rule": {"Id", "Name"}, "File": {"Path", "Name", "Hash"},
+///  "User": {"Name", "Sid"}, "Process": {"Path", "Id"}}
+/// ```
+///
+/// Outcome is detected, remediated, failed, blocked, audited or allowed. IDs
+/// are decoded into names, Defender's `file:_` resources and AppLocker's
+/// `%OSDRIVE%`-style paths into plain paths, and what a source doesn't
+/// report is null. Other events are left alone.
+pub fn parse(event: &mut JsonValue) {
+    let (Some(channel), Some(id)) = (hub::channel(event), hub::event_id(event)) else {
+        return;
+    };
+    let alert = if channel.eq_ignore_ascii_case(DEFENDER_CHANNEL) {
+        defender(event, id)
+    } else if channel
+        .to_ascii_lowercase()
+        .starts_with(&APPLOCKER_CHANNEL_PREFIX.to_ascii_lowercase())
+    {
+        applocker(event, id)
+    } else {
+        None
+    };
+    if let (Some(alert), Some(obj)) = (alert, event.as_object_mut()) {
+        obj.insert("SecurityAlert".to_string(), alert);
+    }
+}
+
+fn defender(event: &JsonValue, id: u32) -> Option<JsonValue> {
+    let (_, kind, outcome) = DEFENDER_EVENTS.iter().find(|(i, ..)| *i == id)?;
+    let data = event.get("EventData")?.as_object()?;
+
+    let decoded = |id_field: &str, name_field: &str, names: &[(u64, &str)]| {
+        let id = number(data, id_field);
+        id.and_then(|id| names.iter().find(|(i, _)| *i == id))
+            .map(|(_, name)| name.to_string())
+            .or_else(|| text(data, name_field))
+    };
+    let path = text(data, "Path").map(|p| defender_path(&p));
+    // ASR, Controlled Folder Access and Network Protection events name the
+    // triggering rule by its GUID
+    let rule = text(data, "ID").map(|id| json!({ "Id": xml::normalize_guid(&id), "Name": null }));
+
+    Some(json!({
+        "Source": "Defender",
+        "Type": kind,
+        "Outcome": outcome,
+        "Severity": decoded("Severity ID", "Severity Name", &SEVERITIES),
+        "Threat": text(data, "Threat Name").map(|name| {
+            json!({
+                "Id": number(data, "Threat ID"),
+                "Name": name,
+                "Category": decoded("Category ID", "Category Name", &CATEGORIES),
+            })
+        }),
+        "Action": decoded("Action ID", "Action Name", &ACTIONS),
+        "Rule": rule,
+        "File": file(path, None),
+        "User": text(data, "Detection User")
+            .or_else(|| text(data, "User"))
+            .map(|name| json!({ "Name": name, "Sid": null })),
+        "Process": text(data, "Process Name").map(|path| json!({ "Path": path, "Id": null })),
+    }))
+}
+
+fn applocker(event: &JsonValue, id: u32) -> Option<JsonValue> {
+    let outcome = match id {
+        8002 | 8005 | 8020 | 8023 => "allowed",
+        8003 | 8006 | 8021 | 8024 | 8028 => "audited",
+        8004 | 8007 | 8022 | 8025 | 8029 => "blocked",
+        _ => return None,
+    };
+    // UserData holds one element: RuleAndFileData, or RuleAndPackageData for
+    // packaged apps
+    let data = event
+        .get("UserData")?
+        .as_object()?
+        .values()
+        .find_map(|v| v.as_object())?;
+
+    let kind = match text(data, "PolicyName")
+        .map(|p| p.to_uppercase())
+        .as_deref()
+    {
+        Some("EXE") => "AppLockerExecutable",
+        Some("DLL") => "AppLockerDll",
+        Some("MSI") => "AppLockerInstaller",
+        Some("SCRIPT") => "AppLockerScript",
+        Some("APPX") => "AppLockerPackagedApp",
+        _ => "AppLocker",
+    };
+    let path = text(data, "FullFilePath")
+        .or_else(|| text(data, "FilePath").map(|p| applocker_path(&p)))
+        .or_else(|| text(data, "PackageName"));
+    let rule = text(data, "RuleId").map(|id| {
+        json!({
+            "Id": xml::normalize_guid(&id),
+            "Name": text(data, "RuleName"),
+        })
+    });
+
+    Some(json!({
+        "Source": "AppLocker",
+        "Type": kind,
+        "Outcome": outcome,
+        "Severity": null,
+        "Threat": null,
+        "Action": null,
+        "Rule": rule,
+        "File": file(path, text(data, "FileHash")),
+        "User": text(data, "TargetUser").map(|sid| json!({ "Name": null, "Sid": sid })),
+        "Process": number(data, "TargetProcessId").map(|pid| json!({ "Path": null, "Id": pid })),
+    }))
+}
+
+fn file(path: Option<String>, hash: Option<String>) -> Option<JsonValue> {
+    let path = path?;
+    let name = path.rsplit(['\\', '/']).next().map(str::to_string);
+    Some(json!({ "Path": path, "Name": name, "Hash": hash }))
+}
+
+// A value, with the "-" and empty placeholders sources write for "none" as
+// missing
+fn text(data: &Map<String, JsonValue>, key: &str) -> Option<String> {
+    let value = match data.get(key)? {
+        JsonValue::String(s) => s.trim().to_string(),
+        JsonValue::Number(n) => n.to_string(),
+        _ => return None,
+    };
+    (!value.is_empty() && value != "-").then_some(value)
+}
+
+// Decimal, or hex with 0x as AppLocker writes some IDs
+fn number(data: &Map<String, JsonValue>, key: &str) -> Option<u64> {
+    let value = text(data, key)?;
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+// "file:_C:\x\evil.exe; process:_pid:1234,ProcessStart:..." -> the first
+// file (or file in an archive); other resources as they are
+fn defender_path(path: &str) -> String {
+    let resources: Vec<&str> = path.split(';').map(str::trim).collect();
+    resources
+        .iter()
+        .find_map(|r| {
+            r.strip_prefix("file:_")
+                .or_else(|| r.strip_prefix("containerfile:_"))
+        })
+        .unwrap_or(resources[0])
+        .to_string()
+}
+
+// AppLocker writes paths with its own variables, e.g.
+// %OSDRIVE%\USERS\BOB\DOWNLOADS\TOOL.EXE
+fn applocker_path(path: &str) -> String {
+    let env = |name: &str, default: &str| std::env::var(name).unwrap_or(default.to_string());
+    let system_root = env("SystemRoot", "C:\\Windows");
+    let variables = [
+        ("%OSDRIVE%", env("SystemDrive", "C:")),
+        ("%WINDIR%", system_root.clone()),
+        ("%SYSTEM32%", format!("{}\\System32", system_root)),
+        ("%PROGRAMFILES%", env("ProgramFiles", "C:\\Program Files")),
+    ];
+    for (variable, value) in variables {
+        if let Some(head) = path.get(..variable.len())
+            && head.eq_ignore_ascii_case(variable)
+        {
+            return format!("{}{}", value, &path[variable.len()..]);
+        }
+    }
+    path.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_defender_detections() {
+        let mut event = json!({
+            "Channel": DEFENDER_CHANNEL,
+            "EventID": 1116,
+            "EventData": {
+                "Threat ID": "2147735503",
+                "Threat Name": "Trojan:Win32/Wacatac.B!ml",
+                "Severity ID": "5",
+                "Severity Name": "Severe",
+                "Category ID": "8",
+                "Category Name": "Trojan",
+                "Path": "file:_C:\\Users\\bob\\Downloads\\evil.exe; process:_pid:1234,ProcessStart:1",
+                "Action ID": "2",
+                "Detection User": "CORP\\bob",
+                "Process Name": "C:\\Windows\\explorer.exe",
+            },
+        });
+        parse(&mut event);
+        assert_eq!(
+            event["SecurityAlert"],
+            json!({
+                "Source": "Defender",
+                "Type": "MalwareDetected",
+                "Outcome": "detected",
+                "Severity": "Severe",
+                "Threat": {
+                    "Id": 2147735503u64,
+                    "Name": "Trojan:Win32/Wacatac.B!ml",
+                    "Category": "Trojan",
+                },
+                "Action": "Quarantine",
+                "Rule": null,
+                "File": {
+                    "Path": "C:\\Users\\bob\\Downloads\\evil.exe",
+                    "Name": "evil.exe",
+                    "Hash": null,
+                },
+                "User": { "Name": "CORP\\bob", "Sid": null },
+                "Process": { "Path": "C:\\Windows\\explorer.exe", "Id": null },
+            })
+        );
+    }
+
+    #[test]
+    fn names_asr_rules_by_guid() {
+        let mut event = json!({
+            "Channel": DEFENDER_CHANNEL,
+            "EventID": 1121,
+            "EventData": {
+                "ID": "{D4F940AB-401B-4EFC-AADC-AD5F3C50688A}",
+                "Path": "C:\\Users\\bob\\macro.docm",
+                "Process Name": "-",
+            },
+        });
+        parse(&mut event);
+        let alert = &event["SecurityAlert"];
+        assert_eq!(alert["Type"], "AttackSurfaceReduction");
+        assert_eq!(alert["Outcome"], "blocked");
+        assert_eq!(alert["Rule"]["Id"], "d4f940ab-401b-4efc-aadc-ad5f3c50688a");
+        assert_eq!(alert["File"]["Name"], "macro.docm");
+        assert_eq!(alert["Process"], JsonValue::Null);
+    }
+
+    const APPLOCKER_BLOCK: &str = r#"<Event xmlns="http://schemas.microsoft.com/win/2004/08/events/event"><System><Provider Name="Microsoft-Windows-AppLocker"/><EventID>8004</EventID><Channel>Microsoft-Windows-AppLocker/EXE and DLL</Channel></System><UserData><RuleAndFileData xmlns="http://schemas.microsoft.com/schemas/event/Microsoft.Windows/1.0.0.0"><PolicyNameLength>3</PolicyNameLength><PolicyName>EXE</PolicyName><RuleId>{FD686D83-A829-4351-8FF4-27C7DE5755D2}</RuleId><RuleNameLength>9</RuleNameLength><RuleName>Block tool</RuleName><FilePath>%OSDRIVE%\USERS\BOB\DOWNLOADS\TOOL.EXE</FilePath><FileHash>0B3F7D1E</FileHash><TargetUser>S-1-5-21-1-2-3-1001</TargetUser><TargetProcessId>4242</TargetProcessId></RuleAndFileData></UserData></Event>"#;
+
+    #[test]
+    fn reads_applocker_user_data() {
+        let mut event = xml::parse_with_user_data(APPLOCKER_BLOCK).unwrap();
+        parse(&mut event);
+        let alert = &event["SecurityAlert"];
+        assert_eq!(alert["Source"], "AppLocker");
+        assert_eq!(alert["Type"], "AppLockerExecutable");
+        assert_eq!(alert["Outcome"], "blocked");
+        assert_eq!(
+            alert["Rule"],
+            json!({ "Id": "fd686d83-a829-4351-8ff4-27c7de5755d2", "Name": "Block tool" })
+        );
+        assert!(
+            alert["File"]["Path"]
+                .as_str()
+                .unwrap()
+                .ends_with("\\USERS\\BOB\\DOWNLOADS\\TOOL.EXE")
+        );
+        assert_eq!(alert["File"]["Name"], "TOOL.EXE");
+        assert_eq!(alert["File"]["Hash"], "0B3F7D1E");
+        assert_eq!(alert["User"]["Sid"], "S-1-5-21-1-2-3-1001");
+        assert_eq!(alert["Process"]["Id"], 4242);
+    }
+
+    #[test]
+    fn user_data_is_only_kept_for_parsers() {
+        let event = xml::parse_to_json(APPLOCKER_BLOCK).unwrap();
+        assert!(event.get("UserData").is_none());
+    }
+
+    #[test]
+    fn leaves_other_events_alone() {
+        for (channel, id) in [(DEFENDER_CHANNEL, 5007), ("Security", 1116)] {
+            let mut event = json!({ "Channel": channel, "EventID": id, "EventData": {} });
+            parse(&mut event);
+            assert!(event.get("SecurityAlert").is_none());
+        }
+    }
+
+    #[test]
+    fn normalizes_paths() {
+        assert_eq!(
+            defender_path("containerfile:_C:\\x\\a.zip; file:_C:\\x\\a.zip->b.exe"),
+            "C:\\x\\a.zip"
+        );
+        assert_eq!(defender_path("regkey:_HKLM\\Run"), "regkey:_HKLM\\Run");
+        assert!(applocker_path("%osdrive%\\TOOL.EXE").ends_with(":\\TOOL.EXE"));
+        assert_eq!(
+            applocker_path("\\\\server\\share\\a.exe"),
+            "\\\\server\\share\\a.exe"
+        );
+    }
+}
@@ -0,0 +1,240 @@
+use crate::checkpoint::Bookmark;
+use crate::config::Config;
+use crate::eventlog::{self, Runtime};
+use crate::hub::{self, Hub};
+use crate::output;
+use crate::stats::Stats;
+use serde_json::{Value as JsonValue, json};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use windows::Win32::System::EventLog::*;
+use windows::core::{HSTRING, PCWSTR};
+
+const CHANNEL: &str = "Application";
+const SOURCE: &str = "rs-wineventlog-selftest";
+const EVENT_ID: u32 = 4242;
+// Left out of the channel's query by exclude_event_ids
+const FILTERED_EVENT_ID: u32 = 4243;
+// Taken by the test route, so only written to its output
+const ROUTED_EVENT_ID: u32 = 4244;
+
+/// End-to-end check of a real pipeline run: writes marked events to the
+/// Application log, reads them back with `--once` semantics into a temp
+/// directory and checks the JSON, the field filter, event filters, routing,
+/// checkpoints and output rotation. Events other programs log meanwhile are
+/// ignored.
+pub fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir().join(format!("rs-wineventlog-selftest-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let marker = format!("selftest-{}", chrono::Utc::now().timestamp_micros());
+    let output_file = dir.join("events.ndjson");
+    let routed_file = dir.join("routed.ndjson");
+    let config: Config = serde_json::from_value(json!({
+        "channels": [{
+            "name": CHANNEL,
+            "exclude_event_ids": [FILTERED_EVENT_ID],
+            "exclude_messages": ["dropped by message"],
+        }],
+        "routes": [{
+            "name": "selftest",
+            "providers": [SOURCE],
+            "event_ids": [ROUTED_EVENT_ID],
+            "outputs": [routed_file],
+            "stop": true,
+        }],
+        "output_file": output_file,
+        "state_file": dir.join("state.redb"),
+        "checkpoint_file": dir.join("checkpoints.json"),
+        "sink": { "exclude_fields": ["Execution"] },
+        "typed_json": true,
+    }))?;
+
+    // Start from the newest event, not the whole Application log; the
+    // checkpoint file is imported into the new state file
+    std::fs::write(
+        dir.join("checkpoints.json"),
+        json!({ CHANNEL: newest_bookmark()? }).to_string(),
+    )?;
+
+    let mut report = Report::default();
+    for i in 1..=3 {
+        write_event(EVENT_ID, &format!("{} event {}", marker, i))?;
+    }
+    write_event(
+        FILTERED_EVENT_ID,
+        &format!("{} filtered by EventID", marker),
+    )?;
+    write_event(EVENT_ID, &format!("{} dropped by message", marker))?;
+    write_event(ROUTED_EVENT_ID, &format!("{} routed", marker))?;
+    drain(&config)?;
+    let events = marked(&output_file, &marker)?;
+    report.check("the three test events are written", events.len() == 3);
+    let routed = marked(&routed_file, &marker)?;
+    let written = || events.iter().chain(&routed);
+    report.check(
+        "exclude_event_ids leaves events out",
+        written().all(|e| hub::event_id(e) != Some(FILTERED_EVENT_ID)),
+    );
+    report.check(
+        "exclude_messages drops events",
+        written().all(|e| !e.to_string().contains("dropped by message")),
+    );
+    report.check(
+        "a route sends its events to its outputs",
+        routed.len() == 1 && hub::event_id(&routed[0]) == Some(ROUTED_EVENT_ID),
+    );
+    report.check(
+        "a stop route keeps its events from the default output",
+        events
+            .iter()
+            .all(|e| hub::event_id(e) != Some(ROUTED_EVENT_ID)),
+    );
+    let records: Vec<u64> = events.iter().filter_map(hub::record_id).collect();
+    report.check(
+        "events keep their order",
+        records.len() == 3 && records.windows(2).all(|w| w[0] < w[1]),
+    );
+    if let Some(event) = events.first() {
+        report.check(
+            "EventID is a number",
+            hub::event_id(event) == Some(EVENT_ID) && event["EventID"].is_number(),
+        );
+        report.check(
+            "TimeCreated keeps 100ns precision",
+            hub::time_created(event)
+                .and_then(|t| t.split_once('.'))
+                .is_some_and(|(_, frac)| {
+                    frac.chars().take_while(char::is_ascii_digit).count() == 7
+                }),
+        );
+        report.check("received_at is set", event.get("received_at").is_some());
+        report.check(
+            "Keywords are resolved next to their mask",
+            event["Keywords"].is_array() && event.get("KeywordsMask").is_some(),
+        );
+        report.check(
+            "Message is set",
+            event["Message"].as_str().is_some_and(|m| !m.is_empty()),
+        );
+        report.check(
+            "exclude_fields drops Execution",
+            event.get("Execution").is_none(),
+        );
+    }
+
+    drain(&config)?;
+    report.check(
+        "a second run resumes from the checkpoint",
+        marked(&output_file, &marker)?.len() == 3,
+    );
+    write_event(EVENT_ID, &format!("{} event 4", marker))?;
+    drain(&config)?;
+    report.check(
+        "a new event is read after the checkpoint",
+        marked(&output_file, &marker)?.len() == 4,
+    );
+
+    let rotated = output::create(&config)?.rotate()?;
+    report.check(
+        "rotation moves the events to a new file",
+        marked(&rotated, &marker)?.len() == 4 && marked(&output_file, &marker)?.is_empty(),
+    );
+
+    if report.failed > 0 {
+        return Err(format!(
+            "{} of {} checks failed, output kept in {}",
+            report.failed,
+            report.passed + report.failed,
+            dir.display()
+        )
+        .into());
+    }
+    println!("All {} checks passed", report.passed);
+    let _ = std::fs::remove_dir_all(&dir);
+    Ok(())
+}
+
+#[derive(Default)]
+struct Report {
+    passed: usize,
+    failed: usize,
+}
+
+impl Report {
+    fn check(&mut self, name: &str, ok: bool) {
+        if ok {
+            self.passed += 1;
+            println!("ok   {}", name);
+        } else {
+            self.failed += 1;
+            println!("FAIL {}", name);
+        }
+    }
+}
+
+fn drain(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let runtime = Runtime {
+        shutdown: Arc::new(AtomicBool::new(false)),
+        stats: Arc::new(Stats::default()),
+        hub: Arc::new(Hub::new(0)),
+        budget: None,
+    };
+    eventlog::drain(config, output::create(config)?, false, &runtime)?;
+    Ok(())
+}
+
+// The written events carrying this run's marker
+fn marked(path: &Path, marker: &str) -> Result<Vec<JsonValue>, Box<dyn std::error::Error>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(text
+        .lines()
+        .filter(|line| line.contains(marker))
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+fn write_event(id: u32, message: &str) -> windows::core::Result<()> {
+    unsafe {
+        let source = RegisterEventSourceW(PCWSTR::null(), &HSTRING::from(SOURCE))?;
+        let message = HSTRING::from(message);
+        let result = ReportEventW(
+            source,
+            EVENTLOG_INFORMATION_TYPE,
+            0,
+            id,
+            None,
+            0,
+            Some(&[PCWSTR(message.as_ptr())]),
+            None,
+        );
+        let _ = DeregisterEventSource(source);
+        result
+    }
+}
+
+// Bookmark XML of the channel's newest event
+fn newest_bookmark() -> Result<String, Box<dyn std::error::Error>> {
+    let bookmark = Bookmark::new(None)?;
+    unsafe {
+        let query = EvtQuery(
+            None,
+            &HSTRING::from(CHANNEL),
+            PCWSTR::null(),
+            EvtQueryChannelPath.0 | EvtQueryReverseDirection.0,
+        )?;
+        let mut events = [0isize; 1];
+        let mut returned = 0u32;
+        if EvtNext(query, &mut events, 1000, 0, &mut returned).is_ok() && returned == 1 {
+            let event = EVT_HANDLE(events[0]);
+            let _ = bookmark.update(event);
+            let _ = EvtClose(event);
+        }
+        let _ = EvtClose(query);
+    }
+    Ok(bookmark.to_xml()?)
+}
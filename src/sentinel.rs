@@ -0,0 +1,650 @@
+use crate::config::{Config, Framing, SentinelConfig};
+use crate::output::{Record, Sink};
+use crate::{http_client, hub};
+use glob_match::glob_match;
+use log::warn;
+use serde_json::{Map, Value as JsonValue};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use ureq::Agent;
+
+// The Logs Ingestion API takes at most 1MB per call
+const MAX_BODY: usize = 1_000_000;
+// Used when the sink sets no batch limits
+const DEFAULT_BATCH_EVENTS: usize = 500;
+const DEFAULT_BATCH_INTERVAL: Duration = Duration::from_secs(5);
+// Pending events held in memory before they are spooled to disk
+const DEFAULT_BUFFER_MAX: usize = 64 << 20;
+// Wait before retrying a failed call when the service doesn't say how long
+const RETRY_AFTER: Duration = Duration::from_secs(5);
+// Tokens are renewed this long before they expire
+const TOKEN_MARGIN: Duration = Duration::from_secs(300);
+const API_VERSION: &str = "2023-01-01";
+
+/// Sends events to Microsoft Sentinel (any Log Analytics workspace) through
+/// the Azure Monitor Logs Ingestion API. Events are batched per stream and
+/// sent as JSON arrays of at most 1MB once a batch holds `max_events`, once
+/// its oldest event is `max_interval` old (see `send_if_due`), or on flush.
+/// A batch only counts as written once the service accepted it; while the
+/// service is throttling, failing or unreachable its events stay pending and
+/// are retried (after `Retry-After` when throttled). One the service refuses
+/// outright, say for a schema mismatch, would be refused again and is
+/// dropped. Pending events beyond `buffer_max` bytes are spooled to a file
+/// and read back, oldest first, once calls succeed again.
+pub struct Sentinel {
+    config: SentinelConfig,
+    agent: Agent,
+    // Bearer token and when to renew it
+    token: Option<(String, Instant)>,
+    // Serialized records waiting to be sent, by stream
+    pending: BTreeMap<String, Vec<String>>,
+    pending_bytes: usize,
+    buffer_max: usize,
+    // Rows moved out of memory, one "<stream>\t<row>" line each; those
+    // before `unspooled` are back in `pending` or sent
+    spool_path: PathBuf,
+    spooled: usize,
+    unspooled: u64,
+    // Pending events, in memory and spooled
+    events: usize,
+    oldest: Instant,
+    retry_at: Instant,
+    max_events: usize,
+    max_interval: Duration,
+    // Bodies this size or larger are sent gzipped
+    gzip_min_bytes: Option<usize>,
+}
+
+impl Sentinel {
+    fn new(config: &Config) -> Result<Sentinel, Box<dyn std::error::Error>> {
+        let mut output = Sentinel::open(config)?;
+        // Fail at startup on credentials that don't work
+        output
+            .token()
+            .map_err(|e| format!("cannot sign in to Entra ID: {}", e))?;
+        Ok(output)
+    }
+
+    fn open(config: &Config) -> Result<Sentinel, Box<dyn std::error::Error>> {
+        let sentinel = config
+            .sentinel
+            .as_ref()
+            .ok_or("sentinel:// output needs a sentinel: section")?;
+        let spool_path = match &sentinel.spool_file {
+            Some(spool) => PathBuf::from(spool),
+            None => std::env::current_exe()?
+                .parent()
+                .ok_or("executable has no parent directory")?
+                .join("sentinel.spool"),
+        };
+        // Events spooled by an earlier run that couldn't send them
+        let spooled = match File::open(&spool_path) {
+            Ok(file) => BufReader::new(file).lines().count(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(format!("cannot read {}: {}", spool_path.display(), e).into()),
+        };
+        Ok(Sentinel {
+            config: sentinel.clone(),
+            agent: http_client::agent(Duration::from_secs(60)),
+            token: None,
+            pending: BTreeMap::new(),
+            pending_bytes: 0,
+            buffer_max: sentinel
+                .buffer_max
+                .map_or(DEFAULT_BUFFER_MAX, |max| max as usize),
+            spool_path,
+            spooled,
+            unspooled: 0,
+            events: spooled,
+            oldest: Instant::now(),
+            retry_at: Instant::now(),
+            max_events: config
+                .sink
+                .batch_max_events
+                .unwrap_or(DEFAULT_BATCH_EVENTS)
+                .max(1),
+            max_interval: config
+                .sink
+                .batch_max_interval_ms
+                .map_or(DEFAULT_BATCH_INTERVAL, Duration::from_millis),
+            gzip_min_bytes: config.sink.gzip_min_bytes,
+        })
+    }
+
+    fn write_event(&mut self, record: &Record) -> io::Result<()> {
+        let row = self.row(record)?;
+        let stream = self.stream(record.event).to_string();
+        if self.events == 0 {
+            self.oldest = Instant::now();
+        }
+        self.pending_bytes += row.len();
+        self.pending.entry(stream).or_default().push(row);
+        self.events += 1;
+        if self.pending_bytes > self.buffer_max {
+            self.spill()?;
+        }
+        if self.events >= self.max_events && Instant::now() >= self.retry_at {
+            self.send()
+        } else {
+            Ok(())
+        }
+    }
+
+    // The record as a row of the stream: the configured columns, or the
+    // event as the output writes it with TimeGenerated added
+    fn row(&self, record: &Record) -> io::Result<String> {
+        let mut row = if self.config.columns.is_empty() {
+            match serde_json::from_str(record.line)? {
+                JsonValue::Object(row) => row,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "not an event")),
+            }
+        } else {
+            self.config
+                .columns
+                .iter()
+                .filter_map(|(column, path)| {
+                    let value = path
+                        .split('.')
+                        .try_fold(record.event, |v, key| v.get(key))?;
+                    Some((column.clone(), value.clone()))
+                })
+                .collect::<Map<_, _>>()
+        };
+        if !row.contains_key("TimeGenerated")
+            && let Some(time) = hub::time_created(record.event)
+        {
+            row.insert("TimeGenerated".to_string(), JsonValue::from(time));
+        }
+        Ok(JsonValue::Object(row).to_string())
+    }
+
+    fn stream(&self, event: &JsonValue) -> &str {
+        let channel = hub::channel(event).unwrap_or_default().to_lowercase();
+        self.config
+            .streams
+            .iter()
+            .find(|s| {
+                s.channels
+                    .iter()
+                    .any(|c| glob_match(&c.to_lowercase(), &channel))
+            })
+            .map_or(&self.config.stream, |s| &s.stream)
+    }
+
+    // Appends the events held in memory to the spool
+    fn spill(&mut self) -> io::Result<()> {
+        if self.spooled == 0 {
+            warn!(
+                "Logs Ingestion API not accepting events, spooling them to {}",
+                self.spool_path.display()
+            );
+        }
+        let mut spool = io::BufWriter::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.spool_path)?,
+        );
+        for (stream, rows) in &self.pending {
+            for row in rows {
+                writeln!(spool, "{}\t{}", stream, row)?;
+            }
+        }
+        spool.flush()?;
+        self.spooled += self.pending.values().map(Vec::len).sum::<usize>();
+        self.pending.clear();
+        self.pending_bytes = 0;
+        Ok(())
+    }
+
+    // Moves the oldest spooled events, up to buffer_max bytes, back into
+    // memory. False when nothing is spooled.
+    fn unspool(&mut self) -> io::Result<bool> {
+        if self.spooled == 0 {
+            return Ok(false);
+        }
+        let mut file = File::open(&self.spool_path)?;
+        file.seek(SeekFrom::Start(self.unspooled))?;
+        let mut reader = BufReader::new(file);
+        let mut line = String::new();
+        while self.spooled > 0
+            && self.pending_bytes < self.buffer_max
+            && reader.read_line(&mut line)? > 0
+        {
+            self.unspooled += line.len() as u64;
+            self.spooled -= 1;
+            if let Some((stream, row)) = line.trim_end().split_once('\t') {
+                self.pending_bytes += row.len();
+                self.pending
+                    .entry(stream.to_string())
+                    .or_default()
+                    .push(row.to_string());
+            }
+            line.clear();
+        }
+        if self.spooled == 0 || reader.fill_buf()?.is_empty() {
+            self.spooled = 0;
+            self.unspooled = 0;
+            std::fs::remove_file(&self.spool_path)?;
+        }
+        Ok(true)
+    }
+
+    /// Sends the events held in memory, then the oldest spooled ones that fit
+    /// in memory.
+    fn send(&mut self) -> io::Result<()> {
+        self.send_pending()?;
+        if self.unspool()? {
+            self.send_pending()?;
+        }
+        Ok(())
+    }
+
+    /// Sends the events held in memory, each stream in calls of at most 1MB.
+    /// What was sent before a call failed is not sent again.
+    fn send_pending(&mut self) -> io::Result<()> {
+        let streams: Vec<String> = self.pending.keys().cloned().collect();
+        for stream in streams {
+            let rows = self.pending.remove(&stream).unwrap_or_default();
+            let mut sent = 0;
+            while sent < rows.len() {
+                let (body, count) = chunk(&rows[sent..]);
+                if count == 0 {
+                    warn!(
+                        "Dropped an event of {} bytes, over the Logs Ingestion API's 1MB limit",
+                        rows[sent].len()
+                    );
+                    sent += 1;
+                    continue;
+                }
+                match self.post(&stream, body) {
+                    Ok(Delivery::Accepted) => {}
+                    Ok(Delivery::Rejected(reason)) => warn!(
+                        "Dropped {} events the Logs Ingestion API refused: {}",
+                        count, reason
+                    ),
+                    Err(e) => {
+                        self.pending.insert(stream, rows[sent..].to_vec());
+                        self.pending_bytes = self.pending.values().flatten().map(String::len).sum();
+                        self.events =
+                            self.pending.values().map(Vec::len).sum::<usize>() + self.spooled;
+                        return Err(e);
+                    }
+                }
+                sent += count;
+            }
+        }
+        self.pending_bytes = 0;
+        self.events = self.spooled;
+        Ok(())
+    }
+
+    fn post(&mut self, stream: &str, body: String) -> io::Result<Delivery> {
+        let url = format!(
+            "{}/dataCollectionRules/{}/streams/{}?api-version={}",
+            self.config.endpoint.trim_end_matches('/'),
+            self.config.dcr_id,
+            stream,
+            API_VERSION
+        );
+        // The 1MB limit is on the uncompressed body, which chunk() keeps to
+        let (body, encoding) = http_client::encode(body, self.gzip_min_bytes)?;
+        // Once more with a new token when the current one was refused
+        let mut renewed = false;
+        loop {
+            let token = self.token()?;
+            let mut request = self
+                .agent
+                .post(&url)
+                .config()
+                .http_status_as_error(false)
+                .build()
+                .header("Authorization", &format!("Bearer {}", token))
+                .header("Content-Type", "application/json");
+            if let Some(encoding) = encoding {
+                request = request.header("Content-Encoding", encoding);
+            }
+            let response = request.send(&body[..]).map_err(io::Error::other)?;
+            let status = response.status().as_u16();
+            if (200..300).contains(&status) {
+                return Ok(Delivery::Accepted);
+            }
+            if status == 401 && !renewed {
+                self.token = None;
+                renewed = true;
+                continue;
+            }
+            if !retryable(status) {
+                let detail = response.into_body().read_to_string().unwrap_or_default();
+                return Ok(Delivery::Rejected(format!(
+                    "{} for stream {}: {}",
+                    status,
+                    stream,
+                    detail.trim()
+                )));
+            }
+            let wait = response
+                .headers()
+                .get("Retry-After")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse().ok())
+                .map_or(RETRY_AFTER, Duration::from_secs);
+            self.retry_at = Instant::now() + wait;
+            let detail = response.into_body().read_to_string().unwrap_or_default();
+            return Err(io::Error::other(format!(
+                "Logs Ingestion API returned {} for stream {}: {}",
+                status,
+                stream,
+                detail.trim()
+            )));
+        }
+    }
+
+    // A token for the Logs Ingestion API from the client credentials flow,
+    // reused until shortly before it expires
+    fn token(&mut self) -> io::Result<String> {
+        if let Some((token, renew_at)) = &self.token
+            && Instant::now() < *renew_at
+        {
+            return Ok(token.clone());
+        }
+        let url = format!(
+            "https://{}/{}/oauth2/v2.0/token",
+            self.config.authority, self.config.tenant_id
+        );
+        let response = self
+            .agent
+            .post(&url)
+            .send_form([
+                ("grant_type", "client_credentials"),
+                ("client_id", self.config.client_id.as_str()),
+                ("client_secret", self.config.client_secret.as_str()),
+                ("scope", "https://monitor.azure.com//.default"),
+            ])
+            .map_err(io::Error::other)?;
+        let text = response
+            .into_body()
+            .read_to_string()
+            .map_err(io::Error::other)?;
+        let reply: JsonValue = serde_json::from_str(&text)?;
+        let token = reply
+            .get("access_token")
+            .and_then(JsonValue::as_str)
+            .ok_or_else(|| io::Error::other("token response has no access_token"))?
+            .to_string();
+        let lifetime = reply
+            .get("expires_in")
+            .and_then(JsonValue::as_u64)
+            .map_or(Duration::from_secs(3600), Duration::from_secs);
+        let renew_at = Instant::now() + lifetime.saturating_sub(TOKEN_MARGIN);
+        self.token = Some((token.clone(), renew_at));
+        Ok(token)
+    }
+}
+
+impl Sink for Sentinel {
+    fn send_batch(&mut self, batch: &[Record]) -> io::Result<()> {
+        batch.iter().try_for_each(|r| self.write_event(r))
+    }
+
+    // Everything pending, spooled events included, has to be sent before a
+    // checkpoint may cover it
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_pending()?;
+        while self.unspool()? {
+            self.send_pending()?;
+        }
+        Ok(())
+    }
+
+    // Sends a partial batch whose oldest event has waited long enough,
+    // unless the service asked to wait
+    fn send_if_due(&mut self) -> Option<io::Result<()>> {
+        if self.events > 0
+            && self.oldest.elapsed() >= self.max_interval
+            && Instant::now() >= self.retry_at
+        {
+            Some(self.send())
+        } else {
+            Some(Ok(()))
+        }
+    }
+
+    fn healthcheck(&mut self) -> io::Result<()> {
+        self.token().map(|_| ())
+    }
+}
+
+// What the service answered to a call
+#[derive(Debug, PartialEq)]
+enum Delivery {
+    Accepted,
+    // Refused for good, with the status and the service's reason
+    Rejected(String),
+}
+
+// Throttling and server errors pass; other statuses refuse the call itself
+fn retryable(status: u16) -> bool {
+    status == 429 || (500..600).contains(&status)
+}
+
+// A JSON array of as many leading rows as fit in one call, and how many
+// that is (0 when the first doesn't fit on its own)
+fn chunk(rows: &[String]) -> (String, usize) {
+    let mut body = String::from("[");
+    let mut count = 0;
+    for row in rows {
+        let comma = usize::from(count > 0);
+        if body.len() + comma + row.len() + 1 > MAX_BODY {
+            break;
+        }
+        if comma > 0 {
+            body.push(',');
+        }
+        body.push_str(row);
+        count += 1;
+    }
+    body.push(']');
+    (body, count)
+}
+
+/// Opens a `sentinel://` output configured by the `sentinel:` section.
+pub fn create(
+    _target: &str,
+    _framing: Framing,
+    config: &Config,
+) -> Result<Box<dyn Sink>, Box<dyn std::error::Error>> {
+    Ok(Box::new(Sentinel::new(config)?))
+}
+
+/// Signs in to Entra ID for a `sentinel://` output, sending no events.
+pub fn probe(_target: &str, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    Sentinel::new(config).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn rows(count: usize, len: usize) -> Vec<String> {
+        (0..count).map(|_| "x".repeat(len)).collect()
+    }
+
+    #[test]
+    fn chunks_fill_calls_up_to_the_limit() {
+        // 10 rows of 100KB and their commas don't fit in 1MB with the brackets
+        let rows = rows(12, 100_000);
+        let (body, count) = chunk(&rows);
+        assert_eq!(count, 9);
+        assert!(body.len() <= MAX_BODY);
+        assert!(body.starts_with('[') && body.ends_with(']'));
+        assert_eq!(body.matches(',').count(), 8);
+
+        let (_, count) = chunk(&rows[9..]);
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn chunks_take_a_row_that_fills_a_call() {
+        let rows = rows(2, MAX_BODY - 2);
+        let (body, count) = chunk(&rows);
+        assert_eq!(count, 1);
+        assert_eq!(body.len(), MAX_BODY);
+    }
+
+    #[test]
+    fn chunks_leave_rows_over_the_limit() {
+        let mut rows = rows(1, MAX_BODY - 1);
+        rows.push("{}".to_string());
+        let (body, count) = chunk(&rows);
+        assert_eq!((body.as_str(), count), ("[]", 0));
+    }
+
+    fn sentinel(spool: &std::path::Path, endpoint: &str) -> Sentinel {
+        let config: Config = serde_json::from_value(json!({
+            "channels": ["Application"],
+            "sentinel": {
+                "endpoint": endpoint,
+                "dcr_id": "dcr-test",
+                "stream": "Custom-Events_CL",
+                "streams": [{ "channels": ["Security"], "stream": "Custom-Security_CL" }],
+                "tenant_id": "tenant",
+                "client_id": "client",
+                "client_secret": "secret",
+                "buffer_max": 100,
+                "spool_file": spool,
+            },
+            "sink": { "batch_max_events": 1000 },
+        }))
+        .unwrap();
+        Sentinel::open(&config).unwrap()
+    }
+
+    fn write(output: &mut Sentinel, channel: &str, id: u64) {
+        let event = json!({ "Channel": channel, "EventRecordID": id });
+        let line = event.to_string();
+        output
+            .write_event(&Record {
+                event: &event,
+                line: &line,
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn spools_events_over_buffer_max() {
+        let spool = std::env::temp_dir().join(format!("sentinel-spool-{}", std::process::id()));
+        let _ = std::fs::remove_file(&spool);
+        let mut output = sentinel(&spool, "https://127.0.0.1:1");
+        for id in 1..=4 {
+            let channel = if id % 2 == 0 { "Security" } else { "System" };
+            write(&mut output, channel, id);
+        }
+        // Rows are about 40 bytes: the third one overflowed memory
+        assert_eq!((output.spooled, output.events), (3, 4));
+        assert!(output.pending_bytes <= output.buffer_max);
+
+        // Spooled events come back in memory-sized parts, streams kept
+        output.pending.clear();
+        output.pending_bytes = 0;
+        assert!(output.unspool().unwrap());
+        assert_eq!(output.pending["Custom-Events_CL"].len(), 2);
+        assert_eq!(output.pending["Custom-Security_CL"].len(), 1);
+        assert!(output.pending["Custom-Security_CL"][0].contains("\"EventRecordID\":2"));
+        assert!(!spool.exists());
+        assert!(!output.unspool().unwrap());
+    }
+
+    #[test]
+    fn picks_up_an_earlier_spool() {
+        let spool = std::env::temp_dir().join(format!("sentinel-leftover-{}", std::process::id()));
+        std::fs::write(&spool, "Custom-Events_CL\t{}\nCustom-Events_CL\t{}\n").unwrap();
+        let mut output = sentinel(&spool, "https://127.0.0.1:1");
+        assert_eq!((output.spooled, output.events), (2, 2));
+        assert!(output.unspool().unwrap());
+        assert_eq!(output.pending["Custom-Events_CL"], ["{}", "{}"]);
+        assert!(!spool.exists());
+    }
+
+    // A Logs Ingestion API on localhost answering each call with the next
+    // status; the thread returns the bodies it was sent
+    fn service(statuses: &[u16]) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let statuses = statuses.to_vec();
+        let service = thread::spawn(move || {
+            statuses
+                .into_iter()
+                .map(|status| {
+                    let (connection, _) = listener.accept().unwrap();
+                    let mut reader = BufReader::new(connection);
+                    let mut length = 0;
+                    let mut line = String::new();
+                    while reader.read_line(&mut line).unwrap() > 2 {
+                        if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                            length = value.trim().parse().unwrap();
+                        }
+                        line.clear();
+                    }
+                    let mut body = vec![0; length];
+                    reader.read_exact(&mut body).unwrap();
+                    write!(
+                        reader.get_mut(),
+                        "HTTP/1.1 {} Status\r\nContent-Length: 6\r\nConnection: close\r\n\r\nreason",
+                        status
+                    )
+                    .unwrap();
+                    String::from_utf8(body).unwrap()
+                })
+                .collect()
+        });
+        (endpoint, service)
+    }
+
+    fn signed_in(output: &mut Sentinel) {
+        output.token = Some(("token".to_string(), Instant::now() + TOKEN_MARGIN));
+    }
+
+    #[test]
+    fn drops_batches_the_service_refuses() {
+        let spool = std::env::temp_dir().join(format!("sentinel-refused-{}", std::process::id()));
+        let (endpoint, service) = service(&[400, 200]);
+        let mut output = sentinel(&spool, &endpoint);
+        signed_in(&mut output);
+        write(&mut output, "Security", 1);
+        write(&mut output, "System", 2);
+
+        // The refused System batch is not kept; the Security one still goes
+        output.send_pending().unwrap();
+        assert!(output.pending.is_empty());
+        assert_eq!((output.events, output.pending_bytes), (0, 0));
+        let bodies = service.join().unwrap();
+        assert!(bodies[0].contains("\"EventRecordID\":2"));
+        assert!(bodies[1].contains("\"EventRecordID\":1"));
+    }
+
+    #[test]
+    fn keeps_batches_while_the_service_is_failing() {
+        let spool = std::env::temp_dir().join(format!("sentinel-failing-{}", std::process::id()));
+        let (endpoint, service) = service(&[503]);
+        let mut output = sentinel(&spool, &endpoint);
+        signed_in(&mut output);
+        write(&mut output, "System", 1);
+
+        assert!(output.send_pending().is_err());
+        assert_eq!(output.pending["Custom-Events_CL"].len(), 1);
+        assert_eq!(output.events, 1);
+        assert!(output.retry_at > Instant::now());
+        service.join().unwrap();
+
+        assert!(retryable(429) && retryable(500) && retryable(503));
+        assert!(!retryable(400) && !retryable(403) && !retryable(413));
+    }
+}
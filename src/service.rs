@@ -0,0 +1,373 @@
+use log::{error, info, warn};
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use windows::Win32::Foundation::{
+    ERROR_CALL_NOT_IMPLEMENTED, ERROR_SERVICE_SPECIFIC_ERROR, NO_ERROR,
+};
+use windows::Win32::Storage::FileSystem::DELETE;
+use windows::Win32::System::Services::*;
+use windows::core::{PCWSTR, PWSTR};
+
+pub const SERVICE_NAME: &str = "rs-wineventlog";
+const DISPLAY_NAME: &str = "Windows Event Log Exporter (rs-wineventlog)";
+const DESCRIPTION: &str = "Monitors Windows Event Log channels and exports events as JSON.";
+
+// Restart delays applied by the SCM on the 1st, 2nd and subsequent failures
+const RESTART_DELAYS_MS: [u32; 3] = [5_000, 30_000, 120_000];
+// Failure count resets after a day without failures
+const RESET_PERIOD_SECS: u32 = 86_400;
+
+// How long each stop-pending report asks the SCM to wait for the next one
+// while the last events are written
+const STOP_WAIT_HINT_MS: u32 = 30_000;
+
+static SOURCE: OnceLock<crate::config::Source> = OnceLock::new();
+static SHUTDOWN: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+// The service's status handle, for the control handler
+static STATUS_HANDLE: AtomicPtr<c_void> = AtomicPtr::new(std::ptr::null_mut());
+// Set once SERVICE_STOPPED is reported, after which stop-pending reports
+// would bring the service back to life in the SCM's view
+static STOPPED: Mutex<bool> = Mutex::new(false);
+
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(std::iter::once(0)).collect()
+}
+
+pub fn install(
+    config: Option<String>,
+    profile: Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let exe = std::env::current_exe()?;
+    let command = format!(
+        "\"{}\"{} run-service",
+        exe.display(),
+        arguments(config, profile)?
+    );
+
+    let name = wide(SERVICE_NAME);
+    let display = wide(DISPLAY_NAME);
+    let command_w = wide(&command);
+
+    unsafe {
+        let scm = OpenSCManagerW(PCWSTR::null(), PCWSTR::null(), SC_MANAGER_CREATE_SERVICE)?;
+        let service = match CreateServiceW(
+            scm,
+            PCWSTR(name.as_ptr()),
+            PCWSTR(display.as_ptr()),
+            SERVICE_ALL_ACCESS,
+            SERVICE_WIN32_OWN_PROCESS,
+            SERVICE_AUTO_START,
+            SERVICE_ERROR_NORMAL,
+            PCWSTR(command_w.as_ptr()),
+            PCWSTR::null(),
+            None,
+            PCWSTR::null(),
+            PCWSTR::null(),
+            PCWSTR::null(),
+        ) {
+            Ok(s) => s,
+            Err(e) => {
+                let _ = CloseServiceHandle(scm);
+                return Err(e.into());
+            }
+        };
+
+        let mut description = wide(DESCRIPTION);
+        let info = SERVICE_DESCRIPTIONW {
+            lpDescription: PWSTR(description.as_mut_ptr()),
+        };
+        let _ = ChangeServiceConfig2W(
+            service,
+            SERVICE_CONFIG_DESCRIPTION,
+            Some(&info as *const _ as *const c_void),
+        );
+
+        let result = configure_recovery(service);
+        let _ = CloseServiceHandle(service);
+        let _ = CloseServiceHandle(scm);
+        result?;
+    }
+
+    info!("Installed service '{}': {}", SERVICE_NAME, command);
+
+    // The service works without them; only monitoring integrations are affected
+    if let Err(e) = crate::perf::register() {
+        warn!("Could not register performance counters: {}", e);
+    }
+    Ok(())
+}
+
+/// The --config and --profile arguments for a collector started by Windows
+/// rather than from this command line, each with a leading space.
+pub fn arguments(
+    config: Option<String>,
+    profile: Option<String>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut arguments = String::new();
+    if let Some(path) = config {
+        if path == "-" {
+            return Err("a service or scheduled task cannot read its config from stdin".into());
+        }
+        // URLs are passed through; WINEVENTLOG_CONFIG_AUTH supplies any credentials
+        let path = if crate::config::is_url(&path) {
+            path
+        } else {
+            std::path::absolute(path)?.display().to_string()
+        };
+        arguments.push_str(&format!(" --config \"{}\"", path));
+    }
+    if let Some(profile) = profile {
+        arguments.push_str(&format!(" --profile \"{}\"", profile));
+    }
+    Ok(arguments)
+}
+
+/// Restart the service with increasing delays when it crashes or stops with
+/// a non-zero exit code.
+unsafe fn configure_recovery(service: SC_HANDLE) -> Result<(), Box<dyn std::error::Error>> {
+    unsafe {
+        let mut actions: Vec<SC_ACTION> = RESTART_DELAYS_MS
+            .iter()
+            .map(|&delay| SC_ACTION {
+                Type: SC_ACTION_RESTART,
+                Delay: delay,
+            })
+            .collect();
+        let failure_actions = SERVICE_FAILURE_ACTIONSW {
+            dwResetPeriod: RESET_PERIOD_SECS,
+            lpRebootMsg: PWSTR::null(),
+            lpCommand: PWSTR::null(),
+            cActions: actions.len() as u32,
+            lpsaActions: actions.as_mut_ptr(),
+        };
+        ChangeServiceConfig2W(
+            service,
+            SERVICE_CONFIG_FAILURE_ACTIONS,
+            Some(&failure_actions as *const _ as *const c_void),
+        )?;
+
+        let flag = SERVICE_FAILURE_ACTIONS_FLAG {
+            fFailureActionsOnNonCrashFailures: true.into(),
+        };
+        ChangeServiceConfig2W(
+            service,
+            SERVICE_CONFIG_FAILURE_ACTIONS_FLAG,
+            Some(&flag as *const _ as *const c_void),
+        )?;
+    }
+    Ok(())
+}
+
+pub fn uninstall() -> Result<(), Box<dyn std::error::Error>> {
+    let name = wide(SERVICE_NAME);
+    unsafe {
+        let scm = OpenSCManagerW(PCWSTR::null(), PCWSTR::null(), SC_MANAGER_CONNECT)?;
+        let service = match OpenServiceW(
+            scm,
+            PCWSTR(name.as_ptr()),
+            SERVICE_STOP | SERVICE_QUERY_STATUS | DELETE.0,
+        ) {
+            Ok(s) => s,
+            Err(e) => {
+                let _ = CloseServiceHandle(scm);
+                return Err(e.into());
+            }
+        };
+
+        // Stopping fails if the service isn't running, which is fine here
+        let mut status = SERVICE_STATUS::default();
+        let _ = ControlService(service, SERVICE_CONTROL_STOP, &mut status);
+
+        let result = DeleteService(service);
+        let _ = CloseServiceHandle(service);
+        let _ = CloseServiceHandle(scm);
+        result?;
+    }
+
+    if let Err(e) = crate::perf::unregister() {
+        warn!("Could not unregister performance counters: {}", e);
+    }
+
+    info!("Uninstalled service '{}'", SERVICE_NAME);
+    Ok(())
+}
+
+/// Restarts the installed service if it is running, e.g. to pick up a new
+/// binary. Returns whether a restart happened.
+#[cfg(feature = "update")]
+pub fn restart_if_running() -> Result<bool, Box<dyn std::error::Error>> {
+    let name = wide(SERVICE_NAME);
+    unsafe {
+        let scm = OpenSCManagerW(PCWSTR::null(), PCWSTR::null(), SC_MANAGER_CONNECT)?;
+        let service = match OpenServiceW(
+            scm,
+            PCWSTR(name.as_ptr()),
+            SERVICE_STOP | SERVICE_START | SERVICE_QUERY_STATUS,
+        ) {
+            Ok(s) => s,
+            // Not installed (or not accessible): nothing to restart
+            Err(_) => {
+                let _ = CloseServiceHandle(scm);
+                return Ok(false);
+            }
+        };
+
+        let result = (|| {
+            let mut status = SERVICE_STATUS::default();
+            QueryServiceStatus(service, &mut status)?;
+            if status.dwCurrentState != SERVICE_RUNNING {
+                return Ok(false);
+            }
+
+            ControlService(service, SERVICE_CONTROL_STOP, &mut status)?;
+            let deadline = std::time::Instant::now() + std::time::Duration::from_secs(60);
+            while status.dwCurrentState != SERVICE_STOPPED {
+                if std::time::Instant::now() >= deadline {
+                    return Err("timed out waiting for the service to stop".into());
+                }
+                std::thread::sleep(std::time::Duration::from_millis(500));
+                QueryServiceStatus(service, &mut status)?;
+            }
+
+            StartServiceW(service, None)?;
+            Ok(true)
+        })();
+
+        let _ = CloseServiceHandle(service);
+        let _ = CloseServiceHandle(scm);
+        result
+    }
+}
+
+/// Entry point when started by the Service Control Manager. Blocks until the
+/// service is stopped.
+pub fn run(source: crate::config::Source) -> Result<(), Box<dyn std::error::Error>> {
+    let _ = SOURCE.set(source);
+    let mut name = wide(SERVICE_NAME);
+    let table = [
+        SERVICE_TABLE_ENTRYW {
+            lpServiceName: PWSTR(name.as_mut_ptr()),
+            lpServiceProc: Some(service_main),
+        },
+        SERVICE_TABLE_ENTRYW::default(),
+    ];
+    unsafe { StartServiceCtrlDispatcherW(table.as_ptr())? };
+    Ok(())
+}
+
+unsafe extern "system" fn service_main(_argc: u32, _argv: *mut PWSTR) {
+    let shutdown = Arc::clone(SHUTDOWN.get_or_init(|| Arc::new(AtomicBool::new(false))));
+    let name = wide(SERVICE_NAME);
+
+    let handle = match unsafe {
+        RegisterServiceCtrlHandlerExW(PCWSTR(name.as_ptr()), Some(control_handler), None)
+    } {
+        Ok(h) => h,
+        Err(e) => {
+            error!("Failed to register service control handler: {}", e);
+            return;
+        }
+    };
+
+    STATUS_HANDLE.store(handle.0, Ordering::SeqCst);
+    set_status(handle, SERVICE_RUNNING, 0, 0);
+
+    let source = SOURCE.get().cloned().unwrap_or_default();
+    // A panic must not unwind into the SCM's dispatcher
+    let run = std::panic::catch_unwind(|| crate::run(source, false, false, None, shutdown));
+    let exit_code = match run {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) => {
+            error!("Service stopped with error: {}", e);
+            crate::fatal::code(e.as_ref()).into()
+        }
+        Err(_) => {
+            error!("Service crashed, see crashes.ndjson next to the state file");
+            crate::fatal::Kind::Crash.code().into()
+        }
+    };
+
+    // A non-zero exit code lets the SCM apply the configured recovery actions
+    let mut stopped = STOPPED.lock().unwrap_or_else(|e| e.into_inner());
+    set_status(handle, SERVICE_STOPPED, exit_code, 0);
+    *stopped = true;
+}
+
+// Reports SERVICE_STOP_PENDING with a rising checkpoint until the service
+// has stopped, so the SCM waits for the output to be flushed rather than
+// taking the service for hung
+fn report_stop_pending() {
+    // Status handles aren't Send
+    let handle = STATUS_HANDLE.load(Ordering::SeqCst) as usize;
+    if handle == 0 {
+        return;
+    }
+    std::thread::spawn(move || {
+        let handle = SERVICE_STATUS_HANDLE(handle as *mut c_void);
+        for checkpoint in 1.. {
+            {
+                let stopped = STOPPED.lock().unwrap_or_else(|e| e.into_inner());
+                if *stopped {
+                    return;
+                }
+                set_status(handle, SERVICE_STOP_PENDING, 0, checkpoint);
+            }
+            std::thread::sleep(Duration::from_millis(u64::from(STOP_WAIT_HINT_MS / 3)));
+        }
+    });
+}
+
+unsafe extern "system" fn control_handler(
+    control: u32,
+    _event_type: u32,
+    _event_data: *mut c_void,
+    _context: *mut c_void,
+) -> u32 {
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            info!("Received service stop request, stopping...");
+            if let Some(shutdown) = SHUTDOWN.get()
+                && !shutdown.swap(true, Ordering::SeqCst)
+            {
+                report_stop_pending();
+            }
+            NO_ERROR.0
+        }
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR.0,
+        _ => ERROR_CALL_NOT_IMPLEMENTED.0,
+    }
+}
+
+fn set_status(
+    handle: SERVICE_STATUS_HANDLE,
+    state: SERVICE_STATUS_CURRENT_STATE,
+    exit_code: u32,
+    checkpoint: u32,
+) {
+    let status = SERVICE_STATUS {
+        dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+        dwCurrentState: state,
+        dwControlsAccepted: if state == SERVICE_RUNNING {
+            SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN
+        } else {
+            0
+        },
+        dwWin32ExitCode: if exit_code == 0 {
+            NO_ERROR.0
+        } else {
+            ERROR_SERVICE_SPECIFIC_ERROR.0
+        },
+        dwServiceSpecificExitCode: exit_code,
+        dwCheckPoint: checkpoint,
+        dwWaitHint: if state == SERVICE_STOP_PENDING {
+            STOP_WAIT_HINT_MS
+        } else {
+            0
+        },
+    };
+    unsafe {
+        let _ = SetServiceStatus(handle, &status);
+    }
+}
@@ -0,0 +1,234 @@
+use crate::config::{AfterUpload, SftpConfig};
+use log::{info, warn};
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread;
+
+/// Uploads rotated output files to an SFTP server, oldest first, with the
+/// Windows OpenSSH client. Each file is sent as `<remote>.part` and renamed
+/// once complete; a failed upload is retried every `retry_interval`, resuming
+/// the partial remote file. Files still waiting are listed in
+/// `<output>.uploads`, so they are picked up again after a restart, and
+/// retention doesn't delete them.
+pub struct Uploader {
+    queue: Sender<PathBuf>,
+}
+
+impl Uploader {
+    pub fn start(output: &Path, config: &SftpConfig) -> Result<Uploader, String> {
+        if config.after_upload == AfterUpload::Archive && config.archive_dir.is_none() {
+            return Err("sftp.after_upload: archive needs sftp.archive_dir".to_string());
+        }
+        let list = list_path(output);
+        let pending = pending(output).into();
+        let (queue, received) = mpsc::channel();
+        let config = config.clone();
+        thread::spawn(move || run(&config, &list, pending, received));
+        Ok(Uploader { queue })
+    }
+
+    pub fn upload(&self, path: PathBuf) {
+        let _ = self.queue.send(path);
+    }
+}
+
+/// The rotated files of `output` still waiting to be uploaded, oldest first.
+pub fn pending(output: &Path) -> Vec<PathBuf> {
+    std::fs::read_to_string(list_path(output))
+        .unwrap_or_default()
+        .lines()
+        .filter(|l| !l.is_empty())
+        .map(PathBuf::from)
+        .collect()
+}
+
+fn list_path(output: &Path) -> PathBuf {
+    let mut name = output.as_os_str().to_owned();
+    name.push(".uploads");
+    PathBuf::from(name)
+}
+
+fn run(config: &SftpConfig, list: &Path, mut pending: VecDeque<PathBuf>, queue: Receiver<PathBuf>) {
+    let mut retry = false;
+    // Alternates after failures: a resume needs the partial remote file, which
+    // a failed first attempt may never have created
+    let mut resume = false;
+    loop {
+        let received = if pending.is_empty() {
+            queue.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else if retry {
+            queue.recv_timeout(config.retry_interval())
+        } else {
+            // Checked before every upload, so once the output is closed this
+            // thread stops instead of racing the next uploader for the list
+            queue.try_recv().map_err(|e| match e {
+                TryRecvError::Empty => RecvTimeoutError::Timeout,
+                TryRecvError::Disconnected => RecvTimeoutError::Disconnected,
+            })
+        };
+        match received {
+            Ok(path) => {
+                pending.push_back(path);
+                pending.extend(queue.try_iter());
+                save(list, &pending);
+                continue;
+            }
+            // The output was closed (shutdown or reload); whoever opens it
+            // next carries on from the list
+            Err(RecvTimeoutError::Disconnected) => return,
+            Err(RecvTimeoutError::Timeout) => retry = false,
+        }
+
+        let Some(path) = pending.front().cloned() else {
+            continue;
+        };
+        if path.exists() {
+            match upload(config, &path, resume) {
+                Ok(remote) => {
+                    info!("Uploaded {} to {}", path.display(), remote);
+                    after_upload(config, &path);
+                    resume = false;
+                }
+                Err(e) => {
+                    warn!(
+                        "Upload of {} failed, retrying in {}s: {}",
+                        path.display(),
+                        config.retry_interval().as_secs(),
+                        e
+                    );
+                    resume = !resume;
+                    retry = true;
+                    continue;
+                }
+            }
+        } else {
+            warn!("{} no longer exists, not uploading it", path.display());
+        }
+        pending.pop_front();
+        save(list, &pending);
+    }
+}
+
+fn upload(
+    config: &SftpConfig,
+    path: &Path,
+    resume: bool,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let remote = config
+        .remote_path
+        .replace("{file}", &name)
+        .replace(
+            "{hostname}",
+            &std::env::var("COMPUTERNAME").unwrap_or_else(|_| "localhost".to_string()),
+        )
+        .replace("{date}", &period(path).format("%Y-%m-%d").to_string());
+    let partial = format!("{}.part", remote);
+
+    // A leading "-" lets the batch go on when the command fails, e.g. for
+    // directories that already exist
+    let mut batch = String::new();
+    let mut dir = String::new();
+    if let Some((parents, _)) = remote.rsplit_once('/') {
+        for part in parents.split('/') {
+            dir.push_str(part);
+            if !part.is_empty() {
+                writeln!(batch, "-mkdir {}", quote(&dir))?;
+            }
+            dir.push('/');
+        }
+    }
+    let put = if resume { "reput" } else { "put" };
+    writeln!(
+        batch,
+        "{} {} {}",
+        put,
+        quote(&path.to_string_lossy()),
+        quote(&partial)
+    )?;
+    writeln!(batch, "-rm {}", quote(&remote))?;
+    writeln!(batch, "rename {} {}", quote(&partial), quote(&remote))?;
+
+    let mut command = Command::new("sftp");
+    command.args(["-b", "-", "-P", &config.port.to_string()]);
+    if let Some(key) = &config.key_file {
+        command.arg("-i").arg(key);
+    }
+    if let Some(known_hosts) = &config.known_hosts {
+        command
+            .arg("-o")
+            .arg(format!("UserKnownHostsFile={}", known_hosts));
+    }
+    command
+        .arg(format!("{}@{}", config.user, config.host))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    let mut child = command
+        .spawn()
+        .map_err(|e| format!("cannot run sftp (is the OpenSSH client installed?): {}", e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(batch.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr)
+            .trim()
+            .to_string()
+            .into());
+    }
+    Ok(remote)
+}
+
+// When the file's events were written, going by its last write: a retried
+// or resumed upload still goes where the file's day is, not the upload's
+fn period(path: &Path) -> chrono::DateTime<chrono::Local> {
+    std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .map(chrono::DateTime::from)
+        .unwrap_or_else(|_| chrono::Local::now())
+}
+
+fn after_upload(config: &SftpConfig, path: &Path) {
+    let result = match (config.after_upload, &config.archive_dir) {
+        (AfterUpload::Delete, _) => std::fs::remove_file(path),
+        (AfterUpload::Archive, Some(dir)) => std::fs::create_dir_all(dir).and_then(|()| {
+            std::fs::rename(
+                path,
+                Path::new(dir).join(path.file_name().unwrap_or_default()),
+            )
+        }),
+        _ => Ok(()),
+    };
+    if let Err(e) = result {
+        warn!("Uploaded {} but cannot remove it: {}", path.display(), e);
+    }
+}
+
+// The files still to upload, one path per line
+fn save(list: &Path, pending: &VecDeque<PathBuf>) {
+    let result = if pending.is_empty() {
+        std::fs::remove_file(list).or_else(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => Ok(()),
+            _ => Err(e),
+        })
+    } else {
+        let text: String = pending
+            .iter()
+            .map(|p| format!("{}\n", p.display()))
+            .collect();
+        std::fs::write(list, text)
+    };
+    if let Err(e) = result {
+        warn!("Cannot update {}: {}", list.display(), e);
+    }
+}
+
+// Batch file arguments are double-quoted, with backslash escapes
+fn quote(arg: &str) -> String {
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
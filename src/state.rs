@@ -0,0 +1,409 @@
+use crate::stats::Stats;
+#[cfg(feature = "state")]
+use log::info;
+#[cfg(feature = "state")]
+use redb::{
+    Database, DatabaseError, ReadOnlyDatabase, ReadableDatabase, ReadableTable,
+    ReadableTableMetadata, TableDefinition,
+};
+#[cfg(feature = "state")]
+use serde_json::{Map, Value as JsonValue, json};
+#[cfg(feature = "state")]
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+#[cfg(feature = "state")]
+use std::path::PathBuf;
+use std::sync::Arc;
+#[cfg(feature = "state")]
+use std::sync::Mutex;
+
+#[cfg(feature = "state")]
+// Channel -> Event Log bookmark XML
+const BOOKMARKS: TableDefinition<&str, &str> = TableDefinition::new("bookmarks");
+#[cfg(feature = "state")]
+// Channel -> EventRecordID of the newest event written, which outlives a
+// bookmark that is lost or corrupt
+const HIGH_WATER: TableDefinition<&str, u64> = TableDefinition::new("high_water");
+#[cfg(feature = "state")]
+// "<channel>/<counter>" -> total over all runs, e.g. "Security/dropped"
+const COUNTERS: TableDefinition<&str, u64> = TableDefinition::new("counters");
+
+#[cfg(feature = "state")]
+// A database file can only be opened once per process; reloads reuse it
+static OPEN: Mutex<Option<Arc<State>>> = Mutex::new(None);
+
+#[cfg(feature = "state")]
+/// Collector state kept between --once and scheduled runs in one embedded
+/// database (redb, built with the "state" feature): the per-channel bookmarks reading resumes from, the
+/// newest EventRecordID written (which keeps events from being written twice
+/// when a bookmark is lost), and event, drop and write error counts over all
+/// runs. Every update is a transaction, so a crash leaves the last committed
+/// state intact.
+pub struct State {
+    path: PathBuf,
+    db: Database,
+    // Counter values of this process's stats already added to the totals
+    saved: Mutex<HashMap<String, u64>>,
+}
+
+#[cfg(feature = "state")]
+/// Opens (or creates) the state database at `path`. Bookmarks from an
+/// older JSON checkpoint file at `legacy` are imported into a new database,
+/// and the file is renamed to *.imported.
+pub fn open(path: &Path, legacy: &Path) -> Result<Arc<State>, Box<dyn std::error::Error>> {
+    let mut open = OPEN.lock().unwrap();
+    if let Some(state) = open.as_ref().filter(|s| s.path == path) {
+        return Ok(Arc::clone(state));
+    }
+    let db = Database::create(path).map_err(|e| match e {
+        DatabaseError::DatabaseAlreadyOpen => format!(
+            "state file {} is in use by another instance (set state_file to give each its own)",
+            path.display()
+        ),
+        e => format!("cannot open state file {}: {}", path.display(), e),
+    })?;
+
+    // Created up front, so reads never find a table missing
+    let txn = db.begin_write()?;
+    let imported = {
+        let mut bookmarks = txn.open_table(BOOKMARKS)?;
+        txn.open_table(COUNTERS)?;
+        txn.open_table(HIGH_WATER)?;
+        match std::fs::read_to_string(legacy) {
+            Ok(text) if bookmarks.is_empty()? => {
+                let legacy_bookmarks: BTreeMap<String, String> = serde_json::from_str(&text)
+                    .map_err(|e| format!("invalid checkpoint file {}: {}", legacy.display(), e))?;
+                for (channel, xml) in &legacy_bookmarks {
+                    bookmarks.insert(channel.as_str(), xml.as_str())?;
+                }
+                Some(legacy_bookmarks.len())
+            }
+            _ => None,
+        }
+    };
+    txn.commit()?;
+    if let Some(count) = imported {
+        let mut done = legacy.as_os_str().to_owned();
+        done.push(".imported");
+        std::fs::rename(legacy, &done)?;
+        info!(
+            "Imported {} bookmarks from {} into {}",
+            count,
+            legacy.display(),
+            path.display()
+        );
+    }
+
+    let state = Arc::new(State {
+        path: path.to_path_buf(),
+        db,
+        saved: Mutex::new(HashMap::new()),
+    });
+    *open = Some(Arc::clone(&state));
+    Ok(state)
+}
+
+#[cfg(feature = "state")]
+impl State {
+    pub fn bookmark(&self, channel: &str) -> Option<String> {
+        let txn = self.db.begin_read().ok()?;
+        let table = txn.open_table(BOOKMARKS).ok()?;
+        let xml = table.get(channel).ok()??;
+        Some(xml.value().to_string())
+    }
+
+    /// Saves a channel's bookmark and, with it, the EventRecordID of the
+    /// newest event written.
+    pub fn set_bookmark(
+        &self,
+        channel: &str,
+        xml: &str,
+        high_water: Option<u64>,
+    ) -> Result<(), redb::Error> {
+        let txn = self.db.begin_write()?;
+        txn.open_table(BOOKMARKS)?.insert(channel, xml)?;
+        if let Some(record_id) = high_water {
+            txn.open_table(HIGH_WATER)?.insert(channel, record_id)?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    pub fn high_water(&self, channel: &str) -> Option<u64> {
+        let txn = self.db.begin_read().ok()?;
+        let table = txn.open_table(HIGH_WATER).ok()?;
+        let record_id = table.get(channel).ok()??;
+        Some(record_id.value())
+    }
+
+    /// Adds what the channels counted since the last call to the totals.
+    pub fn save_counters(&self, stats: &Stats) -> Result<(), redb::Error> {
+        let mut saved = self.saved.lock().unwrap();
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(COUNTERS)?;
+            for (channel, counters) in stats.snapshot() {
+                for (name, value) in [
+                    ("events", counters.events()),
+                    ("dropped", counters.dropped()),
+                    ("write_errors", counters.write_errors()),
+                ] {
+                    let key = format!("{}/{}", channel, name);
+                    let last = saved.insert(key.clone(), value).unwrap_or(0);
+                    // Smaller than before: a new set of stats, e.g. the next
+                    // self-test pass, counting from zero
+                    let added = value.checked_sub(last).unwrap_or(value);
+                    if added > 0 {
+                        let total = table.get(key.as_str())?.map_or(0, |v| v.value());
+                        table.insert(key.as_str(), total + added)?;
+                    }
+                }
+            }
+        }
+        txn.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "state")]
+/// Prints what the state database at `path` holds as JSON: each channel's
+/// bookmark (and the EventRecordID it points at), high-water mark and
+/// counters.
+pub fn show(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if !path.exists() {
+        return Err(format!("no state file at {}", path.display()).into());
+    }
+    let db = ReadOnlyDatabase::open(path).map_err(|e| match e {
+        DatabaseError::DatabaseAlreadyOpen => format!(
+            "state file {} is in use by a running collector; stop it first",
+            path.display()
+        ),
+        e => format!("cannot open state file {}: {}", path.display(), e),
+    })?;
+    let txn = db.begin_read()?;
+
+    let mut channels: BTreeMap<String, Map<String, JsonValue>> = BTreeMap::new();
+    for entry in txn.open_table(BOOKMARKS)?.iter()? {
+        let (channel, xml) = entry?;
+        let channel = channels.entry(channel.value().to_string()).or_default();
+        channel.insert("record_id".to_string(), json!(record_id(xml.value())));
+        channel.insert("bookmark".to_string(), json!(xml.value()));
+    }
+    // Missing from databases of older versions
+    if let Ok(table) = txn.open_table(HIGH_WATER) {
+        for entry in table.iter()? {
+            let (channel, record_id) = entry?;
+            channels
+                .entry(channel.value().to_string())
+                .or_default()
+                .insert("high_water".to_string(), json!(record_id.value()));
+        }
+    }
+    for entry in txn.open_table(COUNTERS)?.iter()? {
+        let (key, total) = entry?;
+        if let Some((channel, name)) = key.value().rsplit_once('/') {
+            channels
+                .entry(channel.to_string())
+                .or_default()
+                .insert(name.to_string(), json!(total.value()));
+        }
+    }
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&json!({
+            "path": path,
+            "channels": channels,
+        }))?
+    );
+    Ok(())
+}
+
+#[cfg(feature = "state")]
+/// Checks the state database at `path` for corruption (repairing it when
+/// redb can) and that every bookmark can be resumed from. Returns what was
+/// found, or why the file can't be used.
+pub fn check(path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+    let mut db = Database::open(path).map_err(|e| match e {
+        DatabaseError::DatabaseAlreadyOpen => format!(
+            "state file {} is in use by a running collector; stop it to check the file",
+            path.display()
+        ),
+        e => format!("cannot open state file {}: {}", path.display(), e),
+    })?;
+    let repaired = !db
+        .check_integrity()
+        .map_err(|e| format!("state file {} is corrupt: {}", path.display(), e))?;
+
+    let txn = db.begin_read()?;
+    let mut bookmarks = 0;
+    let mut unreadable = Vec::new();
+    for entry in txn.open_table(BOOKMARKS)?.iter()? {
+        let (channel, xml) = entry?;
+        bookmarks += 1;
+        if record_id(xml.value()).is_none() {
+            unreadable.push(channel.value().to_string());
+        }
+    }
+    if !unreadable.is_empty() {
+        return Err(format!(
+            "unreadable bookmarks for {}; those channels will read from the oldest event again",
+            unreadable.join(", ")
+        )
+        .into());
+    }
+    Ok(format!(
+        "{} bookmarks{}",
+        bookmarks,
+        if repaired { ", file was repaired" } else { "" }
+    ))
+}
+
+/// The EventRecordID a bookmark's XML points at.
+pub fn record_id(xml: &str) -> Option<u64> {
+    let doc = roxmltree::Document::parse(xml).ok()?;
+    doc.descendants()
+        .find(|n| n.has_tag_name("Bookmark"))?
+        .attribute("RecordId")?
+        .parse()
+        .ok()
+}
+
+// Without the "state" feature there is no database: --once and scheduled
+// runs fail to start, and live monitoring never gets a State
+#[cfg(not(feature = "state"))]
+pub enum State {}
+
+#[cfg(not(feature = "state"))]
+impl State {
+    pub fn bookmark(&self, _channel: &str) -> Option<String> {
+        match *self {}
+    }
+
+    pub fn set_bookmark(
+        &self,
+        _channel: &str,
+        _xml: &str,
+        _high_water: Option<u64>,
+    ) -> Result<(), std::io::Error> {
+        match *self {}
+    }
+
+    pub fn high_water(&self, _channel: &str) -> Option<u64> {
+        match *self {}
+    }
+
+    pub fn save_counters(&self, _stats: &Stats) -> Result<(), std::io::Error> {
+        match *self {}
+    }
+}
+
+#[cfg(not(feature = "state"))]
+const DISABLED: &str = "checkpoints (--once, schedule) require a build with the 'state' feature";
+
+#[cfg(not(feature = "state"))]
+pub fn open(_path: &Path, _legacy: &Path) -> Result<Arc<State>, Box<dyn std::error::Error>> {
+    Err(DISABLED.into())
+}
+
+#[cfg(not(feature = "state"))]
+pub fn show(_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    Err(DISABLED.into())
+}
+
+#[cfg(not(feature = "state"))]
+pub fn check(_path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+    Err(DISABLED.into())
+}
+
+#[cfg(all(test, feature = "state"))]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn temp(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "rs-wineventlog-state-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn bookmark(channel: &str, record_id: u64) -> String {
+        format!(
+            "<BookmarkList><Bookmark Channel='{}' RecordId='{}' IsCurrent='true'/></BookmarkList>",
+            channel, record_id
+        )
+    }
+
+    #[test]
+    fn keeps_bookmarks_and_high_water() {
+        let state = open(&temp("bookmarks.redb"), &temp("bookmarks.json")).unwrap();
+        assert_eq!(state.bookmark("Security"), None);
+        assert_eq!(state.high_water("Security"), None);
+
+        state
+            .set_bookmark("Security", &bookmark("Security", 42), Some(42))
+            .unwrap();
+        // Saved without a record ID (a structured query): the mark stays
+        state
+            .set_bookmark("Security", &bookmark("Security", 43), None)
+            .unwrap();
+        let saved = state.bookmark("Security").unwrap();
+        assert_eq!(record_id(&saved), Some(43));
+        assert_eq!(state.high_water("Security"), Some(42));
+    }
+
+    #[test]
+    fn imports_legacy_checkpoints() {
+        let legacy = temp("import.json");
+        let mut imported = legacy.as_os_str().to_owned();
+        imported.push(".imported");
+        let _ = std::fs::remove_file(&imported);
+        let checkpoints = json!({
+            "Application": bookmark("Application", 7),
+            "System": bookmark("System", 9),
+        });
+        std::fs::write(&legacy, checkpoints.to_string()).unwrap();
+
+        let state = open(&temp("import.redb"), &legacy).unwrap();
+        let record = |channel| state.bookmark(channel).and_then(|xml| record_id(&xml));
+        assert_eq!(record("Application"), Some(7));
+        assert_eq!(record("System"), Some(9));
+        assert!(!legacy.exists());
+        assert!(Path::new(&imported).exists());
+    }
+
+    #[test]
+    fn invalid_legacy_checkpoints_are_an_error() {
+        let legacy = temp("invalid.json");
+        std::fs::write(&legacy, "not json").unwrap();
+
+        let err = open(&temp("invalid.redb"), &legacy).err().unwrap();
+        assert!(err.to_string().contains("invalid checkpoint file"));
+        assert!(legacy.exists());
+    }
+
+    #[test]
+    fn adds_counters_since_last_save() {
+        let state = open(&temp("counters.redb"), &temp("counters.json")).unwrap();
+        let stats = Stats::default();
+        let counters = stats.channel("Application");
+        counters.record(Duration::ZERO);
+        counters.record(Duration::ZERO);
+        state.save_counters(&stats).unwrap();
+        counters.record(Duration::ZERO);
+        counters.drop_event();
+        state.save_counters(&stats).unwrap();
+        // Nothing new since the last save
+        state.save_counters(&stats).unwrap();
+
+        let txn = state.db.begin_read().unwrap();
+        let table = txn.open_table(COUNTERS).unwrap();
+        let total = |key| table.get(key).unwrap().map(|v| v.value());
+        assert_eq!(total("Application/events"), Some(3));
+        assert_eq!(total("Application/dropped"), Some(1));
+        assert_eq!(total("Application/write_errors"), None);
+    }
+}
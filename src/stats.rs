@@ -0,0 +1,202 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+pub struct ChannelStats {
+    events: AtomicU64,
+    lag_micros: AtomicU64,
+    // TimeCreated of the last written event to the time it was written
+    collection_lag_ms: AtomicU64,
+    // Events read from the subscription but not yet handed to the sink
+    queued: AtomicU64,
+    write_errors: AtomicU64,
+    // Events dropped by max_events_per_sec
+    dropped: AtomicU64,
+    // EventRecordID of the last written event, 0 before the first
+    last_record: AtomicU64,
+}
+
+impl ChannelStats {
+    /// Counts one written event. `lag` is the time between the event being
+    /// read from the subscription and the sink accepting it.
+    pub fn record(&self, lag: Duration) {
+        self.events.fetch_add(1, Ordering::Relaxed);
+        self.lag_micros
+            .store(lag.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_collection_lag(&self, lag: Duration) {
+        self.collection_lag_ms
+            .store(lag.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_queued(&self, queued: u64) {
+        self.queued.store(queued, Ordering::Relaxed);
+    }
+
+    pub fn set_last_record(&self, record_id: u64) {
+        self.last_record.store(record_id, Ordering::Relaxed);
+    }
+
+    pub fn write_error(&self) {
+        self.write_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn drop_event(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn events(&self) -> u64 {
+        self.events.load(Ordering::Relaxed)
+    }
+
+    pub fn lag(&self) -> Duration {
+        Duration::from_micros(self.lag_micros.load(Ordering::Relaxed))
+    }
+
+    pub fn collection_lag(&self) -> Duration {
+        Duration::from_millis(self.collection_lag_ms.load(Ordering::Relaxed))
+    }
+
+    pub fn queued(&self) -> u64 {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    pub fn write_errors(&self) -> u64 {
+        self.write_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn last_record(&self) -> Option<u64> {
+        Some(self.last_record.load(Ordering::Relaxed)).filter(|&id| id > 0)
+    }
+}
+
+#[derive(Default)]
+pub struct Stats {
+    channels: Mutex<BTreeMap<String, Arc<ChannelStats>>>,
+}
+
+impl Stats {
+    pub fn channel(&self, name: &str) -> Arc<ChannelStats> {
+        let mut channels = self.channels.lock().unwrap();
+        Arc::clone(channels.entry(name.to_string()).or_default())
+    }
+
+    pub fn snapshot(&self) -> Vec<(String, Arc<ChannelStats>)> {
+        self.channels
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, s)| (name.clone(), Arc::clone(s)))
+            .collect()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.snapshot().iter().map(|(_, s)| s.events()).sum()
+    }
+
+    pub fn queued(&self) -> u64 {
+        self.snapshot().iter().map(|(_, s)| s.queued()).sum()
+    }
+
+    /// The furthest behind any channel is.
+    pub fn collection_lag(&self) -> Duration {
+        self.snapshot()
+            .iter()
+            .map(|(_, s)| s.collection_lag())
+            .max()
+            .unwrap_or_default()
+    }
+
+    pub fn write_errors(&self) -> u64 {
+        self.snapshot().iter().map(|(_, s)| s.write_errors()).sum()
+    }
+}
+
+// The status line as last drawn, empty while none is shown; log writes
+// clear it and draw it again below themselves
+static STATUS: Mutex<String> = Mutex::new(String::new());
+
+/// Writes log records to stderr around the status line: the line is cleared
+/// before each record and redrawn after it, so the two don't interleave.
+pub struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let status = STATUS.lock().unwrap_or_else(|e| e.into_inner());
+        let mut stderr = std::io::stderr().lock();
+        if !status.is_empty() {
+            write!(stderr, "\r{}\r", " ".repeat(status.len()))?;
+        }
+        stderr.write_all(buf)?;
+        if !status.is_empty() && buf.ends_with(b"\n") {
+            write!(stderr, "{}", status)?;
+        }
+        stderr.flush()?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()
+    }
+}
+
+/// Renders a single, periodically refreshed status line on stderr until
+/// shutdown is requested.
+pub fn spawn_status_line(stats: Arc<Stats>, shutdown: Arc<AtomicBool>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut last_total = stats.total();
+        let mut last_tick = Instant::now();
+
+        while !shutdown.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(250));
+            if last_tick.elapsed() < Duration::from_secs(1) {
+                continue;
+            }
+
+            let snapshot = stats.snapshot();
+            let total: u64 = snapshot.iter().map(|(_, s)| s.events()).sum();
+            let rate = (total - last_total) as f64 / last_tick.elapsed().as_secs_f64();
+            let lag = snapshot
+                .iter()
+                .map(|(_, s)| s.lag())
+                .max()
+                .unwrap_or_default();
+            let per_channel: Vec<String> = snapshot
+                .iter()
+                .map(|(name, s)| format!("{}={}", name, s.events()))
+                .collect();
+
+            let line = format!(
+                "{:.1} ev/s | total {} | lag {}ms | {}",
+                rate,
+                total,
+                lag.as_millis(),
+                per_channel.join(" ")
+            );
+            let mut status = STATUS.lock().unwrap_or_else(|e| e.into_inner());
+            let pad = status.len().saturating_sub(line.len());
+            eprint!("\r{}{}", line, " ".repeat(pad));
+            let _ = std::io::stderr().flush();
+
+            *status = line;
+            drop(status);
+            last_total = total;
+            last_tick = Instant::now();
+        }
+
+        let mut status = STATUS.lock().unwrap_or_else(|e| e.into_inner());
+        if !status.is_empty() {
+            eprintln!();
+            status.clear();
+        }
+    })
+}
@@ -0,0 +1,108 @@
+use crate::hub;
+use serde_json::{Map, Value as JsonValue};
+
+pub const CHANNEL: &str = "Microsoft-Windows-Sysmon/Operational";
+
+// Sysmon event IDs and their names in the Sysmon schema
+const EVENT_TYPES: [(u32, &str); 29] = [
+    (1, "ProcessCreate"),
+    (2, "FileCreateTime"),
+    (3, "NetworkConnect"),
+    (4, "SysmonServiceStateChange"),
+    (5, "ProcessTerminate"),
+    (6, "DriverLoad"),
+    (7, "ImageLoad"),
+    (8, "CreateRemoteThread"),
+    (9, "RawAccessRead"),
+    (10, "ProcessAccess"),
+    (11, "FileCreate"),
+    (12, "RegistryAddOrDelete"),
+    (13, "RegistryValueSet"),
+    (14, "RegistryRename"),
+    (15, "FileCreateStreamHash"),
+    (16, "SysmonConfigStateChange"),
+    (17, "PipeCreated"),
+    (18, "PipeConnected"),
+    (19, "WmiEventFilter"),
+    (20, "WmiEventConsumer"),
+    (21, "WmiEventConsumerToFilter"),
+    (22, "DnsQuery"),
+    (23, "FileDelete"),
+    (24, "ClipboardChange"),
+    (25, "ProcessTampering"),
+    (26, "FileDeleteDetected"),
+    (27, "FileBlockExecutable"),
+    (28, "FileBlockShredding"),
+    (29, "FileExecutableDetected"),
+];
+
+/// Normalizes the EventData of Sysmon events: adds `SysmonEventType` (e.g.
+/// `ProcessCreate`), splits `Hashes` into an object keyed by algorithm,
+/// turns `*UtcTime` fields into RFC 3339, makes IDs and ports numbers and
+/// `true`/`false` booleans, and drops `RuleName` when no rule matched.
+/// Other channels are left alone.
+pub fn parse(event: &mut JsonValue) {
+    if !hub::channel(event).is_some_and(|c| c.eq_ignore_ascii_case(CHANNEL)) {
+        return;
+    }
+    let event_type = hub::event_id(event)
+        .and_then(|id| EVENT_TYPES.iter().find(|(i, _)| *i == id))
+        .map(|(_, name)| *name);
+    let Some(data) = event.get_mut("EventData").and_then(|d| d.as_object_mut()) else {
+        return;
+    };
+
+    if let Some(name) = event_type {
+        data.insert(
+            "SysmonEventType".to_string(),
+            JsonValue::String(name.to_string()),
+        );
+    }
+    if data
+        .get("RuleName")
+        .and_then(|r| r.as_str())
+        .is_some_and(|r| r.is_empty() || r == "-")
+    {
+        data.remove("RuleName");
+    }
+
+    for (key, value) in data.iter_mut() {
+        let Some(text) = value.as_str() else {
+            continue;
+        };
+        let parsed = if key == "Hashes" || key == "Hash" {
+            hashes(text)
+        } else if key.ends_with("UtcTime") {
+            utc_time(text)
+        } else if key.ends_with("ProcessId") || key.ends_with("ThreadId") || key.ends_with("Port") {
+            text.parse::<u64>().ok().map(JsonValue::from)
+        } else {
+            text.parse::<bool>().ok().map(JsonValue::Bool)
+        };
+        if let Some(parsed) = parsed {
+            *value = parsed;
+        }
+    }
+}
+
+// SHA1=AB..,MD5=CD..,IMPHASH=EF.. -> {"SHA1": "AB..", "MD5": "CD..", ...}
+fn hashes(text: &str) -> Option<JsonValue> {
+    let mut map = Map::new();
+    for pair in text.split(',') {
+        let (algorithm, hash) = pair.split_once('=')?;
+        map.insert(
+            algorithm.trim().to_uppercase(),
+            JsonValue::String(hash.trim().to_string()),
+        );
+    }
+    Some(JsonValue::Object(map))
+}
+
+// Sysmon writes "2024-05-01 12:34:56.789" in UTC
+fn utc_time(text: &str) -> Option<JsonValue> {
+    let time = chrono::NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S%.f").ok()?;
+    Some(JsonValue::String(
+        time.and_utc()
+            .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+    ))
+}
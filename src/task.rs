@@ -0,0 +1,238 @@
+use log::info;
+use std::io::{BufRead, IsTerminal};
+use std::process::Command;
+use std::time::Duration;
+use windows::Win32::System::Com::{
+    CLSCTX_INPROC_SERVER, COINIT_MULTITHREADED, CoCreateInstance, CoInitializeEx,
+};
+use windows::Win32::System::Console::{
+    CONSOLE_MODE, ENABLE_ECHO_INPUT, GetConsoleMode, GetStdHandle, STD_INPUT_HANDLE, SetConsoleMode,
+};
+use windows::Win32::System::TaskScheduler::{
+    ITaskService, TASK_CREATE_OR_UPDATE, TASK_LOGON_PASSWORD, TASK_LOGON_SERVICE_ACCOUNT,
+    TaskScheduler,
+};
+use windows::Win32::System::Variant::VARIANT;
+use windows::core::BSTR;
+
+pub const DEFAULT_NAME: &str = "rs-wineventlog";
+
+// Accounts Task Scheduler logs on without a password
+const BUILTIN_ACCOUNTS: [&str; 3] = ["SYSTEM", "LOCAL SERVICE", "NETWORK SERVICE"];
+
+/// Registers a Task Scheduler task running the collector with highest
+/// privileges as `user`: monitoring from boot, or with `every` a --once
+/// catch-up run at that interval. A lighter alternative to the service.
+/// Accounts other than the built-in ones need their password, which is
+/// prompted for (or read from stdin when it is piped) and handed to the
+/// Task Scheduler API, never put on a command line.
+pub fn install(
+    name: &str,
+    every: Option<Duration>,
+    user: &str,
+    config: Option<String>,
+    profile: Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let exe = std::env::current_exe()?;
+    let mut arguments = crate::service::arguments(config, profile)?;
+
+    let trigger = match every {
+        Some(interval) => {
+            let minutes = minutes(interval)?;
+            arguments.push_str(" --once");
+            format!(
+                "<TimeTrigger><StartBoundary>{}</StartBoundary>\
+                 <Repetition><Interval>PT{}M</Interval></Repetition></TimeTrigger>",
+                chrono::Local::now().format("%Y-%m-%dT%H:%M:%S"),
+                minutes
+            )
+        }
+        None => "<BootTrigger/>".to_string(),
+    };
+    let account = user
+        .strip_prefix("NT AUTHORITY\\")
+        .unwrap_or(user)
+        .to_uppercase();
+    let password = if BUILTIN_ACCOUNTS.contains(&account.as_str()) {
+        None
+    } else {
+        Some(read_password(user)?)
+    };
+    let logon = if password.is_some() {
+        "Password"
+    } else {
+        "ServiceAccount"
+    };
+
+    // A second start while one run is still going is skipped; a crashed
+    // monitor is restarted like the service would be
+    let xml = format!(
+        r#"<?xml version="1.0" encoding="UTF-16"?>
+<Task version="1.2" xmlns="http://schemas.microsoft.com/windows/2004/02/mit/task">
+  <RegistrationInfo>
+    <Description>Monitors Windows Event Log channels and exports events as JSON.</Description>
+  </RegistrationInfo>
+  <Triggers>{trigger}</Triggers>
+  <Principals>
+    <Principal id="Author">
+      <UserId>{user}</UserId>
+      <LogonType>{logon}</LogonType>
+      <RunLevel>HighestAvailable</RunLevel>
+    </Principal>
+  </Principals>
+  <Settings>
+    <MultipleInstancesPolicy>IgnoreNew</MultipleInstancesPolicy>
+    <DisallowStartIfOnBatteries>false</DisallowStartIfOnBatteries>
+    <StopIfGoingOnBatteries>false</StopIfGoingOnBatteries>
+    <StartWhenAvailable>true</StartWhenAvailable>
+    <ExecutionTimeLimit>PT0S</ExecutionTimeLimit>
+    <RestartOnFailure>
+      <Interval>PT1M</Interval>
+      <Count>3</Count>
+    </RestartOnFailure>
+  </Settings>
+  <Actions Context="Author">
+    <Exec>
+      <Command>{command}</Command>
+      <Arguments>{arguments}</Arguments>
+    </Exec>
+  </Actions>
+</Task>
+"#,
+        user = escape(user),
+        command = escape(&exe.display().to_string()),
+        arguments = escape(arguments.trim_start()),
+    );
+
+    register(name, &xml, user, password.as_deref())
+        .map_err(|e| format!("could not create task '{}': {}", name, e))?;
+
+    match every {
+        Some(interval) => info!(
+            "Installed task '{}' running every {}m as {}",
+            name,
+            interval.as_secs() / 60,
+            user
+        ),
+        None => info!("Installed task '{}' running at boot as {}", name, user),
+    }
+    Ok(())
+}
+
+pub fn uninstall(name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    // A monitor started by the task keeps running after it is deleted
+    let _ = Command::new("schtasks")
+        .args(["/End", "/TN", name])
+        .output();
+    let status = Command::new("schtasks")
+        .args(["/Delete", "/F", "/TN", name])
+        .status()?;
+    if !status.success() {
+        return Err(format!("schtasks could not delete task '{}'", name).into());
+    }
+    info!("Uninstalled task '{}'", name);
+    Ok(())
+}
+
+// The --every interval in minutes, which is what the task repeats in
+fn minutes(every: Duration) -> Result<u64, String> {
+    if every < Duration::from_secs(60) {
+        return Err("--every must be at least 1m".to_string());
+    }
+    if !every.as_secs().is_multiple_of(60) || every.subsec_nanos() != 0 {
+        return Err(format!(
+            "--every must be a whole number of minutes, not {}s",
+            every.as_secs_f64()
+        ));
+    }
+    Ok(every.as_secs() / 60)
+}
+
+// Registered through the Task Scheduler API rather than schtasks, so the
+// password stays off every command line
+fn register(
+    name: &str,
+    xml: &str,
+    user: &str,
+    password: Option<&str>,
+) -> windows::core::Result<()> {
+    unsafe {
+        // Fails harmlessly when COM is already initialized on this thread
+        let _ = CoInitializeEx(None, COINIT_MULTITHREADED);
+        let service: ITaskService = CoCreateInstance(&TaskScheduler, None, CLSCTX_INPROC_SERVER)?;
+        let none = VARIANT::default();
+        service.Connect(&none, &none, &none, &none)?;
+        let folder = service.GetFolder(&BSTR::from("\\"))?;
+        let (password, logon) = match password {
+            Some(password) => (VARIANT::from(password), TASK_LOGON_PASSWORD),
+            None => (VARIANT::default(), TASK_LOGON_SERVICE_ACCOUNT),
+        };
+        folder.RegisterTask(
+            &BSTR::from(name),
+            &BSTR::from(xml),
+            TASK_CREATE_OR_UPDATE.0,
+            &VARIANT::from(user),
+            &password,
+            logon,
+            &none,
+        )?;
+    }
+    Ok(())
+}
+
+// Prompts for the account's password without echoing it, or reads the
+// first line of stdin when it isn't a console
+fn read_password(user: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let stdin = std::io::stdin();
+    let console = stdin.is_terminal();
+    let mut echo = None;
+    if console {
+        eprint!("Password for {}: ", user);
+        unsafe {
+            let input = GetStdHandle(STD_INPUT_HANDLE)?;
+            let mut mode = CONSOLE_MODE::default();
+            if GetConsoleMode(input, &mut mode).is_ok()
+                && SetConsoleMode(input, mode & !ENABLE_ECHO_INPUT).is_ok()
+            {
+                echo = Some((input, mode));
+            }
+        }
+    }
+    let mut password = String::new();
+    let read = stdin.lock().read_line(&mut password);
+    if let Some((input, mode)) = echo {
+        unsafe {
+            let _ = SetConsoleMode(input, mode);
+        }
+    }
+    if console {
+        eprintln!();
+    }
+    read?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err(format!("no password given for {}", user).into());
+    }
+    Ok(password.to_string())
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeats_in_whole_minutes() {
+        assert_eq!(minutes(Duration::from_secs(60)), Ok(1));
+        assert_eq!(minutes(Duration::from_secs(15 * 60)), Ok(15));
+        assert!(minutes(Duration::from_secs(30)).is_err());
+        assert!(minutes(Duration::from_secs(90)).is_err());
+        assert!(minutes(Duration::from_millis(60_500)).is_err());
+    }
+}
@@ -0,0 +1,111 @@
+use crate::config::{Config, Framing, SinkConfig};
+use crate::output::{Framer, Record, Sink};
+use std::io::{self, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+// Used when only batch_max_events is set
+const DEFAULT_BATCH_INTERVAL: Duration = Duration::from_millis(1000);
+
+/// A TCP output that sends events in batches: a batch goes out once it holds
+/// `max_events`, once its oldest event is `max_interval` old (see
+/// `send_if_due`), or on flush.
+pub struct Tcp {
+    stream: TcpStream,
+    addr: String,
+    batch: Framer,
+    oldest: Instant,
+    max_events: usize,
+    max_interval: Duration,
+}
+
+impl Tcp {
+    fn connect(addr: &str, framing: Framing, sink: &SinkConfig) -> io::Result<Tcp> {
+        Ok(Tcp {
+            stream: TcpStream::connect(addr)?,
+            addr: addr.to_string(),
+            batch: Framer::new(framing),
+            oldest: Instant::now(),
+            max_events: sink.batch_max_events.unwrap_or(1).max(1),
+            max_interval: sink
+                .batch_max_interval_ms
+                .map_or(DEFAULT_BATCH_INTERVAL, Duration::from_millis),
+        })
+    }
+
+    fn write_event(&mut self, line: &str) -> io::Result<()> {
+        if self.batch.events() == 0 {
+            self.oldest = Instant::now();
+        }
+        self.batch.push(line)?;
+        if self.batch.events() >= self.max_events {
+            self.send()
+        } else {
+            Ok(())
+        }
+    }
+
+    // A batch that fails to go out stays pending and can keep growing
+    fn send(&mut self) -> io::Result<()> {
+        if self.batch.events() == 0 {
+            return Ok(());
+        }
+        let (stream, addr) = (&mut self.stream, &self.addr);
+        self.batch.write_with(|bytes| {
+            if stream.write_all(bytes).is_err() {
+                // One reconnect attempt, so a restarted receiver doesn't
+                // fail every channel
+                *stream = TcpStream::connect(addr.as_str())?;
+                stream.write_all(bytes)?;
+            }
+            Ok(())
+        })?;
+        self.stream.flush()
+    }
+}
+
+impl Sink for Tcp {
+    fn send_batch(&mut self, batch: &[Record]) -> io::Result<()> {
+        batch.iter().try_for_each(|r| self.write_event(r.line))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+
+    // Sends a partial batch whose oldest event has waited long enough
+    fn send_if_due(&mut self) -> Option<io::Result<()>> {
+        if self.batch.events() > 0 && self.oldest.elapsed() >= self.max_interval {
+            Some(self.send())
+        } else {
+            Some(Ok(()))
+        }
+    }
+
+    // A receiver that went away shows as a pending socket error
+    fn healthcheck(&mut self) -> io::Result<()> {
+        match self.stream.take_error()? {
+            Some(e) => Err(e),
+            None => self.stream.peer_addr().map(|_| ()),
+        }
+    }
+}
+
+/// Opens a `tcp://<host>:<port>` output.
+pub fn create(
+    target: &str,
+    framing: Framing,
+    config: &Config,
+) -> Result<Box<dyn Sink>, Box<dyn std::error::Error>> {
+    let addr = target.strip_prefix("tcp://").unwrap_or(target);
+    let tcp = Tcp::connect(addr, framing, &config.sink)
+        .map_err(|e| format!("cannot connect to output {}: {}", target, e))?;
+    Ok(Box::new(tcp))
+}
+
+/// Connects to a `tcp://<host>:<port>` output and hangs up again.
+pub fn probe(target: &str, _config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let addr = target.strip_prefix("tcp://").unwrap_or(target);
+    TcpStream::connect(addr).map_err(|e| format!("cannot connect to output {}: {}", target, e))?;
+    Ok(())
+}
@@ -0,0 +1,228 @@
+use crate::evtapi::{EventLogApi, Handle, Win32};
+use crate::hub;
+use crate::message::{self, Formatted};
+use crate::metadata::Cache;
+use crate::timestamp::{self, Timezone};
+use crate::xml;
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::ValueEnum;
+use serde_json::{Value as JsonValue, json};
+use std::io::{self, BufWriter, Write};
+use windows::Win32::System::EventLog::*;
+use windows::core::HSTRING;
+
+// Events fetched per EvtNext call
+const BATCH: usize = 256;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Format {
+    Csv,
+    Jsonl,
+}
+
+/// Where events come from: live channels or exported .evtx files.
+pub enum Source {
+    Channel(String),
+    File(String),
+}
+
+struct Entry {
+    // FILETIME, for sorting
+    ticks: u64,
+    time: String,
+    channel: String,
+    event_id: Option<u32>,
+    provider: String,
+    computer: String,
+    record_id: Option<u64>,
+    summary: String,
+}
+
+/// Reads the events created between `from` and `to` from every source and
+/// writes them as one timeline, oldest first: time (UTC), channel, EventID,
+/// provider, computer, EventRecordID and a one-line summary.
+pub fn run(
+    sources: &[Source],
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    format: Format,
+    metadata: Option<&Cache>,
+    out: &mut dyn Write,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let query = format!(
+        "*[System[TimeCreated[@SystemTime>='{}' and @SystemTime<='{}']]]",
+        from.to_rfc3339_opts(SecondsFormat::Millis, true),
+        to.to_rfc3339_opts(SecondsFormat::Millis, true)
+    );
+    let mut entries = Vec::new();
+    for source in sources {
+        let (path, flags, name) = match source {
+            Source::Channel(c) => (c, EvtQueryChannelPath, "channel"),
+            Source::File(f) => (f, EvtQueryFilePath, "file"),
+        };
+        read(path, flags, &query, metadata, &mut entries)
+            .map_err(|e| format!("cannot read {} '{}': {}", name, path, e))?;
+    }
+    // Stable, so events with the same time keep their source's order
+    entries.sort_by_key(|e| e.ticks);
+
+    let mut out = BufWriter::new(out);
+    if let Format::Csv = format {
+        writeln!(
+            out,
+            "time,channel,event_id,provider,computer,record_id,summary"
+        )?;
+    }
+    for e in &entries {
+        let event_id = e.event_id.map(|id| id.to_string()).unwrap_or_default();
+        let record_id = e.record_id.map(|id| id.to_string()).unwrap_or_default();
+        match format {
+            Format::Csv => writeln!(
+                out,
+                "{},{},{},{},{},{},{}",
+                e.time,
+                csv(&e.channel),
+                event_id,
+                csv(&e.provider),
+                csv(&e.computer),
+                record_id,
+                csv(&e.summary)
+            )?,
+            Format::Jsonl => writeln!(
+                out,
+                "{}",
+                json!({
+                    "time": e.time,
+                    "channel": e.channel,
+                    "event_id": e.event_id,
+                    "provider": e.provider,
+                    "computer": e.computer,
+                    "record_id": e.record_id,
+                    "summary": e.summary,
+                })
+            )?,
+        }
+    }
+    out.flush()?;
+    eprintln!("{} events from {} sources", entries.len(), sources.len());
+    Ok(())
+}
+
+fn read(
+    path: &str,
+    flags: EVT_QUERY_FLAGS,
+    query: &str,
+    metadata: Option<&Cache>,
+    entries: &mut Vec<Entry>,
+) -> windows::core::Result<()> {
+    let results = Handle(unsafe {
+        EvtQuery(
+            None,
+            &HSTRING::from(path),
+            &HSTRING::from(query),
+            flags.0 | EvtQueryForwardDirection.0,
+        )?
+    });
+    let mut events = [0isize; BATCH];
+    let mut returned = 0u32;
+    // Fails with ERROR_NO_MORE_ITEMS at the end
+    while unsafe { EvtNext(results.0, &mut events, u32::MAX, 0, &mut returned) }.is_ok() {
+        for &event in &events[..returned as usize] {
+            if let Some(entry) = entry(&Handle(EVT_HANDLE(event)), metadata) {
+                entries.push(entry);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn entry(event: &Handle, metadata: Option<&Cache>) -> Option<Entry> {
+    let api = Win32;
+    let v = api
+        .render_values(event)
+        .or_else(|| xml::parse_to_json(&api.render_xml(event)?))?;
+    let ticks = api.time_created(event)?;
+    let provider = hub::provider(&v).unwrap_or_default().to_string();
+    let mut message = api.message(event, &provider, 0);
+    // Messages of providers that aren't installed here, e.g. in .evtx files
+    // from other machines, from the metadata cache
+    if message.is_none()
+        && let Some(cached) = metadata.and_then(|m| m.get(&provider))
+        && let Some(cached_event) = cached.event(&v)
+    {
+        message = cached_event.message.clone().map(Formatted::Partial);
+    }
+    let text = |key: &str| v.get(key).and_then(JsonValue::as_str).unwrap_or_default();
+    Some(Entry {
+        ticks,
+        time: timestamp::format(timestamp::from_filetime(ticks)?, &Timezone::Utc),
+        channel: text("Channel").to_string(),
+        event_id: hub::event_id(&v),
+        computer: text("Computer").to_string(),
+        record_id: hub::record_id(&v),
+        summary: summary(&v, message),
+        provider,
+    })
+}
+
+// The first line of the event's message or, when the provider's messages
+// aren't installed here (common for .evtx files from other machines), its
+// EventData values
+fn summary(event: &JsonValue, formatted: Option<Formatted>) -> String {
+    let text = match formatted.filter(|m| !m.text().trim().is_empty()) {
+        Some(m) => message::complete(event, Some(m)),
+        None => match event.get("EventData") {
+            Some(JsonValue::Object(data)) => data
+                .iter()
+                .map(|(key, value)| {
+                    let value = match value {
+                        JsonValue::String(s) => s.clone(),
+                        JsonValue::Array(items) => items
+                            .iter()
+                            .map(|i| i.as_str().unwrap_or_default())
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                        other => other.to_string(),
+                    };
+                    if key == "Data" {
+                        value
+                    } else {
+                        format!("{}={}", key, value)
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(", "),
+            _ => String::new(),
+        },
+    };
+    text.lines()
+        .map(str::trim)
+        .find(|l| !l.is_empty())
+        .unwrap_or_default()
+        .to_string()
+}
+
+// Quoted when it holds a separator, quote or line break
+fn csv(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Parses a --from/--to time, RFC 3339 like 2024-05-01T08:00:00Z.
+pub fn parse_time(value: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|e| format!("expected an RFC 3339 time like 2024-05-01T08:00:00Z: {}", e))
+}
+
+/// Opens `path` for the timeline, or stdout for "-".
+pub fn output(path: &str) -> io::Result<Box<dyn Write>> {
+    if path == "-" {
+        Ok(Box::new(io::stdout()))
+    } else {
+        Ok(Box::new(std::fs::File::create(path)?))
+    }
+}
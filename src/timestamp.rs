@@ -0,0 +1,102 @@
+use chrono::{DateTime, FixedOffset, Local, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+
+/// Time zone event timestamps are written in: `utc` (the default), `local`
+/// or an IANA name such as `Europe/Berlin`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Timezone {
+    #[default]
+    Utc,
+    Local,
+    Named(Tz),
+}
+
+impl TryFrom<String> for Timezone {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        match name.to_lowercase().as_str() {
+            "utc" | "z" => Ok(Timezone::Utc),
+            "local" => Ok(Timezone::Local),
+            _ => name
+                .parse()
+                .map(Timezone::Named)
+                .map_err(|_| format!("unknown timezone '{}'", name)),
+        }
+    }
+}
+
+impl From<Timezone> for String {
+    fn from(tz: Timezone) -> String {
+        match tz {
+            Timezone::Utc => "utc".to_string(),
+            Timezone::Local => "local".to_string(),
+            Timezone::Named(tz) => tz.name().to_string(),
+        }
+    }
+}
+
+/// Rewrites TimeCreated in `tz`. Event Log timestamps are UTC already, so
+/// this only does something for local or named time zones.
+pub fn localize(event: &mut JsonValue, tz: &Timezone) {
+    if let Timezone::Utc = tz {
+        return;
+    }
+    relocalize(event, tz);
+}
+
+/// Rewrites TimeCreated in `tz` whatever offset it is in, e.g. for events
+/// written by an earlier run.
+pub fn relocalize(event: &mut JsonValue, tz: &Timezone) {
+    let Some(time) = event.pointer_mut("/TimeCreated/@SystemTime") else {
+        return;
+    };
+    if let Some(utc) = time
+        .as_str()
+        .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+    {
+        *time = JsonValue::String(format(utc.with_timezone(&Utc), tz));
+    }
+}
+
+// FILETIMEs count 100ns ticks since 1601-01-01
+const FILETIME_UNIX_EPOCH: u64 = 116_444_736_000_000_000;
+
+/// Converts a FILETIME (e.g. an event's TimeCreated) to a UTC timestamp.
+pub fn from_filetime(ticks: u64) -> Option<DateTime<Utc>> {
+    let since_epoch = ticks.checked_sub(FILETIME_UNIX_EPOCH)?;
+    Utc.timestamp_opt(
+        (since_epoch / 10_000_000) as i64,
+        (since_epoch % 10_000_000) as u32 * 100,
+    )
+    .single()
+}
+
+/// RFC 3339 with the 100ns precision Windows records, e.g.
+/// `2024-05-01T12:34:56.1234567+02:00` (or `Z` in UTC).
+pub fn format(time: DateTime<Utc>, tz: &Timezone) -> String {
+    match tz {
+        Timezone::Utc => with_ticks(time.fixed_offset(), "Z"),
+        Timezone::Local => {
+            let local = time.with_timezone(&Local).fixed_offset();
+            with_ticks(local, &local.format("%:z").to_string())
+        }
+        Timezone::Named(zone) => {
+            let named = time.with_timezone(zone).fixed_offset();
+            with_ticks(named, &named.format("%:z").to_string())
+        }
+    }
+}
+
+// chrono has no 7-digit fraction specifier
+fn with_ticks(time: DateTime<FixedOffset>, offset: &str) -> String {
+    format!(
+        "{}.{:07}{}",
+        time.format("%Y-%m-%dT%H:%M:%S"),
+        time.timestamp_subsec_nanos() / 100,
+        offset
+    )
+}
@@ -0,0 +1,306 @@
+use crate::config;
+use crate::eventlog::{self, Runtime};
+use crate::hub::{self, Hub, StreamFilter};
+use crate::output::Output;
+use crate::stats::Stats;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+use serde_json::Value as JsonValue;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// Events kept in the viewer; older ones scroll off
+const MAX_EVENTS: usize = 10_000;
+
+struct App {
+    events: VecDeque<Arc<JsonValue>>,
+    // Indices into `events` matching the current filter
+    visible: Vec<usize>,
+    list: ListState,
+    filter: String,
+    editing: bool,
+    follow: bool,
+    detail_scroll: u16,
+    status: Option<String>,
+}
+
+/// Runs the monitor in the background and shows its events in a terminal UI
+/// until the user quits.
+pub fn run(source: &config::Source) -> Result<(), Box<dyn std::error::Error>> {
+    let config = config::load(source)?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let hub = Arc::new(Hub::new(0));
+    let (_, events) = hub.subscribe(MAX_EVENTS, &StreamFilter::default(), 0);
+
+    let monitor = {
+        let shutdown = Arc::clone(&shutdown);
+        thread::spawn(move || {
+            let runtime = Runtime {
+                shutdown,
+                stats: Arc::new(Stats::default()),
+                hub,
+                budget: None,
+            };
+            // No control pipe in the viewer; the sender just has to stay alive
+            let (_requests, control_rx) = mpsc::channel();
+            eventlog::monitor(
+                &config,
+                Output::discard(),
+                false,
+                false,
+                &runtime,
+                &control_rx,
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+        })
+    };
+
+    let mut app = App {
+        events: VecDeque::new(),
+        visible: Vec::new(),
+        list: ListState::default(),
+        filter: String::new(),
+        editing: false,
+        follow: true,
+        detail_scroll: 0,
+        status: None,
+    };
+
+    let mut terminal = ratatui::init();
+    let result = app.run(&mut terminal, &events, monitor);
+    ratatui::restore();
+
+    shutdown.store(true, Ordering::SeqCst);
+    result
+}
+
+impl App {
+    fn run(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        events: &Receiver<Arc<JsonValue>>,
+        monitor: JoinHandle<Result<(), String>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut monitor = Some(monitor);
+        loop {
+            while let Ok(event) = events.try_recv() {
+                self.push(event);
+            }
+
+            if monitor.as_ref().is_some_and(|m| m.is_finished()) {
+                self.status = match monitor.take().unwrap().join() {
+                    Ok(Err(e)) => Some(format!("Monitoring stopped: {}", e)),
+                    _ => Some("Monitoring stopped".to_string()),
+                };
+            }
+
+            terminal.draw(|frame| self.draw(frame))?;
+
+            if event::poll(Duration::from_millis(100))?
+                && let Event::Key(key) = event::read()?
+                && key.kind == KeyEventKind::Press
+            {
+                if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+                    return Ok(());
+                }
+                if self.editing {
+                    match key.code {
+                        KeyCode::Enter => self.editing = false,
+                        KeyCode::Esc => {
+                            self.editing = false;
+                            self.filter.clear();
+                            self.refilter();
+                        }
+                        KeyCode::Backspace => {
+                            self.filter.pop();
+                            self.refilter();
+                        }
+                        KeyCode::Char(c) => {
+                            self.filter.push(c);
+                            self.refilter();
+                        }
+                        _ => {}
+                    }
+                    continue;
+                }
+                match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                    KeyCode::Char('/') => self.editing = true,
+                    KeyCode::Char('f') => self.follow = !self.follow,
+                    KeyCode::Up | KeyCode::Char('k') => self.select_by(-1),
+                    KeyCode::Down | KeyCode::Char('j') => self.select_by(1),
+                    KeyCode::PageUp => self.select_by(-20),
+                    KeyCode::PageDown => self.select_by(20),
+                    KeyCode::Home => self.select_by(isize::MIN / 2),
+                    KeyCode::End => self.select_by(isize::MAX / 2),
+                    KeyCode::Char('J') => self.detail_scroll = self.detail_scroll.saturating_add(1),
+                    KeyCode::Char('K') => self.detail_scroll = self.detail_scroll.saturating_sub(1),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn push(&mut self, event: Arc<JsonValue>) {
+        if self.events.len() == MAX_EVENTS {
+            self.events.pop_front();
+            // Every stored index shifts down by one
+            self.visible.retain(|&i| i > 0);
+            self.visible.iter_mut().for_each(|i| *i -= 1);
+            if let Some(selected) = self.list.selected() {
+                self.list
+                    .select(Some(selected.min(self.visible.len().saturating_sub(1))));
+            }
+        }
+        self.events.push_back(event);
+        if matches(&self.filter, self.events.back().unwrap()) {
+            self.visible.push(self.events.len() - 1);
+            if self.follow {
+                self.list.select(Some(self.visible.len() - 1));
+            }
+        }
+    }
+
+    fn refilter(&mut self) {
+        self.visible = (0..self.events.len())
+            .filter(|&i| matches(&self.filter, &self.events[i]))
+            .collect();
+        self.list.select(self.visible.len().checked_sub(1));
+        self.detail_scroll = 0;
+    }
+
+    fn select_by(&mut self, delta: isize) {
+        if self.visible.is_empty() {
+            return;
+        }
+        let current = self.list.selected().unwrap_or(0) as isize;
+        let last = self.visible.len() as isize - 1;
+        let next = (current + delta).clamp(0, last) as usize;
+        self.list.select(Some(next));
+        self.detail_scroll = 0;
+        // Moving away from the newest event stops auto-scrolling
+        self.follow = next as isize == last;
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [list_area, detail_area, status_area] = Layout::vertical([
+            Constraint::Percentage(55),
+            Constraint::Fill(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let items: Vec<ListItem> = self
+            .visible
+            .iter()
+            .map(|&i| summary(&self.events[i]))
+            .collect();
+        let list = List::new(items)
+            .block(Block::bordered().title(format!(
+                " Events {}/{} ",
+                self.visible.len(),
+                self.events.len()
+            )))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, list_area, &mut self.list);
+
+        let detail = self
+            .list
+            .selected()
+            .and_then(|s| self.visible.get(s))
+            .map(|&i| serde_json::to_string_pretty(&*self.events[i]).unwrap_or_default())
+            .unwrap_or_default();
+        frame.render_widget(
+            Paragraph::new(detail)
+                .block(Block::bordered().title(" Detail "))
+                .wrap(Wrap { trim: false })
+                .scroll((self.detail_scroll, 0)),
+            detail_area,
+        );
+
+        let status = if self.editing {
+            Line::from(vec![
+                Span::styled(" Filter: ", Style::new().fg(Color::Yellow)),
+                Span::raw(format!("{}_", self.filter)),
+            ])
+        } else {
+            let mut text = format!(
+                " q quit  / filter  f follow ({})  ↑↓ select  J/K scroll detail",
+                if self.follow { "on" } else { "off" }
+            );
+            if !self.filter.is_empty() {
+                text.push_str(&format!("  [filter: {}]", self.filter));
+            }
+            if let Some(status) = &self.status {
+                text.push_str(&format!("  {}", status));
+            }
+            Line::from(text)
+        };
+        frame.render_widget(Paragraph::new(status), status_area);
+    }
+}
+
+/// Filter terms are whitespace separated and all must match:
+/// `channel:<name>`, `id:<event id>`, or free text searched in the whole event.
+fn matches(filter: &str, event: &JsonValue) -> bool {
+    let mut text = None;
+    filter.split_whitespace().all(|term| {
+        if let Some(channel) = term.strip_prefix("channel:") {
+            hub::channel(event).is_some_and(|c| c.to_lowercase().contains(&channel.to_lowercase()))
+        } else if let Some(id) = term.strip_prefix("id:") {
+            hub::event_id(event).is_some_and(|e| e.to_string() == id)
+        } else {
+            text.get_or_insert_with(|| event.to_string().to_lowercase())
+                .contains(&term.to_lowercase())
+        }
+    })
+}
+
+fn summary(event: &JsonValue) -> ListItem<'static> {
+    let level = event.get("Level").map(level_name).unwrap_or_default();
+    let message = event
+        .get("Message")
+        .and_then(|m| m.as_str())
+        .and_then(|m| m.lines().next())
+        .unwrap_or_default();
+    let line = format!(
+        "{:<28} {:<24} {:>6} {:<12} {:<30} {}",
+        hub::time_created(event).unwrap_or_default(),
+        hub::channel(event).unwrap_or_default(),
+        hub::event_id(event)
+            .map(|id| id.to_string())
+            .unwrap_or_default(),
+        level,
+        hub::provider(event).unwrap_or_default(),
+        message
+    );
+    ListItem::new(line).style(level_style(hub::level(event)))
+}
+
+fn level_name(level: &JsonValue) -> String {
+    match level {
+        JsonValue::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn level_style(level: Option<config::Level>) -> Style {
+    match level {
+        Some(config::Level::Critical) => {
+            Style::new().fg(Color::Magenta).add_modifier(Modifier::BOLD)
+        }
+        Some(config::Level::Error) => Style::new().fg(Color::Red),
+        Some(config::Level::Warning) => Style::new().fg(Color::Yellow),
+        Some(config::Level::Verbose) => Style::new().fg(Color::DarkGray),
+        _ => Style::new(),
+    }
+}
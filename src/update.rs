@@ -0,0 +1,240 @@
+use crate::{http_client, identity, service};
+use log::{info, warn};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+use ureq::Agent;
+
+pub const LATEST_RELEASE_URL: &str =
+    "https://api.github.com/repos/bdwyertech/rs-wineventlog/releases/latest";
+
+// Release archives are well under this; it only guards against runaway downloads
+const MAX_DOWNLOAD: u64 = 256 * 1024 * 1024;
+
+// Signing certificates must come from this repository's release workflow run
+// for a tag; forks and other repositories of the owner don't qualify
+const SIGNER_IDENTITY: &str =
+    r"^https://github\.com/bdwyertech/rs-wineventlog/\.github/workflows/release\.yml@refs/tags/";
+
+// GitHub release JSON; mirrors must serve the same shape
+#[derive(Deserialize)]
+struct Release {
+    tag_name: String,
+    assets: Vec<Asset>,
+}
+
+#[derive(Deserialize)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+/// Replaces the running binary with the newest release published at
+/// `release_url`, after checking it against the release's checksums.txt and
+/// the checksums' Sigstore signature, which `allow_unsigned` lets go
+/// unchecked when the release has none or cosign isn't installed. A running
+/// service is restarted onto the new binary.
+pub fn self_update(
+    release_url: &str,
+    check_only: bool,
+    force: bool,
+    allow_unsigned: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let agent = http_client::agent(Duration::from_secs(300));
+    let release: Release = serde_json::from_slice(&download(&agent, release_url)?)
+        .map_err(|e| format!("unexpected release metadata from {}: {}", release_url, e))?;
+
+    let current = identity::build_info()["version"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string();
+    if !force && !is_newer(&release.tag_name, &current) {
+        println!("Already up to date ({})", current);
+        return Ok(());
+    }
+    if check_only {
+        println!("Update available: {} -> {}", current, release.tag_name);
+        return Ok(());
+    }
+
+    let arch = if cfg!(target_arch = "aarch64") {
+        "arm64"
+    } else {
+        "amd64"
+    };
+    let suffix = format!("_windows_{}.zip", arch);
+    let asset = |name: &str| release.assets.iter().find(|a| a.name == name);
+    let archive = release
+        .assets
+        .iter()
+        .find(|a| a.name.ends_with(&suffix))
+        .ok_or_else(|| format!("release {} has no *{} archive", release.tag_name, suffix))?;
+    let checksums = asset("checksums.txt")
+        .ok_or_else(|| format!("release {} has no checksums.txt", release.tag_name))?;
+
+    info!("Downloading {}", archive.name);
+    let checksums_text = download(&agent, &checksums.browser_download_url)?;
+    let bundle = match asset("checksums.txt.sigstore.json") {
+        Some(b) => Some(download(&agent, &b.browser_download_url)?),
+        None => None,
+    };
+    verify_signature(&checksums_text, bundle.as_deref(), allow_unsigned)?;
+
+    let data = download(&agent, &archive.browser_download_url)?;
+    let expected = String::from_utf8_lossy(&checksums_text)
+        .lines()
+        .find_map(|line| {
+            let (hash, name) = line.split_once(char::is_whitespace)?;
+            (name.trim().trim_start_matches('*') == archive.name).then(|| hash.to_lowercase())
+        })
+        .ok_or_else(|| format!("checksums.txt has no entry for {}", archive.name))?;
+    let actual = format!("{:x}", Sha256::digest(&data));
+    if actual != expected {
+        return Err(format!(
+            "checksum mismatch for {}: expected {}, got {}",
+            archive.name, expected, actual
+        )
+        .into());
+    }
+
+    let exe = std::env::current_exe()?;
+    replace_binary(&exe, &extract_exe(&data)?)?;
+    println!("Updated {} -> {}", current, release.tag_name);
+
+    if service::restart_if_running()? {
+        println!("Restarted service '{}'", service::SERVICE_NAME);
+    }
+    Ok(())
+}
+
+/// Removes the binary left behind by a previous update; it can't be deleted
+/// while the old process is still running, so this is retried at every start.
+pub fn cleanup() {
+    if let Ok(exe) = std::env::current_exe() {
+        let _ = std::fs::remove_file(old_path(&exe));
+    }
+}
+
+fn download(agent: &Agent, url: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    agent
+        .get(url)
+        .header("User-Agent", "rs-wineventlog")
+        .call()
+        .and_then(|r| {
+            r.into_body()
+                .with_config()
+                .limit(MAX_DOWNLOAD)
+                .read_to_vec()
+        })
+        .map_err(|e| format!("cannot download {}: {}", url, e).into())
+}
+
+// Compares dotted numeric versions, ignoring a leading "v" and any suffix
+// like "-next"; anything unparsable counts as newer when it differs
+fn is_newer(latest: &str, current: &str) -> bool {
+    let parse = |v: &str| -> Option<Vec<u64>> {
+        v.trim_start_matches('v')
+            .split(['-', '+'])
+            .next()?
+            .split('.')
+            .map(|p| p.parse().ok())
+            .collect()
+    };
+    match (parse(latest), parse(current)) {
+        (Some(l), Some(c)) => l > c,
+        _ => latest.trim_start_matches('v') != current.trim_start_matches('v'),
+    }
+}
+
+// Releases sign checksums.txt with a keyless cosign bundle; verifying it needs
+// the cosign CLI
+fn verify_signature(
+    checksums: &[u8],
+    bundle: Option<&[u8]>,
+    allow_unsigned: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(bundle) = bundle else {
+        if !allow_unsigned {
+            return Err(
+                "release has no checksums.txt.sigstore.json signature (--allow-unsigned skips it)"
+                    .into(),
+            );
+        }
+        warn!("Release checksums are not signed; relying on checksums only");
+        return Ok(());
+    };
+
+    let dir = std::env::temp_dir().join(format!("rs-wineventlog-update-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let checksums_path = dir.join("checksums.txt");
+    let bundle_path = dir.join("checksums.txt.sigstore.json");
+    std::fs::write(&checksums_path, checksums)?;
+    std::fs::write(&bundle_path, bundle)?;
+
+    let result = Command::new("cosign")
+        .arg("verify-blob")
+        .arg(&checksums_path)
+        .arg("--bundle")
+        .arg(&bundle_path)
+        .arg("--certificate-identity-regexp")
+        .arg(SIGNER_IDENTITY)
+        .arg("--certificate-oidc-issuer=https://token.actions.githubusercontent.com")
+        .output();
+    let _ = std::fs::remove_dir_all(&dir);
+
+    match result {
+        Ok(output) if output.status.success() => {
+            info!("Verified checksums signature with cosign");
+            Ok(())
+        }
+        Ok(output) => Err(format!(
+            "checksums signature verification failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into()),
+        Err(_) if allow_unsigned => {
+            warn!("cosign not found; checksums verified but their signature was not");
+            Ok(())
+        }
+        Err(e) => Err(format!(
+            "cannot run cosign to verify the signature (--allow-unsigned skips it): {}",
+            e
+        )
+        .into()),
+    }
+}
+
+fn extract_exe(archive: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut zip = zip::ZipArchive::new(Cursor::new(archive))?;
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i)?;
+        if entry.is_file() && entry.name().to_lowercase().ends_with(".exe") {
+            let mut exe = Vec::with_capacity(entry.size() as usize);
+            entry.read_to_end(&mut exe)?;
+            return Ok(exe);
+        }
+    }
+    Err("release archive contains no .exe".into())
+}
+
+// Windows lets a running executable be renamed but not overwritten, so the
+// current one is moved aside before the new one takes its place
+fn replace_binary(exe: &Path, new: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let staged = exe.with_extension("exe.new");
+    let old = old_path(exe);
+    std::fs::write(&staged, new)?;
+    let _ = std::fs::remove_file(&old);
+    std::fs::rename(exe, &old)?;
+    if let Err(e) = std::fs::rename(&staged, exe) {
+        let _ = std::fs::rename(&old, exe);
+        return Err(format!("cannot install new binary: {}", e).into());
+    }
+    Ok(())
+}
+
+fn old_path(exe: &Path) -> PathBuf {
+    exe.with_extension("exe.old")
+}
@@ -0,0 +1,161 @@
+use crate::alert::{self, Alert};
+use crate::config::{self, WebhookConfig};
+use crate::hub;
+use serde_json::{Value as JsonValue, json};
+use std::fmt;
+use std::time::Duration;
+use ureq::Agent;
+
+// Longer messages are cut to keep the card compact
+const MAX_MESSAGE: usize = 1000;
+
+#[derive(Clone, Copy)]
+pub enum Kind {
+    Slack,
+    Teams,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Kind::Slack => "Slack",
+            Kind::Teams => "Teams",
+        })
+    }
+}
+
+/// Posts alerts to a Slack or Teams incoming webhook as a card: a title
+/// with the rule, provider and EventID in the color of the event's level,
+/// the first lines of its message, and the configured key fields.
+pub struct Webhook {
+    kind: Kind,
+    url: String,
+    fields: Vec<String>,
+    agent: Agent,
+}
+
+impl Webhook {
+    pub fn new(kind: Kind, config: &WebhookConfig) -> Webhook {
+        Webhook {
+            kind,
+            url: config.webhook_url.clone(),
+            fields: config.fields.clone(),
+            agent: crate::http_client::agent(Duration::from_secs(30)),
+        }
+    }
+
+    pub fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    pub fn send(&self, alert: &Alert) -> Result<(), Box<dyn std::error::Error>> {
+        let title = match (alert.matches, &alert.group) {
+            (1, _) => alert::expand("{rule}: {Provider} {EventID}", alert),
+            (_, None) => alert::expand("{rule}: {matches} events", alert),
+            (_, Some(_)) => alert::expand("{rule}: {matches} events for {group}", alert),
+        };
+        let message = message(&alert.event);
+        let facts: Vec<(&str, String)> = self
+            .fields
+            .iter()
+            .map(|name| (name.as_str(), alert::field(name, alert)))
+            .filter(|(_, value)| !value.is_empty())
+            .collect();
+        let level = Level::of(&alert.event);
+        let card = match self.kind {
+            Kind::Slack => json!({
+                "text": title,
+                "attachments": [{
+                    "color": level.hex(),
+                    "title": title,
+                    "text": message,
+                    "fields": facts
+                        .iter()
+                        .map(|(name, value)| json!({ "title": name, "value": value, "short": true }))
+                        .collect::<Vec<_>>(),
+                }],
+            }),
+            // Adaptive Card, as taken by Teams workflow webhooks
+            Kind::Teams => json!({
+                "type": "message",
+                "attachments": [{
+                    "contentType": "application/vnd.microsoft.card.adaptive",
+                    "content": {
+                        "$schema": "http://adaptivecards.io/schemas/adaptive-card.json",
+                        "type": "AdaptiveCard",
+                        "version": "1.4",
+                        "body": [
+                            {
+                                "type": "TextBlock",
+                                "text": title,
+                                "weight": "Bolder",
+                                "color": level.adaptive_color(),
+                                "wrap": true,
+                            },
+                            { "type": "TextBlock", "text": message, "wrap": true },
+                            {
+                                "type": "FactSet",
+                                "facts": facts
+                                    .iter()
+                                    .map(|(name, value)| json!({ "title": name, "value": value }))
+                                    .collect::<Vec<_>>(),
+                            },
+                        ],
+                    },
+                }],
+            }),
+        };
+        self.agent
+            .post(&self.url)
+            .header("Content-Type", "application/json")
+            .send(card.to_string())?;
+        Ok(())
+    }
+}
+
+fn message(event: &JsonValue) -> String {
+    let text = event
+        .get("Message")
+        .and_then(JsonValue::as_str)
+        .unwrap_or_default()
+        .trim();
+    match text.char_indices().nth(MAX_MESSAGE) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+enum Level {
+    Critical,
+    Error,
+    Warning,
+    Other,
+}
+
+impl Level {
+    fn of(event: &JsonValue) -> Level {
+        match hub::level(event) {
+            Some(config::Level::Critical) => Level::Critical,
+            Some(config::Level::Error) => Level::Error,
+            Some(config::Level::Warning) => Level::Warning,
+            _ => Level::Other,
+        }
+    }
+
+    fn hex(&self) -> &'static str {
+        match self {
+            Level::Critical => "#8b0000",
+            Level::Error => "#d00000",
+            Level::Warning => "#e8a317",
+            Level::Other => "#439fe0",
+        }
+    }
+
+    fn adaptive_color(&self) -> &'static str {
+        match self {
+            Level::Critical | Level::Error => "Attention",
+            Level::Warning => "Warning",
+            Level::Other => "Accent",
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use crate::hub::{self, Hub, StreamFilter};
+use log::{debug, error, info, warn};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::Duration;
+use tungstenite::handshake::server::{Request, Response};
+use tungstenite::{Message, accept_hdr};
+
+// Events buffered per client before it starts losing events
+const SUBSCRIBER_BUFFER: usize = 1024;
+// Idle clients are pinged so dead connections get noticed and dropped
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Accepts WebSocket clients on `listen` and streams each one the rendered
+/// events matching the filter given in its request URL, e.g.
+/// `ws://127.0.0.1:8765/?channel=Security&event_id=4624,4625`. A `history=N`
+/// parameter first replays up to N buffered events matching the filter.
+pub fn serve(listen: &str, hub: Arc<Hub>) -> Result<(), Box<dyn std::error::Error>> {
+    let listener = TcpListener::bind(listen)?;
+    info!(
+        "WebSocket stream listening on ws://{}",
+        listener.local_addr()?
+    );
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let hub = Arc::clone(&hub);
+                    thread::spawn(move || handle_client(stream, hub));
+                }
+                Err(e) => warn!("WebSocket accept failed: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+// The handshake callback signature (and its large error type) is dictated by tungstenite
+#[allow(clippy::result_large_err)]
+fn handle_client(stream: TcpStream, hub: Arc<Hub>) {
+    let peer = stream
+        .peer_addr()
+        .map(|a| a.to_string())
+        .unwrap_or_default();
+
+    let mut query = String::new();
+    let mut ws = match accept_hdr(stream, |req: &Request, resp: Response| {
+        query = req.uri().query().unwrap_or_default().to_string();
+        Ok(resp)
+    }) {
+        Ok(ws) => ws,
+        Err(e) => {
+            debug!("WebSocket handshake with {} failed: {}", peer, e);
+            return;
+        }
+    };
+
+    let filter = StreamFilter::from_query(&query);
+    let history = hub::query_pairs(&query)
+        .into_iter()
+        .find(|(k, _)| k == "history")
+        .and_then(|(_, v)| v.parse().ok())
+        .unwrap_or(0);
+    let (backlog, events) = hub.subscribe(SUBSCRIBER_BUFFER, &filter, history);
+    info!("WebSocket client {} connected", peer);
+
+    for event in backlog {
+        if ws.send(Message::text(event.to_string())).is_err() {
+            return;
+        }
+    }
+
+    loop {
+        let result = match events.recv_timeout(PING_INTERVAL) {
+            Ok(event) if filter.matches(&event) => ws.send(Message::text(event.to_string())),
+            Ok(_) => continue,
+            Err(RecvTimeoutError::Timeout) => ws.send(Message::Ping(Default::default())),
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if let Err(e) = result {
+            match e {
+                tungstenite::Error::ConnectionClosed | tungstenite::Error::Io(_) => {}
+                e => error!("WebSocket client {}: {}", peer, e),
+            }
+            break;
+        }
+    }
+
+    info!("WebSocket client {} disconnected", peer);
+}
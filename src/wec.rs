@@ -0,0 +1,569 @@
+use log::{info, warn};
+use roxmltree::Document;
+use serde_json::json;
+use windows::Win32::Foundation::ERROR_NO_MORE_ITEMS;
+use windows::Win32::System::EventCollector::*;
+use windows::core::{HSTRING, PCWSTR};
+
+/// Who may forward events to a new subscription when `--allowed-sources`
+/// isn't given: Domain Computers, as in Event Viewer.
+pub const DEFAULT_ALLOWED_SOURCES: &str = "O:NSG:BAD:P(A;;GA;;;DC)S:";
+
+// Schema of wecutil's subscription XML
+const SUBSCRIPTION_NS: &str = "http://schemas.microsoft.com/2006/03/windows/events/subscription";
+
+/// A source-initiated (push) subscription as `wec create` sets it up, from
+/// flags or from a wecutil subscription XML file. What isn't set keeps the
+/// Event Collector's default.
+#[derive(Default)]
+pub struct Subscription {
+    pub name: String,
+    pub description: Option<String>,
+    pub enabled: Option<bool>,
+    pub uri: Option<String>,
+    // Normal, MinLatency, MinBandwidth or Custom (see the Ec* constants)
+    pub configuration_mode: Option<EC_SUBSCRIPTION_CONFIGURATION_MODE>,
+    // Custom mode only
+    pub max_items: Option<u32>,
+    pub max_latency_ms: Option<u32>,
+    pub heartbeat_ms: Option<u32>,
+    // A <QueryList> document
+    pub query: Option<String>,
+    pub read_existing_events: Option<bool>,
+    pub transport: Option<String>,
+    pub content_format: Option<EC_SUBSCRIPTION_CONTENT_FORMAT>,
+    pub locale: Option<String>,
+    pub log_file: Option<String>,
+    pub publisher: Option<String>,
+    // SDDL of the computers allowed to forward events
+    pub allowed_sources: Option<String>,
+}
+
+impl Subscription {
+    /// Reads a subscription file as `wecutil cs` takes it. Collector-initiated
+    /// subscriptions, which name their sources, are left to wecutil.
+    pub fn from_xml(xml: &str) -> Result<Subscription, Box<dyn std::error::Error>> {
+        let doc = Document::parse(xml)?;
+        let root = doc.root_element();
+        if !root.has_tag_name((SUBSCRIPTION_NS, "Subscription")) {
+            return Err("not a subscription file: expected a <Subscription> element".into());
+        }
+        let mut sub = Subscription::default();
+        for node in root.children().filter(|n| n.is_element()) {
+            let text = node.text().unwrap_or_default().trim().to_string();
+            let flag = || text.eq_ignore_ascii_case("true");
+            match node.tag_name().name() {
+                "SubscriptionId" => sub.name = text,
+                "SubscriptionType" if !text.eq_ignore_ascii_case("SourceInitiated") => {
+                    return Err(format!(
+                        "only source-initiated subscriptions are supported, this one is {}",
+                        text
+                    )
+                    .into());
+                }
+                "SubscriptionType" => {}
+                "Description" => sub.description = Some(text),
+                "Enabled" => sub.enabled = Some(flag()),
+                "Uri" => sub.uri = Some(text),
+                "ConfigurationMode" => {
+                    sub.configuration_mode = Some(match text.to_lowercase().as_str() {
+                        "normal" => EcConfigurationModeNormal,
+                        "minlatency" => EcConfigurationModeMinLatency,
+                        "minbandwidth" => EcConfigurationModeMinBandwidth,
+                        "custom" => EcConfigurationModeCustom,
+                        _ => return Err(format!("unknown ConfigurationMode '{}'", text).into()),
+                    })
+                }
+                "Delivery" => {
+                    let descendant = |name: &str| {
+                        node.descendants()
+                            .find(|n| n.has_tag_name((SUBSCRIPTION_NS, name)))
+                    };
+                    let number = |name: &str| descendant(name)?.text()?.trim().parse().ok();
+                    sub.max_items = number("MaxItems");
+                    sub.max_latency_ms = number("MaxLatencyTime");
+                    sub.heartbeat_ms = descendant("Heartbeat")
+                        .and_then(|h| h.attribute("Interval"))
+                        .and_then(|i| i.parse().ok());
+                }
+                "Query" => sub.query = Some(text),
+                "ReadExistingEvents" => sub.read_existing_events = Some(flag()),
+                "TransportName" => sub.transport = Some(text),
+                "ContentFormat" => {
+                    sub.content_format = Some(if text.eq_ignore_ascii_case("Events") {
+                        EcContentFormatEvents
+                    } else {
+                        EcContentFormatRenderedText
+                    })
+                }
+                "Locale" => sub.locale = node.attribute("Language").map(str::to_string),
+                "LogFile" => sub.log_file = Some(text),
+                "PublisherName" => sub.publisher = Some(text),
+                "AllowedSourceDomainComputers" => sub.allowed_sources = Some(text),
+                "AllowedSourceNonDomainComputers" | "CredentialsType" => {
+                    warn!(
+                        "Ignoring {}: not supported, set it with wecutil",
+                        node.tag_name().name()
+                    )
+                }
+                other => warn!("Ignoring unknown subscription setting {}", other),
+            }
+        }
+        if sub.name.is_empty() {
+            return Err("subscription file has no SubscriptionId".into());
+        }
+        Ok(sub)
+    }
+}
+
+/// A <QueryList> selecting `xpath` (or every event) from each channel.
+pub fn query_list(channels: &[String], xpath: Option<&str>) -> String {
+    let selects: String = channels
+        .iter()
+        .map(|channel| {
+            format!(
+                "<Select Path=\"{}\">{}</Select>",
+                escape(channel),
+                escape(xpath.unwrap_or("*"))
+            )
+        })
+        .collect();
+    format!("<QueryList><Query Id=\"0\">{}</Query></QueryList>", selects)
+}
+
+/// Creates the subscription on this collector, or with `replace` updates
+/// one of the same name.
+pub fn create(sub: &Subscription, replace: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let flags = if replace {
+        EC_OPEN_ALWAYS
+    } else {
+        EC_CREATE_NEW
+    };
+    let handle = open(&sub.name, EC_READ_ACCESS | EC_WRITE_ACCESS, flags)?;
+
+    let strings = [
+        (EcSubscriptionDescription, &sub.description),
+        (EcSubscriptionURI, &sub.uri),
+        (EcSubscriptionQuery, &sub.query),
+        (EcSubscriptionTransportName, &sub.transport),
+        (EcSubscriptionLocale, &sub.locale),
+        (EcSubscriptionLogFile, &sub.log_file),
+        (EcSubscriptionPublisherName, &sub.publisher),
+    ];
+    let numbers = [
+        (
+            EcSubscriptionType,
+            Some(EcSubscriptionTypeSourceInitiated.0 as u32),
+        ),
+        (
+            EcSubscriptionConfigurationMode,
+            sub.configuration_mode.map(|m| m.0 as u32),
+        ),
+        (EcSubscriptionDeliveryMaxItems, sub.max_items),
+        (EcSubscriptionDeliveryMaxLatencyTime, sub.max_latency_ms),
+        (EcSubscriptionHeartbeatInterval, sub.heartbeat_ms),
+        (
+            EcSubscriptionContentFormat,
+            sub.content_format.map(|f| f.0 as u32),
+        ),
+    ];
+    let flags = [
+        (EcSubscriptionEnabled, sub.enabled),
+        (EcSubscriptionReadExistingEvents, sub.read_existing_events),
+    ];
+    unsafe {
+        // The allowed sources are only checked against a source-initiated
+        // type, so it goes first
+        for (id, value) in numbers {
+            if let Some(value) = value {
+                set(&handle, id, uint(value))?;
+            }
+        }
+        for (id, value) in strings {
+            if let Some(value) = value {
+                let value = HSTRING::from(value.as_str());
+                set(&handle, id, string(&value))?;
+            }
+        }
+        for (id, value) in flags {
+            if let Some(value) = value {
+                set(&handle, id, boolean(value))?;
+            }
+        }
+        let allowed = HSTRING::from(
+            sub.allowed_sources
+                .as_deref()
+                .unwrap_or(DEFAULT_ALLOWED_SOURCES),
+        );
+        set(
+            &handle,
+            EcSubscriptionAllowedSourceDomainComputers,
+            string(&allowed),
+        )?;
+        EcSaveSubscription(handle.0, 0)
+            .ok()
+            .map_err(|e| format!("cannot save subscription '{}': {}", sub.name, e))?;
+    }
+    info!("Saved subscription '{}'", sub.name);
+    Ok(())
+}
+
+pub fn delete(name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    unsafe { EcDeleteSubscription(&HSTRING::from(name), 0) }
+        .ok()
+        .map_err(|e| format!("cannot delete subscription '{}': {}", name, e))?;
+    info!("Deleted subscription '{}'", name);
+    Ok(())
+}
+
+/// Prints the collector's subscriptions: whether each is enabled and active,
+/// how many sources forward to it and which log it fills.
+pub fn list(as_json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let mut rows = Vec::new();
+    for name in names()? {
+        let handle = open(&name, EC_READ_ACCESS, EC_OPEN_EXISTING)?;
+        let (enabled, source_initiated, description, log_file) = unsafe {
+            (
+                get(&handle, EcSubscriptionEnabled).and_then(|v| v.boolean()),
+                get(&handle, EcSubscriptionType)
+                    .and_then(|v| v.uint())
+                    .map(|t| t == EcSubscriptionTypeSourceInitiated.0 as u32),
+                get(&handle, EcSubscriptionDescription).and_then(|v| v.string()),
+                get(&handle, EcSubscriptionLogFile).and_then(|v| v.string()),
+            )
+        };
+        let (status, sources) = unsafe { runtime_status(&name) };
+        rows.push(json!({
+            "name": name,
+            "enabled": enabled,
+            "type": source_initiated.map(|s| if s { "SourceInitiated" } else { "CollectorInitiated" }),
+            "status": status,
+            "sources": sources,
+            "log_file": log_file,
+            "description": description,
+        }));
+    }
+
+    if as_json {
+        println!("{}", serde_json::to_string_pretty(&rows)?);
+        return Ok(());
+    }
+    println!(
+        "{:<32}  {:<7}  {:<18}  {:<8}  {:>7}  LOG FILE",
+        "NAME", "ENABLED", "TYPE", "STATUS", "SOURCES"
+    );
+    for row in &rows {
+        let field = |key: &str| match &row[key] {
+            serde_json::Value::Null => "-".to_string(),
+            serde_json::Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        println!(
+            "{:<32}  {:<7}  {:<18}  {:<8}  {:>7}  {}",
+            field("name"),
+            field("enabled"),
+            field("type"),
+            field("status"),
+            field("sources"),
+            field("log_file")
+        );
+    }
+    if rows.is_empty() {
+        println!("(no subscriptions)");
+    }
+    Ok(())
+}
+
+// A subscription handle, closed when dropped
+struct Handle(isize);
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        let _ = unsafe { EcClose(self.0) };
+    }
+}
+
+fn open(name: &str, access: u32, flags: u32) -> Result<Handle, Box<dyn std::error::Error>> {
+    match unsafe { EcOpenSubscription(&HSTRING::from(name), access, flags) } {
+        0 => Err(format!(
+            "cannot open subscription '{}': {} (is the Windows Event Collector service running? \
+             wecutil qc sets it up)",
+            name,
+            windows::core::Error::from_thread()
+        )
+        .into()),
+        handle => Ok(Handle(handle)),
+    }
+}
+
+fn names() -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let handle = match unsafe { EcOpenSubscriptionEnum(0) } {
+        0 => {
+            return Err(format!(
+                "cannot list subscriptions: {} (is the Windows Event Collector service running?)",
+                windows::core::Error::from_thread()
+            )
+            .into());
+        }
+        handle => Handle(handle),
+    };
+    let mut names = Vec::new();
+    let mut buffer = vec![0u16; 256];
+    loop {
+        let mut used = 0u32;
+        if unsafe { EcEnumNextSubscription(handle.0, Some(&mut buffer), &mut used) }.as_bool() {
+            let len = (used as usize).saturating_sub(1);
+            names.push(String::from_utf16_lossy(&buffer[..len]));
+            continue;
+        }
+        let error = windows::core::Error::from_thread();
+        if error.code() == ERROR_NO_MORE_ITEMS.to_hresult() {
+            break;
+        }
+        // Grow the buffer for a long name
+        if used as usize > buffer.len() {
+            buffer.resize(used as usize, 0);
+            continue;
+        }
+        return Err(format!("cannot list subscriptions: {}", error).into());
+    }
+    Ok(names)
+}
+
+// A property value, in u64 words so the buffer is aligned for EC_VARIANT
+struct Value(Vec<u64>);
+
+impl Value {
+    fn variant(&self) -> &EC_VARIANT {
+        unsafe { &*(self.0.as_ptr() as *const EC_VARIANT) }
+    }
+
+    fn boolean(&self) -> Option<bool> {
+        let v = self.variant();
+        (v.Type == EcVarTypeBoolean.0 as u32).then(|| unsafe { v.Anonymous.BooleanVal.as_bool() })
+    }
+
+    fn uint(&self) -> Option<u32> {
+        let v = self.variant();
+        (v.Type == EcVarTypeUInt32.0 as u32).then_some(unsafe { v.Anonymous.UInt32Val })
+    }
+
+    fn string(&self) -> Option<String> {
+        let v = self.variant();
+        if v.Type != EcVarTypeString.0 as u32 {
+            return None;
+        }
+        unsafe { v.Anonymous.StringVal.to_string().ok() }
+    }
+}
+
+unsafe fn get(handle: &Handle, id: EC_SUBSCRIPTION_PROPERTY_ID) -> Option<Value> {
+    unsafe {
+        let mut used = 0u32;
+        let _ = EcGetSubscriptionProperty(handle.0, id, 0, 0, std::ptr::null_mut(), &mut used);
+        if used == 0 {
+            return None;
+        }
+        let mut buffer = vec![0u64; (used as usize).div_ceil(8)];
+        EcGetSubscriptionProperty(
+            handle.0,
+            id,
+            0,
+            used,
+            buffer.as_mut_ptr() as *mut EC_VARIANT,
+            &mut used,
+        )
+        .ok()
+        .ok()?;
+        Some(Value(buffer))
+    }
+}
+
+// Whether the subscription is active, and how many sources it has heard from
+unsafe fn runtime_status(name: &str) -> (Option<&'static str>, Option<u32>) {
+    let status = |id| unsafe {
+        let name = HSTRING::from(name);
+        let mut used = 0u32;
+        let _ = EcGetSubscriptionRunTimeStatus(
+            &name,
+            id,
+            PCWSTR::null(),
+            0,
+            0,
+            std::ptr::null_mut(),
+            &mut used,
+        );
+        if used == 0 {
+            return None;
+        }
+        let mut buffer = vec![0u64; (used as usize).div_ceil(8)];
+        EcGetSubscriptionRunTimeStatus(
+            &name,
+            id,
+            PCWSTR::null(),
+            0,
+            used,
+            buffer.as_mut_ptr() as *mut EC_VARIANT,
+            &mut used,
+        )
+        .ok()
+        .ok()?;
+        Some(Value(buffer))
+    };
+    let active = status(EcSubscriptionRunTimeStatusActive)
+        .and_then(|v| v.uint())
+        .map(|s| {
+            [
+                (EcRuntimeStatusActiveStatusActive, "active"),
+                (EcRuntimeStatusActiveStatusDisabled, "disabled"),
+                (EcRuntimeStatusActiveStatusInactive, "inactive"),
+                (EcRuntimeStatusActiveStatusTrying, "trying"),
+            ]
+            .into_iter()
+            .find(|(status, _)| status.0 as u32 == s)
+            .map_or("unknown", |(_, name)| name)
+        });
+    // An array of source names
+    let sources = status(EcSubscriptionRunTimeStatusEventSources).map(|v| v.variant().Count);
+    (active, sources)
+}
+
+unsafe fn set(
+    handle: &Handle,
+    id: EC_SUBSCRIPTION_PROPERTY_ID,
+    mut value: EC_VARIANT,
+) -> Result<(), Box<dyn std::error::Error>> {
+    unsafe { EcSetSubscriptionProperty(handle.0, id, 0, &mut value) }
+        .ok()
+        .map_err(|e| format!("cannot set subscription property {}: {}", id.0, e).into())
+}
+
+fn string(value: &HSTRING) -> EC_VARIANT {
+    EC_VARIANT {
+        Anonymous: EC_VARIANT_0 {
+            StringVal: PCWSTR(value.as_ptr()),
+        },
+        Count: 0,
+        Type: EcVarTypeString.0 as u32,
+    }
+}
+
+fn uint(value: u32) -> EC_VARIANT {
+    EC_VARIANT {
+        Anonymous: EC_VARIANT_0 { UInt32Val: value },
+        Count: 0,
+        Type: EcVarTypeUInt32.0 as u32,
+    }
+}
+
+fn boolean(value: bool) -> EC_VARIANT {
+    EC_VARIANT {
+        Anonymous: EC_VARIANT_0 {
+            BooleanVal: value.into(),
+        },
+        Count: 0,
+        Type: EcVarTypeBoolean.0 as u32,
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query;
+
+    const SUBSCRIPTION: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Subscription xmlns="http://schemas.microsoft.com/2006/03/windows/events/subscription">
+    <SubscriptionId>Security Events</SubscriptionId>
+    <SubscriptionType>SourceInitiated</SubscriptionType>
+    <Description>Logons from every workstation</Description>
+    <Enabled>true</Enabled>
+    <Uri>http://schemas.microsoft.com/wbem/wsman/1/windows/EventLog</Uri>
+    <ConfigurationMode>Custom</ConfigurationMode>
+    <Delivery Mode="Push">
+        <Batching>
+            <MaxItems>5</MaxItems>
+            <MaxLatencyTime>30000</MaxLatencyTime>
+        </Batching>
+        <PushSettings>
+            <Heartbeat Interval="3600000"/>
+        </PushSettings>
+    </Delivery>
+    <Query><![CDATA[<QueryList><Query Id="0"><Select Path="Security">*[System[(EventID=4624)]]</Select></Query></QueryList>]]></Query>
+    <ReadExistingEvents>false</ReadExistingEvents>
+    <TransportName>HTTP</TransportName>
+    <ContentFormat>RenderedText</ContentFormat>
+    <Locale Language="en-US"/>
+    <LogFile>ForwardedEvents</LogFile>
+    <AllowedSourceDomainComputers>O:NSG:BAD:P(A;;GA;;;DC)S:</AllowedSourceDomainComputers>
+</Subscription>"#;
+
+    #[test]
+    fn reads_subscription_files() {
+        let sub = Subscription::from_xml(SUBSCRIPTION).unwrap();
+        assert_eq!(sub.name, "Security Events");
+        assert_eq!(
+            sub.description.as_deref(),
+            Some("Logons from every workstation")
+        );
+        assert_eq!(sub.enabled, Some(true));
+        assert_eq!(sub.configuration_mode, Some(EcConfigurationModeCustom));
+        assert_eq!(sub.max_items, Some(5));
+        assert_eq!(sub.max_latency_ms, Some(30000));
+        assert_eq!(sub.heartbeat_ms, Some(3600000));
+        assert_eq!(
+            query::select_paths(sub.query.as_deref().unwrap()).unwrap(),
+            ["Security"]
+        );
+        assert_eq!(sub.read_existing_events, Some(false));
+        assert_eq!(sub.transport.as_deref(), Some("HTTP"));
+        assert_eq!(sub.content_format, Some(EcContentFormatRenderedText));
+        assert_eq!(sub.locale.as_deref(), Some("en-US"));
+        assert_eq!(sub.log_file.as_deref(), Some("ForwardedEvents"));
+        assert_eq!(
+            sub.allowed_sources.as_deref(),
+            Some(DEFAULT_ALLOWED_SOURCES)
+        );
+    }
+
+    #[test]
+    fn rejects_other_subscriptions() {
+        let collector = SUBSCRIPTION.replace(
+            "<SubscriptionType>SourceInitiated</SubscriptionType>",
+            "<SubscriptionType>CollectorInitiated</SubscriptionType>",
+        );
+        assert!(Subscription::from_xml(&collector).is_err());
+        let unnamed = SUBSCRIPTION.replace("<SubscriptionId>Security Events</SubscriptionId>", "");
+        assert!(Subscription::from_xml(&unnamed).is_err());
+        let mode = SUBSCRIPTION.replace(">Custom<", ">Fastest<");
+        assert!(Subscription::from_xml(&mode).is_err());
+        assert!(Subscription::from_xml("<QueryList/>").is_err());
+    }
+
+    #[test]
+    fn builds_query_lists() {
+        let channels = [
+            "Security".to_string(),
+            "Microsoft-Windows-Sysmon/Operational".to_string(),
+        ];
+        let query = query_list(&channels, Some("*[System[Level<3 and EventID=1]]"));
+        assert_eq!(
+            query,
+            "<QueryList><Query Id=\"0\">\
+             <Select Path=\"Security\">*[System[Level&lt;3 and EventID=1]]</Select>\
+             <Select Path=\"Microsoft-Windows-Sysmon/Operational\">*[System[Level&lt;3 and EventID=1]]</Select>\
+             </Query></QueryList>"
+        );
+        assert_eq!(query::select_paths(&query).unwrap(), channels);
+        assert_eq!(
+            query_list(&channels[..1], None),
+            "<QueryList><Query Id=\"0\"><Select Path=\"Security\">*</Select></Query></QueryList>"
+        );
+    }
+}
@@ -1,49 +1,471 @@
-use roxmltree::Document;
+use quick_xml::Reader;
+use quick_xml::escape::resolve_predefined_entity;
+use quick_xml::events::{BytesStart, Event};
 use serde_json::Value as JsonValue;
-use std::collections::HashMap;
 
+type JsonMap = serde_json::Map<String, JsonValue>;
+
+/// Converts rendered event XML in a single pass over the text, without
+/// building a document tree. System's children become the top-level fields
+/// and EventData is added after them; other sections are skipped.
 pub fn parse_to_json(xml: &str) -> Option<JsonValue> {
-    let doc = Document::parse(xml).ok()?;
-    let root = doc
-        .root_element()
-        .first_element_child()
-        .unwrap_or(doc.root_element());
-    Some(element_to_json(root))
+    parse(xml, false)
+}
+
+/// The same, keeping UserData (as nested objects) after EventData too, for
+/// the parsers that read it.
+pub fn parse_with_user_data(xml: &str) -> Option<JsonValue> {
+    parse(xml, true)
 }
 
-fn element_to_json(node: roxmltree::Node) -> JsonValue {
-    let mut map = serde_json::Map::new();
+fn parse(xml: &str, keep_user_data: bool) -> Option<JsonValue> {
+    let mut reader = Reader::from_str(xml);
+    loop {
+        match reader.read_event().ok()? {
+            Event::Start(_) => break,
+            Event::Empty(_) => return Some(JsonValue::Object(JsonMap::new())),
+            Event::Eof => return None,
+            _ => {}
+        }
+    }
+
+    let mut system = None;
+    let mut event_data = None;
+    let mut user_data = None;
+    let mut qualifiers = None;
+    while let Some((start, empty)) = next_child(&mut reader)? {
+        if system.is_none() {
+            system = Some(element(&mut reader, &start, empty, &mut |child| {
+                if child.local_name().as_ref() == b"EventID" {
+                    qualifiers = attribute(child, "Qualifiers");
+                }
+            })?);
+        } else if start.local_name().as_ref() == b"EventData" {
+            event_data = Some(event_data_to_json(&mut reader, empty)?);
+        } else if keep_user_data && start.local_name().as_ref() == b"UserData" {
+            user_data = Some(element(&mut reader, &start, empty, &mut |_| {})?);
+        } else if !empty {
+            reader.read_to_end(start.name()).ok()?;
+        }
+    }
+
+    let mut json = system.unwrap_or_else(|| JsonValue::Object(JsonMap::new()));
+    if let Some(obj) = json.as_object_mut() {
+        split_event_id(obj, qualifiers);
+        add_activity_ids(obj);
+        if let Some(data) = event_data {
+            obj.insert("EventData".to_string(), data);
+        }
+        if let Some(data) = user_data {
+            obj.insert("UserData".to_string(), data);
+        }
+    }
+    Some(json)
+}
 
-    for attr in node.attributes() {
-        map.insert(
-            format!("@{}", attr.name()),
-            JsonValue::String(attr.value().to_string()),
+// Classic events carry <EventID Qualifiers="16384">7036</EventID>; keep
+// EventID plain text and move the qualifiers to their own field
+fn split_event_id(obj: &mut JsonMap, qualifiers: Option<String>) {
+    if let Some(qualifiers) = qualifiers {
+        insert_after(
+            obj,
+            "EventID",
+            "EventIDQualifiers",
+            JsonValue::String(qualifiers),
         );
     }
+}
 
-    let mut children: HashMap<String, Vec<JsonValue>> = HashMap::new();
-    for child in node.children().filter(|n| n.is_element()) {
-        let name = child.tag_name().name().to_string();
-        children
-            .entry(name)
-            .or_default()
-            .push(element_to_json(child));
+// <Correlation ActivityID="{9E0B...}"/> -> "ActivityID": "9e0b..." next to it
+fn add_activity_ids(obj: &mut JsonMap) {
+    let Some(correlation) = obj.get("Correlation").and_then(JsonValue::as_object) else {
+        return;
+    };
+    let ids: Vec<(&str, String)> = ["ActivityID", "RelatedActivityID"]
+        .into_iter()
+        .filter_map(|attr| {
+            let guid = correlation.get(&format!("@{}", attr))?.as_str()?;
+            Some((attr, normalize_guid(guid)))
+        })
+        .collect();
+    let mut after = "Correlation";
+    for (attr, guid) in ids {
+        insert_after(obj, after, attr, JsonValue::String(guid));
+        after = attr;
     }
+}
+
+pub fn insert_after(obj: &mut JsonMap, after: &str, key: &str, value: JsonValue) {
+    let index = obj
+        .keys()
+        .position(|k| k == after)
+        .map_or(obj.len(), |i| i + 1);
+    obj.shift_insert(index, key.to_string(), value);
+}
 
-    for (k, v) in children {
-        if v.len() == 1 {
-            map.insert(k, v.into_iter().next().unwrap());
+// {9E0B0FA2-...} -> 9e0b0fa2-..., the form tracing systems use
+pub fn normalize_guid(guid: &str) -> String {
+    guid.trim()
+        .trim_start_matches('{')
+        .trim_end_matches('}')
+        .to_ascii_lowercase()
+}
+
+// <Data Name="x">v</Data> becomes "x": "v". Unnamed <Data> elements (classic
+// providers) keep their position in a "Data" array, empty values included.
+fn event_data_to_json(reader: &mut Reader<&[u8]>, empty: bool) -> Option<JsonValue> {
+    let mut map = JsonMap::new();
+    let mut unnamed = Vec::new();
+
+    while let Some((child, empty)) = if empty { None } else { next_child(reader)? } {
+        let value = element(reader, &child, empty, &mut |_| {})?;
+        if child.local_name().as_ref() == b"Data" {
+            let value = match value {
+                JsonValue::String(_) => value,
+                _ => JsonValue::String(String::new()),
+            };
+            match attribute(&child, "Name") {
+                Some(name) => {
+                    map.insert(name, value);
+                }
+                None => unnamed.push(value),
+            }
         } else {
-            map.insert(k, JsonValue::Array(v));
+            map.insert(local_name(&child)?, value);
         }
     }
 
-    if node.children().filter(|n| n.is_element()).count() == 0 {
-        let text = node.text().unwrap_or("").trim();
-        if !text.is_empty() {
-            return JsonValue::String(text.to_string());
+    if !unnamed.is_empty() {
+        map.insert("Data".to_string(), JsonValue::Array(unnamed));
+    }
+    Some(JsonValue::Object(map))
+}
+
+// System fields that are numeric in the event schema
+const NUMERIC_FIELDS: [&str; 6] = [
+    "EventID",
+    "EventIDQualifiers",
+    "Version",
+    "Level",
+    "Task",
+    "EventRecordID",
+];
+
+/// Turns numeric System fields, the process/thread IDs and port-like or
+/// boolean EventData values into JSON numbers and booleans. Values that don't
+/// parse (e.g. a Level already rendered to "Information") stay strings, as
+/// do the EventData values in `typed`, which a template gave their type.
+pub fn coerce_types(event: &mut JsonValue, typed: &[String]) {
+    let Some(obj) = event.as_object_mut() else {
+        return;
+    };
+    for key in NUMERIC_FIELDS {
+        if let Some(value) = obj.get_mut(key) {
+            to_number(value);
+        }
+    }
+    if let Some(execution) = obj.get_mut("Execution").and_then(|e| e.as_object_mut()) {
+        for key in ["@ProcessID", "@ThreadID"] {
+            if let Some(value) = execution.get_mut(key) {
+                to_number(value);
+            }
         }
     }
+    if let Some(data) = obj.get_mut("EventData").and_then(|d| d.as_object_mut()) {
+        for (key, value) in data.iter_mut() {
+            if typed.contains(key) {
+                continue;
+            }
+            if key.ends_with("Port") {
+                to_number(value);
+            } else if let Some(b) = value.as_str().and_then(|s| s.parse::<bool>().ok()) {
+                *value = JsonValue::Bool(b);
+            }
+        }
+    }
+}
+
+fn to_number(value: &mut JsonValue) {
+    if let Some(n) = value.as_str().and_then(|s| s.parse::<u64>().ok()) {
+        *value = JsonValue::from(n);
+    }
+}
 
-    JsonValue::Object(map)
+// Converts the element opened by `start`, reading up to its end tag:
+// attributes become "@name" keys and child elements keys of their own (an
+// array when a name repeats, at its first position). An element holding only
+// text becomes that text. `child` sees each direct child's start tag.
+fn element(
+    reader: &mut Reader<&[u8]>,
+    start: &BytesStart,
+    empty: bool,
+    child: &mut dyn FnMut(&BytesStart),
+) -> Option<JsonValue> {
+    let mut map = JsonMap::new();
+    for attr in start.attributes() {
+        let attr = attr.ok()?;
+        // Namespace declarations aren't part of the event
+        if attr.key.as_namespace_binding().is_some() {
+            continue;
+        }
+        let name = std::str::from_utf8(attr.key.local_name().into_inner()).ok()?;
+        let value = attr.unescape_value().ok()?;
+        map.insert(format!("@{}", name), JsonValue::String(value.into_owned()));
+    }
+    if empty {
+        return Some(JsonValue::Object(map));
+    }
+
+    let mut text = String::new();
+    let mut has_children = false;
+    loop {
+        let (start, empty) = match reader.read_event().ok()? {
+            Event::Start(e) => (e, false),
+            Event::Empty(e) => (e, true),
+            Event::End(_) => break,
+            Event::Eof => return None,
+            Event::Text(t) if !has_children => {
+                text.push_str(&t.decode().ok()?);
+                continue;
+            }
+            Event::CData(t) if !has_children => {
+                text.push_str(&t.decode().ok()?);
+                continue;
+            }
+            Event::GeneralRef(r) if !has_children => {
+                match r.resolve_char_ref().ok()? {
+                    Some(c) => text.push(c),
+                    None => text.push_str(resolve_predefined_entity(&r.decode().ok()?)?),
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        has_children = true;
+        child(&start);
+        let value = element(reader, &start, empty, &mut |_| {})?;
+        let name = local_name(&start)?;
+        match map.get_mut(&name) {
+            Some(JsonValue::Array(items)) => items.push(value),
+            Some(first) => *first = JsonValue::Array(vec![first.take(), value]),
+            None => {
+                map.insert(name, value);
+            }
+        }
+    }
+
+    if !has_children && !text.trim().is_empty() {
+        if text.trim().len() != text.len() {
+            text = text.trim().to_string();
+        }
+        return Some(JsonValue::String(text));
+    }
+    Some(JsonValue::Object(map))
+}
+
+// The next child element's start tag and whether it is self-closing; None at
+// the parent's end tag
+fn next_child<'a>(reader: &mut Reader<&'a [u8]>) -> Option<Option<(BytesStart<'a>, bool)>> {
+    loop {
+        match reader.read_event().ok()? {
+            Event::Start(e) => return Some(Some((e, false))),
+            Event::Empty(e) => return Some(Some((e, true))),
+            Event::End(_) => return Some(None),
+            Event::Eof => return None,
+            _ => {}
+        }
+    }
+}
+
+fn attribute(start: &BytesStart, name: &str) -> Option<String> {
+    let attr = start.try_get_attribute(name).ok()??;
+    Some(attr.unescape_value().ok()?.into_owned())
+}
+
+fn local_name(start: &BytesStart) -> Option<String> {
+    std::str::from_utf8(start.local_name().into_inner())
+        .ok()
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn coerces_untyped_values_only() {
+        let mut event = json!({
+            "EventID": "4624",
+            "Level": "Information",
+            "Execution": { "@ProcessID": "700" },
+            "EventData": {
+                "IpPort": "445",
+                "Elevated": "true",
+                "Comment": "true",
+                "Name": "alice",
+            },
+        });
+        coerce_types(&mut event, &["Comment".to_string()]);
+        assert_eq!(
+            event,
+            json!({
+                "EventID": 4624,
+                "Level": "Information",
+                "Execution": { "@ProcessID": 700 },
+                "EventData": {
+                    "IpPort": 445,
+                    "Elevated": true,
+                    "Comment": "true",
+                    "Name": "alice",
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn splits_qualifiers_and_types_them_only_when_asked() {
+        let xml = r#"<Event><System><Provider Name="Service Control Manager"/><EventID Qualifiers="16384">7036</EventID><Level>4</Level></System></Event>"#;
+        let mut event = parse_to_json(xml).unwrap();
+        assert_eq!(
+            event,
+            json!({
+                "Provider": { "@Name": "Service Control Manager" },
+                "EventID": "7036",
+                "EventIDQualifiers": "16384",
+                "Level": "4",
+            })
+        );
+
+        coerce_types(&mut event, &[]);
+        assert_eq!(event["EventID"], 7036);
+        assert_eq!(event["EventIDQualifiers"], 16384);
+        assert_eq!(event["Level"], 4);
+    }
+
+    const SECURITY: &str = r#"<Event xmlns="http://schemas.microsoft.com/win/2004/08/events/event"><System><Provider Name="Microsoft-Windows-Security-Auditing" Guid="{54849625-5478-4994-A5BA-3E3B0328C30D}"/><EventID>4624</EventID><Version>2</Version><Level>0</Level><Task>12544</Task><Opcode>0</Opcode><Keywords>0x8020000000000000</Keywords><TimeCreated SystemTime="2024-05-01T12:34:56.1234567Z"/><EventRecordID>1234</EventRecordID><Correlation ActivityID="{9E0B0FA2-1B2C-4D5E-8F90-A1B2C3D4E5F6}"/><Execution ProcessID="700" ThreadID="9000"/><Channel>Security</Channel><Computer>HOST</Computer><Security/></System><EventData><Data Name="TargetUserName">alice</Data><Data Name="LogonType">3</Data><Data Name="IpAddress">10.0.0.1</Data><Data Name="IpPort">445</Data></EventData><RenderingInfo Culture="en-US"><Message>An account was successfully logged on.</Message></RenderingInfo></Event>"#;
+
+    // Pretty-printed, as wevtutil and Event Viewer show it
+    const SYSMON: &str = r#"<Event xmlns="http://schemas.microsoft.com/win/2004/08/events/event">
+  <System>
+    <Provider Name="Microsoft-Windows-Sysmon" Guid="{5770385F-C22A-43E0-BF4C-06F5698FFBD9}" />
+    <EventID>1</EventID>
+    <Execution ProcessID="3000" ThreadID="4000" />
+    <Channel>Microsoft-Windows-Sysmon/Operational</Channel>
+    <Security UserID="S-1-5-18" />
+  </System>
+  <EventData>
+    <Data Name="RuleName">-</Data>
+    <Data Name="Image">C:\Windows\System32\cmd.exe</Data>
+    <Data Name="CommandLine">"C:\Windows\system32\cmd.exe" /c echo &lt;a&gt; &amp; b&#x21;</Data>
+    <Data Name="Hashes">SHA256=ABC</Data>
+  </EventData>
+</Event>"#;
+
+    const CLASSIC: &str = r#"<Event xmlns="http://schemas.microsoft.com/win/2004/08/events/event"><System><Provider Name="Service Control Manager" EventSourceName="Service Control Manager"/><EventID Qualifiers="16384">7036</EventID><Channel>System</Channel></System><EventData><Data>Windows Update</Data><Data></Data><Data/><Binary>7700750061007500</Binary></EventData></Event>"#;
+
+    const POWERSHELL: &str = r#"<Event xmlns="http://schemas.microsoft.com/win/2004/08/events/event"><System><Provider Name="Microsoft-Windows-PowerShell"/><EventID>4104</EventID></System><EventData><Data Name="ScriptBlockText"><![CDATA[if ($a -lt 1) { "<b>" }]]></Data><Data Name="Path">a &amp; <![CDATA[<b>]]></Data></EventData></Event>"#;
+
+    const USER_DATA: &str = r#"<Event xmlns="http://schemas.microsoft.com/win/2004/08/events/event"><System><Provider Name="Microsoft-Windows-Eventlog"/><EventID>1102</EventID></System><UserData><LogFileCleared xmlns="http://manifests.microsoft.com/win/2004/08/windows/eventlog"><SubjectUserSid>S-1-5-21-1</SubjectUserSid><SubjectUserName>alice</SubjectUserName><Param>a</Param><Param>b</Param><Options/></LogFileCleared></UserData></Event>"#;
+
+    #[test]
+    fn converts_representative_events() {
+        let security = json!({
+            "Provider": {
+                "@Name": "Microsoft-Windows-Security-Auditing",
+                "@Guid": "{54849625-5478-4994-A5BA-3E3B0328C30D}",
+            },
+            "EventID": "4624",
+            "Version": "2",
+            "Level": "0",
+            "Task": "12544",
+            "Opcode": "0",
+            "Keywords": "0x8020000000000000",
+            "TimeCreated": { "@SystemTime": "2024-05-01T12:34:56.1234567Z" },
+            "EventRecordID": "1234",
+            "Correlation": { "@ActivityID": "{9E0B0FA2-1B2C-4D5E-8F90-A1B2C3D4E5F6}" },
+            "ActivityID": "9e0b0fa2-1b2c-4d5e-8f90-a1b2c3d4e5f6",
+            "Execution": { "@ProcessID": "700", "@ThreadID": "9000" },
+            "Channel": "Security",
+            "Computer": "HOST",
+            "Security": {},
+            "EventData": {
+                "TargetUserName": "alice",
+                "LogonType": "3",
+                "IpAddress": "10.0.0.1",
+                "IpPort": "445",
+            },
+        });
+        let sysmon = json!({
+            "Provider": {
+                "@Name": "Microsoft-Windows-Sysmon",
+                "@Guid": "{5770385F-C22A-43E0-BF4C-06F5698FFBD9}",
+            },
+            "EventID": "1",
+            "Execution": { "@ProcessID": "3000", "@ThreadID": "4000" },
+            "Channel": "Microsoft-Windows-Sysmon/Operational",
+            "Security": { "@UserID": "S-1-5-18" },
+            "EventData": {
+                "RuleName": "-",
+                "Image": r"C:\Windows\System32\cmd.exe",
+                "CommandLine": r#""C:\Windows\system32\cmd.exe" /c echo <a> & b!"#,
+                "Hashes": "SHA256=ABC",
+            },
+        });
+        let classic = json!({
+            "Provider": {
+                "@Name": "Service Control Manager",
+                "@EventSourceName": "Service Control Manager",
+            },
+            "EventID": "7036",
+            "EventIDQualifiers": "16384",
+            "Channel": "System",
+            "EventData": {
+                "Binary": "7700750061007500",
+                "Data": ["Windows Update", "", ""],
+            },
+        });
+        let powershell = json!({
+            "Provider": { "@Name": "Microsoft-Windows-PowerShell" },
+            "EventID": "4104",
+            "EventData": {
+                "ScriptBlockText": r#"if ($a -lt 1) { "<b>" }"#,
+                "Path": "a & <b>",
+            },
+        });
+        let system = json!({
+            "Provider": { "@Name": "Microsoft-Windows-Eventlog" },
+            "EventID": "1102",
+        });
+        let mut user_data = system.clone();
+        user_data["UserData"] = json!({
+            "LogFileCleared": {
+                "SubjectUserSid": "S-1-5-21-1",
+                "SubjectUserName": "alice",
+                "Param": ["a", "b"],
+                "Options": {},
+            },
+        });
+
+        let cases = [
+            ("security", SECURITY, false, security),
+            ("sysmon", SYSMON, false, sysmon),
+            ("classic", CLASSIC, false, classic),
+            ("cdata and entities", POWERSHELL, false, powershell),
+            ("user data dropped", USER_DATA, false, system),
+            ("user data kept", USER_DATA, true, user_data),
+        ];
+        for (name, xml, keep_user_data, expected) in cases {
+            let json = parse(xml, keep_user_data).unwrap();
+            // Compared as text so that field order counts too
+            assert_eq!(json.to_string(), expected.to_string(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_xml() {
+        assert_eq!(parse_to_json(""), None);
+        assert_eq!(parse_to_json("<Event><System><EventID>1</EventID>"), None);
+        assert_eq!(parse_to_json("<Event/>"), Some(json!({})));
+    }
 }